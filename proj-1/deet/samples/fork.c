@@ -0,0 +1,30 @@
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+int child_work(int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) {
+        total += i;
+    }
+    return total;
+}
+
+int main(int argc, char *argv[]) {
+    if (argc > 1) {
+        // Re-executed by the child below
+        printf("exec'd with %s\n", argv[1]);
+        return child_work(3);
+    }
+
+    pid_t pid = fork();
+    if (pid == 0) {
+        printf("child computed %d\n", child_work(5));
+        execl(argv[0], argv[0], "again", NULL);
+        return 1;
+    }
+    int status;
+    waitpid(pid, &status, 0);
+    printf("parent: child exited with %d\n", WEXITSTATUS(status));
+    return 0;
+}
@@ -0,0 +1,8 @@
+#include <stdio.h>
+
+int greet_count = 0;
+
+void greet(const char *name) {
+    greet_count++;
+    printf("Hello, %s! (%d)\n", name, greet_count);
+}
@@ -0,0 +1,7 @@
+#include <stdio.h>
+
+int plugin_run(int x) {
+    int doubled = x * 2;
+    printf("plugin_run(%d) = %d\n", x, doubled);
+    return doubled;
+}
@@ -0,0 +1,20 @@
+#include <dlfcn.h>
+#include <stdio.h>
+
+void greet(const char *name);
+
+int main() {
+    greet("world");
+
+    void *plugin = dlopen("libplugin.so", RTLD_NOW);
+    if (!plugin) {
+        printf("dlopen failed: %s\n", dlerror());
+        return 1;
+    }
+    int (*plugin_run)(int) = (int (*)(int))dlsym(plugin, "plugin_run");
+    plugin_run(21);
+    dlclose(plugin);
+
+    greet("again");
+    return 0;
+}
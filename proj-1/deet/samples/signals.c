@@ -0,0 +1,19 @@
+#include <signal.h>
+#include <stdio.h>
+
+int handled = 0;
+
+void on_usr1(int sig) {
+    handled++;
+}
+
+int main() {
+    signal(SIGUSR1, on_usr1);
+    raise(SIGUSR1);
+    raise(SIGUSR1);
+    printf("handled %d signals\n", handled);
+
+    int *p = NULL;
+    *p = handled;
+    return 0;
+}
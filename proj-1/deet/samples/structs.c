@@ -0,0 +1,42 @@
+#include <stdio.h>
+
+enum color { RED, GREEN, BLUE };
+
+typedef unsigned int uint;
+
+struct point {
+    int x;
+    int y;
+};
+
+struct node {
+    int value;
+    enum color color;
+    struct point position;
+    char name[8];
+    struct node *next;
+};
+
+struct node nodes[3];
+int grid[2][3] = {{1, 2, 3}, {4, 5, 6}};
+
+uint sum_list(struct node *head) {
+    uint sum = 0;
+    for (struct node *n = head; n != NULL; n = n->next) {
+        sum += n->value;
+    }
+    return sum;
+}
+
+int main() {
+    for (int i = 0; i < 3; i++) {
+        nodes[i].value = i * 10;
+        nodes[i].color = (enum color)i;
+        nodes[i].position.x = i;
+        nodes[i].position.y = -i;
+        snprintf(nodes[i].name, sizeof(nodes[i].name), "node%d", i);
+        nodes[i].next = i < 2 ? &nodes[i + 1] : NULL;
+    }
+    printf("sum = %u\n", sum_list(&nodes[0]));
+    return 0;
+}
@@ -0,0 +1,31 @@
+#include <pthread.h>
+#include <stdio.h>
+
+#define NUM_THREADS 3
+
+int counter = 0;
+pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    for (int i = 0; i < 2; i++) {
+        pthread_mutex_lock(&lock);
+        counter += id;
+        pthread_mutex_unlock(&lock);
+    }
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[NUM_THREADS];
+    int ids[NUM_THREADS];
+    for (int i = 0; i < NUM_THREADS; i++) {
+        ids[i] = i + 1;
+        pthread_create(&threads[i], NULL, worker, &ids[i]);
+    }
+    for (int i = 0; i < NUM_THREADS; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    printf("counter = %d\n", counter);
+    return 0;
+}
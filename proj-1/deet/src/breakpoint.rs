@@ -0,0 +1,170 @@
+use crate::dwarf_data::Type;
+use crate::expr::Expr;
+
+/// A breakpoint the user asked for. Whether 0xcc is actually written to the inferior is tracked
+/// separately by the debugger, since several breakpoints (or internal ones used for stepping) may
+/// share an address.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub num: usize,
+    // Where the user asked for the breakpoint, e.g. `main` or `file.c:42`, and the address that
+    // resolves to in the program being debugged
+    pub location: String,
+    pub addr: usize,
+    pub enabled: bool,
+    // The location doesn't exist in the current program (after an exec), so there is nothing to
+    // insert until the inferior runs one where it does
+    pub pending: bool,
+    pub hit_count: usize,
+    // Only stop when this evaluates to non-zero
+    pub condition: Option<Expr>,
+    // Number of upcoming hits to resume from without stopping
+    pub ignore_count: usize,
+    // Commands to run when the breakpoint is hit, see `commands`
+    pub commands: Vec<String>,
+    // Deleted the first time it is hit, see `tbreak`
+    pub temporary: bool,
+}
+
+impl Breakpoint {
+    pub fn new(num: usize, location: &str, addr: usize, condition: Option<Expr>) -> Breakpoint {
+        Breakpoint {
+            num,
+            location: location.to_string(),
+            addr,
+            enabled: true,
+            pending: false,
+            hit_count: 0,
+            condition,
+            ignore_count: 0,
+            commands: Vec::new(),
+            temporary: false,
+        }
+    }
+
+    /// Whether the breakpoint should be inserted at `addr`.
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.pending
+    }
+
+    /// What happens to the breakpoint when it is hit, as `info breakpoints` shows it.
+    pub fn disposition(&self) -> &'static str {
+        if self.temporary {
+            "del"
+        } else {
+            "keep"
+        }
+    }
+}
+
+/// The user breakpoint a stop at `addr` is reported for, if any. A temporary breakpoint is
+/// deleted then, and not when a hit is passed over for its condition or ignore count, so it is
+/// taken out of `breakpoints`.
+pub fn take_hit(breakpoints: &mut Vec<Breakpoint>, addr: usize) -> Option<Breakpoint> {
+    let index = breakpoints
+        .iter()
+        .position(|bp| bp.is_active() && bp.addr == addr)?;
+    if breakpoints[index].temporary {
+        Some(breakpoints.remove(index))
+    } else {
+        Some(breakpoints[index].clone())
+    }
+}
+
+/// A trap the debugger inserts for itself while running to somewhere (`finish`, `until`,
+/// `advance`) and removes once it gets there. Reaching `addr` only counts on the thread that was
+/// running to it, with rsp at or above `min_sp`, so that deeper recursive calls passing through
+/// the same code are run through.
+#[derive(Debug, Clone, Copy)]
+pub struct TransientTrap {
+    pub addr: usize,
+    pub thread: usize,
+    pub min_sp: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    pub fn description(self) -> &'static str {
+        match self {
+            WatchKind::Write => "Hardware watchpoint",
+            WatchKind::Read => "Hardware read watchpoint",
+            WatchKind::Access => "Hardware access (read/write) watchpoint",
+        }
+    }
+
+    /// The R/W field of DR7 for this kind. x86 cannot trap on reads alone, so read watchpoints
+    /// trap on any access.
+    pub fn dr7_rw_bits(self) -> u64 {
+        match self {
+            WatchKind::Write => 0b01,
+            WatchKind::Read | WatchKind::Access => 0b11,
+        }
+    }
+}
+
+/// A hardware watchpoint on a variable, programmed into one of the debug registers DR0-DR3.
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub num: usize,
+    pub kind: WatchKind,
+    pub expression: String,
+    pub addr: usize,
+    pub value_type: Type,
+    pub enabled: bool,
+    pub hit_count: usize,
+    // Debug register the watchpoint is installed in, if it is installed
+    pub slot: Option<usize>,
+    // Value as of the last stop, to report what changed
+    pub old_value: Vec<u8>,
+    // For locals, the frame the variable lives in: its return address and the caller's rsp. The
+    // variable is gone once rsp is above that, or another call has replaced the frame.
+    pub scope: Option<(usize, usize)>,
+}
+
+impl Watchpoint {
+    /// The LEN field of DR7 for the watched value's size (1, 2, 4 or 8 bytes).
+    pub fn dr7_len_bits(&self) -> u64 {
+        match self.value_type.size {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            _ => 0b11,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn breakpoint(num: usize, addr: usize, temporary: bool) -> Breakpoint {
+        let mut breakpoint = Breakpoint::new(num, "main", addr, None);
+        breakpoint.temporary = temporary;
+        breakpoint
+    }
+
+    #[test]
+    fn test_take_hit() {
+        let mut breakpoints = vec![breakpoint(1, 0x1000, false), breakpoint(2, 0x2000, true)];
+        assert_eq!(take_hit(&mut breakpoints, 0x1000).map(|bp| bp.num), Some(1));
+        assert_eq!(breakpoints.len(), 2);
+        assert!(take_hit(&mut breakpoints, 0x3000).is_none());
+        // A temporary breakpoint goes once its hit is reported
+        assert_eq!(take_hit(&mut breakpoints, 0x2000).map(|bp| bp.num), Some(2));
+        assert_eq!(breakpoints.len(), 1);
+        assert!(take_hit(&mut breakpoints, 0x2000).is_none());
+
+        // Disabled and pending breakpoints aren't hit, so they stay
+        let mut breakpoints = vec![breakpoint(3, 0x1000, true), breakpoint(4, 0x1000, true)];
+        breakpoints[0].enabled = false;
+        breakpoints[1].pending = true;
+        assert!(take_hit(&mut breakpoints, 0x1000).is_none());
+        assert_eq!(breakpoints.len(), 2);
+    }
+}
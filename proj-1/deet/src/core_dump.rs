@@ -0,0 +1,532 @@
+//! Reading ELF core dumps, so that a crashed program's stack, registers and variables can be
+//! examined without a live process. A core holds a PT_LOAD segment for each mapping of the
+//! process, a PT_NOTE segment with the registers of every thread (NT_PRSTATUS) and the files that
+//! were mapped (NT_FILE). Read-only file mappings such as code usually aren't dumped, so their
+//! contents are read from the mapped files instead.
+
+use crate::process::Process;
+use libc::user_regs_struct;
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::fs;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x4649_4c45;
+
+// Offsets into struct elf_prstatus and struct elf_prpsinfo on x86-64
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+const PRPSINFO_PSARGS: usize = 56;
+const PRPSINFO_PSARGS_LEN: usize = 80;
+
+/// A PT_LOAD segment: memory at `vaddr`, of which the first `filesz` bytes are stored in the
+/// core at `offset`.
+struct Segment {
+    vaddr: usize,
+    memsz: usize,
+    offset: usize,
+    filesz: usize,
+}
+
+/// A file the process had mapped, from NT_FILE.
+struct MappedFile {
+    start: usize,
+    end: usize,
+    // Offset into the file that `start` maps
+    file_offset: usize,
+    path: String,
+}
+
+pub struct CoreDump {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+    mapped_files: Vec<MappedFile>,
+    // Registers of each thread, the one that crashed first
+    threads: Vec<(Pid, user_regs_struct)>,
+    current: usize,
+    // The signal that killed the process, and its command line
+    signal: Option<Signal>,
+    command: Option<String>,
+    auxv: Option<Vec<u8>>,
+}
+
+impl CoreDump {
+    pub fn open(path: &str) -> Result<CoreDump, String> {
+        let data = fs::read(path).or_else(|err| Err(format!("{}: {}.", path, err)))?;
+        CoreDump::parse(path, data)
+    }
+
+    /// Parses the contents `data` of the core dump at `path`, which is only used in messages.
+    fn parse(path: &str, data: Vec<u8>) -> Result<CoreDump, String> {
+        let not_a_core = || {
+            format!(
+                "\"{}\" is not a core dump: file format not recognized",
+                path
+            )
+        };
+        if data.len() < 64 || &data[..4] != b"\x7fELF" {
+            return Err(not_a_core());
+        }
+        // Only 64-bit little endian x86 cores, like the rest of deet
+        if data[4] != 2 || data[5] != 1 || read_u16(&data, 0x12) != Some(EM_X86_64) {
+            return Err(format!("\"{}\": unsupported core dump architecture", path));
+        }
+        if read_u16(&data, 0x10) != Some(ET_CORE) {
+            return Err(not_a_core());
+        }
+
+        let mut core = CoreDump {
+            data: Vec::new(),
+            segments: Vec::new(),
+            mapped_files: Vec::new(),
+            threads: Vec::new(),
+            current: 0,
+            signal: None,
+            command: None,
+            auxv: None,
+        };
+        let phoff = read_u64(&data, 0x20).ok_or_else(not_a_core)? as usize;
+        let phentsize = read_u16(&data, 0x36).ok_or_else(not_a_core)? as usize;
+        let phnum = read_u16(&data, 0x38).ok_or_else(not_a_core)? as usize;
+        for i in 0..phnum {
+            let header = i
+                .checked_mul(phentsize)
+                .and_then(|offset| phoff.checked_add(offset))
+                .ok_or_else(not_a_core)?;
+            let field = |offset: usize| {
+                let value = read_u64(&data, header.checked_add(offset)?)?;
+                Some(value as usize)
+            };
+            let (p_type, offset, vaddr, filesz, memsz) = match (
+                read_u32(&data, header),
+                field(0x08),
+                field(0x10),
+                field(0x20),
+                field(0x28),
+            ) {
+                (Some(p_type), Some(offset), Some(vaddr), Some(filesz), Some(memsz)) => {
+                    (p_type, offset, vaddr, filesz, memsz)
+                }
+                _ => return Err(not_a_core()),
+            };
+            let end = offset.checked_add(filesz).ok_or_else(not_a_core)?;
+            if end > data.len() {
+                return Err(format!("\"{}\" is truncated", path));
+            }
+            match p_type {
+                PT_LOAD => core.segments.push(Segment {
+                    vaddr,
+                    memsz,
+                    offset,
+                    filesz,
+                }),
+                PT_NOTE => core.parse_notes(&data[offset..end]),
+                _ => {}
+            }
+        }
+        if core.threads.is_empty() {
+            return Err(format!("\"{}\" has no thread registers", path));
+        }
+        core.data = data;
+        Ok(core)
+    }
+
+    fn parse_notes(&mut self, mut notes: &[u8]) {
+        let align = |size: usize| size.checked_add(3).map(|size| size & !3);
+        while notes.len() >= 12 {
+            let namesz = read_u32(notes, 0).unwrap() as usize;
+            let descsz = read_u32(notes, 4).unwrap() as usize;
+            let note_type = read_u32(notes, 8).unwrap();
+            let desc_start = match align(namesz).and_then(|namesz| namesz.checked_add(12)) {
+                Some(desc_start) => desc_start,
+                None => return,
+            };
+            let desc = match desc_start
+                .checked_add(descsz)
+                .and_then(|desc_end| notes.get(desc_start..desc_end))
+            {
+                Some(desc) => desc,
+                None => return,
+            };
+            match note_type {
+                NT_PRSTATUS => self.parse_prstatus(desc),
+                NT_PRPSINFO if desc.len() >= PRPSINFO_PSARGS + PRPSINFO_PSARGS_LEN => {
+                    self.command = Some(c_string(
+                        &desc[PRPSINFO_PSARGS..PRPSINFO_PSARGS + PRPSINFO_PSARGS_LEN],
+                    ));
+                }
+                NT_AUXV => self.auxv = Some(desc.to_vec()),
+                NT_FILE => self.parse_file_note(desc),
+                _ => {}
+            }
+            let next = align(descsz).and_then(|descsz| desc_start.checked_add(descsz));
+            notes = &notes[next.unwrap_or(notes.len()).min(notes.len())..];
+        }
+    }
+
+    fn parse_prstatus(&mut self, desc: &[u8]) {
+        if desc.len() < PRSTATUS_REGS + size_of::<user_regs_struct>() {
+            return;
+        }
+        // The thread that got the signal comes first
+        if self.threads.is_empty() {
+            let signal = read_u16(desc, PRSTATUS_CURSIG).unwrap();
+            self.signal = Signal::try_from(signal as i32).ok();
+        }
+        let tid = Pid::from_raw(read_u32(desc, PRSTATUS_PID).unwrap() as i32);
+        // pr_reg is laid out exactly like user_regs_struct
+        let regs = unsafe {
+            std::ptr::read_unaligned(desc[PRSTATUS_REGS..].as_ptr() as *const user_regs_struct)
+        };
+        self.threads.push((tid, regs));
+    }
+
+    /// NT_FILE holds the number of mappings and the page size, then start, end and file offset
+    /// (in pages) of each mapping, then their file names.
+    fn parse_file_note(&mut self, desc: &[u8]) {
+        let count = match read_u64(desc, 0) {
+            Some(count) => count as usize,
+            None => return,
+        };
+        let page_size = read_u64(desc, 8).unwrap_or(0) as usize;
+        // The names follow the entries, so there can't be more entries than fit in the note
+        let names_start = match count.checked_mul(24).and_then(|size| size.checked_add(16)) {
+            Some(names_start) if names_start <= desc.len() => names_start,
+            _ => return,
+        };
+        let mut names = desc[names_start..].split(|byte| *byte == 0);
+        for i in 0..count {
+            let entry = 16 + i * 24;
+            let (start, end, page_offset) = match (
+                read_u64(desc, entry),
+                read_u64(desc, entry + 8),
+                read_u64(desc, entry + 16),
+            ) {
+                (Some(start), Some(end), Some(page_offset)) => (start, end, page_offset),
+                _ => return,
+            };
+            let path = match names.next() {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => return,
+            };
+            let file_offset = match (page_offset as usize).checked_mul(page_size) {
+                Some(file_offset) => file_offset,
+                None => return,
+            };
+            self.mapped_files.push(MappedFile {
+                start: start as usize,
+                end: end as usize,
+                file_offset,
+                path,
+            });
+        }
+    }
+
+    /// The signal that killed the process.
+    pub fn signal(&self) -> Option<Signal> {
+        self.signal
+    }
+
+    /// The command line the process was started with, as far as the core recorded it.
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_deref()
+    }
+
+    /// Reads as much of `len` bytes at `addr` as lie in one segment or mapped file, at least one.
+    fn read_chunk(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| addr >= segment.vaddr && addr - segment.vaddr < segment.memsz);
+        if let Some(segment) = segment {
+            let offset = addr - segment.vaddr;
+            if offset < segment.filesz {
+                let len = len.min(segment.filesz - offset);
+                let start = segment.offset + offset;
+                return Ok(self.data[start..start + len].to_vec());
+            }
+        }
+
+        // Not dumped, fall back to the file that was mapped there
+        let file = self
+            .mapped_files
+            .iter()
+            .find(|file| addr >= file.start && addr < file.end)
+            .ok_or(nix::Error::Sys(Errno::EIO))?;
+        let len = len.min(file.end - addr);
+        let file_offset = file
+            .file_offset
+            .checked_add(addr - file.start)
+            .ok_or(nix::Error::Sys(Errno::EIO))?;
+        let mut bytes = vec![0; len];
+        let read = fs::File::open(&file.path)
+            .and_then(|f| f.read_at(&mut bytes, file_offset as u64))
+            .or(Err(nix::Error::Sys(Errno::EIO)))?;
+        if read == 0 {
+            return Err(nix::Error::Sys(Errno::EIO));
+        }
+        bytes.truncate(read);
+        Ok(bytes)
+    }
+}
+
+impl Process for CoreDump {
+    fn pid(&self) -> Pid {
+        self.threads[0].0
+    }
+
+    fn threads(&self) -> Vec<(usize, Pid)> {
+        self.threads
+            .iter()
+            .enumerate()
+            .map(|(i, (tid, _))| (i + 1, *tid))
+            .collect()
+    }
+
+    fn current_thread(&self) -> (usize, Pid) {
+        (self.current + 1, self.threads[self.current].0)
+    }
+
+    fn select_thread(&mut self, num: usize) -> bool {
+        if num == 0 || num > self.threads.len() {
+            return false;
+        }
+        self.current = num - 1;
+        true
+    }
+
+    fn getregs(&self) -> Result<user_regs_struct, nix::Error> {
+        Ok(self.threads[self.current].1)
+    }
+
+    fn getregs_of(&self, tid: Pid) -> Result<user_regs_struct, nix::Error> {
+        self.threads
+            .iter()
+            .find(|(thread, _)| *thread == tid)
+            .map(|(_, regs)| *regs)
+            .ok_or(nix::Error::Sys(Errno::ESRCH))
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        // Memory wrapping around the end of the address space can't be mapped
+        addr.checked_add(len).ok_or(nix::Error::Sys(Errno::EIO))?;
+        let mut bytes = Vec::new();
+        while bytes.len() < len {
+            let chunk = self.read_chunk(addr + bytes.len(), len - bytes.len())?;
+            bytes.extend(chunk);
+        }
+        Ok(bytes)
+    }
+
+    fn auxv(&self) -> Option<Vec<u8>> {
+        self.auxv.clone()
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(data.get(offset..offset.checked_add(2)?)?);
+    Some(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(data.get(offset..offset.checked_add(4)?)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(data.get(offset..offset.checked_add(8)?)?);
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// The string in a fixed size, NUL padded field.
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len])
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOAD_ADDR: usize = 0x10000;
+
+    fn note(note_type: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = Vec::new();
+        note.extend_from_slice(&5u32.to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&note_type.to_le_bytes());
+        note.extend_from_slice(b"CORE\0\0\0\0");
+        note.extend_from_slice(desc);
+        while note.len() % 4 != 0 {
+            note.push(0);
+        }
+        note
+    }
+
+    /// An NT_PRSTATUS note for thread `tid` stopped with `signal` at `rip`.
+    fn prstatus(tid: u32, signal: u16, rip: u64, rsp: u64) -> Vec<u8> {
+        let mut desc = vec![0; PRSTATUS_REGS + size_of::<user_regs_struct>() + 8];
+        desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&signal.to_le_bytes());
+        desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&tid.to_le_bytes());
+        // rip and rsp are the 17th and 20th registers of user_regs_struct
+        let rip_at = PRSTATUS_REGS + 16 * 8;
+        let rsp_at = PRSTATUS_REGS + 19 * 8;
+        desc[rip_at..rip_at + 8].copy_from_slice(&rip.to_le_bytes());
+        desc[rsp_at..rsp_at + 8].copy_from_slice(&rsp.to_le_bytes());
+        note(NT_PRSTATUS, &desc)
+    }
+
+    /// An NT_FILE note for `/bin/true` mapped at 0x400000 from its third page on.
+    fn file_note(count: u64) -> Vec<u8> {
+        let mut desc = Vec::new();
+        for value in [count, 4096, 0x400000, 0x401000, 2].iter() {
+            desc.extend_from_slice(&value.to_le_bytes());
+        }
+        desc.extend_from_slice(b"/bin/true\0");
+        note(NT_FILE, &desc)
+    }
+
+    /// A core with a PT_NOTE segment holding `notes` and a PT_LOAD segment at LOAD_ADDR.
+    fn core_data(notes: &[u8]) -> Vec<u8> {
+        let load = b"hello world!";
+        let notes_at = 64 + 2 * 56;
+        let load_at = notes_at + notes.len();
+        let mut data = vec![0; load_at];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[0x10..0x12].copy_from_slice(&ET_CORE.to_le_bytes());
+        data[0x12..0x14].copy_from_slice(&EM_X86_64.to_le_bytes());
+        data[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        let segments = [
+            (PT_NOTE, notes_at, 0, notes.len(), notes.len()),
+            (PT_LOAD, load_at, LOAD_ADDR, load.len(), 0x1000),
+        ];
+        for (i, (p_type, offset, vaddr, filesz, memsz)) in segments.iter().enumerate() {
+            let header = 64 + i * 56;
+            data[header..header + 4].copy_from_slice(&p_type.to_le_bytes());
+            let fields = [(0x08, *offset), (0x10, *vaddr), (0x20, *filesz), (0x28, *memsz)];
+            for (field, value) in fields.iter() {
+                let at = header + field;
+                data[at..at + 8].copy_from_slice(&(*value as u64).to_le_bytes());
+            }
+        }
+        data[notes_at..].copy_from_slice(notes);
+        data.extend_from_slice(load);
+        data
+    }
+
+    fn two_thread_core() -> Vec<u8> {
+        let mut notes = prstatus(1234, 11, 0x401136, 0x7ffc0000);
+        notes.extend(prstatus(1235, 0, 0x401200, 0x7ffb0000));
+        notes.extend(file_note(1));
+        core_data(&notes)
+    }
+
+    fn parse_error(data: Vec<u8>) -> String {
+        CoreDump::parse("core", data).err().unwrap()
+    }
+
+    fn set_u64(data: &mut [u8], at: usize, value: u64) {
+        data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_header_validation() {
+        let not_a_core = "\"core\" is not a core dump: file format not recognized";
+        assert_eq!(parse_error(b"\x7fELF".to_vec()), not_a_core);
+        assert_eq!(parse_error(vec![0; 64]), not_a_core);
+
+        let mut executable = two_thread_core();
+        executable[0x10] = 2;
+        assert_eq!(parse_error(executable), not_a_core);
+
+        let mut arm = two_thread_core();
+        arm[0x12] = 183;
+        assert_eq!(parse_error(arm), "\"core\": unsupported core dump architecture");
+
+        // Offsets and sizes pointing past the end of the address space
+        let mut far_headers = two_thread_core();
+        set_u64(&mut far_headers, 0x20, u64::MAX - 8);
+        assert_eq!(parse_error(far_headers), not_a_core);
+        let mut far_segment = two_thread_core();
+        set_u64(&mut far_segment, 64 + 56 + 0x08, u64::MAX);
+        assert_eq!(parse_error(far_segment), not_a_core);
+
+        let mut truncated = two_thread_core();
+        set_u64(&mut truncated, 64 + 56 + 0x20, 0x1000);
+        assert_eq!(parse_error(truncated), "\"core\" is truncated");
+
+        assert_eq!(parse_error(core_data(&[])), "\"core\" has no thread registers");
+    }
+
+    #[test]
+    fn test_prstatus_registers() {
+        let mut core = CoreDump::parse("core", two_thread_core()).unwrap();
+        assert_eq!(core.signal(), Some(Signal::SIGSEGV));
+        assert_eq!(core.pid(), Pid::from_raw(1234));
+        assert_eq!(
+            core.threads(),
+            vec![(1, Pid::from_raw(1234)), (2, Pid::from_raw(1235))]
+        );
+        let regs = core.getregs().unwrap();
+        assert_eq!((regs.rip, regs.rsp), (0x401136, 0x7ffc0000));
+        assert!(core.select_thread(2));
+        assert_eq!(core.getregs().unwrap().rip, 0x401200);
+        assert!(!core.select_thread(3));
+
+        // Notes whose sizes run past the segment are ignored
+        let mut notes = prstatus(1234, 11, 0x401136, 0x7ffc0000);
+        notes[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_error(core_data(&notes)), "\"core\" has no thread registers");
+    }
+
+    #[test]
+    fn test_file_note() {
+        let core = CoreDump::parse("core", two_thread_core()).unwrap();
+        assert_eq!(core.mapped_files.len(), 1);
+        let file = &core.mapped_files[0];
+        assert_eq!((file.start, file.end), (0x400000, 0x401000));
+        assert_eq!(file.file_offset, 2 * 4096);
+        assert_eq!(file.path, "/bin/true");
+
+        // A count that doesn't fit in the note
+        let mut notes = prstatus(1234, 11, 0x401136, 0x7ffc0000);
+        notes.extend(file_note(u64::MAX / 8));
+        let core = CoreDump::parse("core", core_data(&notes)).unwrap();
+        assert!(core.mapped_files.is_empty());
+    }
+
+    #[test]
+    fn test_read_bytes() {
+        let core = CoreDump::parse("core", two_thread_core()).unwrap();
+        assert_eq!(core.read_bytes(LOAD_ADDR + 6, 5), Ok(b"world".to_vec()));
+        // Past the dumped part of the segment, with no file mapped there
+        assert!(core.read_bytes(LOAD_ADDR + 12, 1).is_err());
+        assert_eq!(
+            core.read_bytes(usize::MAX - 1, 4),
+            Err(nix::Error::Sys(Errno::EIO))
+        );
+    }
+}
@@ -1,10 +1,83 @@
-use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
+use crate::breakpoint::{self, Breakpoint, TransientTrap, WatchKind, Watchpoint};
+use crate::core_dump::CoreDump;
+use crate::debugger_command::{DebuggerCommand, DisassembleModifiers, ExamineFormat};
+use crate::disassemble;
+use crate::dwarf_data::{
+    DwarfData, Error as DwarfError, Line, Location, Type, TypeKind, TypeRef, Variable,
+};
+use crate::expr::{self, Expr, Value};
+use crate::infcall::{self, Argument, Outcome};
 use crate::inferior::{Inferior, Status};
+use crate::interpreter::{self, Json};
+use crate::process::Process;
+use crate::record::{self, RecordedStep, Recording, StepKind};
+use crate::registers;
+use crate::shared_libs;
+use crate::signals::{self, SignalPolicy};
+use crate::symbols::Symbols;
+use crate::unwind::{self, Frame, FrameRegisters};
+use crate::{console_print, console_println};
+use nix::errno::Errno;
 use nix::sys::signal;
+use nix::unistd::{isatty, Pid};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+
+// Backtraces stop after this many frames, in case the stack is corrupt or very deep
+const MAX_BACKTRACE_DEPTH: usize = 256;
+const PAGE_SIZE: usize = 4096;
+// The most code `disassemble` reads at once, which keeps a mistyped range from using up memory
+const MAX_DISASSEMBLY_LEN: usize = 1 << 20;
+// How many lines `list` shows at a time
+const LIST_LINES: usize = 10;
+const PROMPT: &str = "(deet) ";
+const PENDING_QUERY: &str = "Make breakpoint pending on future shared library load? (y or [n]) ";
+// Scripts run at startup, from the home directory and then the current one
+const INIT_FILE: &str = ".deetinit";
+
+/// Why a breakpoint location couldn't be resolved.
+enum LocationError {
+    // There is no such function or file in the executable or the libraries loaded so far, though
+    // a library loaded later may have it
+    NotFound(String),
+    // Anything else, e.g. a line without code
+    Invalid(String),
+}
+
+/// Where a plain `list` picks up.
+#[derive(Clone)]
+enum ListPosition {
+    // Around a line of a file: where the inferior stopped or the selected frame is
+    Around(String, usize),
+    // After the lines of a file that were listed last, the first and last of them
+    Listed(String, usize, usize),
+}
+
+/// Why the inferior stopped, as the debugger reports it.
+enum Stop {
+    // What the inferior reported, with rip moved back onto our traps
+    Status(Status),
+    // The program ran into a trap of its own, e.g. an int3 compiled into it, rather than one of
+    // our traps, so it is reported like any other signal. Contains rip after the trap
+    Trapped(usize),
+}
+
+impl From<Status> for Stop {
+    fn from(status: Status) -> Self {
+        Stop::Status(status)
+    }
+}
+
+/// A process that is traced but not the one being debugged: the other side of a fork while
+/// detach-on-fork is off. It stays stopped until the user switches to it with `inferior`.
+struct HeldInferior {
+    num: usize,
+    inferior: Inferior,
+    target: String,
+    breakpoint_map: HashMap<u64, u8>,
+}
 
 pub struct Debugger {
     target: String,
@@ -12,9 +85,51 @@ pub struct Debugger {
     readline: Editor<()>,
     inferior: Option<Inferior>,
     running: bool,
-    debug_data: DwarfData,
-    breakpoints: Vec<usize>,
+    // A core dump being examined instead of a live process
+    core: Option<CoreDump>,
+    debug_data: Symbols,
+    // Where the inferior stops for us to look for newly loaded or unloaded libraries: the dynamic
+    // linker's r_brk, or until the dynamic linker has set that up, the entry point
+    shlib_event_addr: Option<usize>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Breakpoints and watchpoints share one numbering
+    next_breakpoint_num: usize,
+    // Addresses where 0xcc is currently written in the inferior, mapped to the original byte
     breakpoint_map: HashMap<u64, u8>,
+    // Traps inserted while running to a location, see run_to
+    transient_traps: Vec<TransientTrap>,
+    // Stack frame that variables are looked up in, 0 being the innermost
+    selected_frame: usize,
+    // After a fork, whether to debug the child instead of the parent, and whether to let go of
+    // the process we don't debug or hold on to it
+    follow_fork_child: bool,
+    detach_on_fork: bool,
+    held_inferiors: Vec<HeldInferior>,
+    // What to do when the inferior gets each signal, see `handle`
+    signal_policies: HashMap<signal::Signal, SignalPolicy>,
+    // Inferiors are numbered like gdb does, the first one being 1
+    inferior_num: usize,
+    next_inferior_num: usize,
+    list_position: Option<ListPosition>,
+    // In JSON mode, the id of the command being carried out, for its `done` record
+    command_id: Json,
+    // Lines to run before reading more commands: from scripts, user-defined commands and the
+    // commands of breakpoints that were hit
+    queued_lines: VecDeque<String>,
+    // Commands added with `define`, and the lines they run
+    user_commands: HashMap<String, Vec<String>>,
+    // Quit once queued_lines runs out instead of reading commands from the user
+    batch: bool,
+    // The last line read was typed by the user, rather than queued or sent in JSON mode
+    line_from_user: bool,
+    // The execution log while the inferior is being recorded, see `record`
+    recording: Option<Recording>,
+    // How many instructions a recording keeps, see `set record full insn-number-max`
+    record_limit: usize,
+    // How the inferior ended if it did while running a function called from an expression, to be
+    // reported once the command is done
+    exited_in_call: Option<Status>,
 }
 
 impl Debugger {
@@ -29,27 +144,45 @@ impl Debugger {
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
             Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
+                console_println!("Could not open file {}", target);
                 std::process::exit(1);
             }
             Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
+                console_println!("Could not debugging symbols from {}: {:?}", target, err);
                 std::process::exit(1);
             }
         };
 
-        println!("[debug]: debug_data");
-        debug_data.print();
-
         Debugger {
             target: target.to_string(),
             history_path,
             readline,
             inferior: None,
             running: false,
-            debug_data,
+            core: None,
+            debug_data: Symbols::new(debug_data),
+            shlib_event_addr: None,
             breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_breakpoint_num: 1,
             breakpoint_map: HashMap::new(),
+            transient_traps: Vec::new(),
+            selected_frame: 0,
+            follow_fork_child: false,
+            detach_on_fork: true,
+            held_inferiors: Vec::new(),
+            signal_policies: signals::default_policies(),
+            inferior_num: 1,
+            next_inferior_num: 2,
+            list_position: None,
+            command_id: Json::Null,
+            queued_lines: VecDeque::new(),
+            user_commands: HashMap::new(),
+            batch: false,
+            line_from_user: false,
+            recording: None,
+            record_limit: record::DEFAULT_LIMIT,
+            exited_in_call: None,
         }
     }
 
@@ -57,29 +190,158 @@ impl Debugger {
         loop {
             match self.get_next_command() {
                 DebuggerCommand::Run(args) => self.handle_run_command(&args),
+                DebuggerCommand::Start(args) => self.handle_start_command(&args),
                 DebuggerCommand::Continue => self.handle_cont_command(),
-                DebuggerCommand::Backtrace => self.handle_backtrace_command(),
-                DebuggerCommand::Breakpoint(raw_addr) => self.handle_breakpoint_command(&raw_addr),
-                DebuggerCommand::Quit => self.handle_quit_command(),
+                DebuggerCommand::Next => self.handle_step_command(false),
+                DebuggerCommand::Step => self.handle_step_command(true),
+                DebuggerCommand::Finish => self.handle_finish_command(),
+                DebuggerCommand::Until(location) => self.handle_until_command(location.as_deref()),
+                DebuggerCommand::Advance(location) => self.handle_advance_command(&location),
+                DebuggerCommand::Backtrace(count) => self.handle_backtrace_command(count),
+                DebuggerCommand::Up(count) => self.handle_up_command(count),
+                DebuggerCommand::Down(count) => self.handle_down_command(count),
+                DebuggerCommand::Frame(level) => self.handle_frame_command(level),
+                DebuggerCommand::InfoLocals => self.handle_info_variables_command(false),
+                DebuggerCommand::InfoArgs => self.handle_info_variables_command(true),
+                DebuggerCommand::InfoThreads => self.handle_info_threads_command(),
+                DebuggerCommand::Thread(num) => self.handle_thread_command(num),
+                DebuggerCommand::InfoInferiors => self.handle_info_inferiors_command(),
+                DebuggerCommand::InfoSharedLibrary => self.handle_info_shared_library_command(),
+                DebuggerCommand::Inferior(num) => self.handle_inferior_command(num),
+                DebuggerCommand::Breakpoint(raw_addr, condition, temporary) => {
+                    self.handle_breakpoint_command(&raw_addr, condition.as_deref(), temporary);
+                }
+                DebuggerCommand::InfoBreakpoints => self.handle_info_breakpoints_command(),
+                DebuggerCommand::Delete(num) => self.handle_delete_command(num),
+                DebuggerCommand::Enable(num) => self.handle_enable_command(num, true),
+                DebuggerCommand::Disable(num) => self.handle_enable_command(num, false),
+                DebuggerCommand::Ignore(num, count) => self.handle_ignore_command(num, count),
+                DebuggerCommand::Watch(kind, name) => self.handle_watch_command(kind, &name),
+                DebuggerCommand::Print(input) => self.handle_print_command(&input, false),
+                DebuggerCommand::Call(input) => self.handle_print_command(&input, true),
+                DebuggerCommand::InfoRegisters(names) => self.handle_info_registers_command(&names),
+                DebuggerCommand::SetRegister(name, value) => {
+                    self.handle_set_register_command(&name, &value)
+                }
+                DebuggerCommand::Handle(name, actions) => {
+                    self.handle_handle_command(&name, &actions)
+                }
+                DebuggerCommand::InfoSignals(name) => {
+                    self.handle_info_signals_command(name.as_deref())
+                }
+                DebuggerCommand::Set(name, value) => self.handle_set_command(&name, &value),
+                DebuggerCommand::Show(name) => self.handle_show_command(&name),
+                DebuggerCommand::Examine(format, addr) => {
+                    self.handle_examine_command(format, &addr)
+                }
+                DebuggerCommand::List(location) => self.handle_list_command(location.as_deref()),
+                DebuggerCommand::Disassemble(modifiers, location) => {
+                    self.handle_disassemble_command(modifiers, location.as_deref())
+                }
+                DebuggerCommand::Define(name) => self.handle_define_command(&name),
+                DebuggerCommand::Commands(num) => self.handle_commands_command(num),
+                DebuggerCommand::Source(path) => self.handle_source_command(&path),
+                DebuggerCommand::Record => self.handle_record_command(),
+                DebuggerCommand::RecordStop => self.handle_record_stop_command(),
+                DebuggerCommand::InfoRecord => self.handle_info_record_command(),
+                DebuggerCommand::ReverseStepi => {
+                    if self.can_reverse() {
+                        let result = self.reverse_stepi();
+                        self.report_reverse(result);
+                    }
+                }
+                DebuggerCommand::ReverseNext => {
+                    if self.can_reverse() {
+                        let result = self.reverse_next();
+                        self.report_reverse(result);
+                    }
+                }
+                DebuggerCommand::ReverseContinue => {
+                    if self.can_reverse() {
+                        let result = self.reverse_continue();
+                        self.report_reverse(result);
+                    }
+                }
+                DebuggerCommand::Attach(pid) => self.attach(pid),
+                DebuggerCommand::Detach => self.handle_detach_command(),
+                DebuggerCommand::Quit => {
+                    self.handle_quit_command();
+                    self.finish_command();
+                    return;
+                }
             }
+            if let Some(status) = self.exited_in_call.take() {
+                self.report_status(status.into());
+            }
+            self.finish_command();
+        }
+    }
+
+    /// Ends the output of a command in JSON mode with a `done` record.
+    fn finish_command(&mut self) {
+        if interpreter::is_json() {
+            let id = std::mem::replace(&mut self.command_id, Json::Null);
+            interpreter::emit(&Json::object(vec![("type", "done".into()), ("id", id)]));
         }
     }
 
-    /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
+    /// Reads commands until one is recognized and returns it. Blank lines and comments are
+    /// skipped, and user-defined commands are replaced with their definitions.
     fn get_next_command(&mut self) -> DebuggerCommand {
         loop {
-            // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
+            let line = match self.next_line(PROMPT) {
+                Some(line) => line,
+                None => return DebuggerCommand::Quit,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                // Still answered in JSON mode
+                self.finish_command();
+                continue;
+            }
+            if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                return cmd;
+            }
+            match self.user_commands.get(tokens[0]) {
+                Some(body) => {
+                    let body = expand_arguments(body, &tokens[1..]);
+                    if body.is_empty() {
+                        self.finish_command();
+                    }
+                    self.queue_lines(body);
+                }
+                None if interpreter::is_json() => {
+                    let id = std::mem::replace(&mut self.command_id, Json::Null);
+                    emit_error(id, "Unrecognized command.");
+                }
+                None => console_println!("Unrecognized command."),
+            }
+        }
+    }
+
+    /// Reads the next line of input. Lines queued by scripts, user-defined commands and
+    /// breakpoint commands come first, then what the user types at `prompt`. Returns None at the
+    /// end of input, or once the queue is empty in batch mode.
+    fn next_line(&mut self, prompt: &str) -> Option<String> {
+        self.line_from_user = false;
+        if let Some(line) = self.queued_lines.pop_front() {
+            return Some(line);
+        }
+        if self.batch {
+            return None;
+        }
+        if interpreter::is_json() {
+            return self.next_json_line(prompt);
+        }
+        loop {
+            match self.readline.readline(prompt) {
                 Err(ReadlineError::Interrupted) => {
                     // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
+                    console_println!("Type \"quit\" to exit");
                 }
                 Err(ReadlineError::Eof) => {
                     // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
+                    return None;
                 }
                 Err(err) => {
                     panic!("Unexpected I/O error: {:?}", err);
@@ -90,158 +352,4013 @@ impl Debugger {
                     }
                     self.readline.add_history_entry(line.as_str());
                     if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
+                        console_println!(
                             "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
+                            self.history_path,
+                            err
                         );
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                    self.line_from_user = true;
+                    return Some(line);
+                }
+            }
+        }
+    }
+
+    /// next_line for JSON mode: the command of the next request on stdin. Requests read for the
+    /// body of `define` or `commands` aren't answered; the `done` record at its `end` has the id
+    /// of the request that started the block.
+    fn next_json_line(&mut self, prompt: &str) -> Option<String> {
+        loop {
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => panic!("Unexpected I/O error: {:?}", err),
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match interpreter::parse_request(&line) {
+                Ok((id, command)) => {
+                    if prompt == PROMPT {
+                        self.command_id = id;
                     }
+                    return Some(command);
                 }
+                Err(err) => emit_error(Json::Null, &err),
+            }
+        }
+    }
+
+    /// Runs `lines` before reading any other input.
+    fn queue_lines(&mut self, lines: Vec<String>) {
+        for line in lines.into_iter().rev() {
+            self.queued_lines.push_front(line);
+        }
+    }
+
+    /// Runs the commands in the script at `path` once the debugger starts, after any scripts
+    /// queued before it. This is how `-x` and the .deetinit files are run.
+    pub fn queue_script(&mut self, path: &str) {
+        match read_script(path) {
+            Ok(lines) => self.queued_lines.extend(lines),
+            Err(err) => console_println!("{}", err),
+        }
+    }
+
+    /// Queues ~/.deetinit and then ./.deetinit, those that exist.
+    pub fn queue_init_files(&mut self) {
+        let home_init = std::env::var("HOME")
+            .ok()
+            .map(|home| format!("{}/{}", home, INIT_FILE));
+        if let Some(home_init) = &home_init {
+            if fs::metadata(home_init).is_ok() {
+                self.queue_script(home_init);
             }
         }
+        let is_home_init = |path: &str| match (&home_init, fs::canonicalize(path)) {
+            (Some(home_init), Ok(path)) => fs::canonicalize(home_init).ok() == Some(path),
+            _ => false,
+        };
+        if fs::metadata(INIT_FILE).is_ok() && !is_home_init(INIT_FILE) {
+            self.queue_script(INIT_FILE);
+        }
+    }
+
+    /// Makes deet quit once it has run the queued scripts instead of reading commands from the
+    /// user.
+    pub fn set_batch(&mut self) {
+        self.batch = true;
+    }
+
+    fn handle_source_command(&mut self, path: &str) {
+        match read_script(path) {
+            Ok(lines) => self.queue_lines(lines),
+            Err(err) => console_println!("{}", err),
+        }
+    }
+
+    /// Reads the lines of a `define` or `commands` block up to its `end`, along with any blocks
+    /// nested in it.
+    fn read_command_block(&mut self) -> Vec<String> {
+        read_block(|| self.next_line(">"))
+    }
+
+    /// Whether the command being run was typed by the user at the prompt, rather than coming
+    /// from a script, a user-defined or breakpoint command, a pipe, or a program in JSON mode.
+    fn is_interactive(&self) -> bool {
+        self.line_from_user && isatty(libc::STDIN_FILENO).unwrap_or(false)
+    }
+
+    fn handle_define_command(&mut self, name: &str) {
+        if self.is_interactive() {
+            console_println!("Type commands for definition of \"{}\".", name);
+            console_println!("End with a line saying just \"end\".");
+        }
+        let body = self.read_command_block();
+        self.user_commands.insert(name.to_string(), body);
+    }
+
+    /// Sets the commands run when breakpoint `num` (by default the last one set) is hit.
+    fn handle_commands_command(&mut self, num: Option<usize>) {
+        let num = match num.or_else(|| self.breakpoints.last().map(|bp| bp.num)) {
+            Some(num) => num,
+            None => return console_println!("No breakpoints specified."),
+        };
+        if !self.breakpoints.iter().any(|bp| bp.num == num) {
+            return console_println!("No breakpoint number {}.", num);
+        }
+        if self.is_interactive() {
+            console_println!("Type commands for breakpoint(s) {}, one per line.", num);
+            console_println!("End with a line saying just \"end\".");
+        }
+        let commands = self.read_command_block();
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|bp| bp.num == num) {
+            breakpoint.commands = commands;
+        }
+    }
+
+    /// Queues the commands of a breakpoint that was hit, to run before reading the next command.
+    /// Returns whether they start with `silent`, which leaves out the usual report of the stop.
+    fn queue_breakpoint_commands(&mut self, mut commands: Vec<String>) -> bool {
+        let silent = commands.first().map(|command| command.as_str()) == Some("silent");
+        if silent {
+            commands.remove(0);
+        }
+        self.queue_lines(commands);
+        silent
+    }
+
+    /// Resolves a location as accepted by `break`: `*0x401136`, `func`, `42`, `file.c:42` or
+    /// `file.c:func`. A bare line number refers to the file the inferior is stopped in (or the file
+    /// containing main if it isn't running).
+    fn parse_address(&self, location: &str) -> Result<usize, LocationError> {
+        if location.starts_with('*') {
+            let addr = &location[1..];
+            let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
+                &addr[2..]
+            } else {
+                addr
+            };
+            return usize::from_str_radix(addr_without_0x, 16).or_else(|_| {
+                Err(LocationError::Invalid(format!(
+                    "Invalid address \"{}\".",
+                    addr
+                )))
+            });
+        }
+
+        let (file, spec) = match location.rfind(':') {
+            Some(index) => (Some(&location[..index]), &location[index + 1..]),
+            None => (None, location),
+        };
+        if let Some(file) = file {
+            if self.debug_data.get_target_file(file).is_none() {
+                return Err(LocationError::NotFound(format!(
+                    "No source file named {}.",
+                    file
+                )));
+            }
+        }
+
+        match spec.parse::<usize>() {
+            Ok(line_number) => self
+                .parse_line(file, line_number)
+                .or_else(|err| Err(LocationError::Invalid(err))),
+            Err(_) => self
+                .debug_data
+                .get_addr_for_function(file, spec)
+                .ok_or_else(|| {
+                    LocationError::NotFound(match file {
+                        Some(file) => {
+                            format!("Function \"{}\" not defined in \"{}\".", spec, file)
+                        }
+                        None => format!("Function \"{}\" not defined.", spec),
+                    })
+                }),
+        }
     }
 
-    fn parse_address(&self, addr: &str) -> Option<usize> {
-        let addr_without_0x = if addr.to_lowercase().starts_with("*0x") {
-            &addr[3..]
+    /// `location` in a form that still means the same place when it is looked up again, e.g.
+    /// after the program execs: a bare line number is in the file it was set in, not whichever
+    /// file the program happens to be in then.
+    fn canonical_location(&self, location: &str) -> String {
+        match (location.parse::<usize>(), self.default_source_file()) {
+            (Ok(line_number), Some(file)) => format!("{}:{}", file, line_number),
+            _ => location.to_string(),
+        }
+    }
+
+    fn parse_line(&self, file: Option<&str>, line_number: usize) -> Result<usize, String> {
+        let file_name = match file {
+            Some(file) => self.debug_data.get_target_file(file).unwrap().name.clone(),
+            None => self
+                .default_source_file()
+                .ok_or_else(|| "No symbol table is loaded.".to_string())?,
+        };
+
+        let addr = self
+            .debug_data
+            .get_addr_for_line(Some(&file_name), line_number)
+            .ok_or_else(|| format!("Line {} is out of range for \"{}\".", line_number, file_name))?;
+        match self.debug_data.get_line_from_addr(addr) {
+            Some(line) if line.number != line_number => Err(format!(
+                "Line {} of \"{}\" has no code (the next line with code is {}).",
+                line_number, file_name, line.number
+            )),
+            _ => Ok(addr),
+        }
+    }
+
+    /// The file bare line numbers refer to: where the inferior is stopped, or else where main is.
+    fn default_source_file(&self) -> Option<String> {
+        let addr = if let Some(process) = self.process() {
+            process.getregs().ok()?.rip as usize
         } else {
-            &addr
+            self.debug_data.get_addr_for_function(None, "main")?
         };
+        match self.debug_data.get_line_from_addr(addr) {
+            Some(line) => Some(line.file),
+            None => self.debug_data.get_file_names().first().cloned(),
+        }
+    }
 
-        match addr_without_0x.parse::<usize>() {
-            Ok(line) => self.debug_data.get_addr_for_line(None, line), // doesn't work...
-            _ => match self.debug_data.get_addr_for_function(None, addr_without_0x) {
-                Some(addr) => Some(addr),
-                _ => usize::from_str_radix(addr_without_0x, 16).ok(),
-            },
+    fn handle_cont_command(&mut self) {
+        if !self.running {
+            return console_println!("Please run the target program first!");
+        }
+
+        match self.continue_inferior() {
+            Ok(stop) => self.report_status(stop),
+            Err(err) => self.report_error(err),
         }
     }
 
-    fn handle_cont_command(&self) {
+    fn handle_step_command(&mut self, step_into: bool) {
         if !self.running {
-            return println!("Please run the target program first!");
+            return console_println!("Please run the target program first!");
         }
 
-        match self.inferior.as_ref().unwrap().cont() {
-            // use :? for saving devlopment time, it's a toy project anyway
-            Ok(status) => println!("Child stopped status={:?}", status),
-            Err(err) => println!("error={}", err),
+        match self.step_line(step_into) {
+            Ok(stop) => self.report_status(stop),
+            Err(err) => self.report_error(err),
         }
     }
 
-    fn handle_backtrace_command(&self) {
+    fn handle_finish_command(&mut self) {
         if !self.running {
-            return println!("Please run the target program first!");
+            return console_println!("Please run the target program first!");
         }
 
-        let inferior = self.inferior.as_ref().unwrap();
-        match inferior.backtrace(&self.debug_data) {
-            Ok(status) => println!("Child stopped status={:?}", status),
-            Err(err) => println!("error={}", err),
+        // Finishes the selected frame, which need not be the innermost one
+        let (pc, bounds) = match self.selected_frame_info() {
+            Ok(frame) => frame,
+            Err(err) => return console_println!("error={}", err),
+        };
+        let func = match self.debug_data.get_function_from_addr(pc) {
+            Some(func) => func,
+            None => return console_println!("Cannot find bounds of current function"),
+        };
+        if func == "main" {
+            return console_println!("\"finish\" not meaningful in the outermost frame.");
+        }
+        let (return_addr, caller_sp) = match bounds {
+            Some(frame) => frame,
+            None => return console_println!("Cannot find bounds of current function"),
+        };
+
+        console_println!("Run till exit from {}", func);
+        match self.run_until_return(return_addr, caller_sp) {
+            Ok(Some(stop)) => self.report_status(stop),
+            Ok(None) => {
+                let regs = self.inferior.as_ref().unwrap().getregs().unwrap();
+                if interpreter::is_json() {
+                    let fields = vec![
+                        ("reason", "function-finished".into()),
+                        ("return_value", (regs.rax as i64).into()),
+                    ];
+                    return self.emit_stop(fields, regs.rip as usize);
+                }
+                self.print_location(regs.rip as usize);
+                self.print_source_line(regs.rip as usize);
+                console_println!("Value returned: rax = {} ({:#x})", regs.rax as i64, regs.rax);
+            }
+            Err(err) => self.report_error(err),
+        }
+    }
+
+    fn handle_until_command(&mut self, location: Option<&str>) {
+        if !self.running {
+            return console_println!("Please run the target program first!");
+        }
+
+        match location {
+            Some(location) => self.run_to_location(location, true),
+            None => match self.until_next_line() {
+                Ok(stop) => self.report_status(stop),
+                Err(err) => self.report_error(err),
+            },
+        }
+    }
+
+    fn handle_advance_command(&mut self, location: &str) {
+        if !self.running {
+            return console_println!("Please run the target program first!");
         }
+        self.run_to_location(location, false);
+    }
+
+    /// Runs until the inferior gets to `location` or the current frame returns, for `until` and
+    /// `advance`. With `skip_recursion`, getting to the location only counts in the current frame
+    /// or one of its callers.
+    fn run_to_location(&mut self, location: &str, skip_recursion: bool) {
+        let addr = match self.parse_address(location) {
+            Ok(addr) => addr,
+            Err(LocationError::NotFound(err)) | Err(LocationError::Invalid(err)) => {
+                return console_println!("{}", err)
+            }
+        };
+        let result = self.current_frame().and_then(|frame| {
+            let rsp = self.inferior.as_ref().unwrap().getregs()?.rsp as usize;
+            let mut locations = vec![(addr, if skip_recursion { rsp } else { 0 })];
+            if let Some(frame) = frame {
+                locations.push(frame);
+            }
+            self.run_to(&locations)
+        });
+        let stop = match result {
+            Ok(Some(stop)) => stop,
+            Ok(None) => match self.inferior.as_ref().unwrap().getregs() {
+                Ok(regs) => Status::Stopped(signal::Signal::SIGTRAP, regs.rip as usize).into(),
+                Err(err) => return console_println!("error={}", err),
+            },
+            Err(err) => return self.report_error(err),
+        };
+        self.report_status(stop);
     }
 
-    fn handle_breakpoint_command(&mut self, raw_addr: &str) {
-        let addr = self.parse_address(&raw_addr).unwrap();
+    /// `until` without a location: like `next`, but only stops in the same frame once it is past
+    /// where it started, so that it gets out of loops instead of going around them.
+    fn until_next_line(&mut self) -> Result<Stop, nix::Error> {
+        let start = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        let frame = self.current_frame()?;
+        loop {
+            match self.step_line(false)? {
+                Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, rip))
+                    if rip <= start
+                        && !self
+                            .breakpoints
+                            .iter()
+                            .any(|bp| bp.is_active() && bp.addr == rip)
+                        && self.current_frame()? == frame => {}
+                stop => return Ok(stop),
+            }
+        }
+    }
 
+    fn handle_record_command(&mut self) {
         if !self.running {
-            self.breakpoints.push(addr);
+            return console_println!("The program is not being run.");
+        }
+        if self.recording.is_some() {
+            return console_println!(
+                "The process is already being recorded.  Use \"record stop\" to stop recording \
+                 first."
+            );
+        }
+        self.recording = Some(Recording::new(self.record_limit));
+    }
+
+    fn handle_record_stop_command(&mut self) {
+        match self.recording.take() {
+            Some(_) => {
+                console_println!("Process record is stopped and all execution logs are deleted.")
+            }
+            None => console_println!("No recording is currently active."),
+        }
+    }
+
+    fn handle_info_record_command(&self) {
+        let recording = match self.recording.as_ref() {
+            Some(recording) => recording,
+            None => return console_println!("No recording is currently active."),
+        };
+        console_println!("Active record target: record-full");
+        console_println!("Record mode:");
+        if recording.len() == 0 {
+            console_println!("No instructions have been logged.");
         } else {
-            let inferior = self.inferior.as_mut().unwrap();
-            let orig_byte = inferior.breakpoint(addr).unwrap();
-            self.breakpoint_map.insert(addr as u64, orig_byte);
+            let first = recording.first_number();
+            console_println!("Lowest recorded instruction number is {}.", first);
+            console_println!(
+                "Highest recorded instruction number is {}.",
+                first + recording.len() - 1
+            );
+            console_println!("Log contains {} instructions.", recording.len());
+        }
+        if recording.limit() == usize::MAX {
+            console_println!("Max logged instructions is unlimited.");
+        } else {
+            console_println!("Max logged instructions is {}.", recording.limit());
         }
     }
 
-    fn handle_quit_command(&mut self) {
+    /// Checks that there is a recording to run the inferior backwards through.
+    fn can_reverse(&self) -> bool {
         if !self.running {
-            return println!("Please run the target program first!");
+            console_println!("The program is not being run.");
+            return false;
         }
+        if self.recording.is_none() {
+            console_println!("Target native does not support this command.");
+            return false;
+        }
+        true
+    }
 
-        self.do_kill();
+    /// Reports where a reverse execution command stopped, None meaning the start of the log.
+    fn report_reverse(&mut self, result: Result<Option<Status>, nix::Error>) {
+        match result {
+            Ok(Some(status)) => self.report_status(status.into()),
+            Ok(None) => {
+                let rip = match self.inferior.as_ref().unwrap().getregs() {
+                    Ok(regs) => regs.rip as usize,
+                    Err(err) => return console_println!("error={}", err),
+                };
+                if interpreter::is_json() {
+                    self.selected_frame = 0;
+                    return self.emit_stop(vec![("reason", "no-history".into())], rip);
+                }
+                console_println!("\nNo more reverse-execution history.");
+                self.report_status(Status::Stopped(signal::Signal::SIGTRAP, rip).into());
+            }
+            Err(err) => console_println!("error={}", err),
+        }
     }
 
-    fn handle_run_command(&mut self, args: &Vec<String>) {
-        if self.running {
-            self.do_kill();
+    fn handle_backtrace_command(&self, count: Option<usize>) {
+        if self.process().is_none() {
+            return console_println!("Please run the target program first!");
         }
 
-        if let Some(inferior) = Inferior::new(&self.target, &args) {
-            self.inferior = Some(inferior);
-            self.running = true;
+        let frames = match self.backtrace() {
+            Ok(frames) => frames,
+            Err(err) => return console_println!("error={}", err),
+        };
+        let count = count.unwrap_or(MAX_BACKTRACE_DEPTH).min(frames.len());
+        let more = count < frames.len() || frames.len() == MAX_BACKTRACE_DEPTH;
+        if interpreter::is_json() {
+            let frames = frames
+                .iter()
+                .take(count)
+                .enumerate()
+                .map(|(level, frame)| {
+                    let pc = frame.pc as usize;
+                    let mut fields = vec![("level", level.into())];
+                    fields.append(&mut self.frame_fields(pc, frame.lookup_pc as usize));
+                    Json::object(fields)
+                })
+                .collect();
+            return interpreter::emit(&Json::object(vec![
+                ("type", "backtrace".into()),
+                ("frames", Json::Array(frames)),
+                ("more", more.into()),
+            ]));
+        }
+        for (level, frame) in frames.iter().take(count).enumerate() {
+            self.print_frame(level, frame);
+        }
+        if more {
+            console_println!("(More stack frames follow...)");
+        }
+    }
 
-            self.set_breakpoints();
+    /// Returns the selected frame's code address, along with its return address and the caller's
+    /// rsp (see current_frame). For frames other than the innermost, the code address is that of
+    /// the call instruction rather than the return address, which may belong to the next line or
+    /// even the next function, unless the frame was interrupted by a signal.
+    fn selected_frame_info(&self) -> Result<(usize, Option<(usize, usize)>), String> {
+        if self.selected_frame == 0 {
+            let process = self.process().unwrap();
+            let rip = process.getregs().or_else(|err| Err(err.to_string()))?.rip;
+            let bounds = self.current_frame().or_else(|err| Err(err.to_string()))?;
+            return Ok((rip as usize, bounds));
+        }
 
-            let status = self.inferior.as_ref().unwrap().cont().unwrap();
+        let frames = self.backtrace().or_else(|err| Err(err.to_string()))?;
+        let frame = frames
+            .get(self.selected_frame)
+            .ok_or_else(|| "No stack.".to_string())?;
+        let bounds = match (frame.return_addr, frame.cfa) {
+            (Some(return_addr), Some(cfa)) => Some((return_addr as usize, cfa as usize)),
+            _ => None,
+        };
+        Ok((frame.lookup_pc as usize, bounds))
+    }
 
-            match status {
-                Status::Stopped(signal, rip) => {
-                    if signal == signal::Signal::SIGTRAP {
-                        self.handle_stop_at_breakpoint(rip);
-                    }
+    /// Prints a frame as a line of a backtrace, e.g. `#1  func1 (foo.c:12)`.
+    fn print_frame(&self, level: usize, frame: &Frame) {
+        let pc = frame.pc as usize;
+        console_print!("#{:<3}", level);
+        if level == 0 {
+            self.print_location(pc);
+        } else {
+            // Look up the call instruction (see selected_frame_info), but show the return address
+            let lookup_pc = frame.lookup_pc as usize;
+            let func = self
+                .debug_data
+                .get_function_from_addr(lookup_pc)
+                .unwrap_or_else(|| "??".to_string());
+            match self.debug_data.get_line_from_addr(lookup_pc) {
+                Some(line) => console_println!("{} ({})", func, line),
+                None => console_println!("{} ({:#x})", func, pc),
+            }
+        }
+    }
+
+    /// Unwinds the inferior's stack, stopping at main (or wherever unwinding fails) and after
+    /// MAX_BACKTRACE_DEPTH frames.
+    fn backtrace(&self) -> Result<Vec<Frame>, nix::Error> {
+        let process = self.process().unwrap();
+        let regs = process.getregs()?;
+        let read_word = |addr: u64| process.read_word(addr as usize).ok();
+        let mut frames = unwind::backtrace(
+            &|pc| self.debug_data.call_frame_info(pc),
+            &regs,
+            &read_word,
+            MAX_BACKTRACE_DEPTH,
+        );
+        // Like gdb, don't show the libc frames that call main
+        if let Some(main) = frames.iter().position(|frame| {
+            let pc = frame.pc as usize;
+            self.debug_data.get_function_from_addr(pc).as_deref() == Some("main")
+        }) {
+            frames.truncate(main + 1);
+        }
+        Ok(frames)
+    }
+
+    fn handle_up_command(&mut self, count: usize) {
+        if self.process().is_none() {
+            return console_println!("No stack.");
+        }
+
+        let frames = match self.backtrace() {
+            Ok(frames) => frames,
+            Err(err) => return console_println!("error={}", err),
+        };
+        if self.selected_frame + 1 >= frames.len() {
+            return console_println!("Initial frame selected; you cannot go up.");
+        }
+        self.selected_frame = self.selected_frame.saturating_add(count).min(frames.len() - 1);
+        self.print_frame(self.selected_frame, &frames[self.selected_frame]);
+        self.print_frame_source_line(&frames[self.selected_frame]);
+    }
+
+    fn handle_down_command(&mut self, count: usize) {
+        if self.process().is_none() {
+            return console_println!("No stack.");
+        }
+
+        if self.selected_frame == 0 {
+            return console_println!("Bottom (innermost) frame selected; you cannot go down.");
+        }
+        self.selected_frame = self.selected_frame.saturating_sub(count);
+        self.handle_frame_command(None);
+    }
+
+    /// Selects frame `level`, or just describes the selected frame if no level is given.
+    fn handle_frame_command(&mut self, level: Option<usize>) {
+        if self.process().is_none() {
+            return console_println!("No stack.");
+        }
+
+        let frames = match self.backtrace() {
+            Ok(frames) => frames,
+            Err(err) => return console_println!("error={}", err),
+        };
+        let level = level.unwrap_or(self.selected_frame);
+        match frames.get(level) {
+            Some(frame) => {
+                self.selected_frame = level;
+                self.print_frame(level, frame);
+                self.print_frame_source_line(frame);
+            }
+            None => console_println!("No frame at level {}.", level),
+        }
+    }
+
+    fn handle_info_threads_command(&self) {
+        let process = match self.process() {
+            Some(process) => process,
+            None => return console_println!("No threads."),
+        };
+        let (current, _) = process.current_thread();
+        console_println!("  Id   Target Id         Frame");
+        for (num, tid) in process.threads() {
+            let marker = if num == current { '*' } else { ' ' };
+            console_print!("{} {:<4} {:<17} ", marker, num, format!("LWP {}", tid));
+            match process.getregs_of(tid) {
+                Ok(regs) => self.print_location(regs.rip as usize),
+                Err(err) => console_println!("error={}", err),
+            }
+        }
+    }
+
+    /// Selects the thread whose registers and stack other commands look at, or with no number
+    /// shows which one is selected.
+    fn handle_thread_command(&mut self, num: Option<usize>) {
+        let process = match self.process_mut() {
+            Some(process) => process,
+            None => return console_println!("The program is not being run."),
+        };
+        let num = match num {
+            Some(num) => num,
+            None => {
+                let (num, tid) = process.current_thread();
+                return console_println!("[Current thread is {} (LWP {})]", num, tid);
+            }
+        };
+        if !process.select_thread(num) {
+            return console_println!("Invalid thread ID: {}", num);
+        }
+        let (_, tid) = process.current_thread();
+        console_println!("[Switching to thread {} (LWP {})]", num, tid);
+        self.selected_frame = 0;
+        match self.process().unwrap().getregs() {
+            Ok(regs) => {
+                console_print!("#0  ");
+                self.print_location(regs.rip as usize);
+                self.print_source_line(regs.rip as usize);
+            }
+            Err(err) => console_println!("error={}", err),
+        }
+    }
+
+    /// Changes what happens when the inferior receives a signal (`all` meaning every signal but
+    /// the ones the debugger uses), then shows the resulting policy.
+    fn handle_handle_command(&mut self, name: &str, actions: &[String]) {
+        let selected: Vec<signal::Signal> = if name == "all" {
+            signal::Signal::iterator()
+                .filter(|signal| {
+                    *signal != signal::Signal::SIGTRAP && *signal != signal::Signal::SIGINT
+                })
+                .collect()
+        } else {
+            match signals::parse_signal(name) {
+                Some(signal) => vec![signal],
+                None => {
+                    return console_println!("Unrecognized or ambiguous flag word: \"{}\".", name)
                 }
-                other => {
-                    println!("Child stopped as {:?}", other)
+            }
+        };
+        let mut updated = Vec::new();
+        for signal in selected.iter() {
+            let mut policy = self.signal_policies[signal];
+            for action in actions {
+                if !policy.apply(action) {
+                    return console_println!(
+                        "Unrecognized or ambiguous flag word: \"{}\".",
+                        action
+                    );
                 }
             }
-        } else {
-            println!("Error starting subprocess");
+            updated.push((*signal, policy));
         }
+        self.signal_policies.extend(updated);
+        self.print_signal_policies(&selected);
     }
 
-    fn do_kill(&mut self) {
-        let inferior = self.inferior.as_mut().unwrap();
-        println!("Killing running inferior (pid {})", inferior.pid());
-        match inferior.kill() {
-            Ok(status) => {
-                println!(
-                    "child process is killed pid={}, status={}",
-                    inferior.pid(),
-                    status
-                );
-            }
-            Err(error) => {
-                println!("Can't kill child, error={}", error)
+    fn handle_info_signals_command(&self, name: Option<&str>) {
+        match name {
+            Some(name) => match signals::parse_signal(name) {
+                Some(signal) => self.print_signal_policies(&[signal]),
+                None => console_println!("Unrecognized or ambiguous flag word: \"{}\".", name),
+            },
+            None => {
+                let all: Vec<signal::Signal> = signal::Signal::iterator().collect();
+                self.print_signal_policies(&all);
             }
         }
+    }
 
-        self.running = false;
+    fn print_signal_policies(&self, selected: &[signal::Signal]) {
+        let yes_no = |flag: bool| if flag { "Yes" } else { "No" };
+        console_println!("Signal        Stop\tPrint\tPass to program\tDescription");
+        for signal in selected {
+            let policy = self.signal_policies[signal];
+            console_println!(
+                "{:<13} {}\t{}\t{}\t\t{}",
+                signal.as_str(),
+                yes_no(policy.stop),
+                yes_no(policy.print),
+                yes_no(policy.pass),
+                signals::description(*signal)
+            );
+        }
     }
 
-    fn set_breakpoints(&mut self) {
-        let inferior = self.inferior.as_mut().unwrap();
+    fn handle_set_command(&mut self, name: &str, value: &str) {
+        match (name, value) {
+            ("follow-fork-mode", "parent") => self.follow_fork_child = false,
+            ("follow-fork-mode", "child") => self.follow_fork_child = true,
+            ("follow-fork-mode", _) => console_println!("Undefined item: \"{}\".", value),
+            ("detach-on-fork", "on") => self.detach_on_fork = true,
+            ("detach-on-fork", "off") => self.detach_on_fork = false,
+            ("detach-on-fork", _) => console_println!("\"on\" or \"off\" expected."),
+            ("record", _) => self.handle_set_record_command(value),
+            ("var", _) | ("variable", _) => self.handle_set_variable_command(value),
+            // Like gdb, anything that isn't a setting is taken as an expression, e.g. `set x = 3`
+            _ => self.handle_set_variable_command(&format!("{} {}", name, value)),
+        }
+    }
+
+    /// `set var x = 3`: evaluates an expression for what it does, which normally is assigning.
+    fn handle_set_variable_command(&mut self, input: &str) {
+        if input.trim().is_empty() {
+            return console_println!("Argument required (expression to compute).");
+        }
+        if let Err(err) = self
+            .parse_expression(input)
+            .and_then(|expr| expr::evaluate(&expr, self))
+        {
+            console_println!("{}", err);
+        }
+    }
+
+    /// `set record [full] insn-number-max N`, where 0 or `unlimited` means no limit.
+    fn handle_set_record_command(&mut self, value: &str) {
+        let words: Vec<&str> = value
+            .split_whitespace()
+            .skip_while(|word| *word == "full")
+            .collect();
+        let limit = match words.as_slice() {
+            ["insn-number-max", "unlimited"] | ["insn-number-max", "0"] => usize::MAX,
+            ["insn-number-max", limit] => match limit.parse() {
+                Ok(limit) => limit,
+                Err(_) => return console_println!("Invalid number \"{}\".", limit),
+            },
+            _ => return console_println!("Undefined set record command: \"{}\".", value),
+        };
+        self.record_limit = limit;
+        if let Some(recording) = self.recording.as_mut() {
+            recording.set_limit(limit);
+        }
+    }
 
-        for breakpoint in self.breakpoints.iter() {
-            match inferior.breakpoint(*breakpoint) {
-                Ok(orig_byte) => {
-                    self.breakpoint_map.insert(*breakpoint as u64, orig_byte);
+    fn handle_show_command(&self, name: &str) {
+        match name {
+            "follow-fork-mode" => console_println!(
+                "Debugger response to a program call of fork or vfork is \"{}\".",
+                if self.follow_fork_child {
+                    "child"
+                } else {
+                    "parent"
                 }
-                Err(error) => {
-                    println!("failed set breakpoint error={}", error);
+            ),
+            "detach-on-fork" => console_println!(
+                "Whether gdb will detach the child of a fork is {}.",
+                if self.detach_on_fork { "on" } else { "off" }
+            ),
+            "user" => {
+                let mut names: Vec<&String> = self.user_commands.keys().collect();
+                names.sort();
+                for name in names {
+                    console_println!("User command \"{}\":", name);
+                    for line in &self.user_commands[name] {
+                        console_println!("  {}", line);
+                    }
                 }
             }
+            _ => console_println!("Undefined show command: \"{}\".", name),
         }
     }
 
-    fn handle_stop_at_breakpoint(&mut self, rip: usize) {
-        let inferior = self.inferior.as_mut().unwrap();
+    fn handle_info_inferiors_command(&self) {
+        console_println!("  Num  Description       Executable");
+        let description = if self.running {
+            format!("process {}", self.inferior.as_ref().unwrap().pid())
+        } else {
+            "<null>".to_string()
+        };
+        console_println!(
+            "* {:<4} {:<17} {}",
+            self.inferior_num,
+            description,
+            self.target
+        );
+        for held in self.held_inferiors.iter() {
+            console_println!(
+                "  {:<4} {:<17} {}",
+                held.num,
+                format!("process {}", held.inferior.pid()),
+                held.target
+            );
+        }
+    }
 
-        let prev_rip = rip - 1;
+    fn handle_info_shared_library_command(&self) {
+        let libraries = self.debug_data.libraries();
+        if libraries.is_empty() {
+            return console_println!("No shared libraries loaded at this time.");
+        }
+        console_println!(
+            "From                To                  Syms Read   Shared Object Library"
+        );
+        for library in libraries {
+            let (start, end) = library.address_range();
+            console_println!(
+                "{:#018x}  {:#018x}  {:<11} {}",
+                start,
+                end,
+                if library.has_debug_info() {
+                    "Yes"
+                } else {
+                    "Yes (*)"
+                },
+                library.path()
+            );
+        }
+        if libraries.iter().any(|library| !library.has_debug_info()) {
+            console_println!("(*): Shared library is missing debugging information.");
+        }
+    }
 
-        // write origin back
-        let orig_byte = self.breakpoint_map.get(&(prev_rip as u64)).unwrap();
-        inferior.write_byte(prev_rip as usize, *orig_byte).unwrap();
+    /// Switches to one of the processes held after a fork. The one being debugged is held in its
+    /// place, unless it has exited.
+    fn handle_inferior_command(&mut self, num: usize) {
+        if num == self.inferior_num {
+            return console_println!("[Current inferior is {}]", num);
+        }
+        let index = match self.held_inferiors.iter().position(|held| held.num == num) {
+            Some(index) => index,
+            None => return console_println!("Inferior ID {} not known.", num),
+        };
 
-        inferior.go_back_one_step().unwrap();
-        inferior.step().unwrap();
-        inferior.wait(None).unwrap();
-        inferior.write_byte(prev_rip as usize, 204).unwrap();
+        let held = self.held_inferiors.remove(index);
+        // Only the inferior that was recorded can be taken back through its log
+        self.recording = None;
+        if self.running {
+            self.held_inferiors.push(HeldInferior {
+                num: self.inferior_num,
+                inferior: self.inferior.take().unwrap(),
+                target: self.target.clone(),
+                breakpoint_map: std::mem::replace(&mut self.breakpoint_map, HashMap::new()),
+            });
+        }
+        let pid = held.inferior.pid();
+        self.inferior = Some(held.inferior);
+        self.breakpoint_map = held.breakpoint_map;
+        self.inferior_num = held.num;
+        self.running = true;
+        self.selected_frame = 0;
+        if held.target != self.target {
+            match DwarfData::from_file(&held.target) {
+                Ok(debug_data) => self.debug_data = Symbols::new(debug_data),
+                Err(err) => console_println!(
+                    "Could not load debugging symbols from {}: {:?}",
+                    held.target,
+                    err
+                ),
+            }
+            // The breakpoints are looked up in the other program
+            for breakpoint in self.breakpoints.iter_mut() {
+                breakpoint.pending = true;
+            }
+            self.target = held.target;
+        }
+        self.shlib_event_addr = None;
+        self.load_shared_libraries();
+        console_println!(
+            "[Switching to inferior {} [process {}] ({})]",
+            num,
+            pid,
+            self.target
+        );
+
+        // Breakpoints may have changed while the process was held
+        let stale: Vec<u64> = self
+            .breakpoint_map
+            .keys()
+            .filter(|addr| {
+                self.shlib_event_addr != Some(**addr as usize)
+                    && !self
+                        .breakpoints
+                        .iter()
+                        .any(|bp| bp.is_active() && bp.addr as u64 == **addr)
+            })
+            .cloned()
+            .collect();
+        for addr in stale {
+            if let Err(err) = self.remove_trap(addr as usize) {
+                console_println!("failed to remove breakpoint error={}", err);
+            }
+        }
+        self.set_breakpoints();
+        if let Err(err) = self.install_watchpoints() {
+            console_println!("failed to set watchpoints error={}", err);
+        }
+        match self.inferior.as_ref().unwrap().getregs() {
+            Ok(regs) => {
+                console_print!("#0  ");
+                self.print_location(regs.rip as usize);
+                self.print_source_line(regs.rip as usize);
+            }
+            Err(err) => console_println!("error={}", err),
+        }
+    }
+
+    /// Prints the arguments of the selected frame's function, or its other local variables.
+    fn handle_info_variables_command(&self, parameters: bool) {
+        if self.process().is_none() {
+            return console_println!("No frame selected.");
+        }
+
+        let frame = match self.selected_frame_info() {
+            Ok(frame) => frame,
+            Err(err) => return console_println!("{}", err),
+        };
+        // Each is read from its own location, as a name may be in the function more than once
+        let variables: Vec<&Variable> = match self.debug_data.get_function_containing(frame.0) {
+            Some(func) => func
+                .variables
+                .iter()
+                .filter(|var| var.is_parameter == parameters)
+                .collect(),
+            None => return console_println!("No symbol table info available."),
+        };
+        if interpreter::is_json() {
+            let variables = variables
+                .iter()
+                .map(|var| {
+                    let value = match self.read_variable(var, frame) {
+                        Ok(bytes) => ("value", var.entity_type.format_value(&bytes).into()),
+                        Err(err) => ("error", err.into()),
+                    };
+                    Json::object(vec![("name", var.name.as_str().into()), value])
+                })
+                .collect();
+            return interpreter::emit(&Json::object(vec![
+                ("type", "variables".into()),
+                ("variables", Json::Array(variables)),
+            ]));
+        }
+        if variables.is_empty() {
+            return console_println!(
+                "{}",
+                if parameters {
+                    "No arguments."
+                } else {
+                    "No locals."
+                }
+            );
+        }
+        for var in variables {
+            match self.read_variable(var, frame) {
+                Ok(bytes) => {
+                    console_println!("{} = {}", var.name, var.entity_type.format_value(&bytes))
+                }
+                Err(err) => console_println!("{} = <error: {}>", var.name, err),
+            }
+        }
+    }
+
+    /// Prints the value of an expression. For `call` (`is_call`), calls of functions returning
+    /// void print nothing.
+    fn handle_print_command(&mut self, input: &str, is_call: bool) {
+        if self.process().is_none() {
+            return console_println!("Please run the target program first!");
+        }
+
+        let value = self
+            .parse_expression(input)
+            .and_then(|expr| expr::evaluate(&expr, self));
+        if is_call
+            && value
+                .as_ref()
+                .map_or(false, |value| value.value_type().is_void())
+        {
+            return;
+        }
+        let value = value.and_then(|value| self.format_value(&value));
+        if interpreter::is_json() {
+            let value = match value {
+                Ok(value) => ("value", value.into()),
+                Err(err) => ("error", err.into()),
+            };
+            return interpreter::emit(&Json::object(vec![
+                ("type", "value".into()),
+                ("expression", input.into()),
+                value,
+            ]));
+        }
+        match value {
+            Ok(value) => console_println!("{} = {}", input, value),
+            Err(err) => console_println!("{}", err),
+        }
+    }
+
+    /// Parses an expression for `print`, `call` or `set var`, where casts can name the program's
+    /// typedefs.
+    fn parse_expression(&self, input: &str) -> Result<Expr, String> {
+        expr::parse_with_typedefs(input, &|name| {
+            self.debug_data.get_type_by_name(name).is_some()
+        })
+    }
+
+    /// Formats a value for `print`. Pointers an expression made, e.g. with `&x`, show their type
+    /// like gdb does: `(int *) 0x7ffe3a8c`.
+    fn format_value(&self, value: &Value) -> Result<String, String> {
+        let value_type = value.value_type();
+        let bytes = value.bytes(self)?;
+        Ok(match value_type.strip_typedefs().kind {
+            TypeKind::PointerTo(_) => format!(
+                "({}) {:#x}",
+                value_type.name,
+                value_type.integer_value(&bytes)
+            ),
+            _ => value_type.format_value(&bytes),
+        })
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.process()
+            .ok_or_else(|| format!("Cannot access memory at address {:#x}", addr))?
+            .read_bytes(addr, len)
+            .or_else(|err| {
+                Err(format!(
+                    "Cannot access memory at address {:#x}: {}",
+                    addr, err
+                ))
+            })
+    }
+
+    /// Calls a function of the inferior for an expression, converting the arguments to the types
+    /// of its parameters. `return_type` is what to take the result as when the function has no
+    /// debugging information. Breakpoints don't stop the function.
+    fn call_function(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        return_type: Option<&Type>,
+    ) -> Result<Value, String> {
+        let inferior = self
+            .inferior
+            .as_ref()
+            .filter(|_| self.running)
+            .ok_or_else(|| "You can't do that without a process to debug.".to_string())?;
+        // What the function does couldn't be undone
+        if self.recording.is_some() {
+            return Err("Cannot call functions in the program while recording.".to_string());
+        }
+        let (addr, parameters, declared_type) = match self.debug_data.get_function(name) {
+            Some(func) => {
+                let parameters: Vec<Type> = func
+                    .variables
+                    .iter()
+                    .filter(|var| var.is_parameter)
+                    .map(|var| var.entity_type.clone())
+                    .collect();
+                let declared_type = func
+                    .return_type
+                    .clone()
+                    .unwrap_or_else(|| Type::new("void".to_string(), 0));
+                (func.address, parameters, Some(declared_type))
+            }
+            None => match self.debug_data.get_symbol_address(name) {
+                Some(addr) => (addr, Vec::new(), None),
+                None => return Err(format!("No symbol \"{}\" in current context.", name)),
+            },
+        };
+        let result_type = declared_type
+            .or_else(|| return_type.cloned())
+            .ok_or_else(|| {
+                format!(
+                    "'{}' has unknown return type; cast the call to its declared return type",
+                    name
+                )
+            })?;
+        if !result_type.is_scalar() {
+            return Err("Calling functions that return a struct or union isn't supported.".into());
+        }
+        if args.len() < parameters.len() {
+            return Err("Too few arguments in function call.".to_string());
+        }
+
+        let mut arguments = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            // Arguments a prototype doesn't cover are promoted, floats to double
+            let arg = match parameters.get(i) {
+                Some(parameter) => expr::convert(arg.clone(), parameter, self)?,
+                None if arg.value_type().is_float() => {
+                    expr::convert(arg.clone(), &Type::new("double".to_string(), 8), self)?
+                }
+                None => arg.clone(),
+            };
+            if !arg.value_type().is_scalar() {
+                return Err("Passing structs and unions by value isn't supported.".to_string());
+            }
+            arguments.push(if arg.value_type().is_float() {
+                let mut bits = [0u8; 8];
+                let bytes = arg.bytes(self)?;
+                bits[..bytes.len()].copy_from_slice(&bytes);
+                Argument::Sse(u64::from_le_bytes(bits))
+            } else {
+                Argument::Integer(arg.to_integer(self)? as u64)
+            });
+        }
+
+        for (bp_addr, orig_byte) in self.breakpoint_map.iter() {
+            inferior
+                .write_bytes(*bp_addr as usize, &[*orig_byte])
+                .or_else(|err| Err(err.to_string()))?;
+        }
+        let outcome = self
+            .run_call(addr, &arguments)
+            .or_else(|err| Err(err.to_string()))?;
+        let inferior = self.inferior.as_ref().unwrap();
+        let abandoned = format!(
+            "Evaluation of the expression containing the function\n({}) will be abandoned.",
+            name
+        );
+        let returned = match outcome {
+            Outcome::Returned(rax, xmm0) => (rax, xmm0),
+            Outcome::Interrupted(status @ Status::Exited(_))
+            | Outcome::Interrupted(status @ Status::Signaled(_)) => {
+                self.exited_in_call = Some(status);
+                return Err(format!(
+                    "The program being debugged exited while in a function called from deet.\n{}",
+                    abandoned
+                ));
+            }
+            Outcome::Interrupted(status) => {
+                for bp_addr in self.breakpoint_map.keys() {
+                    let _ = inferior.write_bytes(*bp_addr as usize, &[0xcc]);
+                }
+                // Nothing could be put back for the thread that is gone or the program replaced
+                let gone = match status {
+                    Status::ThreadExited => Some("The thread being debugged exited"),
+                    Status::Execed => Some("The program being debugged stopped"),
+                    _ => None,
+                };
+                if let Some(gone) = gone {
+                    return Err(format!(
+                        "{} while in a function called from deet.\n{}",
+                        gone, abandoned
+                    ));
+                }
+                let stopped = match status {
+                    Status::Stopped(signal, _) if signal != signal::Signal::SIGTRAP => format!(
+                        "\nProgram received signal {}, {}.\nThe program being debugged was \
+                         signaled while in a function called from deet.",
+                        signal.as_str(),
+                        signals::description(signal)
+                    ),
+                    _ => "The program being debugged stopped while in a function called from deet."
+                        .to_string(),
+                };
+                return Err(format!(
+                    "{}\ndeet has restored the context to what it was before the call.\n{}",
+                    stopped, abandoned
+                ));
+            }
+        };
+        for bp_addr in self.breakpoint_map.keys() {
+            inferior
+                .write_bytes(*bp_addr as usize, &[0xcc])
+                .or_else(|err| Err(err.to_string()))?;
+        }
+
+        // Floating point values come back in xmm0, everything else in rax
+        let (rax, xmm0) = returned;
+        let bits = if result_type.is_float() { xmm0 } else { rax };
+        let size = result_type.size.min(8);
+        Ok(Value::Computed(
+            result_type,
+            bits.to_le_bytes()[..size].to_vec(),
+        ))
+    }
+
+    /// Calls the function at `addr` in the current thread, returning to the entry point.
+    fn run_call(&mut self, addr: usize, arguments: &[Argument]) -> Result<Outcome, nix::Error> {
+        let return_address = self.debug_data.executable().entry();
+        let inferior = self.inferior.as_mut().unwrap();
+        let call = infcall::start(inferior, addr, arguments, return_address)?;
+        // The signal the thread stopped with is for when the program goes on, not for the call
+        let signal = inferior.take_signal();
+        let status = self.run_called_function()?;
+        let inferior = self.inferior.as_mut().unwrap();
+        let outcome = infcall::finish(inferior, call, status)?;
+        if let Outcome::Returned(_, _) = outcome {
+            inferior.set_signal(signal);
+        }
+        Ok(outcome)
+    }
+
+    /// Runs the function infcall::start set up in the current thread until the thread stops,
+    /// dealing with forks, execs and signals on the way like when the program runs.
+    fn run_called_function(&mut self) -> Result<Status, nix::Error> {
+        let (_, tid) = self.inferior.as_ref().unwrap().current_thread();
+        let mut status = self.inferior.as_mut().unwrap().run_current_thread()?;
+        loop {
+            status = self.handle_process_events(status)?;
+            match status {
+                // A signal the program doesn't stop for doesn't stop the call either
+                Status::Stopped(signal, _)
+                    if signal != signal::Signal::SIGTRAP
+                        && self.inferior.as_ref().unwrap().current_thread().1 == tid
+                        && !self.should_stop_for_signal(signal) => {}
+                status => return Ok(status),
+            }
+            status = self.inferior.as_mut().unwrap().run_current_thread()?;
+        }
+    }
+
+    fn handle_info_registers_command(&self, names: &Vec<String>) {
+        let regs = match self.process().map(|process| process.getregs()) {
+            Some(Ok(regs)) => regs,
+            Some(Err(err)) => return console_println!("error={}", err),
+            None => return console_println!("The program has no registers now."),
+        };
+        let names: Vec<&str> = if names.is_empty() {
+            registers::REGISTER_NAMES.to_vec()
+        } else {
+            names.iter().map(|name| name.as_str()).collect()
+        };
+        for name in names {
+            let value = match registers::get_register(&regs, name) {
+                Some(value) => value,
+                None => return console_println!("Invalid register `{}'", name),
+            };
+            let natural = match name {
+                "rip" | "pc" => format!("{:#x}{}", value, self.symbolize(value as usize)),
+                "rsp" | "sp" | "rbp" | "fp" => format!("{:#x}", value),
+                "eflags" => registers::format_eflags(value),
+                _ => format!("{}", value as i64),
+            };
+            console_println!("{:<15}{:<19}{}", name, format!("{:#x}", value), natural);
+        }
+    }
+
+    fn handle_set_register_command(&mut self, name: &str, value: &str) {
+        if !self.running {
+            return console_println!("The program has no registers now.");
+        }
+
+        let value = match expr::parse(value).and_then(|value| expr::eval(&value, self)) {
+            Ok(value) => value,
+            Err(err) => return console_println!("{}", err),
+        };
+        let inferior = self.inferior.as_ref().unwrap();
+        let result = inferior.getregs().and_then(|mut regs| {
+            if !registers::set_register(&mut regs, name, value as u64) {
+                return Ok(false);
+            }
+            inferior.setregs(regs).map(|_| true)
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => console_println!("Invalid register `{}'", name),
+            Err(err) => console_println!("error={}", err),
+        }
+    }
+
+    /// Examines memory like gdb's `x`: `count` units of `size` bytes starting at the address
+    /// `addr` evaluates to, printed in `format`. Stops at the first address that can't be read.
+    fn handle_examine_command(&mut self, format: ExamineFormat, addr: &str) {
+        // Code can be read from the executable without a process
+        if self.process().is_none() && format.format != 'i' {
+            return console_println!("Please run the target program first!");
+        }
+
+        let mut addr = match self.evaluate_code_address(addr) {
+            Ok(addr) => addr,
+            Err(err) => return console_println!("{}", err),
+        };
+
+        if format.format == 'i' {
+            let pc = self.selected_frame_pc();
+            for _ in 0..format.count {
+                let code = self.read_code(addr, disassemble::MAX_INSTRUCTION_LEN);
+                if code.is_empty() {
+                    return console_println!("Cannot access memory at address {:#x}", addr);
+                }
+                let instruction = self.decode_instruction(&code, addr);
+                let marker = if pc == Some(addr) { "=> " } else { "   " };
+                console_println!(
+                    "{}{:#x}{}:\t{}",
+                    marker,
+                    addr,
+                    self.symbolize(addr),
+                    instruction.text
+                );
+                addr = match addr.checked_add(instruction.len) {
+                    Some(next) => next,
+                    None => return,
+                };
+            }
+            return;
+        }
+
+        if format.format == 's' {
+            for _ in 0..format.count {
+                match self.read_c_string(addr) {
+                    Ok(bytes) => {
+                        let text: String = bytes
+                            .iter()
+                            .map(|byte| (*byte as char).escape_default().to_string())
+                            .collect();
+                        console_println!("{:#x}{}:\t\"{}\"", addr, self.symbolize(addr), text);
+                        addr = match addr.checked_add(bytes.len() + 1) {
+                            Some(next) => next,
+                            None => return,
+                        };
+                    }
+                    Err(bad_addr) => {
+                        return console_println!("Cannot access memory at address {:#x}", bad_addr)
+                    }
+                }
+            }
+            return;
+        }
+
+        let per_row = match (format.format, format.size) {
+            ('a', _) | (_, 8) => 2,
+            (_, 4) => 4,
+            _ => 8,
+        };
+        for i in 0..format.count {
+            let unit_addr = addr.wrapping_add(i.wrapping_mul(format.size));
+            // Units wrapping past the end of the address space can't be read
+            let bytes = match i.checked_mul(format.size).and_then(|off| addr.checked_add(off)) {
+                Some(_) => self.read_original_bytes(unit_addr, format.size),
+                None => Err(nix::Error::Sys(Errno::EIO)),
+            };
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    if i % per_row != 0 {
+                        console_println!();
+                    }
+                    return console_println!("Cannot access memory at address {:#x}", unit_addr);
+                }
+            };
+            if i % per_row == 0 {
+                console_print!("{:#x}{}:", unit_addr, self.symbolize(unit_addr));
+            }
+            console_print!("\t{}", self.format_unit(format, &bytes));
+            if i % per_row == per_row - 1 || i == format.count - 1 {
+                console_println!();
+            }
+        }
+    }
+
+    /// Formats one unit of memory read by `x`.
+    fn format_unit(&self, format: ExamineFormat, bytes: &[u8]) -> String {
+        let mut raw = [0u8; 8];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        let unsigned = u64::from_le_bytes(raw);
+        let bits = 8 * bytes.len();
+        match format.format {
+            'x' => format!("0x{:0width$x}", unsigned, width = 2 * bytes.len()),
+            'd' => Type::new("long".to_string(), bytes.len()).format_value(bytes),
+            'u' => format!("{}", unsigned),
+            'o' => format!("0{:o}", unsigned),
+            't' => format!("{:0width$b}", unsigned, width = bits),
+            'c' => Type::new("char".to_string(), 1).format_value(&bytes[..1]),
+            'a' => format!("{:#x}{}", unsigned, self.symbolize(unsigned as usize)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Lists source lines like gdb's `list`: around a line, function or address, after the lines
+    /// listed last (before them for `list -`), or around where the inferior stopped.
+    fn handle_list_command(&mut self, location: Option<&str>) {
+        let centered = |number: usize| {
+            let first = number.saturating_sub(LIST_LINES / 2).max(1);
+            (first, first + LIST_LINES - 1)
+        };
+        let position = match location {
+            Some(spec) if spec != "-" => None,
+            _ => self.list_position.clone(),
+        };
+        let (file, first, last) = match (location, position) {
+            (Some("-"), Some(position)) => {
+                let (file, first) = match position {
+                    ListPosition::Around(file, number) => (file, centered(number).0),
+                    ListPosition::Listed(file, first, _) => (file, first),
+                };
+                if first <= 1 {
+                    return console_println!("Already at the start of {}.", file);
+                }
+                (file, first.saturating_sub(LIST_LINES).max(1), first - 1)
+            }
+            (None, Some(ListPosition::Listed(file, _, last))) => {
+                (file, last + 1, last + LIST_LINES)
+            }
+            (None, Some(ListPosition::Around(file, number))) => {
+                let (first, last) = centered(number);
+                (file, first, last)
+            }
+            // Nothing listed yet, start around main like gdb
+            (spec, _) => match self.resolve_list_location(spec.unwrap_or("main")) {
+                Ok((file, number)) => {
+                    let (first, last) = centered(number);
+                    (file, first, last)
+                }
+                Err(err) => return console_println!("{}", err),
+            },
+        };
+        self.print_source_lines(file, first, last);
+    }
+
+    /// Resolves what `list` is given to a file and line: `42`, `file.c:42`, `func`, `file.c:func`
+    /// or `*addr`. Bare line numbers are in the file listed last.
+    fn resolve_list_location(&mut self, spec: &str) -> Result<(String, usize), String> {
+        if spec.starts_with('*') {
+            let addr = self.evaluate_code_address(&spec[1..])?;
+            let line = self.debug_data.get_line_from_addr(addr).ok_or_else(|| {
+                format!(
+                    "No line number information available for address {:#x}",
+                    addr
+                )
+            })?;
+            return Ok((line.file, line.number));
+        }
+
+        let (file, spec) = match spec.rfind(':') {
+            Some(index) => (Some(&spec[..index]), &spec[index + 1..]),
+            None => (None, spec),
+        };
+        let file = match file {
+            Some(file) => Some(
+                self.debug_data
+                    .get_target_file(file)
+                    .ok_or_else(|| format!("No source file named {}.", file))?
+                    .name
+                    .clone(),
+            ),
+            None => None,
+        };
+        if let Ok(number) = spec.parse::<usize>() {
+            let file = match (file, &self.list_position) {
+                (Some(file), _) => file,
+                (None, Some(ListPosition::Around(file, _)))
+                | (None, Some(ListPosition::Listed(file, _, _))) => file.clone(),
+                (None, None) => self
+                    .default_source_file()
+                    .ok_or_else(|| "No symbol table is loaded.".to_string())?,
+            };
+            return Ok((file, number));
+        }
+
+        let addr = self
+            .debug_data
+            .get_addr_for_function(file.as_deref(), spec)
+            .ok_or_else(|| format!("Function \"{}\" not defined.", spec))?;
+        let line = self
+            .debug_data
+            .get_line_from_addr(addr)
+            .ok_or_else(|| format!("No line number known for {}.", spec))?;
+        Ok((line.file, line.number))
+    }
+
+    /// Prints lines `first` to `last` of a source file for `list`, marking the selected frame's
+    /// line with `>` and lines with breakpoints with `B` (`b` if disabled).
+    fn print_source_lines(&mut self, file: String, first: usize, last: usize) {
+        let lines = match read_source_file(&file) {
+            Some(lines) => lines,
+            None => return console_println!("{}: No such file or directory.", file),
+        };
+        if first > lines.len() {
+            return console_println!(
+                "Line number {} out of range; \"{}\" has {} lines.",
+                first,
+                file,
+                lines.len()
+            );
+        }
+        let last = last.min(lines.len());
+
+        let current = if self.process().is_some() {
+            self.selected_frame_info()
+                .ok()
+                .and_then(|(pc, _)| self.debug_data.get_line_from_addr(pc))
+        } else {
+            None
+        };
+        let breakpoint_lines: Vec<(Line, bool)> = self
+            .breakpoints
+            .iter()
+            .filter(|bp| !bp.pending)
+            .filter_map(|bp| Some((self.debug_data.get_line_from_addr(bp.addr)?, bp.enabled)))
+            .collect();
+        let is_line =
+            |line: &Line, number: usize| line.number == number && same_file(&line.file, &file);
+
+        for number in first..=last {
+            let breakpoint = match breakpoint_lines
+                .iter()
+                .filter(|(line, _)| is_line(line, number))
+                .map(|(_, enabled)| *enabled)
+                .max()
+            {
+                Some(true) => 'B',
+                Some(false) => 'b',
+                None => ' ',
+            };
+            let current = match current {
+                Some(ref line) if is_line(line, number) => '>',
+                _ => ' ',
+            };
+            console_println!(
+                "{}{} {}\t{}",
+                breakpoint,
+                current,
+                number,
+                lines[number - 1]
+            );
+        }
+        self.list_position = Some(ListPosition::Listed(file, first, last));
+    }
+
+    /// Disassembles the function containing the selected frame's pc, a function given by name or
+    /// by an address in it, or the range `start,end` or `start,+length`, like gdb's `disassemble`.
+    fn handle_disassemble_command(
+        &mut self,
+        modifiers: DisassembleModifiers,
+        location: Option<&str>,
+    ) {
+        let (func, start, end) = match self.disassembly_range(location) {
+            Ok(range) => range,
+            Err(err) => return console_println!("{}", err),
+        };
+        match func {
+            Some(ref name) => console_println!("Dump of assembler code for function {}:", name),
+            None => console_println!("Dump of assembler code from {:#x} to {:#x}:", start, end),
+        }
+
+        let pc = self.selected_frame_pc();
+        let code = self.read_code(start, end - start);
+        let mut last_line: Option<(String, usize)> = None;
+        let mut addr = start;
+        while addr < end {
+            let offset = addr - start;
+            if offset >= code.len() {
+                console_println!("Cannot access memory at address {:#x}", addr);
+                break;
+            }
+            let instruction = self.decode_instruction(&code[offset..], addr);
+
+            if modifiers.source {
+                if let Some(line) = self.debug_data.get_line_from_addr(addr) {
+                    let location = (line.file.clone(), line.number);
+                    if last_line.as_ref() != Some(&location) {
+                        match last_line {
+                            Some((ref file, _)) if *file == line.file => console_println!(),
+                            Some(_) => console_println!("\n{}:", line.file),
+                            None => console_println!("{}:", line.file),
+                        }
+                        let text = read_source_file(&line.file)
+                            .and_then(|lines| lines.get(line.number.wrapping_sub(1)).cloned())
+                            .unwrap_or_default();
+                        console_println!("{}\t{}", line.number, text);
+                        last_line = Some(location);
+                    }
+                }
+            }
+
+            let marker = if pc == Some(addr) { "=> " } else { "   " };
+            let symbol = match func {
+                Some(_) => format!(" <+{}>", offset),
+                None => self.symbolize(addr),
+            };
+            let raw = if modifiers.raw {
+                let bytes: Vec<String> = code[offset..(offset + instruction.len).min(code.len())]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                format!("{}\t", bytes.join(" "))
+            } else {
+                String::new()
+            };
+            console_println!(
+                "{}0x{:016x}{}:\t{}{}",
+                marker,
+                addr,
+                symbol,
+                raw,
+                instruction.text
+            );
+            addr = match addr.checked_add(instruction.len) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        console_println!("End of assembler dump.");
+    }
+
+    /// Works out what `disassemble` should show: the name of the function, if it is a whole
+    /// function, and the start and end addresses. The range is never empty, and at most
+    /// MAX_DISASSEMBLY_LEN bytes long.
+    fn disassembly_range(
+        &mut self,
+        location: Option<&str>,
+    ) -> Result<(Option<String>, usize, usize), String> {
+        let location = match location {
+            Some(location) => location,
+            None => {
+                if self.process().is_none() {
+                    return Err("No frame selected.".to_string());
+                }
+                let (pc, _) = self.selected_frame_info()?;
+                let (name, start, end) =
+                    self.debug_data.get_function_bounds(pc).ok_or_else(|| {
+                        "No function contains program counter for selected frame.".to_string()
+                    })?;
+                return Ok((Some(name), start, end));
+            }
+        };
+
+        if let Some(comma) = location.find(',') {
+            let start = self.evaluate_code_address(&location[..comma])?;
+            let end_spec = location[comma + 1..].trim();
+            let end = if end_spec.starts_with('+') {
+                start
+                    .checked_add(self.evaluate_code_address(&end_spec[1..])?)
+                    .ok_or_else(|| "Invalid address range.".to_string())?
+            } else {
+                self.evaluate_code_address(end_spec)?
+            };
+            return check_disassembly_range(None, start, end);
+        }
+
+        let addr = self.evaluate_code_address(location)?;
+        let (name, start, end) = self
+            .debug_data
+            .get_function_bounds(addr)
+            .ok_or_else(|| "No function contains specified address.".to_string())?;
+        check_disassembly_range(Some(name), start, end)
+    }
+
+    /// Evaluates an address given to `x` or `disassemble`, where a function name means the
+    /// address of the function.
+    fn evaluate_code_address(&mut self, input: &str) -> Result<usize, String> {
+        let name = input.trim();
+        if let Some(addr) = self
+            .debug_data
+            .get_addr_for_function(None, name)
+            .or_else(|| self.debug_data.get_symbol_address(name))
+        {
+            return Ok(addr);
+        }
+        expr::parse(input)
+            .and_then(|addr| expr::eval(&addr, self))
+            .map(|addr| addr as usize)
+    }
+
+    /// Reads up to `len` bytes of code at `addr`: from memory with breakpoints hidden if there is
+    /// a process, otherwise from the executable or library file. Stops short where memory can't be
+    /// read.
+    fn read_code(&self, addr: usize, len: usize) -> Vec<u8> {
+        if self.process().is_none() {
+            return self
+                .debug_data
+                .read_file_bytes(addr, len)
+                .unwrap_or_default();
+        }
+        if let Ok(bytes) = self.read_original_bytes(addr, len) {
+            return bytes;
+        }
+        // The range may run into an unmapped page, so read a page at a time
+        let mut bytes = Vec::new();
+        while bytes.len() < len {
+            let chunk_addr = match addr.checked_add(bytes.len()) {
+                Some(chunk_addr) => chunk_addr,
+                None => break,
+            };
+            let chunk_len = (PAGE_SIZE - chunk_addr % PAGE_SIZE).min(len - bytes.len());
+            match self.read_original_bytes(chunk_addr, chunk_len) {
+                Ok(chunk) => bytes.extend(chunk),
+                Err(_) => break,
+            }
+        }
+        bytes
+    }
+
+    fn decode_instruction(&self, code: &[u8], addr: usize) -> disassemble::Instruction {
+        disassemble::decode(code, addr as u64, &|target| self.symbolize(target as usize))
+    }
+
+    /// The pc of the selected frame, which gdb marks with `=>` in disassembly: where the
+    /// innermost frame is stopped, or where outer frames return to.
+    fn selected_frame_pc(&self) -> Option<usize> {
+        let process = self.process()?;
+        if self.selected_frame == 0 {
+            return process.getregs().ok().map(|regs| regs.rip as usize);
+        }
+        let frames = self.backtrace().ok()?;
+        Some(frames.get(self.selected_frame)?.pc as usize)
+    }
+
+    /// Reads a NUL-terminated string at `addr`, returning the address that couldn't be read on
+    /// failure.
+    fn read_c_string(&self, addr: usize) -> Result<Vec<u8>, usize> {
+        let mut bytes = Vec::new();
+        loop {
+            // Read up to the next word boundary at a time, so the string may end right before an
+            // unmapped page
+            let chunk_addr = addr.checked_add(bytes.len()).ok_or(addr)?;
+            let chunk_len = 8 - chunk_addr % 8;
+            let chunk = self
+                .read_original_bytes(chunk_addr, chunk_len)
+                .or(Err(chunk_addr))?;
+            match chunk.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    return Ok(bytes);
+                }
+                None => bytes.extend_from_slice(&chunk),
+            }
+        }
+    }
+
+    /// Returns ` <func+offset>` for an address inside a function, or an empty string.
+    fn symbolize(&self, addr: usize) -> String {
+        match self.debug_data.get_function_bounds(addr) {
+            Some((name, start, _)) if addr == start => format!(" <{}>", name),
+            Some((name, start, _)) => format!(" <{}+{}>", name, addr - start),
+            None => String::new(),
+        }
+    }
+
+    /// Looks `name` up among the current function's variables, then the globals, and returns it
+    /// along with the address it is stored at.
+    fn locate_variable(&self, name: &str) -> Result<(Variable, usize), String> {
+        let frame = if self.process().is_some() {
+            Some(self.selected_frame_info()?)
+        } else {
+            None
+        };
+        let local = frame.and_then(|(pc, _)| {
+            self.debug_data
+                .get_function_containing(pc)
+                .and_then(|func| func.variables.iter().find(|var| var.name == name))
+        });
+        let var = local
+            .or_else(|| self.debug_data.get_global_variable(name))
+            .ok_or_else(|| format!("No symbol \"{}\" in current context.", name))?;
+        Ok((var.clone(), variable_address(var, frame)?))
+    }
+
+    /// Reads the bytes of `var` out of the inferior, a local being that of the selected frame
+    /// `frame` (see selected_frame_info).
+    fn read_variable(
+        &self,
+        var: &Variable,
+        frame: (usize, Option<(usize, usize)>),
+    ) -> Result<Vec<u8>, String> {
+        let addr = variable_address(var, Some(frame))?;
+        self.read_memory(addr, var.entity_type.size)
+    }
+
+    fn handle_watch_command(&mut self, kind: WatchKind, name: &str) {
+        let (var, addr) = match self.locate_variable(name) {
+            Ok(found) => found,
+            Err(err) => return console_println!("{}", err),
+        };
+        let size = var.entity_type.size;
+        if ![1, 2, 4, 8].contains(&size) {
+            return console_println!(
+                "Cannot watch {}: hardware watchpoints only support 1, 2, 4 or 8 byte values.",
+                name
+            );
+        }
+        if addr % size != 0 {
+            return console_println!(
+                "Cannot watch {}: {:#x} is not aligned to its size.",
+                name,
+                addr
+            );
+        }
+        if self.watchpoint_slots_full() {
+            return self.report_no_watchpoint_slot(self.next_breakpoint_num);
+        }
+        let scope = match var.location {
+            Location::FramePointerOffset(_) => match self.selected_frame_info() {
+                Ok((_, Some(frame))) => Some(frame),
+                _ => return console_println!("Cannot find the frame of the current function"),
+            },
+            Location::Address(_) => None,
+        };
+
+        let watchpoint = Watchpoint {
+            num: self.next_breakpoint_num,
+            kind,
+            expression: name.to_string(),
+            addr,
+            value_type: var.entity_type,
+            enabled: true,
+            hit_count: 0,
+            slot: None,
+            old_value: Vec::new(),
+            scope,
+        };
+        self.next_breakpoint_num += 1;
+        console_println!("{} {}: {}", kind.description(), watchpoint.num, name);
+        self.watchpoints.push(watchpoint);
+
+        // A local goes away when its frame returns, which a trap at the return address catches
+        let result = self.install_watchpoints().and_then(|_| match scope {
+            Some((return_addr, _)) => self.insert_trap(return_addr),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            console_println!("failed to set watchpoint error={}", err);
+        }
+    }
+
+    /// Whether every debug register is taken by an enabled watchpoint.
+    fn watchpoint_slots_full(&self) -> bool {
+        self.watchpoints.iter().filter(|wp| wp.enabled).count() >= 4
+    }
+
+    fn report_no_watchpoint_slot(&self, num: usize) {
+        console_println!("Hardware watchpoint {}: Could not insert watchpoint.", num);
+        console_println!("You may have requested too many hardware breakpoints/watchpoints.");
+    }
+
+    /// Deletes the watchpoint at `index`, along with the trap waiting for its frame to return.
+    fn remove_watchpoint(&mut self, index: usize) {
+        let watchpoint = self.watchpoints.remove(index);
+        let result = self.install_watchpoints().and_then(|_| match watchpoint.scope {
+            Some((return_addr, _)) => self.sync_trap(return_addr),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            console_println!("failed to remove watchpoint error={}", err);
+        }
+    }
+
+    /// Whether the frame a watched local lives in, given by its return address and the caller's
+    /// rsp, has returned (or been replaced by another call).
+    fn frame_is_gone(&self, (return_addr, caller_sp): (usize, usize)) -> bool {
+        let inferior = self.inferior.as_ref().unwrap();
+        match (inferior.getregs(), inferior.read_word(caller_sp.wrapping_sub(8))) {
+            (Ok(regs), Ok(word)) => {
+                (regs.rsp as usize) >= caller_sp || word as usize != return_addr
+            }
+            _ => true,
+        }
+    }
+
+    /// Deletes the watchpoints on locals whose frame has returned, like gdb does when the inferior
+    /// stops outside of it.
+    fn delete_out_of_scope_watchpoints(&mut self) {
+        while let Some(index) = self
+            .watchpoints
+            .iter()
+            .position(|wp| wp.scope.map_or(false, |scope| self.frame_is_gone(scope)))
+        {
+            let num = self.watchpoints[index].num;
+            self.remove_watchpoint(index);
+            console_println!(
+                "Watchpoint {} deleted because the program has left the block in which its \
+                 expression is valid.",
+                num
+            );
+        }
+    }
+
+    /// Programs the debug registers with the enabled watchpoints: addresses go in DR0-DR3, and
+    /// DR7 gets the enable, R/W and LEN bits for each slot.
+    fn install_watchpoints(&mut self) -> Result<(), nix::Error> {
+        if !self.running {
+            return Ok(());
+        }
+        let inferior = self.inferior.as_mut().unwrap();
+
+        // Disable everything first, the kernel validates DR7 against the addresses
+        inferior.set_debug_register(7, 0)?;
+        let mut dr7 = 0;
+        let mut slot = 0;
+        for watchpoint in self.watchpoints.iter_mut() {
+            if !watchpoint.enabled || slot == 4 {
+                watchpoint.slot = None;
+                continue;
+            }
+            inferior.set_debug_register(slot, watchpoint.addr as u64)?;
+            dr7 |= 1 << (slot * 2);
+            dr7 |= watchpoint.kind.dr7_rw_bits() << (16 + slot * 4);
+            dr7 |= watchpoint.dr7_len_bits() << (18 + slot * 4);
+            watchpoint.slot = Some(slot);
+            if watchpoint.old_value.is_empty() {
+                watchpoint.old_value =
+                    inferior.read_bytes(watchpoint.addr, watchpoint.value_type.size)?;
+            }
+            slot += 1;
+        }
+        // Don't leave the addresses of disabled or deleted watchpoints behind
+        for unused in slot..4 {
+            inferior.set_debug_register(unused, 0)?;
+        }
+        inferior.set_debug_register(7, dr7)
+    }
+
+    /// Write watchpoints trap on every write, but are only interesting if the value changed. Read
+    /// watchpoints also trap on writes (see WatchKind::dr7_rw_bits), which show up as changes.
+    fn should_stop_at_watchpoint(&mut self, slot: usize) -> bool {
+        let inferior = self.inferior.as_ref().unwrap();
+        let watchpoint = match self.watchpoints.iter_mut().find(|wp| wp.slot == Some(slot)) {
+            Some(watchpoint) => watchpoint,
+            None => return true,
+        };
+        let value = match inferior.read_bytes(watchpoint.addr, watchpoint.value_type.size) {
+            Ok(value) => value,
+            Err(_) => return true,
+        };
+        match watchpoint.kind {
+            WatchKind::Write => value != watchpoint.old_value,
+            WatchKind::Read => {
+                let unchanged = value == watchpoint.old_value;
+                watchpoint.old_value = value;
+                unchanged
+            }
+            WatchKind::Access => true,
+        }
+    }
+
+    fn report_watchpoint(&mut self, slot: usize, rip: usize) {
+        let index = match self.watchpoints.iter().position(|wp| wp.slot == Some(slot)) {
+            Some(index) => index,
+            None => {
+                console_println!("Stopped by an unknown watchpoint (debug register {})", slot);
+                return self.print_location(rip);
+            }
+        };
+
+        if self.watchpoints[index]
+            .scope
+            .map_or(false, |scope| self.frame_is_gone(scope))
+        {
+            self.delete_out_of_scope_watchpoints();
+            return self.print_location(rip);
+        }
+
+        let inferior = self.inferior.as_ref().unwrap();
+        let watchpoint = &mut self.watchpoints[index];
+        let new_value = inferior
+            .read_bytes(watchpoint.addr, watchpoint.value_type.size)
+            .unwrap_or_default();
+        watchpoint.hit_count += 1;
+        console_println!(
+            "{} {}: {}",
+            watchpoint.kind.description(),
+            watchpoint.num,
+            watchpoint.expression
+        );
+        if watchpoint.kind == WatchKind::Read || new_value == watchpoint.old_value {
+            console_println!("Value = {}", watchpoint.value_type.format_value(&new_value));
+        } else {
+            console_println!(
+                "Old value = {}",
+                watchpoint.value_type.format_value(&watchpoint.old_value)
+            );
+            console_println!(
+                "New value = {}",
+                watchpoint.value_type.format_value(&new_value)
+            );
+        }
+        watchpoint.old_value = new_value;
+        self.print_location(rip);
+    }
+
+    /// Sets a breakpoint at `raw_addr`, returning whether one was set.
+    fn handle_breakpoint_command(
+        &mut self,
+        raw_addr: &str,
+        condition: Option<&str>,
+        temporary: bool,
+    ) -> bool {
+        let location = match self.parse_address(&raw_addr) {
+            Ok(addr) => Some(addr),
+            Err(LocationError::NotFound(err)) => {
+                // It may be in a shared library that hasn't been loaded yet
+                console_println!("{}", err);
+                // In JSON mode nobody is there to answer, so the breakpoint is made pending like
+                // gdb/MI's `-break-insert -f` does
+                if !interpreter::is_json() {
+                    let answer = if self.is_interactive() {
+                        self.readline.readline(PENDING_QUERY).unwrap_or_default()
+                    } else {
+                        // Like gdb, take the default answer when the user isn't there to give one
+                        console_println!("{}[answered N; input not from terminal]", PENDING_QUERY);
+                        String::new()
+                    };
+                    if !answer.trim().starts_with('y') {
+                        return false;
+                    }
+                }
+                None
+            }
+            Err(LocationError::Invalid(err)) => {
+                console_println!("{}", err);
+                return false;
+            }
+        };
+        let condition = match condition.map(expr::parse) {
+            Some(Ok(condition)) => Some(condition),
+            Some(Err(err)) => {
+                console_println!("{}", err);
+                return false;
+            }
+            None => None,
+        };
+
+        let mut breakpoint = Breakpoint::new(
+            self.next_breakpoint_num,
+            &self.canonical_location(raw_addr),
+            location.unwrap_or(0),
+            condition,
+        );
+        breakpoint.temporary = temporary;
+        self.next_breakpoint_num += 1;
+        let kind = if temporary {
+            "Temporary breakpoint"
+        } else {
+            "Breakpoint"
+        };
+        let addr = match location {
+            Some(addr) => addr,
+            None => {
+                breakpoint.pending = true;
+                console_println!("{} {} ({}) pending.", kind, breakpoint.num, raw_addr);
+                self.breakpoints.push(breakpoint);
+                return true;
+            }
+        };
+        console_println!("{} {} at {:#x}", kind, breakpoint.num, addr);
+        self.breakpoints.push(breakpoint);
+
+        if self.running {
+            if let Err(err) = self.insert_trap(addr) {
+                console_println!("failed set breakpoint error={}", err);
+            }
+        }
+        true
+    }
+
+    fn handle_info_breakpoints_command(&self) {
+        if interpreter::is_json() {
+            return self.emit_breakpoint_table();
+        }
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            return console_println!("No breakpoints or watchpoints.");
+        }
+
+        console_println!("Num     Disp Enb Address            Hits  What");
+        for breakpoint in &self.breakpoints {
+            if breakpoint.pending {
+                console_println!(
+                    "{:<7} {:<4} {:<3} {:<18} {:<5} {}",
+                    breakpoint.num,
+                    breakpoint.disposition(),
+                    if breakpoint.enabled { "y" } else { "n" },
+                    "<PENDING>",
+                    breakpoint.hit_count,
+                    breakpoint.location
+                );
+                continue;
+            }
+            let what = match self.debug_data.get_line_from_addr(breakpoint.addr) {
+                Some(line) => format!(
+                    "{} ({})",
+                    self.debug_data
+                        .get_function_from_addr(breakpoint.addr)
+                        .unwrap_or_else(|| "??".to_string()),
+                    line
+                ),
+                None => String::new(),
+            };
+            console_println!(
+                "{:<7} {:<4} {:<3} {:#018x} {:<5} {}",
+                breakpoint.num,
+                breakpoint.disposition(),
+                if breakpoint.enabled { "y" } else { "n" },
+                breakpoint.addr,
+                breakpoint.hit_count,
+                what
+            );
+            if let Some(condition) = &breakpoint.condition {
+                console_println!("        stop only if {}", condition);
+            }
+            if breakpoint.ignore_count > 0 {
+                console_println!(
+                    "        will ignore next {} crossings of breakpoint",
+                    breakpoint.ignore_count
+                );
+            }
+            for command in &breakpoint.commands {
+                console_println!("        {}", command);
+            }
+        }
+        for watchpoint in &self.watchpoints {
+            console_println!(
+                "{:<7} {:<4} {:<3} {:#018x} {:<5} {} {}",
+                watchpoint.num,
+                "keep",
+                if watchpoint.enabled { "y" } else { "n" },
+                watchpoint.addr,
+                watchpoint.hit_count,
+                watchpoint.kind.description(),
+                watchpoint.expression
+            );
+        }
+    }
+
+    /// info breakpoints for JSON mode.
+    fn emit_breakpoint_table(&self) {
+        let mut table = Vec::new();
+        for breakpoint in &self.breakpoints {
+            let mut fields = vec![
+                ("number", breakpoint.num.into()),
+                ("type", "breakpoint".into()),
+                ("disposition", breakpoint.disposition().into()),
+                ("enabled", breakpoint.enabled.into()),
+                ("pending", breakpoint.pending.into()),
+                ("location", breakpoint.location.as_str().into()),
+                ("hits", breakpoint.hit_count.into()),
+                (
+                    "condition",
+                    breakpoint
+                        .condition
+                        .as_ref()
+                        .map(|condition| condition.to_string())
+                        .into(),
+                ),
+                ("ignore_count", breakpoint.ignore_count.into()),
+                (
+                    "commands",
+                    Json::Array(
+                        breakpoint
+                            .commands
+                            .iter()
+                            .map(|command| command.as_str().into())
+                            .collect(),
+                    ),
+                ),
+            ];
+            if !breakpoint.pending {
+                fields.append(&mut self.frame_fields(breakpoint.addr, breakpoint.addr));
+            }
+            table.push(Json::object(fields));
+        }
+        for watchpoint in &self.watchpoints {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => "watchpoint",
+                WatchKind::Read => "read watchpoint",
+                WatchKind::Access => "acc watchpoint",
+            };
+            table.push(Json::object(vec![
+                ("number", watchpoint.num.into()),
+                ("type", kind.into()),
+                ("enabled", watchpoint.enabled.into()),
+                ("expression", watchpoint.expression.as_str().into()),
+                ("addr", format!("{:#x}", watchpoint.addr).into()),
+                ("hits", watchpoint.hit_count.into()),
+            ]));
+        }
+        interpreter::emit(&Json::object(vec![
+            ("type", "breakpoints".into()),
+            ("breakpoints", Json::Array(table)),
+        ]));
+    }
+
+    fn handle_ignore_command(&mut self, num: usize, count: usize) {
+        match self.breakpoints.iter_mut().find(|bp| bp.num == num) {
+            Some(breakpoint) => {
+                breakpoint.ignore_count = count;
+                match count {
+                    0 => console_println!("Will stop next time breakpoint {} is reached.", num),
+                    1 => console_println!("Will ignore next crossing of breakpoint {}.", num),
+                    _ => console_println!(
+                        "Will ignore next {} crossings of breakpoint {}.",
+                        count, num
+                    ),
+                }
+            }
+            None => console_println!("No breakpoint number {}.", num),
+        }
+    }
+
+    /// Deletes breakpoint `num`, or every breakpoint if no number is given.
+    fn handle_delete_command(&mut self, num: Option<usize>) {
+        let removed: Vec<Breakpoint> = match num {
+            Some(num) => {
+                if let Some(index) = self.watchpoints.iter().position(|wp| wp.num == num) {
+                    return self.remove_watchpoint(index);
+                }
+                match self.breakpoints.iter().position(|bp| bp.num == num) {
+                    Some(index) => vec![self.breakpoints.remove(index)],
+                    None => return console_println!("No breakpoint number {}.", num),
+                }
+            }
+            None => {
+                while !self.watchpoints.is_empty() {
+                    self.remove_watchpoint(0);
+                }
+                self.breakpoints.drain(..).collect()
+            }
+        };
+
+        for breakpoint in removed {
+            if let Err(err) = self.sync_trap(breakpoint.addr) {
+                console_println!("failed to remove breakpoint error={}", err);
+            }
+        }
+    }
+
+    fn handle_enable_command(&mut self, num: usize, enabled: bool) {
+        if let Some(index) = self.watchpoints.iter().position(|wp| wp.num == num) {
+            if enabled && !self.watchpoints[index].enabled && self.watchpoint_slots_full() {
+                return self.report_no_watchpoint_slot(num);
+            }
+            self.watchpoints[index].enabled = enabled;
+            if let Err(err) = self.install_watchpoints() {
+                console_println!("failed to update watchpoint error={}", err);
+            }
+            return;
+        }
+
+        let addr = match self.breakpoints.iter_mut().find(|bp| bp.num == num) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                breakpoint.addr
+            }
+            None => return console_println!("No breakpoint number {}.", num),
+        };
+
+        if let Err(err) = self.sync_trap(addr) {
+            console_println!("failed to update breakpoint error={}", err);
+        }
+    }
+
+    fn handle_quit_command(&mut self) {
+        for held in self.held_inferiors.drain(..) {
+            if let Err(err) = release_held_inferior(held) {
+                console_println!("error={}", err);
+            }
+        }
+        if !self.running {
+            return;
+        }
+
+        // A process we attached to was running before we came along, so leave it running
+        if self.inferior.as_ref().unwrap().is_attached() {
+            if let Err(err) = self.do_detach() {
+                console_println!("error={}", err);
+            }
+        } else {
+            self.do_kill();
+        }
+    }
+
+    fn handle_run_command(&mut self, args: &Vec<String>) {
+        self.run_program(args, false);
+    }
+
+    /// `start`: `run`, stopping at a temporary breakpoint on main.
+    fn handle_start_command(&mut self, args: &Vec<String>) {
+        if self
+            .debug_data
+            .get_addr_for_function(None, "main")
+            .is_none()
+        {
+            return console_println!("No symbol table loaded.  Use the \"file\" command.");
+        }
+        self.run_program(args, true);
+    }
+
+    /// Starts the program afresh, killing the current process if any. With `stop_at_main`, a
+    /// temporary breakpoint on main is set once the new process is there.
+    fn run_program(&mut self, args: &Vec<String>, stop_at_main: bool) {
+        if self.running {
+            self.do_kill();
+        }
+
+        let mut inferior = match Inferior::new(&self.target, &args) {
+            Some(inferior) => inferior,
+            None => return console_println!("Error starting subprocess"),
+        };
+        // Not running yet, so this only adds the breakpoint, which start_debugging inserts
+        if stop_at_main && !self.handle_breakpoint_command("main", None, true) {
+            if let Err(err) = inferior.kill() {
+                console_println!("Can't kill child, error={}", err);
+            }
+            return;
+        }
+        self.start_debugging(inferior);
+        match self.continue_inferior() {
+            Ok(stop) => self.report_status(stop),
+            Err(err) => self.report_error(err),
+        }
+    }
+
+    /// Attaches to the running process `pid` and stops it wherever it is.
+    pub fn attach(&mut self, pid: i32) {
+        if self.running {
+            return console_println!(
+                "The program is already being debugged. Kill or detach it first."
+            );
+        }
+
+        console_println!("Attaching to process {}", pid);
+        match Inferior::attach(Pid::from_raw(pid)) {
+            Ok(inferior) => {
+                let rip = match inferior.getregs() {
+                    Ok(regs) => regs.rip as usize,
+                    Err(err) => {
+                        let _ = inferior.detach();
+                        return console_println!("ptrace: {}", err);
+                    }
+                };
+                self.start_debugging(inferior);
+                self.print_location(rip);
+                self.print_source_line(rip);
+            }
+            Err(err) => console_println!("ptrace: {}", err),
+        }
+    }
+
+    /// Opens the core dump at `path` for post-mortem debugging, and shows where the program
+    /// died.
+    pub fn load_core(&mut self, path: &str) {
+        if self.running {
+            return console_println!(
+                "A program is being debugged already. Kill or detach it first."
+            );
+        }
+
+        let core = match CoreDump::open(path) {
+            Ok(core) => core,
+            Err(err) => return console_println!("{}", err),
+        };
+        for (_, tid) in core.threads() {
+            console_println!("[New LWP {}]", tid);
+        }
+        if let Some(command) = core.command() {
+            console_println!("Core was generated by `{}'.", command);
+        }
+        if let Some(signal) = core.signal() {
+            console_println!("{}", termination_message(signal));
+        }
+        let rip = core.getregs().unwrap().rip as usize;
+        self.core = Some(core);
+        self.selected_frame = 0;
+        self.load_shared_libraries();
+        console_print!("#0  ");
+        self.print_location(rip);
+        self.print_source_line(rip);
+    }
+
+    /// The process whose stack, registers and memory are inspected: the inferior while it runs,
+    /// otherwise the core dump if one is loaded.
+    fn process(&self) -> Option<&dyn Process> {
+        if self.running {
+            return self
+                .inferior
+                .as_ref()
+                .map(|inferior| inferior as &dyn Process);
+        }
+        self.core.as_ref().map(|core| core as &dyn Process)
+    }
+
+    fn process_mut(&mut self) -> Option<&mut dyn Process> {
+        if self.running {
+            return self
+                .inferior
+                .as_mut()
+                .map(|inferior| inferior as &mut dyn Process);
+        }
+        self.core.as_mut().map(|core| core as &mut dyn Process)
+    }
+
+    /// Sets up breakpoints and watchpoints in a freshly started or attached inferior.
+    fn start_debugging(&mut self, inferior: Inferior) {
+        self.inferior = Some(inferior);
+        self.running = true;
+        // Like gdb, a live process replaces the core dump
+        self.core = None;
+        self.selected_frame = 0;
+        self.recording = None;
+
+        self.shlib_event_addr = None;
+        self.load_shared_libraries();
+        self.set_breakpoints();
+        // Locals belonged to frames of the previous run
+        self.watchpoints.retain(|wp| wp.scope.is_none());
+        for watchpoint in self.watchpoints.iter_mut() {
+            watchpoint.old_value.clear();
+        }
+        if let Err(err) = self.install_watchpoints() {
+            console_println!("failed to set watchpoints error={}", err);
+        }
+    }
+
+    fn handle_detach_command(&mut self) {
+        if !self.running {
+            return console_println!("The program is not being run.");
+        }
+
+        if let Err(err) = self.do_detach() {
+            console_println!("error={}", err);
+        }
+    }
+
+    /// Removes every trap and watchpoint from the inferior, then lets it run on untraced.
+    fn do_detach(&mut self) -> Result<(), nix::Error> {
+        let traps: Vec<u64> = self.breakpoint_map.keys().cloned().collect();
+        for addr in traps {
+            self.remove_trap(addr as usize)?;
+        }
+        let mut inferior = self.inferior.take().unwrap();
+        inferior.set_debug_register(7, 0)?;
+
+        let pid = inferior.pid();
+        console_println!("Detaching from program: {}, process {}", self.target, pid);
+        self.clear_process_state();
+        inferior.detach()?;
+        console_println!(
+            "[Inferior {} (process {}) detached]",
+            self.inferior_num,
+            pid
+        );
+        Ok(())
+    }
+
+    fn do_kill(&mut self) {
+        let inferior = self.inferior.as_mut().unwrap();
+        console_println!("Killing running inferior (pid {})", inferior.pid());
+        match inferior.kill() {
+            Ok(status) => {
+                console_println!(
+                    "child process is killed pid={}, status={}",
+                    inferior.pid(),
+                    status
+                );
+            }
+            Err(error) => {
+                console_println!("Can't kill child, error={}", error)
+            }
+        }
+
+        self.clear_process_state();
+    }
+
+    fn set_breakpoints(&mut self) {
+        let addrs: Vec<usize> = self
+            .breakpoints
+            .iter()
+            .filter(|bp| bp.is_active())
+            .map(|bp| bp.addr)
+            .collect();
+
+        for addr in addrs {
+            if let Err(error) = self.insert_trap(addr) {
+                console_println!("failed set breakpoint error={}", error);
+            }
+        }
+    }
+
+    /// Writes 0xcc at `addr` unless it is already there.
+    fn insert_trap(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if !self.breakpoint_map.contains_key(&(addr as u64)) {
+            let orig_byte = self.inferior.as_mut().unwrap().breakpoint(addr)?;
+            self.breakpoint_map.insert(addr as u64, orig_byte);
+        }
+        Ok(())
+    }
+
+    /// Writes the original byte back to `addr` if we had replaced it with 0xcc.
+    fn remove_trap(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if let Some(orig_byte) = self.breakpoint_map.remove(&(addr as u64)) {
+            self.inferior.as_mut().unwrap().write_byte(addr, orig_byte)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the inferior's memory at `addr` agree with the breakpoint table: the trap stays only
+    /// if some enabled breakpoint still wants it, or the debugger itself does.
+    fn sync_trap(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if !self.running {
+            return Ok(());
+        }
+        if self
+            .breakpoints
+            .iter()
+            .any(|bp| bp.is_active() && bp.addr == addr)
+            || self.transient_traps.iter().any(|trap| trap.addr == addr)
+            || self.is_watchpoint_scope_trap(addr)
+            || Some(addr) == self.shlib_event_addr
+        {
+            self.insert_trap(addr)
+        } else {
+            self.remove_trap(addr)
+        }
+    }
+
+    /// Takes care of forks and execs the inferior reports while running, resuming it after each,
+    /// and returns the first status that is an actual stop, or the exec that ended a function
+    /// called from an expression.
+    fn handle_process_events(&mut self, mut status: Status) -> Result<Status, nix::Error> {
+        loop {
+            match status {
+                Status::Forked(child, vfork) => self.handle_fork(child, vfork)?,
+                // The breakpoints are out of memory until a called function is done anyway
+                Status::VforkDone if self.inferior.as_ref().unwrap().in_call() => {}
+                Status::VforkDone => {
+                    // The vfork child took our breakpoints out of the memory it shared with us
+                    // (see handle_fork)
+                    let inferior = self.inferior.as_mut().unwrap();
+                    for addr in self.breakpoint_map.keys() {
+                        inferior.write_byte(*addr as usize, 0xcc)?;
+                    }
+                }
+                Status::Execed => {
+                    self.handle_exec()?;
+                    // A called function went away with the old program, so stop there
+                    if self.inferior.as_mut().unwrap().end_call() {
+                        return Ok(Status::Execed);
+                    }
+                }
+                other => return Ok(other),
+            }
+            status = self.inferior.as_mut().unwrap().resume_after_event()?;
+        }
+    }
+
+    /// Decides which side of a fork to go on debugging. The child starts out as a copy of the
+    /// parent, breakpoints included, but without watchpoints.
+    fn handle_fork(&mut self, child_pid: Pid, vfork: bool) -> Result<(), nix::Error> {
+        let parent = self.inferior.as_mut().unwrap();
+        let parent_pid = parent.pid();
+        let child = parent.fork_child(child_pid)?;
+        let fork = if vfork { "vfork" } else { "fork" };
+        let mut other = if self.follow_fork_child {
+            console_println!(
+                "[Attaching after process {} {} to child process {}]",
+                parent_pid,
+                fork,
+                child_pid
+            );
+            let parent = self.inferior.replace(child).unwrap();
+            self.recording = None;
+            self.install_watchpoints()?;
+            parent
+        } else {
+            child
+        };
+
+        if !self.detach_on_fork {
+            let mut num = self.next_inferior_num;
+            self.next_inferior_num += 1;
+            console_println!("[New inferior {} (process {})]", num, child_pid);
+            // The child gets the new number whichever process we follow
+            if self.follow_fork_child {
+                std::mem::swap(&mut num, &mut self.inferior_num);
+            }
+            self.held_inferiors.push(HeldInferior {
+                num,
+                inferior: other,
+                target: self.target.clone(),
+                breakpoint_map: self.breakpoint_map.clone(),
+            });
+            return Ok(());
+        }
+
+        // After a vfork the two processes share memory, so this takes the breakpoints out of the
+        // process we follow too: the parent puts them back once the child is done with the memory
+        // (Status::VforkDone), the child gets new ones when it execs
+        for (addr, orig_byte) in self.breakpoint_map.iter() {
+            other.write_byte(*addr as usize, *orig_byte)?;
+        }
+        if vfork && self.follow_fork_child {
+            self.breakpoint_map.clear();
+        }
+        other.set_debug_register(7, 0)?;
+        console_println!(
+            "[Detaching after {} from {} process {}]",
+            fork,
+            if self.follow_fork_child {
+                "parent"
+            } else {
+                "child"
+            },
+            other.pid()
+        );
+        other.detach()
+    }
+
+    /// The inferior replaced its program: load the new program's debug info and put the
+    /// breakpoints in at wherever their locations are in it.
+    fn handle_exec(&mut self) -> Result<(), nix::Error> {
+        let pid = self.inferior.as_ref().unwrap().pid();
+        let target = std::fs::read_link(format!("/proc/{}/exe", pid))
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| self.target.clone());
+        console_println!("process {} is executing new program: {}", pid, target);
+
+        // The old address space is gone, along with our traps, the debug registers and what the
+        // recording could restore
+        self.breakpoint_map.clear();
+        self.selected_frame = 0;
+        self.recording = None;
+        for watchpoint in self.watchpoints.drain(..) {
+            console_println!(
+                "Watchpoint {} deleted because the program has been replaced.",
+                watchpoint.num
+            );
+        }
+        match DwarfData::from_file(&target) {
+            Ok(debug_data) => self.debug_data = Symbols::new(debug_data),
+            Err(err) => console_println!(
+                "Could not load debugging symbols from {}: {:?}",
+                target,
+                err
+            ),
+        }
+        for breakpoint in self.breakpoints.iter_mut() {
+            breakpoint.pending = true;
+        }
+        self.target = target;
+        self.shlib_event_addr = None;
+        self.load_shared_libraries();
+        self.set_breakpoints();
+        Ok(())
+    }
+
+    /// Looks up the locations of pending breakpoints, which may be in a library that was just
+    /// loaded or in a new program, and moves their traps there.
+    fn resolve_breakpoints(&mut self) {
+        let resolved: Vec<(usize, usize, usize)> = self
+            .breakpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, bp)| bp.pending)
+            .filter_map(|(index, bp)| {
+                let addr = self.parse_address(&bp.location).ok()?;
+                Some((index, bp.addr, addr))
+            })
+            .collect();
+        for (index, old_addr, addr) in resolved {
+            self.breakpoints[index].addr = addr;
+            self.breakpoints[index].pending = false;
+            if let Err(err) = self
+                .sync_trap(old_addr)
+                .and_then(|_| self.sync_trap(addr))
+            {
+                console_println!("failed set breakpoint error={}", err);
+            }
+        }
+    }
+
+    /// Catches the symbols up with where the executable and its shared libraries are loaded in the
+    /// process being debugged, and resolves breakpoints again, which may be in libraries that were
+    /// just loaded. For a live process, also makes sure it will stop the next time the dynamic
+    /// linker changes its list of libraries.
+    fn load_shared_libraries(&mut self) {
+        let process = match self.process() {
+            Some(process) => process,
+            None => return,
+        };
+        let executable = self.debug_data.executable();
+        let bias = shared_libs::executable_bias(process, executable.link_entry());
+        let dynamic = bias.and_then(|bias| {
+            executable
+                .dynamic_section()
+                .map(|addr| addr.wrapping_sub(executable.bias()).wrapping_add(bias))
+        });
+        let r_debug = dynamic.and_then(|dynamic| shared_libs::find_r_debug(process, dynamic));
+        let libraries = r_debug.and_then(|r_debug| shared_libs::read_link_map(process, r_debug));
+        let event_addr = match (r_debug, dynamic, bias) {
+            (Some(r_debug), _, _) => shared_libs::r_brk(process, r_debug),
+            // The dynamic linker hasn't run yet, but has by the time the program starts
+            (None, Some(_), Some(bias)) => Some(executable.link_entry().wrapping_add(bias)),
+            _ => None,
+        };
+
+        if let Some(bias) = bias {
+            if bias != self.debug_data.executable().bias() {
+                // Breakpoints in the executable move with it
+                let executable = self.debug_data.executable();
+                for breakpoint in self.breakpoints.iter_mut() {
+                    if executable.contains(breakpoint.addr) {
+                        breakpoint.pending = true;
+                    }
+                }
+                self.debug_data.executable_mut().relocate(bias);
+            }
+        }
+        if let Some(libraries) = libraries {
+            let is_loaded = |path: &str, bias: usize| {
+                libraries
+                    .iter()
+                    .any(|library| library.path == path && library.bias == bias)
+            };
+            let debug_data = &self.debug_data;
+            let unloaded = |addr: usize| {
+                debug_data.libraries().iter().any(|library| {
+                    library.contains(addr) && !is_loaded(library.path(), library.bias())
+                })
+            };
+            // Traps in unloaded libraries went away with them, and breakpoints there wait for the
+            // library to come back
+            let traps: Vec<u64> = self
+                .breakpoint_map
+                .keys()
+                .filter(|addr| unloaded(**addr as usize))
+                .cloned()
+                .collect();
+            for breakpoint in self.breakpoints.iter_mut() {
+                if unloaded(breakpoint.addr) {
+                    breakpoint.pending = true;
+                }
+            }
+            for addr in traps {
+                self.breakpoint_map.remove(&addr);
+            }
+            self.debug_data
+                .retain_libraries(|library| is_loaded(library.path(), library.bias()));
+
+            for library in libraries {
+                let already_loaded =
+                    self.debug_data.libraries().iter().any(|loaded| {
+                        loaded.path() == library.path && loaded.bias() == library.bias
+                    });
+                if already_loaded {
+                    continue;
+                }
+                // Things like the vDSO aren't files
+                if let Ok(mut debug_data) = DwarfData::from_file(&library.path) {
+                    debug_data.relocate(library.bias);
+                    self.debug_data.add_library(debug_data);
+                }
+            }
+        }
+        self.resolve_breakpoints();
+
+        if self.running && event_addr != self.shlib_event_addr {
+            let old_addr = std::mem::replace(&mut self.shlib_event_addr, event_addr);
+            let result = match old_addr {
+                Some(addr) => self.sync_trap(addr),
+                None => Ok(()),
+            }
+            .and_then(|_| match event_addr {
+                Some(addr) => self.insert_trap(addr),
+                None => Ok(()),
+            });
+            if let Err(err) = result {
+                console_println!("failed to set shared library breakpoint error={}", err);
+            }
+        }
+    }
+
+    /// Resumes the inferior until it stops again. If it is currently sitting on a breakpoint, the
+    /// original instruction is executed first. When the inferior traps on one of our breakpoints,
+    /// rip is moved back onto the breakpoint address so that the stop location is accurate.
+    /// Breakpoints whose condition is false or that are being ignored are resumed from silently,
+    /// unless the inferior has also reached a transient trap there.
+    /// While recording, the inferior is stepped through each instruction instead.
+    fn continue_inferior(&mut self) -> Result<Stop, nix::Error> {
+        loop {
+            if let Some(stop) = self.step_over_breakpoint()? {
+                return Ok(stop);
+            }
+
+            let recording = self.recording.is_some();
+            let stop = if recording {
+                self.step_until_trap()?
+            } else {
+                let status = self.inferior.as_mut().unwrap().cont()?;
+                self.handle_process_events(status)?.into()
+            };
+            match stop {
+                Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, rip)) => {
+                    let stop = if recording {
+                        stop
+                    } else {
+                        match self.handle_stop_at_breakpoint(rip)? {
+                            Some(stop) => stop,
+                            None => continue,
+                        }
+                    };
+                    if let Stop::Status(Status::Stopped(_, addr)) = stop {
+                        let at_trap = self.breakpoint_map.contains_key(&(addr as u64));
+                        if at_trap && Some(addr) == self.shlib_event_addr {
+                            self.load_shared_libraries();
+                            self.set_breakpoints();
+                            if !self
+                                .breakpoints
+                                .iter()
+                                .any(|bp| bp.is_active() && bp.addr == addr)
+                            {
+                                continue;
+                            }
+                        }
+                        if at_trap
+                            && !self.watchpoint_scope_ended(addr)
+                            && !self.should_stop_at_breakpoint(addr)
+                            && !self.reached_transient_trap(addr)?
+                        {
+                            continue;
+                        }
+                    }
+                    return Ok(stop);
+                }
+                Stop::Status(Status::Watchpoint(slot, rip)) => {
+                    if self.should_stop_at_watchpoint(slot) {
+                        return Ok(Status::Watchpoint(slot, rip).into());
+                    }
+                }
+                Stop::Status(Status::Stopped(signal, rip)) => {
+                    if self.should_stop_for_signal(signal) {
+                        return Ok(Status::Stopped(signal, rip).into());
+                    }
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Applies the `handle` policy for a signal the inferior stopped with: the signal is
+    /// discarded unless it should be passed to the program, and it is announced if it should be
+    /// printed but doesn't stop. Returns whether to stop.
+    fn should_stop_for_signal(&mut self, signal: signal::Signal) -> bool {
+        let policy = self.signal_policies[&signal];
+        if !policy.pass {
+            self.inferior.as_mut().unwrap().discard_signal();
+        }
+        if !policy.stop && policy.print {
+            self.print_signal(signal);
+        }
+        policy.stop
+    }
+
+    fn print_signal(&self, signal: signal::Signal) {
+        match self.stopped_thread() {
+            Some(num) => console_print!("Thread {} received", num),
+            None => console_print!("Program received"),
+        }
+        console_println!(
+            " signal {}, {}.",
+            signal.as_str(),
+            signals::description(signal)
+        );
+    }
+
+    /// Makes sense of a SIGTRAP the inferior stopped with while running freely. If it ran into
+    /// one of our traps, rip is moved back onto the trap. Otherwise the program trapped by itself,
+    /// which is Stop::Trapped, and the SIGTRAP is a signal like any other: None if the `handle`
+    /// policy says to go on.
+    fn handle_stop_at_breakpoint(&mut self, rip: usize) -> Result<Option<Stop>, nix::Error> {
+        let prev_rip = rip.wrapping_sub(1);
+        if self.breakpoint_map.contains_key(&(prev_rip as u64)) {
+            self.inferior.as_ref().unwrap().go_back_one_step()?;
+            return Ok(Some(
+                Status::Stopped(signal::Signal::SIGTRAP, prev_rip).into(),
+            ));
+        }
+
+        if !self.should_stop_for_signal(signal::Signal::SIGTRAP) {
+            return Ok(None);
+        }
+        Ok(Some(Stop::Trapped(rip)))
+    }
+
+    /// Decides whether hitting the trap at `addr` should stop the inferior for the user
+    /// breakpoints there, updating their hit and ignore counts. Traps that belong to no user
+    /// breakpoint are internal: transient traps are left to reached_transient_trap and those
+    /// watching for a frame to return to watchpoint_scope_ended, others always stop.
+    fn should_stop_at_breakpoint(&mut self, addr: usize) -> bool {
+        let nums: Vec<usize> = self
+            .breakpoints
+            .iter()
+            .filter(|bp| bp.is_active() && bp.addr == addr)
+            .map(|bp| bp.num)
+            .collect();
+        if nums.is_empty() {
+            return !self.transient_traps.iter().any(|trap| trap.addr == addr)
+                && !self.is_watchpoint_scope_trap(addr);
+        }
+
+        let mut stop = false;
+        for num in nums {
+            let condition = self
+                .breakpoints
+                .iter()
+                .find(|bp| bp.num == num)
+                .unwrap()
+                .condition
+                .clone();
+            let condition_holds = match condition {
+                Some(condition) => match expr::eval(&condition, self) {
+                    Ok(value) => value != 0,
+                    Err(err) => {
+                        console_println!(
+                            "Error in testing condition for breakpoint {}: {}",
+                            num,
+                            err
+                        );
+                        true
+                    }
+                },
+                None => true,
+            };
+            if !condition_holds {
+                continue;
+            }
+
+            let breakpoint = self.breakpoints.iter_mut().find(|bp| bp.num == num).unwrap();
+            breakpoint.hit_count += 1;
+            if breakpoint.ignore_count > 0 {
+                breakpoint.ignore_count -= 1;
+            } else {
+                stop = true;
+            }
+        }
+        stop
+    }
+
+    /// Whether `addr` is the return address of the frame some watched local lives in.
+    fn is_watchpoint_scope_trap(&self, addr: usize) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|wp| wp.scope.map(|(return_addr, _)| return_addr) == Some(addr))
+    }
+
+    /// Whether stopping at `addr` means the frame of a watched local has returned, so that the
+    /// watchpoint is deleted and the inferior stops (see delete_out_of_scope_watchpoints).
+    fn watchpoint_scope_ended(&self, addr: usize) -> bool {
+        self.watchpoints.iter().any(|wp| match wp.scope {
+            Some(scope) => scope.0 == addr && self.frame_is_gone(scope),
+            None => false,
+        })
+    }
+
+    /// Whether the current thread, stopped at `addr`, got to a transient trap there, see
+    /// TransientTrap.
+    fn reached_transient_trap(&self, addr: usize) -> Result<bool, nix::Error> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let thread = inferior.current_thread().0;
+        let rsp = inferior.getregs()?.rsp as usize;
+        Ok(self
+            .transient_traps
+            .iter()
+            .any(|trap| trap.addr == addr && trap.thread == thread && rsp >= trap.min_sp))
+    }
+
+    /// If the inferior is stopped on a breakpoint, temporarily restores the original byte, executes
+    /// that single instruction and puts the 0xcc back. Returns the status if the inferior did
+    /// something other than finishing the step (e.g. exited).
+    fn step_over_breakpoint(&mut self) -> Result<Option<Stop>, nix::Error> {
+        let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        if !self.breakpoint_map.contains_key(&(rip as u64)) {
+            return Ok(None);
+        }
+
+        match self.step_instruction()? {
+            Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, _)) => Ok(None),
+            Stop::Status(Status::Watchpoint(slot, _)) if !self.should_stop_at_watchpoint(slot) => {
+                Ok(None)
+            }
+            other => Ok(Some(other)),
+        }
+    }
+
+    /// Executes exactly one instruction, hiding a breakpoint at the current rip if there is one,
+    /// and logs it first when recording.
+    fn step_instruction(&mut self) -> Result<Stop, nix::Error> {
+        let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        if self.recording.is_some() {
+            self.record_instruction(rip)?;
+        }
+        let orig_byte = self.breakpoint_map.get(&(rip as u64)).cloned();
+        if let Some(orig_byte) = orig_byte {
+            self.inferior.as_mut().unwrap().write_byte(rip, orig_byte)?;
+        }
+
+        let status = loop {
+            let inferior = self.inferior.as_mut().unwrap();
+            inferior.step()?;
+            match inferior.wait(None)? {
+                // The signal is delivered (or not) as the instruction is retried
+                Status::Stopped(signal, _)
+                    if signal != signal::Signal::SIGTRAP
+                        && !self.should_stop_for_signal(signal) =>
+                {
+                    let inferior = self.inferior.as_ref().unwrap();
+                    if !self.signal_policies[&signal].pass || !inferior.catches_signal(signal) {
+                        continue;
+                    }
+                    // Stepping would stop at the first instruction of the handler, so let the
+                    // handler run until it returns here, like gdb's step-resume breakpoint
+                    if orig_byte.is_some() {
+                        self.inferior.as_mut().unwrap().write_byte(rip, 0xcc)?;
+                    }
+                    let rsp = self.inferior.as_ref().unwrap().getregs()?.rsp as usize;
+                    if let Some(stop) = self.run_to(&[(rip, rsp)])? {
+                        return Ok(stop);
+                    }
+                    if let Some(orig_byte) = orig_byte {
+                        self.inferior.as_mut().unwrap().write_byte(rip, orig_byte)?;
+                    }
+                }
+                status => break status,
+            }
+        };
+        // An instruction that faulted didn't run, so there is nothing to undo
+        if let (Some(recording), Status::Stopped(signal, _)) = (self.recording.as_mut(), &status) {
+            let faults = [
+                signal::Signal::SIGSEGV,
+                signal::Signal::SIGBUS,
+                signal::Signal::SIGFPE,
+                signal::Signal::SIGILL,
+            ];
+            if faults.contains(signal) {
+                recording.pop();
+            }
+        }
+
+        if orig_byte.is_some() {
+            match status {
+                Status::Exited(_) | Status::Signaled(_) | Status::Execed => {}
+                _ => {
+                    self.inferior.as_mut().unwrap().write_byte(rip, 0xcc)?;
+                }
+            }
+        }
+        self.handle_process_events(status).map(Stop::from)
+    }
+
+    /// Single steps until execution reaches a different source line. Calls made from the current
+    /// line are run to completion unless `step_into` is set and the callee has line information.
+    fn step_line(&mut self, step_into: bool) -> Result<Stop, nix::Error> {
+        // Like gdb, get out of a function without line information and go on stepping from the
+        // middle of the caller's line, stopping there if the caller has no line information either
+        let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        let mut line = match self.debug_data.get_line_from_addr(rip) {
+            Some(line) => line,
+            None => {
+                let frame = match self.debug_data.get_function_from_addr(rip) {
+                    Some(func) => self.current_frame()?.map(|frame| (func, frame)),
+                    None => None,
+                };
+                let (func, (return_addr, caller_sp)) = match frame {
+                    Some(frame) => frame,
+                    None => {
+                        console_println!("Cannot find bounds of current function");
+                        return Ok(Status::Stopped(signal::Signal::SIGTRAP, rip).into());
+                    }
+                };
+                console_println!(
+                    "Single stepping until exit from function {},\nwhich has no line number \
+                     information.",
+                    func
+                );
+                if let Some(stop) = self.run_until_return(return_addr, caller_sp)? {
+                    return Ok(stop);
+                }
+                let stop = Status::Stopped(signal::Signal::SIGTRAP, return_addr).into();
+                match self.debug_data.get_line_from_addr(return_addr) {
+                    Some(line) => line,
+                    None => return Ok(stop),
+                }
+            }
+        };
+
+        loop {
+            let prev_regs = self.inferior.as_ref().unwrap().getregs()?;
+            let stop = self.step_instruction()?;
+            let rip = match stop {
+                Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, rip)) => rip,
+                Stop::Status(Status::Watchpoint(slot, rip))
+                    if !self.should_stop_at_watchpoint(slot) =>
+                {
+                    rip
+                }
+                other => return Ok(other),
+            };
+            let inferior = self.inferior.as_ref().unwrap();
+            let regs = inferior.getregs()?;
+
+            // A call pushes the address of the next instruction and jumps somewhere else.
+            if regs.rsp == prev_regs.rsp - 8 {
+                let return_addr = inferior.read_word(regs.rsp as usize)? as usize;
+                if return_addr > prev_regs.rip as usize
+                    && return_addr <= prev_regs.rip as usize + 15
+                {
+                    match self.debug_data.get_line_from_addr(rip) {
+                        Some(callee_line) if step_into => {
+                            // Keep going through the prologue until the first line of the body.
+                            line = callee_line;
+                        }
+                        _ => {
+                            if let Some(stop) =
+                                self.run_until_return(return_addr, regs.rsp as usize + 8)?
+                            {
+                                return Ok(stop);
+                            }
+                            // The call may have been the last thing on its line
+                            match self.debug_data.get_line_from_addr(return_addr) {
+                                Some(new_line)
+                                    if new_line.number != line.number
+                                        || new_line.file != line.file =>
+                                {
+                                    return Ok(Status::Stopped(
+                                        signal::Signal::SIGTRAP,
+                                        return_addr,
+                                    )
+                                    .into());
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            // A ret pops the return address we are now at. Unless the call was the last thing on
+            // its line, we are back in the middle of the caller's line, so finish that line first.
+            if regs.rsp > prev_regs.rsp && inferior.read_word(prev_regs.rsp as usize)? == regs.rip {
+                // gdb stops as well when stepping returns to code without line information
+                let caller_line = match self.debug_data.get_line_from_addr(rip) {
+                    Some(caller_line) => caller_line,
+                    None => return Ok(stop),
+                };
+                match self.debug_data.get_line_from_addr(rip - 1) {
+                    Some(call_line)
+                        if call_line.number == caller_line.number
+                            && call_line.file == caller_line.file =>
+                    {
+                        line = caller_line;
+                        continue;
+                    }
+                    _ => return Ok(stop),
+                }
+            }
+
+            if let Some(new_line) = self.debug_data.get_line_from_addr(rip) {
+                if new_line.number != line.number || new_line.file != line.file {
+                    return Ok(stop);
+                }
+            }
+        }
+    }
+
+    /// Returns the return address of the current function and the value rsp will have once it
+    /// returns. Uses the CFI if there is any, otherwise knows about the `push rbp; mov rbp, rsp`
+    /// prologue so that it also works before the frame pointer has been set up.
+    fn current_frame(&self) -> Result<Option<(usize, usize)>, nix::Error> {
+        let process = self.process().unwrap();
+        let regs = process.getregs()?;
+        let read_word = |addr: u64| process.read_word(addr as usize).ok();
+        if let Some(caller) = self
+            .debug_data
+            .call_frame_info(regs.rip)
+            .and_then(|cfi| cfi.unwind(regs.rip, &FrameRegisters::from_regs(&regs), &read_word))
+        {
+            return Ok(match (caller.pc(), caller.sp()) {
+                (Some(pc), Some(sp)) => Some((pc as usize, sp as usize)),
+                _ => None,
+            });
+        }
+
+        let rip = regs.rip as usize;
+        let func = match self.debug_data.get_function_from_addr(rip) {
+            Some(func) => func,
+            None => return Ok(None),
+        };
+        let entry = match self.debug_data.get_addr_for_function(None, &func) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        // Skip over an optional endbr64
+        let mut push_rbp = entry;
+        if self.read_original_bytes(entry, 4)? == [0xf3, 0x0f, 0x1e, 0xfa] {
+            push_rbp += 4;
+        }
+        let mov_rbp = push_rbp + 1;
+        let sp = if rip <= push_rbp {
+            regs.rsp
+        } else if rip <= mov_rbp {
+            regs.rsp + 8
+        } else {
+            regs.rbp + 8
+        } as usize;
+
+        Ok(Some((process.read_word(sp)? as usize, sp + 8)))
+    }
+
+    /// Reads `len` bytes of memory, seeing through any breakpoints we have inserted.
+    fn read_original_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = self.process().unwrap().read_bytes(addr, len)?;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            if let Some(orig_byte) = self.breakpoint_map.get(&((addr + i) as u64)) {
+                *byte = *orig_byte;
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Runs the inferior until the frame whose caller resumes at `return_addr` with rsp equal to
+    /// `caller_sp` returns. Returns None once that happens, or the status of whatever stopped the
+    /// inferior first (a user breakpoint, a signal, exiting).
+    fn run_until_return(
+        &mut self,
+        return_addr: usize,
+        caller_sp: usize,
+    ) -> Result<Option<Stop>, nix::Error> {
+        // Recursive calls return through the same address, only stop for our frame.
+        self.run_to(&[(return_addr, caller_sp)])
+    }
+
+    /// Runs the inferior until the current thread gets to one of `locations`, each an address
+    /// and the lowest rsp it counts at (see TransientTrap). Returns None once that happens, or the
+    /// status of whatever stopped the inferior first. The traps are gone again either way.
+    fn run_to(&mut self, locations: &[(usize, usize)]) -> Result<Option<Stop>, nix::Error> {
+        let thread = self.inferior.as_ref().unwrap().current_thread().0;
+        let first = self.transient_traps.len();
+        for &(addr, min_sp) in locations {
+            self.transient_traps.push(TransientTrap {
+                addr,
+                thread,
+                min_sp,
+            });
+            if let Err(err) = self.insert_trap(addr) {
+                self.remove_transient_traps(first, true)?;
+                return Err(err);
+            }
+        }
+
+        let result = self.continue_inferior();
+        let alive = match result {
+            Ok(Stop::Status(Status::Exited(_))) | Ok(Stop::Status(Status::Signaled(_))) => false,
+            _ => true,
+        };
+        let result = result.and_then(|stop| match stop {
+            Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, rip))
+                if self.reached_transient_trap(rip)? =>
+            {
+                Ok(None)
+            }
+            other => Ok(Some(other)),
+        });
+        self.remove_transient_traps(first, alive)?;
+        result
+    }
+
+    /// Takes out the transient traps from index `first` on, except where something else still
+    /// wants a trap. Once the inferior is gone, there is only the bookkeeping to undo.
+    fn remove_transient_traps(&mut self, first: usize, alive: bool) -> Result<(), nix::Error> {
+        let traps: Vec<TransientTrap> = self.transient_traps.drain(first..).collect();
+        for trap in traps {
+            if alive {
+                self.sync_trap(trap.addr)?;
+            } else {
+                self.breakpoint_map.remove(&(trap.addr as u64));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the inferior like `cont` does, but an instruction at a time so that each one is
+    /// recorded. Stops in front of our traps rather than running into them.
+    fn step_until_trap(&mut self) -> Result<Stop, nix::Error> {
+        loop {
+            match self.step_instruction()? {
+                Stop::Status(Status::Stopped(signal::Signal::SIGTRAP, rip))
+                    if !self.breakpoint_map.contains_key(&(rip as u64)) => {}
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Saves the state the instruction at `rip` is about to change into the recording.
+    fn record_instruction(&mut self, rip: usize) -> Result<(), nix::Error> {
+        let code = self.read_code(rip, disassemble::MAX_INSTRUCTION_LEN);
+        // Only the operands matter, so skip looking up symbols
+        let instruction = disassemble::decode(&code, rip as u64, &|_| String::new());
+        if instruction.text == "(bad)" {
+            // What the instruction changes isn't known, so it couldn't be undone
+            console_println!(
+                "Process record does not support instruction {:#04x} at address {:#x}.",
+                disassemble::opcode_byte(&code).unwrap_or(0),
+                rip
+            );
+            console_println!("Process record: failed to record execution log.");
+            self.recording = None;
+            return Ok(());
+        }
+        let step = RecordedStep::capture(self.inferior.as_ref().unwrap(), &instruction)?;
+        self.recording.as_mut().unwrap().push(step);
+        Ok(())
+    }
+
+    /// Undoes the last recorded instruction, switching to the thread that ran it. Returns what
+    /// kind of instruction it was, or None if the log is empty.
+    fn reverse_step(&mut self) -> Result<Option<StepKind>, nix::Error> {
+        let step = match self.recording.as_mut().unwrap().pop() {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+        let inferior = self.inferior.as_mut().unwrap();
+        step.restore(inferior)?;
+        if inferior.current_thread().1 != step.tid {
+            let num = inferior
+                .threads()
+                .into_iter()
+                .find(|(_, tid)| *tid == step.tid)
+                .map(|(num, _)| num);
+            if let Some(num) = num {
+                inferior.select_thread(num);
+            }
+        }
+        Ok(Some(step.kind))
+    }
+
+    /// Decides whether running backwards should stop where the inferior now is: when the value of
+    /// a watchpoint changed, or if `breakpoints` is set, at a breakpoint.
+    fn reverse_stop(&mut self, breakpoints: bool) -> Result<Option<Status>, nix::Error> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let rip = inferior.getregs()?.rip as usize;
+        for watchpoint in self.watchpoints.iter() {
+            if let Some(slot) = watchpoint.slot {
+                let value = inferior.read_bytes(watchpoint.addr, watchpoint.value_type.size)?;
+                if value != watchpoint.old_value {
+                    return Ok(Some(Status::Watchpoint(slot, rip)));
+                }
+            }
+        }
+        if breakpoints
+            && self
+                .breakpoints
+                .iter()
+                .any(|bp| bp.is_active() && bp.addr == rip)
+            && self.should_stop_at_breakpoint(rip)
+        {
+            return Ok(Some(Status::Stopped(signal::Signal::SIGTRAP, rip)));
+        }
+        Ok(None)
+    }
+
+    /// Goes back one instruction. Returns None if there is no history left.
+    fn reverse_stepi(&mut self) -> Result<Option<Status>, nix::Error> {
+        if self.reverse_step()?.is_none() {
+            return Ok(None);
+        }
+        match self.reverse_stop(false)? {
+            Some(status) => Ok(Some(status)),
+            None => {
+                let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+                Ok(Some(Status::Stopped(signal::Signal::SIGTRAP, rip)))
+            }
+        }
+    }
+
+    /// Goes back to the start of the current line, or if already there, of the line that ran
+    /// before it in the same function, unless a breakpoint in a call stepped back over or a
+    /// watchpoint stops us first.
+    fn reverse_next(&mut self) -> Result<Option<Status>, nix::Error> {
+        let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        let count = self.reverse_next_count(rip);
+        let mut depth = 0;
+        for _ in 0..count.unwrap_or(usize::MAX) {
+            depth += match self.reverse_step()? {
+                Some(StepKind::Call) => -1,
+                Some(StepKind::Return) => 1,
+                Some(StepKind::Other) => 0,
+                None => return Ok(None),
+            };
+            if let Some(status) = self.reverse_stop(depth > 0)? {
+                return Ok(Some(status));
+            }
+        }
+        let rip = self.inferior.as_ref().unwrap().getregs()?.rip as usize;
+        Ok(Some(Status::Stopped(signal::Signal::SIGTRAP, rip)))
+    }
+
+    /// The number of instructions reverse-next goes back from `rip`, found by following the calls
+    /// and returns in the log: going back past the start of the function stops at its call. None
+    /// if the log runs out first.
+    fn reverse_next_count(&self, rip: usize) -> Option<usize> {
+        let mut line = self.line_key(rip);
+        // The last instruction found in `line` at the starting depth, if there was any
+        let mut line_start = None;
+        let mut depth: isize = 0;
+        for (i, step) in self.recording.as_ref().unwrap().iter().rev().enumerate() {
+            depth += match step.kind {
+                StepKind::Call => -1,
+                StepKind::Return => 1,
+                StepKind::Other => 0,
+            };
+            if depth < 0 {
+                return Some(i + 1);
+            }
+            if depth == 0 {
+                let step_line = self.line_key(step.regs.rip as usize);
+                if step_line != line {
+                    if line_start.is_some() {
+                        return line_start;
+                    }
+                    line = step_line;
+                }
+                line_start = Some(i + 1);
+            }
+        }
+        None
+    }
+
+    /// Goes back until a breakpoint or watchpoint stops us, or the log runs out.
+    fn reverse_continue(&mut self) -> Result<Option<Status>, nix::Error> {
+        loop {
+            if self.reverse_step()?.is_none() {
+                return Ok(None);
+            }
+            if let Some(status) = self.reverse_stop(true)? {
+                return Ok(Some(status));
+            }
+        }
+    }
+
+    /// The file and line number of `pc`, to tell lines apart.
+    fn line_key(&self, pc: usize) -> Option<(String, usize)> {
+        self.debug_data
+            .get_line_from_addr(pc)
+            .map(|line| (line.file, line.number))
+    }
+
+    fn print_location(&self, rip: usize) {
+        let func = self
+            .debug_data
+            .get_function_from_addr(rip)
+            .unwrap_or_else(|| "??".to_string());
+        match self.debug_data.get_line_from_addr(rip) {
+            Some(line) => console_println!("{} ({})", func, line),
+            None => console_println!("{} ({:#x})", func, rip),
+        }
+    }
+
+    /// Prints the line of source `pc` is in, like gdb does when the inferior stops, and makes a
+    /// plain `list` show the lines around it.
+    fn print_source_line(&mut self, pc: usize) {
+        let line = match self.debug_data.get_line_from_addr(pc) {
+            Some(line) => line,
+            None => return,
+        };
+        match read_source_file(&line.file) {
+            Some(lines) if line.number > 0 && line.number <= lines.len() => {
+                console_println!("{}\t{}", line.number, lines[line.number - 1])
+            }
+            Some(lines) => console_println!(
+                "Line number {} out of range; \"{}\" has {} lines.",
+                line.number,
+                line.file,
+                lines.len()
+            ),
+            None => console_println!("{}\t{}: No such file or directory.", line.number, line.file),
+        }
+        self.list_position = Some(ListPosition::Around(line.file, line.number));
+    }
+
+    /// Prints the source line of a frame printed with print_frame, which for most outer frames is
+    /// that of the call instruction.
+    fn print_frame_source_line(&mut self, frame: &Frame) {
+        self.print_source_line(frame.lookup_pc as usize);
+    }
+
+    /// The number of the thread that reported the last stop, if the inferior has more than one.
+    fn stopped_thread(&self) -> Option<usize> {
+        let inferior = self.inferior.as_ref().unwrap();
+        if inferior.threads().len() > 1 {
+            Some(inferior.current_thread().0)
+        } else {
+            None
+        }
+    }
+
+    /// Reports an error from resuming the inferior. ESRCH may mean the process was killed behind
+    /// our back, in which case that is reported like any other way of it exiting.
+    fn report_error(&mut self, err: nix::Error) {
+        if let nix::Error::Sys(Errno::ESRCH) = err {
+            if let Ok(Some(status)) = self.inferior.as_mut().unwrap().reap() {
+                return self.report_status(status.into());
+            }
+        }
+        console_println!("error={}", err);
+    }
+
+    /// Forgets what only made sense while the process was alive.
+    fn clear_process_state(&mut self) {
+        self.running = false;
+        self.breakpoint_map.clear();
+        self.transient_traps.clear();
+        self.recording = None;
+        for watchpoint in self.watchpoints.iter_mut() {
+            watchpoint.slot = None;
+        }
+    }
+
+    fn report_status(&mut self, stop: Stop) {
+        // The stack has changed, so go back to the innermost frame
+        self.selected_frame = 0;
+        self.reap_held_inferiors();
+        let status = match stop {
+            Stop::Status(status) => status,
+            Stop::Trapped(rip) => {
+                self.delete_out_of_scope_watchpoints();
+                return self.report_trap(rip);
+            }
+        };
+        if let Status::Exited(_) | Status::Signaled(_) = status {
+            self.clear_process_state();
+        }
+        if let Status::Stopped(..) = status {
+            self.delete_out_of_scope_watchpoints();
+        }
+        if interpreter::is_json() {
+            return self.report_status_json(status);
+        }
+        match status {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) => {
+                if let Some(breakpoint) = self.take_breakpoint_hit(rip) {
+                    if self.queue_breakpoint_commands(breakpoint.commands) {
+                        return self.list_around(rip);
+                    }
+                    let kind = if breakpoint.temporary {
+                        "Temporary breakpoint"
+                    } else {
+                        "Breakpoint"
+                    };
+                    match self.stopped_thread() {
+                        Some(thread) => {
+                            console_print!("Thread {} hit {} {}, ", thread, kind, breakpoint.num)
+                        }
+                        None => console_print!("{} {}, ", kind, breakpoint.num),
+                    }
+                }
+                self.print_location(rip);
+                self.print_source_line(rip);
+            }
+            Status::Stopped(signal, rip) => {
+                self.print_signal(signal);
+                self.print_location(rip);
+                self.print_source_line(rip);
+            }
+            Status::Watchpoint(slot, rip) => {
+                self.report_watchpoint(slot, rip);
+                self.print_source_line(rip);
+            }
+            Status::Exited(code) => {
+                let pid = self.inferior.as_ref().unwrap().pid();
+                console_println!("{}", exit_message(self.inferior_num, pid, code));
+            }
+            Status::Signaled(signal) => {
+                console_println!("\n{}", termination_message(signal));
+                console_println!("The program no longer exists.");
+            }
+            // Never reported, the inferior is resumed after these (see handle_process_events)
+            Status::Forked(..) | Status::VforkDone | Status::Execed => {}
+            // Only ends a call, see call_function
+            Status::ThreadExited => {}
+        }
+    }
+
+    /// Reports the program running into a trap of its own at `rip` like any other signal.
+    fn report_trap(&mut self, rip: usize) {
+        if interpreter::is_json() {
+            let fields = vec![
+                ("reason", "signal-received".into()),
+                ("signal", format!("{:?}", signal::Signal::SIGTRAP).into()),
+            ];
+            return self.emit_stop(fields, rip);
+        }
+        self.print_signal(signal::Signal::SIGTRAP);
+        self.print_location(rip);
+        self.print_source_line(rip);
+    }
+
+    /// Reports the held inferiors that died meanwhile, e.g. from a SIGKILL someone else sent, and
+    /// forgets them.
+    fn reap_held_inferiors(&mut self) {
+        let mut index = 0;
+        while index < self.held_inferiors.len() {
+            let held = &mut self.held_inferiors[index];
+            let pid = held.inferior.pid();
+            match held.inferior.reap() {
+                Ok(Some(Status::Exited(code))) => {
+                    console_println!("{}", exit_message(held.num, pid, code))
+                }
+                Ok(Some(_)) => console_println!("[Inferior {} (process {}) killed]", held.num, pid),
+                _ => {
+                    index += 1;
+                    continue;
+                }
+            }
+            self.held_inferiors.remove(index);
+        }
+    }
+
+    /// The user breakpoint the inferior is stopped at, if any. A temporary breakpoint is deleted
+    /// now that it has been hit.
+    fn take_breakpoint_hit(&mut self, rip: usize) -> Option<Breakpoint> {
+        let breakpoint = breakpoint::take_hit(&mut self.breakpoints, rip)?;
+        if breakpoint.temporary {
+            if let Err(err) = self.sync_trap(rip) {
+                console_println!("failed to remove breakpoint error={}", err);
+            }
+        }
+        Some(breakpoint)
+    }
+
+    /// report_status for JSON mode, with a `stopped` or `exited` record. Watchpoints still print
+    /// their old and new values to the console.
+    fn report_status_json(&mut self, status: Status) {
+        match status {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) => {
+                let fields = match self.take_breakpoint_hit(rip) {
+                    Some(breakpoint) => {
+                        let fields = vec![
+                            ("reason", "breakpoint-hit".into()),
+                            ("breakpoint", breakpoint.num.into()),
+                            ("disposition", breakpoint.disposition().into()),
+                        ];
+                        self.queue_breakpoint_commands(breakpoint.commands);
+                        fields
+                    }
+                    None => vec![("reason", "end-stepping-range".into())],
+                };
+                self.emit_stop(fields, rip);
+            }
+            Status::Stopped(signal, rip) => {
+                let fields = vec![
+                    ("reason", "signal-received".into()),
+                    ("signal", format!("{:?}", signal).into()),
+                ];
+                self.emit_stop(fields, rip);
+            }
+            Status::Watchpoint(slot, rip) => {
+                let num = self
+                    .watchpoints
+                    .iter()
+                    .find(|wp| wp.slot == Some(slot))
+                    .map(|wp| wp.num);
+                self.report_watchpoint(slot, rip);
+                let fields = vec![
+                    ("reason", "watchpoint-trigger".into()),
+                    ("watchpoint", num.into()),
+                ];
+                self.emit_stop(fields, rip);
+            }
+            Status::Exited(code) => interpreter::emit(&Json::object(vec![
+                ("type", "exited".into()),
+                ("code", (code as i64).into()),
+            ])),
+            Status::Signaled(signal) => interpreter::emit(&Json::object(vec![
+                ("type", "exited".into()),
+                ("signal", format!("{:?}", signal).into()),
+            ])),
+            Status::Forked(..) | Status::VforkDone | Status::Execed => {}
+            Status::ThreadExited => {}
+        }
+    }
+
+    /// Emits the `stopped` record of JSON mode for a stop at `rip`, with `fields` saying why, and
+    /// makes a plain `list` show the lines around it like print_source_line does.
+    fn emit_stop(&mut self, fields: Vec<(&str, Json)>, rip: usize) {
+        let thread = self.inferior.as_ref().unwrap().current_thread().0;
+        let mut record = vec![("type", "stopped".into())];
+        record.extend(fields);
+        record.push(("thread", thread.into()));
+        record.push(("frame", Json::object(self.frame_fields(rip, rip))));
+        interpreter::emit(&Json::object(record));
+        self.list_around(rip);
+    }
+
+    /// Makes a plain `list` show the lines around `pc`.
+    fn list_around(&mut self, pc: usize) {
+        if let Some(line) = self.debug_data.get_line_from_addr(pc) {
+            self.list_position = Some(ListPosition::Around(line.file, line.number));
+        }
+    }
+
+    /// Describes the code at `pc` for JSON records, like print_location does for people. The
+    /// function and line are those of `lookup_pc`, see print_frame.
+    fn frame_fields(&self, pc: usize, lookup_pc: usize) -> Vec<(&'static str, Json)> {
+        let mut fields = vec![
+            ("addr", format!("{:#x}", pc).into()),
+            (
+                "function",
+                self.debug_data.get_function_from_addr(lookup_pc).into(),
+            ),
+        ];
+        if let Some(line) = self.debug_data.get_line_from_addr(lookup_pc) {
+            fields.push(("file", line.file.into()));
+            fields.push(("line", line.number.into()));
+        }
+        fields
+    }
+}
+
+/// Reads the commands of a script, for `source` and `-x`.
+fn read_script(path: &str) -> Result<Vec<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(|line| line.to_string()).collect()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Err(format!("{}: No such file or directory.", path))
+        }
+        Err(err) => Err(format!("{}: {}.", path, err)),
+    }
+}
+
+/// Takes the lines of a `define` or `commands` block from `next_line` up to its `end`, along with
+/// any blocks nested in it.
+fn read_block<F>(mut next_line: F) -> Vec<String>
+where
+    F: FnMut() -> Option<String>,
+{
+    let mut lines = Vec::new();
+    let mut depth = 0;
+    while let Some(line) = next_line() {
+        let line = line.trim().to_string();
+        match line.split_whitespace().next() {
+            Some("end") if depth == 0 => break,
+            Some("end") => depth -= 1,
+            Some("define") | Some("commands") => depth += 1,
+            _ => {}
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Substitutes the arguments a user-defined command was run with for `$arg0`, `$arg1`, ... and
+/// `$argc` in its definition.
+fn expand_arguments(body: &[String], args: &[&str]) -> Vec<String> {
+    body.iter()
+        .map(|line| {
+            let mut line = line.replace("$argc", &args.len().to_string());
+            // Backwards, so that $arg1 doesn't replace the start of $arg10
+            for (i, arg) in args.iter().enumerate().rev() {
+                line = line.replace(&format!("$arg{}", i), arg);
+            }
+            line
+        })
+        .collect()
+}
+
+/// How gdb tells that inferior `num` (process `pid`) exited with `code`, which it gives in octal.
+fn exit_message(num: usize, pid: Pid, code: i32) -> String {
+    match code {
+        0 => format!("[Inferior {} (process {}) exited normally]", num, pid),
+        _ => format!(
+            "[Inferior {} (process {}) exited with code {:02o}]",
+            num, pid, code
+        ),
+    }
+}
+
+/// How gdb tells that the program was killed by `signal`.
+fn termination_message(signal: signal::Signal) -> String {
+    format!(
+        "Program terminated with signal {}, {}.",
+        signal.as_str(),
+        signals::description(signal)
+    )
+}
+
+/// Emits the `error` record of JSON mode for a request that couldn't be carried out.
+fn emit_error(id: Json, message: &str) {
+    interpreter::emit(&Json::object(vec![
+        ("type", "error".into()),
+        ("id", id),
+        ("message", message.into()),
+    ]));
+}
+
+/// Lets go of a held inferior when deet quits: processes we attached to are detached from, others
+/// are killed.
+fn release_held_inferior(held: HeldInferior) -> Result<(), nix::Error> {
+    let mut inferior = held.inferior;
+    if !inferior.is_attached() {
+        if let Err(err) = inferior.kill() {
+            console_println!("Can't kill child, error={}", err);
+        }
+        return Ok(());
+    }
+    for (addr, orig_byte) in held.breakpoint_map.iter() {
+        inferior.write_byte(*addr as usize, *orig_byte)?;
+    }
+    inferior.set_debug_register(7, 0)?;
+    inferior.detach()
+}
+
+impl expr::Context for Debugger {
+    fn variable(&self, name: &str) -> Result<(Type, usize), String> {
+        let (var, addr) = self.locate_variable(name)?;
+        Ok((var.entity_type, addr))
+    }
+
+    fn register(&self, name: &str) -> Result<i64, String> {
+        let regs = self
+            .process()
+            .ok_or_else(|| "No registers.".to_string())?
+            .getregs()
+            .or_else(|err| Err(err.to_string()))?;
+        registers::get_register(&regs, name)
+            .map(|value| value as i64)
+            .ok_or_else(|| format!("Invalid register ${}", name))
+    }
+
+    fn set_register(&self, name: &str, value: i64) -> Result<(), String> {
+        let inferior = self
+            .inferior
+            .as_ref()
+            .filter(|_| self.running)
+            .ok_or_else(|| "The program has no registers now.".to_string())?;
+        let mut regs = inferior.getregs().or_else(|err| Err(err.to_string()))?;
+        if !registers::set_register(&mut regs, name, value as u64) {
+            return Err(format!("Invalid register ${}", name));
+        }
+        inferior.setregs(regs).or_else(|err| Err(err.to_string()))
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        Debugger::read_memory(self, addr, len)
+    }
+
+    fn write_memory(&self, addr: usize, bytes: &[u8]) -> Result<(), String> {
+        self.inferior
+            .as_ref()
+            .filter(|_| self.running)
+            .ok_or_else(|| format!("Cannot access memory at address {:#x}", addr))?
+            .write_bytes(addr, bytes)
+            .or_else(|err| {
+                Err(format!(
+                    "Cannot access memory at address {:#x}: {}",
+                    addr, err
+                ))
+            })
+    }
+
+    fn type_at(&self, type_ref: TypeRef) -> Option<Type> {
+        self.debug_data.get_type(type_ref).cloned()
+    }
+
+    fn named_type(&self, name: &str) -> Option<Type> {
+        self.debug_data.get_type_by_name(name).cloned()
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        return_type: Option<&Type>,
+    ) -> Result<Value, String> {
+        self.call_function(name, args, return_type)
+    }
+}
+
+/// Reads the lines of a source file.
+fn read_source_file(path: &str) -> Option<Vec<String>> {
+    let source = fs::read_to_string(path).ok()?;
+    Some(source.lines().map(|line| line.to_string()).collect())
+}
+
+/// Rejects empty ranges and ones too long to disassemble at once, see disassembly_range.
+fn check_disassembly_range(
+    func: Option<String>,
+    start: usize,
+    end: usize,
+) -> Result<(Option<String>, usize, usize), String> {
+    if end <= start {
+        return Err("Invalid address range.".to_string());
+    }
+    if end - start > MAX_DISASSEMBLY_LEN {
+        return Err(format!(
+            "Range of {} bytes is too long to disassemble, the limit is {}.",
+            end - start,
+            MAX_DISASSEMBLY_LEN
+        ));
+    }
+    Ok((func, start, end))
+}
+
+/// Works out where `var` is stored, a local being in `frame` (see selected_frame_info).
+fn variable_address(
+    var: &Variable,
+    frame: Option<(usize, Option<(usize, usize)>)>,
+) -> Result<usize, String> {
+    match var.location {
+        Location::Address(addr) => Ok(addr),
+        Location::FramePointerOffset(offset) => match frame {
+            // gcc uses DW_OP_call_frame_cfa as the frame base, i.e. the value of rsp in the
+            // caller before the call instruction.
+            Some((_, Some((_, caller_sp)))) => Ok((caller_sp as isize + offset) as usize),
+            _ => Err("Cannot find the frame of the current function".to_string()),
+        },
+    }
+}
+
+/// Whether two file names from the debugging information refer to the same file, when one may be
+/// a trailing part of the other's path.
+fn same_file(a: &str, b: &str) -> bool {
+    a == b || a.ends_with(&format!("/{}", b)) || b.ends_with(&format!("/{}", a))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand_arguments() {
+        let body = lines(&["print $arg0 + $arg1", "echo $argc", "print $arg10", "print $arg2"]);
+        let args: Vec<String> = (0..11).map(|i| format!("a{}", i)).collect();
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        assert_eq!(
+            expand_arguments(&body, &args),
+            lines(&["print a0 + a1", "echo 11", "print a10", "print a2"])
+        );
+        // Arguments that weren't given are left alone
+        assert_eq!(
+            expand_arguments(&body, &["x"]),
+            lines(&["print x + $arg1", "echo 1", "print $arg10", "print $arg2"])
+        );
+        assert_eq!(expand_arguments(&body[1..2], &[]), lines(&["echo 0"]));
+    }
+
+    #[test]
+    fn test_read_command_block() {
+        let mut input = lines(&[
+            "  silent",
+            "define inner",
+            "  commands 2",
+            "    print x",
+            "  end",
+            "end",
+            "print y",
+            "end",
+            "next",
+        ])
+        .into_iter();
+        assert_eq!(
+            read_block(|| input.next()),
+            lines(&[
+                "silent",
+                "define inner",
+                "commands 2",
+                "print x",
+                "end",
+                "end",
+                "print y",
+            ])
+        );
+        // The line after the block is left for the next command
+        assert_eq!(input.next(), Some("next".to_string()));
+        // A block cut short by the end of input keeps what was read
+        let mut input = lines(&["print x", "define f"]).into_iter();
+        assert_eq!(read_block(|| input.next()), lines(&["print x", "define f"]));
+    }
 
-        inferior.cont().unwrap();
+    #[test]
+    fn test_exit_messages() {
+        let pid = Pid::from_raw(4242);
+        assert_eq!(
+            exit_message(1, pid, 0),
+            "[Inferior 1 (process 4242) exited normally]"
+        );
+        // Exit codes are in octal, like gdb has them
+        assert_eq!(
+            exit_message(2, pid, 3),
+            "[Inferior 2 (process 4242) exited with code 03]"
+        );
+        assert_eq!(
+            exit_message(1, pid, 10),
+            "[Inferior 1 (process 4242) exited with code 12]"
+        );
+        assert_eq!(
+            termination_message(signal::Signal::SIGKILL),
+            "Program terminated with signal SIGKILL, Killed."
+        );
+        assert_eq!(
+            termination_message(signal::Signal::SIGSEGV),
+            "Program terminated with signal SIGSEGV, Segmentation fault."
+        );
     }
 }
@@ -1,9 +1,122 @@
+use crate::breakpoint::WatchKind;
+
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
+    Start(Vec<String>),
     Continue,
-    Backtrace,
-    Breakpoint(String),
+    Next,
+    Step,
+    Finish,
+    Until(Option<String>),
+    Advance(String),
+    Backtrace(Option<usize>),
+    Up(usize),
+    Down(usize),
+    Frame(Option<usize>),
+    InfoLocals,
+    InfoArgs,
+    InfoThreads,
+    Thread(Option<usize>),
+    InfoInferiors,
+    Inferior(usize),
+    // Location, condition and whether the breakpoint is temporary (`tbreak`)
+    Breakpoint(String, Option<String>, bool),
+    InfoBreakpoints,
+    Delete(Option<usize>),
+    Enable(usize),
+    Disable(usize),
+    Ignore(usize, usize),
+    Watch(WatchKind, String),
+    Print(String),
+    Call(String),
+    InfoRegisters(Vec<String>),
+    Handle(String, Vec<String>),
+    InfoSignals(Option<String>),
+    InfoSharedLibrary,
+    SetRegister(String, String),
+    Set(String, String),
+    Show(String),
+    Examine(ExamineFormat, String),
+    List(Option<String>),
+    Disassemble(DisassembleModifiers, Option<String>),
+    Define(String),
+    Commands(Option<usize>),
+    Source(String),
+    Record,
+    RecordStop,
+    InfoRecord,
+    ReverseStepi,
+    ReverseNext,
+    ReverseContinue,
+    Attach(i32),
+    Detach,
+}
+
+/// The `/<count><fmt><size>` suffix of `x`, e.g. `x/4xg`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExamineFormat {
+    pub count: usize,
+    // One of x, d, u, o, t, c, a, s, i
+    pub format: char,
+    // Unit size in bytes: 1 (b), 2 (h), 4 (w) or 8 (g)
+    pub size: usize,
+}
+
+impl ExamineFormat {
+    fn parse(spec: &str) -> Option<ExamineFormat> {
+        let digits: String = spec.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let count = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().ok()?
+        };
+        let mut format = None;
+        let mut size = None;
+        for c in spec[digits.len()..].chars() {
+            match c {
+                'b' => size = Some(1),
+                'h' => size = Some(2),
+                'w' => size = Some(4),
+                'g' => size = Some(8),
+                'x' | 'd' | 'u' | 'o' | 't' | 'c' | 'a' | 's' | 'i' => format = Some(c),
+                _ => return None,
+            }
+        }
+        let format = format.unwrap_or('x');
+        let size = match format {
+            'a' => 8,
+            'c' => size.unwrap_or(1),
+            _ => size.unwrap_or(4),
+        };
+        Some(ExamineFormat {
+            count,
+            format,
+            size,
+        })
+    }
+}
+
+/// The modifiers of `disassemble`: `/s` (or `/m`) interleaves source lines, `/r` shows the raw
+/// bytes of each instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DisassembleModifiers {
+    pub source: bool,
+    pub raw: bool,
+}
+
+impl DisassembleModifiers {
+    fn parse(spec: &str) -> Option<DisassembleModifiers> {
+        let mut modifiers = DisassembleModifiers::default();
+        for c in spec.chars() {
+            match c {
+                's' | 'm' => modifiers.source = true,
+                'r' => modifiers.raw = true,
+                _ => return None,
+            }
+        }
+        Some(modifiers)
+    }
 }
 
 impl DebuggerCommand {
@@ -16,11 +129,264 @@ impl DebuggerCommand {
                     args.iter().map(|s| s.to_string()).collect(),
                 ))
             }
+            "start" => Some(DebuggerCommand::Start(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "attach" => Some(DebuggerCommand::Attach(tokens.get(1)?.parse().ok()?)),
+            "detach" => Some(DebuggerCommand::Detach),
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
-            "bt" | "backtrace" => Some(DebuggerCommand::Backtrace),
-            "b" | "break" => Some(DebuggerCommand::Breakpoint(tokens[1].to_string())),
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "s" | "step" => Some(DebuggerCommand::Step),
+            "fin" | "finish" => Some(DebuggerCommand::Finish),
+            "u" | "until" => Some(DebuggerCommand::Until(
+                tokens.get(1).map(|location| location.to_string()),
+            )),
+            "advance" => Some(DebuggerCommand::Advance(tokens.get(1)?.to_string())),
+            "rec" | "record" => match tokens.get(1) {
+                None | Some(&"full") => Some(DebuggerCommand::Record),
+                Some(&"stop") => Some(DebuggerCommand::RecordStop),
+                Some(_) => None,
+            },
+            "rsi" | "reverse-stepi" => Some(DebuggerCommand::ReverseStepi),
+            "rn" | "reverse-next" => Some(DebuggerCommand::ReverseNext),
+            "rc" | "reverse-continue" => Some(DebuggerCommand::ReverseContinue),
+            "bt" | "backtrace" => match tokens.get(1) {
+                Some(count) => Some(DebuggerCommand::Backtrace(Some(count.parse().ok()?))),
+                None => Some(DebuggerCommand::Backtrace(None)),
+            },
+            "up" => Some(DebuggerCommand::Up(match tokens.get(1) {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            })),
+            "down" => Some(DebuggerCommand::Down(match tokens.get(1) {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            })),
+            "f" | "frame" => match tokens.get(1) {
+                Some(level) => Some(DebuggerCommand::Frame(Some(level.parse().ok()?))),
+                None => Some(DebuggerCommand::Frame(None)),
+            },
+            "inferior" => Some(DebuggerCommand::Inferior(tokens.get(1)?.parse().ok()?)),
+            "thread" => match tokens.get(1) {
+                Some(num) => Some(DebuggerCommand::Thread(Some(num.parse().ok()?))),
+                None => Some(DebuggerCommand::Thread(None)),
+            },
+            "b" | "break" | "tb" | "tbreak" => {
+                let condition = match tokens.get(2) {
+                    Some(&"if") => Some(tokens[3..].join(" ")),
+                    Some(_) => return None,
+                    None => None,
+                };
+                Some(DebuggerCommand::Breakpoint(
+                    tokens.get(1)?.to_string(),
+                    condition,
+                    tokens[0].starts_with('t'),
+                ))
+            }
+            "i" | "info" => match *tokens.get(1)? {
+                "b" | "break" | "breakpoints" => Some(DebuggerCommand::InfoBreakpoints),
+                "locals" => Some(DebuggerCommand::InfoLocals),
+                "args" => Some(DebuggerCommand::InfoArgs),
+                "threads" => Some(DebuggerCommand::InfoThreads),
+                "inferiors" => Some(DebuggerCommand::InfoInferiors),
+                "shared" | "sharedlibrary" | "dll" => Some(DebuggerCommand::InfoSharedLibrary),
+                "rec" | "record" => Some(DebuggerCommand::InfoRecord),
+                "signals" | "handle" => Some(DebuggerCommand::InfoSignals(
+                    tokens.get(2).map(|name| name.to_string()),
+                )),
+                "r" | "reg" | "registers" => Some(DebuggerCommand::InfoRegisters(
+                    tokens[2..]
+                        .iter()
+                        .map(|name| name.trim_start_matches('$').to_string())
+                        .collect(),
+                )),
+                _ => None,
+            },
+            "d" | "delete" => match tokens.get(1) {
+                Some(num) => Some(DebuggerCommand::Delete(Some(num.parse().ok()?))),
+                None => Some(DebuggerCommand::Delete(None)),
+            },
+            "enable" => Some(DebuggerCommand::Enable(tokens.get(1)?.parse().ok()?)),
+            "disable" => Some(DebuggerCommand::Disable(tokens.get(1)?.parse().ok()?)),
+            "ignore" => Some(DebuggerCommand::Ignore(
+                tokens.get(1)?.parse().ok()?,
+                tokens.get(2)?.parse().ok()?,
+            )),
+            "watch" => Some(DebuggerCommand::Watch(
+                WatchKind::Write,
+                tokens.get(1)?.to_string(),
+            )),
+            "rwatch" => Some(DebuggerCommand::Watch(
+                WatchKind::Read,
+                tokens.get(1)?.to_string(),
+            )),
+            "awatch" => Some(DebuggerCommand::Watch(
+                WatchKind::Access,
+                tokens.get(1)?.to_string(),
+            )),
+            "p" | "print" => {
+                tokens.get(1)?;
+                Some(DebuggerCommand::Print(tokens[1..].join(" ")))
+            }
+            "call" => {
+                tokens.get(1)?;
+                Some(DebuggerCommand::Call(tokens[1..].join(" ")))
+            }
+            "set" if !tokens.get(1)?.starts_with('$') => Some(DebuggerCommand::Set(
+                tokens[1].to_string(),
+                tokens[2..].join(" "),
+            )),
+            "handle" => Some(DebuggerCommand::Handle(
+                tokens.get(1)?.to_string(),
+                tokens[2..]
+                    .iter()
+                    .map(|action| action.to_string())
+                    .collect(),
+            )),
+            "show" => Some(DebuggerCommand::Show(tokens.get(1)?.to_string())),
+            "set" => {
+                // set $reg = value
+                let assignment = tokens[1..].join(" ");
+                let equals = assignment.find('=')?;
+                let register = assignment[..equals].trim();
+                if !register.starts_with('$') || register.len() == 1 {
+                    return None;
+                }
+                Some(DebuggerCommand::SetRegister(
+                    register[1..].to_string(),
+                    assignment[equals + 1..].trim().to_string(),
+                ))
+            }
+            "define" => Some(DebuggerCommand::Define(tokens.get(1)?.to_string())),
+            "commands" => match tokens.get(1) {
+                Some(num) => Some(DebuggerCommand::Commands(Some(num.parse().ok()?))),
+                None => Some(DebuggerCommand::Commands(None)),
+            },
+            "source" => {
+                tokens.get(1)?;
+                Some(DebuggerCommand::Source(tokens[1..].join(" ")))
+            }
+            "l" | "list" => Some(DebuggerCommand::List(
+                tokens.get(1).map(|spec| spec.to_string()),
+            )),
+            command if command == "x" || command.starts_with("x/") => {
+                let format = ExamineFormat::parse(command[1..].trim_start_matches('/'))?;
+                if tokens.len() < 2 {
+                    return None;
+                }
+                Some(DebuggerCommand::Examine(format, tokens[1..].join(" ")))
+            }
+            command
+                if command.split('/').next() == Some("disas")
+                    || command.split('/').next() == Some("disassemble") =>
+            {
+                // disassemble[/mods] [/mods] [location]
+                let mut spec = command.find('/').map(|index| &command[index + 1..]);
+                let mut args = &tokens[1..];
+                if let Some(arg) = args.first() {
+                    if arg.starts_with('/') {
+                        spec = Some(&arg[1..]);
+                        args = &args[1..];
+                    }
+                }
+                let modifiers = DisassembleModifiers::parse(spec.unwrap_or(""))?;
+                let location = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.join(" "))
+                };
+                Some(DebuggerCommand::Disassemble(modifiers, location))
+            }
             // Default case:
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(line: &str) -> Option<DebuggerCommand> {
+        DebuggerCommand::from_tokens(&line.split_whitespace().collect())
+    }
+
+    #[test]
+    fn test_examine_format() {
+        let cases = [
+            ("", Some((1, 'x', 4))),
+            ("4xg", Some((4, 'x', 8))),
+            ("gx", Some((1, 'x', 8))),
+            ("3d", Some((3, 'd', 4))),
+            ("2tb", Some((2, 't', 1))),
+            ("uh", Some((1, 'u', 2))),
+            // Characters are bytes and addresses words unless told otherwise
+            ("10c", Some((10, 'c', 1))),
+            ("ch", Some((1, 'c', 2))),
+            ("2a", Some((2, 'a', 8))),
+            ("ab", Some((1, 'a', 8))),
+            ("5i", Some((5, 'i', 4))),
+            ("xz", None),
+            ("99999999999999999999999x", None),
+        ];
+        for &(spec, expected) in cases.iter() {
+            let parsed = ExamineFormat::parse(spec).map(|f| (f.count, f.format, f.size));
+            assert_eq!(parsed, expected, "x/{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_temporary_breakpoints() {
+        for line in &["tbreak main", "tb main"] {
+            match parse(line) {
+                Some(DebuggerCommand::Breakpoint(location, None, true)) => {
+                    assert_eq!(location, "main")
+                }
+                _ => panic!("{:?} isn't a temporary breakpoint", line),
+            }
+        }
+        match parse("tbreak loop.c:15 if i == 3") {
+            Some(DebuggerCommand::Breakpoint(location, Some(condition), true)) => {
+                assert_eq!(location, "loop.c:15");
+                assert_eq!(condition, "i == 3");
+            }
+            _ => panic!("tbreak with a condition isn't a temporary breakpoint"),
+        }
+        match parse("b main") {
+            Some(DebuggerCommand::Breakpoint(_, None, false)) => {}
+            _ => panic!("b isn't a breakpoint that is kept"),
+        }
+        assert!(parse("tbreak").is_none());
+        assert!(parse("tbreak main i == 3").is_none());
+    }
+
+    #[test]
+    fn test_run_to_location() {
+        match parse("start 1 two") {
+            Some(DebuggerCommand::Start(args)) => assert_eq!(args, vec!["1", "two"]),
+            _ => panic!("start isn't parsed"),
+        }
+        match parse("start") {
+            Some(DebuggerCommand::Start(args)) => assert!(args.is_empty()),
+            _ => panic!("start isn't parsed"),
+        }
+        for line in &["until", "u"] {
+            match parse(line) {
+                Some(DebuggerCommand::Until(None)) => {}
+                _ => panic!("{:?} isn't until without a location", line),
+            }
+        }
+        for line in &["until 17", "u 17"] {
+            match parse(line) {
+                Some(DebuggerCommand::Until(Some(location))) => assert_eq!(location, "17"),
+                _ => panic!("{:?} isn't until with a location", line),
+            }
+        }
+        match parse("advance loop.c:17") {
+            Some(DebuggerCommand::Advance(location)) => assert_eq!(location, "loop.c:17"),
+            _ => panic!("advance isn't parsed"),
+        }
+        // Unlike until, advance needs a location
+        assert!(parse("advance").is_none());
+    }
+}
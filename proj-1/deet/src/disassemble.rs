@@ -0,0 +1,1970 @@
+//! Decoding x86-64 machine code into AT&T syntax, the way gdb and objdump print it. Covers the
+//! general purpose and scalar SSE instructions compilers emit for ordinary C code, and the AVX and
+//! AVX-512 moves and integer operations that string functions such as memcpy are made of; anything
+//! else decodes as a one byte `(bad)`.
+
+// x86-64 instructions are at most this long
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGS16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+// Byte registers with a REX prefix; without one, 4 to 7 are ah, ch, dh and bh
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+const REGS8_LEGACY: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+// Legacy prefixes, which come before a REX prefix and the opcode
+const PREFIXES: [u8; 11] = [0x66, 0x67, 0xf0, 0xf2, 0xf3, 0x2e, 0x36, 0x3e, 0x26, 0x64, 0x65];
+
+// Condition codes of jcc, setcc and cmovcc, in opcode order
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+// Operations of 00-3f and group 1 (80-83), by opcode bits 3-5 or ModRM.reg
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+// Group 2 (c0, c1, d0-d3)
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "shl", "sar"];
+// Group 3 (f6, f7)
+const UNARY: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub len: usize,
+    // Mnemonic and operands, e.g. `mov    %rsp,%rbp`
+    pub text: String,
+    // The memory operand, if there is one: the r/m operand, or where movs and stos store
+    pub memory: Option<MemoryOperand>,
+    // Whether the instruction uses the SSE or AVX registers
+    pub vector: bool,
+}
+
+/// Where a memory operand is, which depends on the registers when the instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryOperand {
+    // Registers are numbered like in REGS64
+    pub base: Option<usize>,
+    // The index register and its scale
+    pub index: Option<(usize, u64)>,
+    pub displacement: i64,
+    // Relative to the end of the instruction instead of a base register
+    pub rip_relative: bool,
+    // fs or gs
+    pub segment: Option<&'static str>,
+}
+
+impl MemoryOperand {
+    /// Computes the address, given the values of the registers (by name, e.g. `rbp` or
+    /// `fs_base`) and the address of the end of the instruction.
+    pub fn address(&self, register: &dyn Fn(&str) -> u64, end: u64) -> u64 {
+        let mut addr = self.displacement as u64;
+        if self.rip_relative {
+            addr = addr.wrapping_add(end);
+        }
+        if let Some(base) = self.base {
+            addr = addr.wrapping_add(register(REGS64[base]));
+        }
+        if let Some((index, scale)) = self.index {
+            addr = addr.wrapping_add(register(REGS64[index]).wrapping_mul(scale));
+        }
+        if let Some(segment) = self.segment {
+            addr = addr.wrapping_add(register(&format!("{}_base", segment)));
+        }
+        addr
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which is at address `addr`. `symbolize`
+/// annotates the addresses of jump targets and rip-relative operands, returning e.g. ` <main+4>`.
+pub fn decode(bytes: &[u8], addr: u64, symbolize: &dyn Fn(u64) -> String) -> Instruction {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        rex: 0,
+        operand_size_prefix: false,
+        repeat_prefix: None,
+        prefix_words: Vec::new(),
+        segment: None,
+        rip_displacement: None,
+        memory: None,
+        vex: None,
+        disp8_scale: 1,
+        vector_state: false,
+    };
+    match decoder.instruction(addr, symbolize) {
+        Some(text) if decoder.pos <= MAX_INSTRUCTION_LEN => Instruction {
+            len: decoder.pos,
+            vector: decoder.vex.is_some() || decoder.vector_state || text.contains("%xmm"),
+            text,
+            memory: decoder.memory,
+        },
+        _ => Instruction {
+            len: 1,
+            text: "(bad)".to_string(),
+            memory: None,
+            vector: false,
+        },
+    }
+}
+
+/// What a VEX or EVEX prefix says besides the REX bits, which go into Decoder::rex.
+#[derive(Clone, Copy)]
+struct Vex {
+    // 1, 2 and 3 for the 0f, 0f38 and 0f3a opcode maps
+    map: u8,
+    // The extra source register (vvvv)
+    source: usize,
+    // The vector length in bytes, which picks the xmm, ymm or zmm registers
+    length: usize,
+    evex: bool,
+    // EVEX only: 16 for registers 16 to 31, in the reg field and in a register r/m operand
+    reg_high: usize,
+    rm_high: usize,
+    // EVEX only: the opmask register, and whether masked off elements are zeroed or kept
+    mask: u8,
+    zeroing: bool,
+}
+
+/// The first byte of the opcode of the instruction at the start of `bytes`, after its prefixes.
+pub fn opcode_byte(bytes: &[u8]) -> Option<u8> {
+    bytes
+        .iter()
+        .find(|byte| !PREFIXES.contains(byte) && *byte & 0xf0 != 0x40)
+        .cloned()
+}
+
+/// The r/m operand of a ModRM byte.
+enum Rm {
+    Reg(usize),
+    Mem(String),
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    rex: u8,
+    operand_size_prefix: bool,
+    // f2 (repne) or f3 (rep), which select a different instruction for many SSE opcodes
+    repeat_prefix: Option<u8>,
+    // Prefixes printed before the mnemonic: lock, and those that have no effect, like segment
+    // overrides other than fs and gs in 64-bit code and repeated operand size prefixes
+    prefix_words: Vec<&'static str>,
+    segment: Option<&'static str>,
+    // Set by a rip-relative memory operand, whose target is only known once the whole
+    // instruction has been decoded
+    rip_displacement: Option<i64>,
+    memory: Option<MemoryOperand>,
+    vex: Option<Vex>,
+    // What an 8-bit displacement is multiplied by, which EVEX instructions set to the size of
+    // their memory operand
+    disp8_scale: i64,
+    // Set by the instructions that save or load the vector registers as a whole, like xsave
+    vector_state: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// Reads a little endian immediate of `size` bytes, sign extended.
+    fn signed(&mut self, size: usize) -> Option<i64> {
+        let bytes = self.bytes.get(self.pos..self.pos + size)?;
+        self.pos += size;
+        let mut raw = [0u8; 8];
+        raw[..size].copy_from_slice(bytes);
+        let shift = 64 - 8 * size as u32;
+        Some(((u64::from_le_bytes(raw) << shift) as i64) >> shift)
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    /// The size of operands that are 16, 32 or 64 bits depending on prefixes.
+    fn operand_size(&self) -> usize {
+        if self.rex_w() {
+            8
+        } else if self.operand_size_prefix {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// The size of push, pop, call and jmp operands, which default to 64 bits.
+    fn stack_operand_size(&self) -> usize {
+        if self.operand_size_prefix {
+            2
+        } else {
+            8
+        }
+    }
+
+    /// Reads an immediate of an instruction operating on `size` bytes: 8, 16 or 32 bits, the
+    /// latter sign extended for 64-bit operations.
+    fn immediate(&mut self, size: usize) -> Option<i64> {
+        self.signed(size.min(4))
+    }
+
+    fn reg(&self, num: usize, size: usize) -> String {
+        let name = match size {
+            1 if self.rex == 0 && num >= 4 && num < 8 => REGS8_LEGACY[num - 4],
+            1 => REGS8[num],
+            2 => REGS16[num],
+            4 => REGS32[num],
+            _ => REGS64[num],
+        };
+        format!("%{}", name)
+    }
+
+    fn rm(&self, rm: &Rm, size: usize) -> String {
+        match rm {
+            Rm::Reg(num) => self.reg(*num, size),
+            Rm::Mem(mem) => mem.clone(),
+        }
+    }
+
+    fn xmm_rm(&self, rm: &Rm) -> String {
+        match rm {
+            Rm::Reg(num) => xmm(*num),
+            Rm::Mem(mem) => mem.clone(),
+        }
+    }
+
+    /// Decodes a ModRM byte and whatever SIB byte and displacement follow it, returning the reg
+    /// field (extended by REX.R) and the r/m operand.
+    fn modrm(&mut self) -> Option<(usize, Rm)> {
+        let modrm = self.byte()?;
+        let mode = modrm >> 6;
+        let reg = ((modrm >> 3) & 7) as usize | if self.rex & 4 != 0 { 8 } else { 0 };
+        let rm = (modrm & 7) as usize;
+        let rex_b = if self.rex & 1 != 0 { 8 } else { 0 };
+        if mode == 3 {
+            return Some((reg, Rm::Reg(rm | rex_b)));
+        }
+
+        let mut base = None;
+        let mut index = None;
+        let mut displacement_size = match mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+        if rm == 4 {
+            let sib = self.byte()?;
+            let scale = 1 << (sib >> 6);
+            let index_num = ((sib >> 3) & 7) as usize | if self.rex & 2 != 0 { 8 } else { 0 };
+            // An index of rsp means none
+            if index_num != 4 {
+                index = Some((index_num, scale));
+            }
+            if sib & 7 == 5 && mode == 0 {
+                displacement_size = 4;
+            } else {
+                base = Some((sib & 7) as usize | rex_b);
+            }
+        } else if rm == 5 && mode == 0 {
+            let displacement = self.signed(4)?;
+            self.rip_displacement = Some(displacement);
+            self.memory = Some(MemoryOperand {
+                base: None,
+                index: None,
+                displacement,
+                rip_relative: true,
+                segment: self.segment,
+            });
+            return Some((reg, Rm::Mem(self.memory(Some(displacement), "%rip"))));
+        } else {
+            base = Some(rm | rex_b);
+        }
+
+        let displacement = match displacement_size {
+            0 => None,
+            1 => Some(self.signed(1)? * self.disp8_scale),
+            _ => Some(self.signed(displacement_size)?),
+        };
+        self.memory = Some(MemoryOperand {
+            base,
+            index: index.map(|(index, scale)| (index, scale as u64)),
+            displacement: displacement.unwrap_or(0),
+            rip_relative: false,
+            segment: self.segment,
+        });
+        let mut registers = match base {
+            Some(base) => format!("%{}", REGS64[base]),
+            None => String::new(),
+        };
+        if let Some((index, scale)) = index {
+            registers.push_str(&format!(",%{},{}", REGS64[index], scale));
+        }
+        let mem = if base.is_none() && index.is_none() {
+            // An absolute address
+            let mut mem = format!("{:#x}", displacement.unwrap() as u32);
+            if let Some(segment) = self.segment {
+                mem = format!("%{}:{}", segment, mem);
+            }
+            mem
+        } else {
+            self.memory(displacement, &registers)
+        };
+        Some((reg, Rm::Mem(mem)))
+    }
+
+    /// Formats a memory operand such as `-0x14(%rbp)` or `%fs:0x0(%rax,%rax,1)`.
+    fn memory(&self, displacement: Option<i64>, registers: &str) -> String {
+        let segment = match self.segment {
+            Some(segment) => format!("%{}:", segment),
+            None => String::new(),
+        };
+        let displacement = match displacement {
+            Some(value) => signed_hex(value),
+            None => String::new(),
+        };
+        format!("{}{}({})", segment, displacement, registers)
+    }
+
+    fn instruction(&mut self, addr: u64, symbolize: &dyn Fn(u64) -> String) -> Option<String> {
+        loop {
+            match *self.bytes.get(self.pos)? {
+                0x66 if self.operand_size_prefix => self.prefix_words.push("data16"),
+                0x66 => self.operand_size_prefix = true,
+                0xf2 | 0xf3 => self.repeat_prefix = self.bytes.get(self.pos).cloned(),
+                0xf0 => self.prefix_words.push("lock"),
+                0x2e => self.prefix_words.push("cs"),
+                0x36 => self.prefix_words.push("ss"),
+                0x3e => self.prefix_words.push("ds"),
+                0x26 => self.prefix_words.push("es"),
+                0x64 => self.segment = Some("fs"),
+                0x65 => self.segment = Some("gs"),
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let (mnemonic, operands, target) = if self.vex_prefix()? {
+            self.vex_opcode()?
+        } else {
+            if let Some(byte) = self.bytes.get(self.pos) {
+                if byte & 0xf0 == 0x40 {
+                    self.rex = *byte;
+                    self.pos += 1;
+                }
+            }
+            let opcode = self.byte()?;
+            if opcode == 0x0f {
+                self.two_byte_opcode()?
+            } else {
+                self.one_byte_opcode(opcode)?
+            }
+        };
+        let end = addr.wrapping_add(self.pos as u64);
+
+        let mut text = if self.prefix_words.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", self.prefix_words.join(" "), mnemonic)
+        };
+        if !operands.is_empty() {
+            text = format!("{:<6} {}", text, operands);
+        }
+        // Relative jumps and calls show where they go
+        if let Some(offset) = target {
+            let target = end.wrapping_add(offset as u64);
+            text = format!("{:<6} {:#x}{}", text, target, symbolize(target));
+        }
+        if let Some(displacement) = self.rip_displacement {
+            let target = end.wrapping_add(displacement as u64);
+            text = format!("{}        # {:#x}{}", text, target, symbolize(target));
+        }
+        Some(text)
+    }
+
+    /// Reads a VEX (c4, c5) or EVEX (62) prefix if the instruction has one, returning whether it
+    /// did. In 64-bit code these bytes can't start another instruction.
+    fn vex_prefix(&mut self) -> Option<bool> {
+        let first = match self.bytes.get(self.pos) {
+            Some(byte) if *byte == 0xc4 || *byte == 0xc5 || *byte == 0x62 => *byte,
+            _ => return Some(false),
+        };
+        // The prefix takes the place of these
+        if self.operand_size_prefix || self.repeat_prefix.is_some() {
+            return None;
+        }
+        self.pos += 1;
+        // R, X, B and vvvv are stored inverted
+        let (vex, pp) = match first {
+            0xc5 => {
+                let byte = self.byte()?;
+                self.rex = 0x40 | if byte & 0x80 == 0 { 4 } else { 0 };
+                let vex = Vex {
+                    map: 1,
+                    source: ((!byte >> 3) & 0xf) as usize,
+                    length: if byte & 4 != 0 { 32 } else { 16 },
+                    evex: false,
+                    reg_high: 0,
+                    rm_high: 0,
+                    mask: 0,
+                    zeroing: false,
+                };
+                (vex, byte & 3)
+            }
+            0xc4 => {
+                let (first, second) = (self.byte()?, self.byte()?);
+                self.rex = 0x40 | ((!first >> 5) & 7) | ((second >> 4) & 8);
+                let vex = Vex {
+                    map: first & 0x1f,
+                    source: ((!second >> 3) & 0xf) as usize,
+                    length: if second & 4 != 0 { 32 } else { 16 },
+                    evex: false,
+                    reg_high: 0,
+                    rm_high: 0,
+                    mask: 0,
+                    zeroing: false,
+                };
+                (vex, second & 3)
+            }
+            _ => {
+                let (first, second, third) = (self.byte()?, self.byte()?, self.byte()?);
+                // Broadcasts and embedded rounding (the b bit) aren't supported
+                if second & 4 == 0 || third & 0x10 != 0 || (third >> 5) & 3 == 3 {
+                    return None;
+                }
+                self.rex = 0x40 | ((!first >> 5) & 7) | ((second >> 4) & 8);
+                let vex = Vex {
+                    map: first & 7,
+                    source: ((!second >> 3) & 0xf) as usize | if third & 8 == 0 { 16 } else { 0 },
+                    length: 16 << ((third >> 5) & 3),
+                    evex: true,
+                    reg_high: if first & 0x10 == 0 { 16 } else { 0 },
+                    rm_high: if first & 0x40 == 0 { 16 } else { 0 },
+                    mask: third & 7,
+                    zeroing: third & 0x80 != 0,
+                };
+                // Full vector operands, which is most of them
+                self.disp8_scale = vex.length as i64;
+                (vex, second & 3)
+            }
+        };
+        match pp {
+            1 => self.operand_size_prefix = true,
+            2 => self.repeat_prefix = Some(0xf3),
+            3 => self.repeat_prefix = Some(0xf2),
+            _ => {}
+        }
+        if vex.map == 0 || vex.map > 3 {
+            return None;
+        }
+        self.vex = Some(vex);
+        Some(true)
+    }
+
+    /// Decodes the opcode after a VEX or EVEX prefix, like one_byte_opcode.
+    fn vex_opcode(&mut self) -> Option<(String, String, Option<i64>)> {
+        let vex = self.vex?;
+        let opcode = self.byte()?;
+        let operation =
+            |mnemonic: &str, operands: String| Some((mnemonic.to_string(), operands, None));
+        let precision = match (self.repeat_prefix, self.operand_size_prefix) {
+            (Some(0xf3), _) => "ss",
+            (Some(_), _) => "sd",
+            (None, true) => "pd",
+            (None, false) => "ps",
+        };
+        let scalar = precision.starts_with('s');
+        // The opmask and zeroing of EVEX instructions go after their destination
+        let masked = |mut operands: String| {
+            if vex.mask != 0 {
+                operands.push_str(&format!("{{%k{}}}", vex.mask));
+                if vex.zeroing {
+                    operands.push_str("{z}");
+                }
+            }
+            operands
+        };
+
+        match (vex.map, opcode) {
+            (1, 0x41) | (1, 0x42) | (1, 0x44..=0x47) | (1, 0x4b) | (1, 0x90..=0x93)
+            | (1, 0x98) | (1, 0x99)
+                if !vex.evex =>
+            {
+                self.opmask_operation(opcode)
+            }
+            (1, 0x10) | (1, 0x11) if scalar => {
+                if vex.evex {
+                    self.disp8_scale = if precision == "ss" { 4 } else { 8 };
+                }
+                let (reg, rm) = self.modrm()?;
+                let reg = xmm(reg | vex.reg_high);
+                let mnemonic = format!("vmov{}", precision);
+                let operands = match (opcode, rm) {
+                    (0x10, Rm::Mem(mem)) => format!("{},{}", mem, reg),
+                    (_, Rm::Mem(mem)) => format!("{},{}", reg, mem),
+                    // Between registers, the upper elements come from the extra source
+                    (0x10, Rm::Reg(num)) => {
+                        format!("{},{},{}", xmm(num | vex.rm_high), xmm(vex.source), reg)
+                    }
+                    (_, Rm::Reg(num)) => {
+                        format!("{},{},{}", reg, xmm(vex.source), xmm(num | vex.rm_high))
+                    }
+                };
+                operation(&mnemonic, masked(operands))
+            }
+            (1, 0x10) | (1, 0x11) | (1, 0x28) | (1, 0x29) => {
+                let kind = if opcode < 0x28 { "u" } else { "a" };
+                let operands = self.vector_move(opcode & 1 == 1)?;
+                operation(&format!("vmov{}{}", kind, precision), masked(operands))
+            }
+            (1, 0x6f) | (1, 0x7f) if precision != "ps" => {
+                let element = if self.rex_w() { 64 } else { 32 };
+                let mnemonic = match (precision, vex.evex) {
+                    ("pd", false) => "vmovdqa".to_string(),
+                    ("ss", false) => "vmovdqu".to_string(),
+                    ("pd", true) => format!("vmovdqa{}", element),
+                    ("ss", true) => format!("vmovdqu{}", element),
+                    ("sd", true) => format!("vmovdqu{}", element / 4),
+                    _ => return None,
+                };
+                let operands = self.vector_move(opcode == 0x7f)?;
+                operation(&mnemonic, masked(operands))
+            }
+            (1, 0x77) if !vex.evex && precision == "ps" => {
+                let mnemonic = if vex.length == 16 { "vzeroupper" } else { "vzeroall" };
+                Some((mnemonic.to_string(), String::new(), None))
+            }
+            (1, 0x6e) | (1, 0x7e) if !vex.evex && precision == "pd" => {
+                let (reg, rm) = self.modrm()?;
+                let gpr_size = if self.rex_w() { 8 } else { 4 };
+                let mnemonic = if self.rex_w() { "vmovq" } else { "vmovd" };
+                if opcode == 0x6e {
+                    operation(mnemonic, format!("{},{}", self.rm(&rm, gpr_size), xmm(reg)))
+                } else {
+                    operation(mnemonic, format!("{},{}", xmm(reg), self.rm(&rm, gpr_size)))
+                }
+            }
+            (1, 0x7e) if !vex.evex && precision == "ss" => {
+                let (reg, rm) = self.modrm()?;
+                operation("vmovq", format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+            }
+            (1, 0xd6) if !vex.evex && precision == "pd" => {
+                let (reg, rm) = self.modrm()?;
+                operation("vmovq", format!("{},{}", xmm(reg), self.xmm_rm(&rm)))
+            }
+            (1, 0xd7) if !vex.evex && precision == "pd" => match self.modrm()? {
+                (reg, Rm::Reg(num)) => {
+                    let operands = format!("{},{}", self.vector(num), self.reg(reg, 4));
+                    operation("vpmovmskb", operands)
+                }
+                _ => None,
+            },
+            (1, 0x51) | (1, 0x54..=0x59) | (1, 0x5c..=0x5f) if !vex.evex => {
+                let name = match opcode {
+                    0x51 => "sqrt",
+                    0x54 => "and",
+                    0x55 => "andn",
+                    0x56 => "or",
+                    0x57 => "xor",
+                    0x58 => "add",
+                    0x59 => "mul",
+                    0x5c => "sub",
+                    0x5d => "min",
+                    0x5e => "div",
+                    _ => "max",
+                };
+                // Bitwise operations only come packed
+                if scalar && (0x54..=0x57).contains(&opcode) {
+                    return None;
+                }
+                let operands = if opcode == 0x51 && !scalar {
+                    self.vector_move(false)?
+                } else {
+                    self.vector_operation(scalar)?
+                };
+                operation(&format!("v{}{}", name, precision), operands)
+            }
+            (1, 0xe7) if precision == "pd" => match self.vector_move(true)? {
+                // Non-temporal stores only go to memory
+                ref operands if self.memory.is_some() => operation("vmovntdq", operands.clone()),
+                _ => None,
+            },
+            (1, 0x64..=0x66) | (1, 0x74..=0x76) if vex.evex && precision == "pd" => {
+                // The EVEX comparisons set an opmask register
+                let (reg, rm) = self.modrm()?;
+                let operands = format!(
+                    "{},{},%k{}",
+                    self.vector_rm(&rm),
+                    self.vector(vex.source),
+                    reg & 7
+                );
+                operation(&format!("v{}", integer_operation(opcode)?), masked(operands))
+            }
+            (1, _) if precision == "pd" => {
+                let name = integer_operation(opcode)?;
+                // The EVEX forms of the bitwise operations work on 32 or 64-bit elements
+                let mnemonic = match (vex.evex, self.rex_w(), name) {
+                    (true, true, "pand") | (true, true, "pandn") => format!("v{}q", name),
+                    (true, true, "por") | (true, true, "pxor") => format!("v{}q", name),
+                    (true, false, "pand") | (true, false, "pandn") => format!("v{}d", name),
+                    (true, false, "por") | (true, false, "pxor") => format!("v{}d", name),
+                    _ => format!("v{}", name),
+                };
+                let operands = self.vector_operation(false)?;
+                operation(&mnemonic, masked(operands))
+            }
+            (2, 0x00) if !vex.evex && precision == "pd" => {
+                let operands = self.vector_operation(false)?;
+                operation("vpshufb", operands)
+            }
+            (2, 0x58) | (2, 0x59) | (2, 0x78) | (2, 0x79) if precision == "pd" => {
+                let (mnemonic, element) = match opcode {
+                    0x78 => ("vpbroadcastb", 1),
+                    0x79 => ("vpbroadcastw", 2),
+                    0x58 => ("vpbroadcastd", 4),
+                    _ => ("vpbroadcastq", 8),
+                };
+                if vex.evex {
+                    self.disp8_scale = element;
+                }
+                // The source is an element, in memory or at the bottom of an xmm register
+                let (reg, rm) = self.modrm()?;
+                let rm = match rm {
+                    Rm::Reg(num) => xmm(num | vex.rm_high),
+                    Rm::Mem(mem) => mem,
+                };
+                let operands = format!("{},{}", rm, self.vector(reg | vex.reg_high));
+                operation(mnemonic, masked(operands))
+            }
+            (2, 0x38..=0x3f) if precision == "pd" => {
+                let name = match opcode {
+                    0x38 => "vpminsb",
+                    0x39 => "vpminsd",
+                    0x3a => "vpminuw",
+                    0x3b => "vpminud",
+                    0x3c => "vpmaxsb",
+                    0x3d => "vpmaxsd",
+                    0x3e => "vpmaxuw",
+                    _ => "vpmaxud",
+                };
+                // EVEX.W picks 64-bit elements for the doubleword ones
+                let mnemonic = if vex.evex && self.rex_w() && name.ends_with('d') {
+                    format!("{}q", &name[..name.len() - 1])
+                } else {
+                    name.to_string()
+                };
+                let operands = self.vector_operation(false)?;
+                operation(&mnemonic, masked(operands))
+            }
+            (2, 0x26) | (2, 0x27) if vex.evex && (precision == "pd" || precision == "ss") => {
+                let element = match (opcode, self.rex_w()) {
+                    (0x26, false) => "b",
+                    (0x26, true) => "w",
+                    (_, false) => "d",
+                    (_, true) => "q",
+                };
+                let test = if precision == "ss" { "vptestnm" } else { "vptestm" };
+                let (reg, rm) = self.modrm()?;
+                let operands = format!(
+                    "{},{},%k{}",
+                    self.vector_rm(&rm),
+                    self.vector(vex.source),
+                    reg & 7
+                );
+                operation(&format!("{}{}", test, element), masked(operands))
+            }
+            (3, 0x1e) | (3, 0x1f) | (3, 0x3e) | (3, 0x3f) if vex.evex && precision == "pd" => {
+                let element = match (opcode & 0xf0, self.rex_w()) {
+                    (0x30, false) => "b",
+                    (0x30, true) => "w",
+                    (_, false) => "d",
+                    (_, true) => "q",
+                };
+                let unsigned = if opcode & 1 == 0 { "u" } else { "" };
+                let (reg, rm) = self.modrm()?;
+                let predicate = self.immediate(1)?;
+                let operands = format!(
+                    "{},{},%k{}",
+                    self.vector_rm(&rm),
+                    self.vector(vex.source),
+                    reg & 7
+                );
+                // Like the assembler, name the predicate in the mnemonic where it has a name
+                let condition = match predicate {
+                    0 => "eq",
+                    1 => "lt",
+                    2 => "le",
+                    4 => "neq",
+                    5 => "nlt",
+                    6 => "nle",
+                    _ => {
+                        let mnemonic = format!("vpcmp{}{}", unsigned, element);
+                        let operands = format!("{},{}", immediate(predicate, 1), operands);
+                        return operation(&mnemonic, masked(operands));
+                    }
+                };
+                let mnemonic = format!("vpcmp{}{}{}", condition, unsigned, element);
+                operation(&mnemonic, masked(operands))
+            }
+            (3, 0x25) if vex.evex && precision == "pd" => {
+                let mnemonic = if self.rex_w() { "vpternlogq" } else { "vpternlogd" };
+                let operands = self.vector_operation(false)?;
+                let imm = self.immediate(1)?;
+                operation(mnemonic, masked(format!("{},{}", immediate(imm, 1), operands)))
+            }
+            (2, 0x7a..=0x7c) if vex.evex && precision == "pd" => {
+                // EVEX can also broadcast from a general purpose register
+                let (mnemonic, size) = match (opcode, self.rex_w()) {
+                    (0x7a, _) => ("vpbroadcastb", 4),
+                    (0x7b, _) => ("vpbroadcastw", 4),
+                    (_, false) => ("vpbroadcastd", 4),
+                    (_, true) => ("vpbroadcastq", 8),
+                };
+                match self.modrm()? {
+                    (reg, Rm::Reg(num)) => {
+                        let operands =
+                            format!("{},{}", self.reg(num, size), self.vector(reg | vex.reg_high));
+                        operation(mnemonic, masked(operands))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Decodes the operands of a move between a vector register and a vector register or memory,
+    /// `store` being whether the r/m operand is the destination. Moves have no extra source.
+    fn vector_move(&mut self, store: bool) -> Option<String> {
+        let vex = self.vex?;
+        if vex.source != 0 {
+            return None;
+        }
+        let (reg, rm) = self.modrm()?;
+        let reg = self.vector(reg | vex.reg_high);
+        let rm = self.vector_rm(&rm);
+        if store {
+            Some(format!("{},{}", reg, rm))
+        } else {
+            Some(format!("{},{}", rm, reg))
+        }
+    }
+
+    /// Decodes the operands of an operation on the r/m operand and the extra source, which puts
+    /// its result in the reg operand. Scalar operations work on xmm registers.
+    fn vector_operation(&mut self, scalar: bool) -> Option<String> {
+        let vex = self.vex?;
+        let (reg, rm) = self.modrm()?;
+        if scalar {
+            return Some(format!("{},{},{}", self.xmm_rm(&rm), xmm(vex.source), xmm(reg)));
+        }
+        Some(format!(
+            "{},{},{}",
+            self.vector_rm(&rm),
+            self.vector(vex.source),
+            self.vector(reg | vex.reg_high)
+        ))
+    }
+
+    /// Names vector register `num`, which is an xmm, ymm or zmm register depending on the vector
+    /// length.
+    fn vector(&self, num: usize) -> String {
+        let kind = match self.vex.map(|vex| vex.length) {
+            Some(64) => "zmm",
+            Some(32) => "ymm",
+            _ => "xmm",
+        };
+        format!("%{}{}", kind, num)
+    }
+
+    /// Decodes the VEX instructions on the opmask registers %k0 to %k7.
+    fn opmask_operation(&mut self, opcode: u8) -> Option<(String, String, Option<i64>)> {
+        let vex = self.vex?;
+        // The prefix and W pick the size of the mask
+        let size = match (self.repeat_prefix, self.operand_size_prefix, self.rex_w()) {
+            (Some(0xf2), _, false) if opcode == 0x92 || opcode == 0x93 => "d",
+            (Some(0xf2), _, true) if opcode == 0x92 || opcode == 0x93 => "q",
+            (Some(_), _, _) => return None,
+            (None, false, false) => "w",
+            (None, false, true) => "q",
+            (None, true, false) => "b",
+            (None, true, true) => "d",
+        };
+        let (reg, rm) = self.modrm()?;
+        let mask = |num: usize| format!("%k{}", num & 7);
+        let gpr_size = if size == "q" { 8 } else { 4 };
+        let (name, operands) = match (opcode, rm) {
+            (0x90, Rm::Reg(num)) => ("kmov", format!("{},{}", mask(num), mask(reg))),
+            (0x90, Rm::Mem(mem)) => ("kmov", format!("{},{}", mem, mask(reg))),
+            (0x91, Rm::Mem(mem)) => ("kmov", format!("{},{}", mask(reg), mem)),
+            (0x92, Rm::Reg(num)) => ("kmov", format!("{},{}", self.reg(num, gpr_size), mask(reg))),
+            (0x93, Rm::Reg(num)) => ("kmov", format!("{},{}", mask(num), self.reg(reg, gpr_size))),
+            (0x98, Rm::Reg(num)) => ("kortest", format!("{},{}", mask(num), mask(reg))),
+            (0x99, Rm::Reg(num)) => ("ktest", format!("{},{}", mask(num), mask(reg))),
+            (0x44, Rm::Reg(num)) => ("knot", format!("{},{}", mask(num), mask(reg))),
+            (0x4b, Rm::Reg(num)) => {
+                let mnemonic = match size {
+                    "b" => "kunpckbw",
+                    "w" => "kunpckwd",
+                    "q" => "kunpckdq",
+                    _ => return None,
+                };
+                let operands = format!("{},{},{}", mask(num), mask(vex.source), mask(reg));
+                return Some((mnemonic.to_string(), operands, None));
+            }
+            (_, Rm::Reg(num)) if opcode != 0x91 && opcode < 0x90 => {
+                let name = match opcode {
+                    0x41 => "kand",
+                    0x42 => "kandn",
+                    0x45 => "kor",
+                    0x46 => "kxnor",
+                    _ => "kxor",
+                };
+                (name, format!("{},{},{}", mask(num), mask(vex.source), mask(reg)))
+            }
+            _ => return None,
+        };
+        Some((format!("{}{}", name, size), operands, None))
+    }
+
+    fn vector_rm(&self, rm: &Rm) -> String {
+        match rm {
+            Rm::Reg(num) => self.vector(num | self.vex.map(|vex| vex.rm_high).unwrap_or(0)),
+            Rm::Mem(mem) => mem.clone(),
+        }
+    }
+
+    /// Decodes the opcodes of the one byte map, returning the mnemonic, the operands and for
+    /// relative jumps, the offset of the target from the end of the instruction.
+    fn one_byte_opcode(&mut self, opcode: u8) -> Option<(String, String, Option<i64>)> {
+        let size = self.operand_size();
+        let simple = |mnemonic: &str| Some((mnemonic.to_string(), String::new(), None));
+        let operation =
+            |mnemonic: &str, operands: String| Some((mnemonic.to_string(), operands, None));
+        let jump =
+            |mnemonic: &str, offset: i64| Some((mnemonic.to_string(), String::new(), Some(offset)));
+
+        match opcode {
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let mnemonic = ARITHMETIC[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0 | 1 | 2 | 3 => {
+                        let size = if opcode & 1 == 0 { 1 } else { size };
+                        let (reg, rm) = self.modrm()?;
+                        let (reg, rm) = (self.reg(reg, size), self.rm(&rm, size));
+                        if opcode & 2 == 0 {
+                            operation(mnemonic, format!("{},{}", reg, rm))
+                        } else {
+                            operation(mnemonic, format!("{},{}", rm, reg))
+                        }
+                    }
+                    4 => {
+                        let imm = self.immediate(1)?;
+                        operation(mnemonic, format!("{},%al", immediate(imm, 1)))
+                    }
+                    _ => {
+                        let imm = self.immediate(size)?;
+                        operation(
+                            mnemonic,
+                            format!("{},{}", immediate(imm, size), self.reg(0, size)),
+                        )
+                    }
+                }
+            }
+            0x50..=0x57 | 0x58..=0x5f => {
+                let num = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let mnemonic = if opcode < 0x58 { "push" } else { "pop" };
+                operation(mnemonic, self.reg(num, self.stack_operand_size()))
+            }
+            0x63 => {
+                let (reg, rm) = self.modrm()?;
+                let operands = format!("{},{}", self.rm(&rm, 4), self.reg(reg, size));
+                operation(if self.rex_w() { "movslq" } else { "movsxd" }, operands)
+            }
+            0x68 | 0x6a => {
+                let imm = self.immediate(if opcode == 0x68 { 4 } else { 1 })?;
+                operation("push", immediate(imm, self.stack_operand_size()))
+            }
+            0x69 | 0x6b => {
+                let (reg, rm) = self.modrm()?;
+                let imm = self.immediate(if opcode == 0x69 { size } else { 1 })?;
+                let operands = format!(
+                    "{},{},{}",
+                    immediate(imm, size),
+                    self.rm(&rm, size),
+                    self.reg(reg, size)
+                );
+                operation("imul", operands)
+            }
+            0x70..=0x7f => {
+                let offset = self.signed(1)?;
+                jump(&format!("j{}", CONDITIONS[(opcode & 0xf) as usize]), offset)
+            }
+            0x80 | 0x81 | 0x83 => {
+                let size = if opcode == 0x80 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                let imm = self.immediate(if opcode == 0x81 { size } else { 1 })?;
+                let mnemonic = suffixed(ARITHMETIC[reg & 7], &rm, size);
+                operation(
+                    &mnemonic,
+                    format!("{},{}", immediate(imm, size), self.rm(&rm, size)),
+                )
+            }
+            0x84..=0x8b => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                let (reg, rm) = (self.reg(reg, size), self.rm(&rm, size));
+                let mnemonic = match opcode {
+                    0x84 | 0x85 => "test",
+                    0x86 | 0x87 => "xchg",
+                    _ => "mov",
+                };
+                if opcode < 0x8a {
+                    operation(mnemonic, format!("{},{}", reg, rm))
+                } else {
+                    operation(mnemonic, format!("{},{}", rm, reg))
+                }
+            }
+            0x8d => {
+                let (reg, rm) = self.modrm()?;
+                match rm {
+                    Rm::Mem(mem) => operation("lea", format!("{},{}", mem, self.reg(reg, size))),
+                    Rm::Reg(_) => None,
+                }
+            }
+            0x8f => {
+                let (reg, rm) = self.modrm()?;
+                if reg & 7 != 0 {
+                    return None;
+                }
+                let size = self.stack_operand_size();
+                operation("pop", self.rm(&rm, size))
+            }
+            0x90 if self.rex & 1 == 0 => {
+                if self.repeat_prefix == Some(0xf3) {
+                    simple("pause")
+                } else if self.operand_size_prefix {
+                    simple("xchg   %ax,%ax")
+                } else {
+                    simple("nop")
+                }
+            }
+            0x90..=0x97 => {
+                let num = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                operation(
+                    "xchg",
+                    format!("{},{}", self.reg(0, size), self.reg(num, size)),
+                )
+            }
+            0x98 => simple(match size {
+                8 => "cltq",
+                2 => "cbtw",
+                _ => "cwtl",
+            }),
+            0x99 => simple(match size {
+                8 => "cqto",
+                2 => "cwtd",
+                _ => "cltd",
+            }),
+            0xa4 | 0xa5 | 0xaa | 0xab => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let prefix = match self.repeat_prefix {
+                    Some(0xf3) => "rep ",
+                    Some(_) => "repnz ",
+                    None => "",
+                };
+                // Both store at rdi
+                self.memory = Some(MemoryOperand {
+                    base: Some(7),
+                    index: None,
+                    displacement: 0,
+                    rip_relative: false,
+                    segment: None,
+                });
+                if opcode < 0xaa {
+                    let mnemonic = format!("{}movs{}", prefix, suffix(size));
+                    operation(&mnemonic, "%ds:(%rsi),%es:(%rdi)".to_string())
+                } else {
+                    let mnemonic = format!("{}stos", prefix);
+                    operation(&mnemonic, format!("{},%es:(%rdi)", self.reg(0, size)))
+                }
+            }
+            0xa8 => {
+                let imm = self.immediate(1)?;
+                operation("test", format!("{},%al", immediate(imm, 1)))
+            }
+            0xa9 => {
+                let imm = self.immediate(size)?;
+                operation(
+                    "test",
+                    format!("{},{}", immediate(imm, size), self.reg(0, size)),
+                )
+            }
+            0xb0..=0xb7 => {
+                let num = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let imm = self.immediate(1)?;
+                operation("mov", format!("{},{}", immediate(imm, 1), self.reg(num, 1)))
+            }
+            0xb8..=0xbf => {
+                let num = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                if self.rex_w() {
+                    let imm = self.signed(8)?;
+                    operation(
+                        "movabs",
+                        format!("{},{}", immediate(imm, 8), self.reg(num, 8)),
+                    )
+                } else {
+                    let imm = self.immediate(size)?;
+                    operation(
+                        "mov",
+                        format!("{},{}", immediate(imm, size), self.reg(num, size)),
+                    )
+                }
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = suffixed(SHIFTS[reg & 7], &rm, size);
+                let operand = self.rm(&rm, size);
+                match opcode {
+                    0xc0 | 0xc1 => {
+                        let imm = self.immediate(1)?;
+                        operation(&mnemonic, format!("{},{}", immediate(imm, 1), operand))
+                    }
+                    0xd0 | 0xd1 => operation(&mnemonic, operand),
+                    _ => operation(&mnemonic, format!("%cl,{}", operand)),
+                }
+            }
+            0xc2 => {
+                let imm = self.signed(2)?;
+                operation("ret", immediate(imm, 2))
+            }
+            0xc3 => simple(match self.repeat_prefix {
+                Some(0xf3) => "repz ret",
+                Some(_) => "bnd ret",
+                None => "ret",
+            }),
+            0xc6 | 0xc7 => {
+                let size = if opcode == 0xc6 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                if reg & 7 != 0 {
+                    return None;
+                }
+                let imm = self.immediate(size)?;
+                let mnemonic = suffixed("mov", &rm, size);
+                operation(
+                    &mnemonic,
+                    format!("{},{}", immediate(imm, size), self.rm(&rm, size)),
+                )
+            }
+            0xc9 => simple("leave"),
+            0xcc => simple("int3"),
+            0xcd => {
+                let imm = self.immediate(1)?;
+                operation("int", immediate(imm, 1))
+            }
+            0xe8 => {
+                let offset = self.signed(4)?;
+                jump("call", offset)
+            }
+            0xe9 => {
+                let offset = self.signed(4)?;
+                jump("jmp", offset)
+            }
+            0xeb => {
+                let offset = self.signed(1)?;
+                jump("jmp", offset)
+            }
+            0xf4 => simple("hlt"),
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = suffixed(UNARY[reg & 7], &rm, size);
+                let operand = self.rm(&rm, size);
+                if reg & 7 < 2 {
+                    let imm = self.immediate(size)?;
+                    operation(&mnemonic, format!("{},{}", immediate(imm, size), operand))
+                } else {
+                    operation(&mnemonic, operand)
+                }
+            }
+            0xfe | 0xff => {
+                let size = if opcode == 0xfe { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                match (opcode, reg & 7) {
+                    (_, 0) => operation(&suffixed("inc", &rm, size), self.rm(&rm, size)),
+                    (_, 1) => operation(&suffixed("dec", &rm, size), self.rm(&rm, size)),
+                    (0xff, 2) | (0xff, 4) => {
+                        let mnemonic = if reg & 7 == 2 { "call" } else { "jmp" };
+                        let operand = self.rm(&rm, self.stack_operand_size());
+                        operation(mnemonic, format!("*{}", operand))
+                    }
+                    (0xff, 6) => {
+                        let size = self.stack_operand_size();
+                        operation("push", self.rm(&rm, size))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Decodes the opcodes following 0f, like one_byte_opcode.
+    fn two_byte_opcode(&mut self) -> Option<(String, String, Option<i64>)> {
+        let opcode = self.byte()?;
+        let size = self.operand_size();
+        let simple = |mnemonic: &str| Some((mnemonic.to_string(), String::new(), None));
+        let operation =
+            |mnemonic: &str, operands: String| Some((mnemonic.to_string(), operands, None));
+        // Scalar and packed SSE operations come in single and double precision flavours, picked
+        // by a mandatory prefix
+        let precision = match (self.repeat_prefix, self.operand_size_prefix) {
+            (Some(0xf3), _) => "ss",
+            (Some(_), _) => "sd",
+            (None, true) => "pd",
+            (None, false) => "ps",
+        };
+
+        match opcode {
+            0x05 => simple("syscall"),
+            0x0b => simple("ud2"),
+            0x31 => simple("rdtsc"),
+            0xa2 => simple("cpuid"),
+            0x1e if self.repeat_prefix == Some(0xf3) && self.bytes.get(self.pos) == Some(&0xfa) => {
+                self.pos += 1;
+                simple("endbr64")
+            }
+            0x18 => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match (reg & 7, &rm) {
+                    (0, Rm::Mem(_)) => "prefetchnta",
+                    (1, Rm::Mem(_)) => "prefetcht0",
+                    (2, Rm::Mem(_)) => "prefetcht1",
+                    (3, Rm::Mem(_)) => "prefetcht2",
+                    _ => return operation(&suffixed("nop", &rm, 4), self.rm(&rm, 4)),
+                };
+                operation(mnemonic, self.rm(&rm, 8))
+            }
+            0x19..=0x1f => {
+                // Hint nops, used as padding
+                let (_, rm) = self.modrm()?;
+                let size = if self.operand_size_prefix { 2 } else { 4 };
+                operation(&suffixed("nop", &rm, size), self.rm(&rm, size))
+            }
+            0x10 | 0x11 | 0x28 | 0x29 => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match (opcode, precision) {
+                    (0x10, _) | (0x11, _) => format!(
+                        "mov{}",
+                        if precision.starts_with('s') {
+                            precision.to_string()
+                        } else {
+                            format!("u{}", precision)
+                        }
+                    ),
+                    (_, "pd") => "movapd".to_string(),
+                    _ => "movaps".to_string(),
+                };
+                if opcode & 1 == 0 {
+                    operation(&mnemonic, format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+                } else {
+                    operation(&mnemonic, format!("{},{}", xmm(reg), self.xmm_rm(&rm)))
+                }
+            }
+            0x2a if self.repeat_prefix.is_some() => {
+                let (reg, rm) = self.modrm()?;
+                let gpr_size = if self.rex_w() { 8 } else { 4 };
+                let mut mnemonic = format!("cvtsi2{}", precision);
+                if let Rm::Mem(_) = rm {
+                    mnemonic.push(suffix(gpr_size));
+                }
+                operation(
+                    &mnemonic,
+                    format!("{},{}", self.rm(&rm, gpr_size), xmm(reg)),
+                )
+            }
+            0x2c | 0x2d if self.repeat_prefix.is_some() => {
+                let (reg, rm) = self.modrm()?;
+                let truncate = if opcode == 0x2c { "t" } else { "" };
+                let mnemonic = format!("cvt{}{}2si", truncate, precision);
+                let gpr_size = if self.rex_w() { 8 } else { 4 };
+                operation(
+                    &mnemonic,
+                    format!("{},{}", self.xmm_rm(&rm), self.reg(reg, gpr_size)),
+                )
+            }
+            0x2e | 0x2f if self.repeat_prefix.is_none() => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = format!(
+                    "{}comis{}",
+                    if opcode == 0x2e { "u" } else { "" },
+                    if self.operand_size_prefix { "d" } else { "s" }
+                );
+                operation(&mnemonic, format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+            }
+            0x40..=0x4f => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = format!("cmov{}", CONDITIONS[(opcode & 0xf) as usize]);
+                operation(
+                    &mnemonic,
+                    format!("{},{}", self.rm(&rm, size), self.reg(reg, size)),
+                )
+            }
+            0x51 | 0x54..=0x59 | 0x5a | 0x5c..=0x5f | 0x14 => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match opcode {
+                    0x14 => format!("unpckl{}", precision),
+                    0x51 => format!("sqrt{}", precision),
+                    0x54 => format!("and{}", precision),
+                    0x55 => format!("andn{}", precision),
+                    0x56 => format!("or{}", precision),
+                    0x57 => format!("xor{}", precision),
+                    0x58 => format!("add{}", precision),
+                    0x59 => format!("mul{}", precision),
+                    0x5a => match precision {
+                        "ss" => "cvtss2sd".to_string(),
+                        "sd" => "cvtsd2ss".to_string(),
+                        "pd" => "cvtpd2ps".to_string(),
+                        _ => "cvtps2pd".to_string(),
+                    },
+                    0x5c => format!("sub{}", precision),
+                    0x5d => format!("min{}", precision),
+                    0x5e => format!("div{}", precision),
+                    _ => format!("max{}", precision),
+                };
+                operation(&mnemonic, format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+            }
+            0x6e | 0x7e if self.operand_size_prefix => {
+                // movd/movq between a general purpose register (or memory) and an xmm register
+                let (reg, rm) = self.modrm()?;
+                let gpr_size = if self.rex_w() { 8 } else { 4 };
+                let mnemonic = if self.rex_w() { "movq" } else { "movd" };
+                if opcode == 0x6e {
+                    operation(mnemonic, format!("{},{}", self.rm(&rm, gpr_size), xmm(reg)))
+                } else {
+                    operation(mnemonic, format!("{},{}", xmm(reg), self.rm(&rm, gpr_size)))
+                }
+            }
+            0x7e if self.repeat_prefix == Some(0xf3) => {
+                let (reg, rm) = self.modrm()?;
+                operation("movq", format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+            }
+            0xd6 if self.operand_size_prefix => {
+                let (reg, rm) = self.modrm()?;
+                operation("movq", format!("{},{}", xmm(reg), self.xmm_rm(&rm)))
+            }
+            0x6f | 0x7f if self.operand_size_prefix || self.repeat_prefix == Some(0xf3) => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = if self.operand_size_prefix {
+                    "movdqa"
+                } else {
+                    "movdqu"
+                };
+                if opcode == 0x6f {
+                    operation(mnemonic, format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+                } else {
+                    operation(mnemonic, format!("{},{}", xmm(reg), self.xmm_rm(&rm)))
+                }
+            }
+            0x12 | 0x13 | 0x16 | 0x17 if self.repeat_prefix.is_none() => {
+                let (reg, rm) = self.modrm()?;
+                let half = if opcode < 0x16 { "l" } else { "h" };
+                let kind = if self.operand_size_prefix { "d" } else { "s" };
+                match (opcode & 1, rm) {
+                    (0, Rm::Mem(mem)) => {
+                        operation(&format!("mov{}p{}", half, kind), format!("{},{}", mem, xmm(reg)))
+                    }
+                    (_, Rm::Mem(mem)) => {
+                        operation(&format!("mov{}p{}", half, kind), format!("{},{}", xmm(reg), mem))
+                    }
+                    // Between registers, the halves are swapped
+                    (0, Rm::Reg(num)) if !self.operand_size_prefix => {
+                        let mnemonic = if opcode == 0x12 { "movhlps" } else { "movlhps" };
+                        operation(mnemonic, format!("{},{}", xmm(num), xmm(reg)))
+                    }
+                    _ => None,
+                }
+            }
+            0x2b if self.repeat_prefix.is_none() => match self.modrm()? {
+                (reg, Rm::Mem(mem)) => {
+                    let kind = if self.operand_size_prefix { "d" } else { "s" };
+                    operation(&format!("movntp{}", kind), format!("{},{}", xmm(reg), mem))
+                }
+                _ => None,
+            },
+            0xe7 if self.operand_size_prefix => match self.modrm()? {
+                (reg, Rm::Mem(mem)) => operation("movntdq", format!("{},{}", xmm(reg), mem)),
+                _ => None,
+            },
+            0xd7 if self.operand_size_prefix => match self.modrm()? {
+                (reg, Rm::Reg(num)) => {
+                    operation("pmovmskb", format!("{},{}", xmm(num), self.reg(reg, 4)))
+                }
+                _ => None,
+            },
+            0x70 if self.operand_size_prefix => {
+                let (reg, rm) = self.modrm()?;
+                let imm = self.immediate(1)?;
+                let operands = format!("{},{},{}", immediate(imm, 1), self.xmm_rm(&rm), xmm(reg));
+                operation("pshufd", operands)
+            }
+            0x71..=0x73 if self.operand_size_prefix => {
+                // Shifts by an immediate, of each element or (psrldq, pslldq) the whole register
+                let (reg, rm) = self.modrm()?;
+                let num = match rm {
+                    Rm::Reg(num) => num,
+                    Rm::Mem(_) => return None,
+                };
+                let mnemonic = match (opcode, reg & 7) {
+                    (0x71, 2) => "psrlw",
+                    (0x71, 4) => "psraw",
+                    (0x71, 6) => "psllw",
+                    (0x72, 2) => "psrld",
+                    (0x72, 4) => "psrad",
+                    (0x72, 6) => "pslld",
+                    (0x73, 2) => "psrlq",
+                    (0x73, 3) => "psrldq",
+                    (0x73, 6) => "psllq",
+                    (0x73, 7) => "pslldq",
+                    _ => return None,
+                };
+                let imm = self.immediate(1)?;
+                operation(mnemonic, format!("{},{}", immediate(imm, 1), xmm(num)))
+            }
+            0x38 if self.operand_size_prefix => {
+                let opcode = self.byte()?;
+                let mnemonic = match opcode {
+                    0x00 => "pshufb",
+                    0x3b => "pminud",
+                    _ => return None,
+                };
+                let (reg, rm) = self.modrm()?;
+                operation(mnemonic, format!("{},{}", self.xmm_rm(&rm), xmm(reg)))
+            }
+            0x3a if self.operand_size_prefix => {
+                let opcode = self.byte()?;
+                let mnemonic = match opcode {
+                    0x0f => "palignr",
+                    0x63 => "pcmpistri",
+                    _ => return None,
+                };
+                let (reg, rm) = self.modrm()?;
+                let imm = self.immediate(1)?;
+                let operands = format!("{},{},{}", immediate(imm, 1), self.xmm_rm(&rm), xmm(reg));
+                operation(mnemonic, operands)
+            }
+            _ if self.operand_size_prefix && integer_operation(opcode).is_some() => {
+                let (reg, rm) = self.modrm()?;
+                let operands = format!("{},{}", self.xmm_rm(&rm), xmm(reg));
+                operation(integer_operation(opcode)?, operands)
+            }
+            0x80..=0x8f => {
+                let offset = self.signed(4)?;
+                let mnemonic = format!("j{}", CONDITIONS[(opcode & 0xf) as usize]);
+                Some((mnemonic, String::new(), Some(offset)))
+            }
+            0x90..=0x9f => {
+                let (_, rm) = self.modrm()?;
+                let mnemonic = format!("set{}", CONDITIONS[(opcode & 0xf) as usize]);
+                operation(&mnemonic, self.rm(&rm, 1))
+            }
+            0xba => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match reg & 7 {
+                    4 => "bt",
+                    5 => "bts",
+                    6 => "btr",
+                    7 => "btc",
+                    _ => return None,
+                };
+                let imm = self.immediate(1)?;
+                operation(
+                    &suffixed(mnemonic, &rm, size),
+                    format!("{},{}", immediate(imm, 1), self.rm(&rm, size)),
+                )
+            }
+            0xbc | 0xbd => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match (opcode, self.repeat_prefix) {
+                    (0xbc, Some(0xf3)) => "tzcnt",
+                    (0xbc, _) => "bsf",
+                    (_, Some(0xf3)) => "lzcnt",
+                    _ => "bsr",
+                };
+                operation(
+                    mnemonic,
+                    format!("{},{}", self.rm(&rm, size), self.reg(reg, size)),
+                )
+            }
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match opcode {
+                    0xa3 => "bt",
+                    0xab => "bts",
+                    0xb3 => "btr",
+                    _ => "btc",
+                };
+                operation(
+                    mnemonic,
+                    format!("{},{}", self.reg(reg, size), self.rm(&rm, size)),
+                )
+            }
+            0xaf => {
+                let (reg, rm) = self.modrm()?;
+                operation(
+                    "imul",
+                    format!("{},{}", self.rm(&rm, size), self.reg(reg, size)),
+                )
+            }
+            0xb0 | 0xb1 | 0xc0 | 0xc1 => {
+                let size = if opcode & 1 == 0 { 1 } else { size };
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = if opcode < 0xc0 { "cmpxchg" } else { "xadd" };
+                operation(
+                    mnemonic,
+                    format!("{},{}", self.reg(reg, size), self.rm(&rm, size)),
+                )
+            }
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let (reg, rm) = self.modrm()?;
+                let source_size = if opcode & 1 == 0 { 1 } else { 2 };
+                let mnemonic = format!(
+                    "mov{}{}{}",
+                    if opcode < 0xbe { "z" } else { "s" },
+                    suffix(source_size),
+                    suffix(size)
+                );
+                operation(
+                    &mnemonic,
+                    format!("{},{}", self.rm(&rm, source_size), self.reg(reg, size)),
+                )
+            }
+            0xc8..=0xcf => {
+                let num = (opcode & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                operation("bswap", self.reg(num, size))
+            }
+            0xae if self.repeat_prefix.is_none() && !self.operand_size_prefix => {
+                let (reg, rm) = self.modrm()?;
+                let mem = match rm {
+                    Rm::Mem(mem) => mem,
+                    Rm::Reg(_) => {
+                        return match reg & 7 {
+                            5 => simple("lfence"),
+                            6 => simple("mfence"),
+                            7 => simple("sfence"),
+                            _ => None,
+                        }
+                    }
+                };
+                let mnemonic = match reg & 7 {
+                    0 => "fxsave",
+                    1 => "fxrstor",
+                    2 => "ldmxcsr",
+                    3 => "stmxcsr",
+                    4 => "xsave",
+                    5 => "xrstor",
+                    6 => "xsaveopt",
+                    _ => return operation("clflush", mem),
+                };
+                self.vector_state = true;
+                if self.rex_w() && !mnemonic.ends_with("mxcsr") {
+                    operation(&format!("{}64", mnemonic), mem)
+                } else {
+                    operation(mnemonic, mem)
+                }
+            }
+            0xc7 if self.repeat_prefix.is_none() && !self.operand_size_prefix => {
+                let (reg, rm) = self.modrm()?;
+                let mem = match rm {
+                    Rm::Mem(mem) => mem,
+                    Rm::Reg(_) => return None,
+                };
+                match (reg & 7, self.rex_w()) {
+                    (1, false) => operation("cmpxchg8b", mem),
+                    (1, true) => operation("cmpxchg16b", mem),
+                    (4, rex_w) => {
+                        self.vector_state = true;
+                        operation(if rex_w { "xsavec64" } else { "xsavec" }, mem)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The SSE2 integer operations on xmm registers or memory, which have a 66 prefix, by opcode.
+/// Their AVX forms are the same with a `v` in front.
+fn integer_operation(opcode: u8) -> Option<&'static str> {
+    Some(match opcode {
+        0x60 => "punpcklbw",
+        0x61 => "punpcklwd",
+        0x62 => "punpckldq",
+        0x64 => "pcmpgtb",
+        0x65 => "pcmpgtw",
+        0x66 => "pcmpgtd",
+        0x68 => "punpckhbw",
+        0x69 => "punpckhwd",
+        0x6a => "punpckhdq",
+        0x6c => "punpcklqdq",
+        0x6d => "punpckhqdq",
+        0x74 => "pcmpeqb",
+        0x75 => "pcmpeqw",
+        0x76 => "pcmpeqd",
+        0xd4 => "paddq",
+        0xd5 => "pmullw",
+        0xd8 => "psubusb",
+        0xd9 => "psubusw",
+        0xda => "pminub",
+        0xdb => "pand",
+        0xdc => "paddusb",
+        0xdd => "paddusw",
+        0xde => "pmaxub",
+        0xdf => "pandn",
+        0xe0 => "pavgb",
+        0xe3 => "pavgw",
+        0xe8 => "psubsb",
+        0xe9 => "psubsw",
+        0xea => "pminsw",
+        0xeb => "por",
+        0xec => "paddsb",
+        0xed => "paddsw",
+        0xee => "pmaxsw",
+        0xef => "pxor",
+        0xf4 => "pmuludq",
+        0xf6 => "psadbw",
+        0xf8 => "psubb",
+        0xf9 => "psubw",
+        0xfa => "psubd",
+        0xfb => "psubq",
+        0xfc => "paddb",
+        0xfd => "paddw",
+        0xfe => "paddd",
+        _ => return None,
+    })
+}
+
+fn xmm(num: usize) -> String {
+    format!("%xmm{}", num)
+}
+
+/// The AT&T size suffix for operands of `size` bytes.
+fn suffix(size: usize) -> char {
+    match size {
+        1 => 'b',
+        2 => 'w',
+        4 => 'l',
+        _ => 'q',
+    }
+}
+
+/// Adds the size suffix to `mnemonic` if its only sized operand is in memory, where the size
+/// can't be told from a register name, e.g. `movl $0x0,-0x4(%rbp)`.
+fn suffixed(mnemonic: &str, rm: &Rm, size: usize) -> String {
+    match rm {
+        Rm::Mem(_) => format!("{}{}", mnemonic, suffix(size)),
+        Rm::Reg(_) => mnemonic.to_string(),
+    }
+}
+
+/// Formats an immediate the way objdump does: as an unsigned number of the operation's size, so
+/// that -1 in a 32-bit operation is `$0xffffffff`.
+fn immediate(value: i64, size: usize) -> String {
+    let mask = if size >= 8 {
+        u64::max_value()
+    } else {
+        (1u64 << (8 * size)) - 1
+    };
+    format!("${:#x}", value as u64 & mask)
+}
+
+fn signed_hex(value: i64) -> String {
+    if value < 0 {
+        format!("-{:#x}", -(value as i128))
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn disassemble(bytes: &[u8]) -> (usize, String) {
+        let symbolize = |addr: u64| {
+            if addr == 0x401136 {
+                " <func>".to_string()
+            } else {
+                String::new()
+            }
+        };
+        let instruction = decode(bytes, 0x401000, &symbolize);
+        (instruction.len, instruction.text)
+    }
+
+    #[test]
+    fn test_prologue_and_epilogue() {
+        assert_eq!(
+            disassemble(&[0xf3, 0x0f, 0x1e, 0xfa]),
+            (4, "endbr64".to_string())
+        );
+        assert_eq!(disassemble(&[0x55]), (1, "push   %rbp".to_string()));
+        assert_eq!(
+            disassemble(&[0x48, 0x89, 0xe5]),
+            (3, "mov    %rsp,%rbp".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x48, 0x83, 0xec, 0x10]),
+            (4, "sub    $0x10,%rsp".to_string())
+        );
+        assert_eq!(disassemble(&[0xc9]), (1, "leave".to_string()));
+        assert_eq!(disassemble(&[0xc3]), (1, "ret".to_string()));
+        assert_eq!(disassemble(&[0x41, 0x5c]), (2, "pop    %r12".to_string()));
+    }
+
+    #[test]
+    fn test_memory_operands() {
+        assert_eq!(
+            disassemble(&[0x89, 0x7d, 0xec]),
+            (3, "mov    %edi,-0x14(%rbp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc7, 0x45, 0xfc, 0x00, 0x00, 0x00, 0x00]),
+            (7, "movl   $0x0,-0x4(%rbp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x83, 0x45, 0xfc, 0x01]),
+            (4, "addl   $0x1,-0x4(%rbp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x8b, 0x04, 0x85, 0x20, 0x40, 0x40, 0x00]),
+            (7, "mov    0x404020(,%rax,4),%eax".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]),
+            (9, "mov    %fs:0x28,%rax".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00]),
+            (6, "nopw   0x0(%rax,%rax,1)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x0f, 0xb6, 0x45, 0xff]),
+            (4, "movzbl -0x1(%rbp),%eax".to_string())
+        );
+    }
+
+    #[test]
+    fn test_memory_operand_addresses() {
+        let register = |name: &str| match name {
+            "rbp" => 0x7fff_0000,
+            "rax" => 0x10,
+            "rdi" => 0x5000,
+            "fs_base" => 0x7000_0000,
+            _ => 0,
+        };
+        let address = |bytes: &[u8]| {
+            let instruction = decode(bytes, 0x401000, &|_| String::new());
+            let end = 0x401000 + instruction.len as u64;
+            instruction
+                .memory
+                .map(|memory| memory.address(&register, end))
+        };
+        // mov %edi,-0x14(%rbp)
+        assert_eq!(address(&[0x89, 0x7d, 0xec]), Some(0x7ffe_ffec));
+        // mov %eax,0x2ee6(%rip)
+        assert_eq!(
+            address(&[0x89, 0x05, 0xe6, 0x2e, 0x00, 0x00]),
+            Some(0x403eec)
+        );
+        // mov %edx,0x8(%rbp,%rax,4)
+        assert_eq!(address(&[0x89, 0x54, 0x85, 0x08]), Some(0x7fff_0048));
+        // mov %fs:0x28,%rax
+        assert_eq!(
+            address(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]),
+            Some(0x7000_0028)
+        );
+        // rep stos %al,%es:(%rdi)
+        assert_eq!(address(&[0xf3, 0xaa]), Some(0x5000));
+        // mov %rsp,%rbp
+        assert_eq!(address(&[0x48, 0x89, 0xe5]), None);
+    }
+
+    #[test]
+    fn test_relative_addresses() {
+        assert_eq!(
+            disassemble(&[0xe8, 0x31, 0x01, 0x00, 0x00]),
+            (5, "call   0x401136 <func>".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x75, 0xfe]),
+            (2, "jne    0x401000".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x48, 0x8d, 0x05, 0x2f, 0x01, 0x00, 0x00]),
+            (
+                7,
+                "lea    0x12f(%rip),%rax        # 0x401136 <func>".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_sse() {
+        assert_eq!(
+            disassemble(&[0xf2, 0x0f, 0x10, 0x45, 0xf8]),
+            (5, "movsd  -0x8(%rbp),%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0xef, 0xc0]),
+            (4, "pxor   %xmm0,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xf2, 0x0f, 0x2a, 0xc0]),
+            (4, "cvtsi2sd %eax,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x48, 0x0f, 0x7e, 0xc0]),
+            (5, "movq   %xmm0,%rax".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sse_integer() {
+        // What the dynamic linker's string functions use
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x12, 0x0f]),
+            (4, "movlpd (%rdi),%xmm1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x0f, 0x17, 0x56, 0x08]),
+            (4, "movhps %xmm2,0x8(%rsi)".to_string())
+        );
+        assert_eq!(disassemble(&[0x0f, 0x12, 0xe3]), (3, "movhlps %xmm3,%xmm4".to_string()));
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0xe7, 0x07]),
+            (4, "movntdq %xmm0,(%rdi)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0xd7, 0xc1]),
+            (4, "pmovmskb %xmm1,%eax".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x70, 0xd1, 0x1b]),
+            (5, "pshufd $0x1b,%xmm1,%xmm2".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x73, 0xfb, 0x04]),
+            (5, "pslldq $0x4,%xmm3".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x41, 0x0f, 0x73, 0xd1, 0x20]),
+            (6, "psrlq  $0x20,%xmm9".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x38, 0x00, 0x08]),
+            (5, "pshufb (%rax),%xmm1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x3a, 0x63, 0x0f, 0x1a]),
+            (6, "pcmpistri $0x1a,(%rdi),%xmm1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x74, 0xc1]),
+            (4, "pcmpeqb %xmm1,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0x0f, 0x60, 0xc9]),
+            (4, "punpcklbw %xmm1,%xmm1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_vex() {
+        assert_eq!(
+            disassemble(&[0xc5, 0xfe, 0x7f, 0x07]),
+            (4, "vmovdqu %ymm0,(%rdi)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0x7c, 0x10, 0x7c, 0x58, 0x20]),
+            (6, "vmovups 0x20(%rax,%rbx,2),%ymm15".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0x41, 0x79, 0x7f, 0x41, 0x10]),
+            (6, "vmovdqa %xmm8,0x10(%r9)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xfa, 0x11, 0x4d, 0xfc]),
+            (5, "vmovss %xmm1,-0x4(%rbp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xf2, 0x10, 0xc2]),
+            (4, "vmovss %xmm2,%xmm1,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xf3, 0x58, 0xc2]),
+            (4, "vaddsd %xmm2,%xmm1,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xfd, 0x74, 0x0f]),
+            (4, "vpcmpeqb (%rdi),%ymm0,%ymm1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xfd, 0xd7, 0xc1]),
+            (4, "vpmovmskb %ymm1,%eax".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0xe1, 0xf9, 0x6e, 0xc0]),
+            (5, "vmovq  %rax,%xmm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0xe2, 0x7d, 0x78, 0xc0]),
+            (5, "vpbroadcastb %xmm0,%ymm0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xfd, 0xe7, 0x07]),
+            (4, "vmovntdq %ymm0,(%rdi)".to_string())
+        );
+        assert_eq!(disassemble(&[0xc5, 0xf8, 0x77]), (3, "vzeroupper".to_string()));
+    }
+
+    #[test]
+    fn test_vector_state() {
+        // What the dynamic linker's lazy binding saves the registers with
+        assert_eq!(
+            disassemble(&[0x0f, 0xc7, 0x64, 0x24, 0x40]),
+            (5, "xsavec 0x40(%rsp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x0f, 0xae, 0x6c, 0x24, 0x40]),
+            (5, "xrstor 0x40(%rsp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x48, 0x0f, 0xae, 0x0c, 0x24]),
+            (5, "fxrstor64 (%rsp)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x0f, 0xae, 0x5d, 0xfc]),
+            (4, "stmxcsr -0x4(%rbp)".to_string())
+        );
+        assert_eq!(disassemble(&[0x0f, 0xae, 0xf0]), (3, "mfence".to_string()));
+        assert_eq!(
+            disassemble(&[0xf0, 0x48, 0x0f, 0xc7, 0x0f]),
+            (5, "lock cmpxchg16b (%rdi)".to_string())
+        );
+        assert!(decode(&[0x0f, 0xae, 0x20], 0, &|_| "".into()).vector);
+        assert!(!decode(&[0x0f, 0xae, 0x3f], 0, &|_| "".into()).vector);
+    }
+
+    #[test]
+    fn test_evex() {
+        // The 8-bit displacement is scaled by the operand size
+        assert_eq!(
+            disassemble(&[0x62, 0xe1, 0xfe, 0x48, 0x7f, 0x47, 0x01]),
+            (7, "vmovdqu64 %zmm16,0x40(%rdi)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe1, 0xfe, 0x49, 0x7f, 0x47, 0x01]),
+            (7, "vmovdqu64 %zmm16,0x40(%rdi){%k1}".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe1, 0x7f, 0xca, 0x6f, 0x0e]),
+            (6, "vmovdqu8 (%rsi),%zmm17{%k2}{z}".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xa1, 0xf5, 0x40, 0xef, 0xd0]),
+            (6, "vpxorq %zmm16,%zmm17,%zmm18".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe2, 0x7d, 0x48, 0x58, 0x46, 0x01]),
+            (7, "vpbroadcastd 0x4(%rsi),%zmm16".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe2, 0x7d, 0x49, 0x78, 0xc0]),
+            (6, "vpbroadcastb %xmm0,%zmm16{%k1}".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe2, 0x7d, 0x48, 0x7a, 0xc6]),
+            (6, "vpbroadcastb %esi,%zmm16".to_string())
+        );
+        // The comparisons and tests set an opmask register
+        assert_eq!(
+            disassemble(&[0x62, 0xb2, 0x66, 0x20, 0x26, 0xc3]),
+            (6, "vptestnmb %ymm19,%ymm19,%k0".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xb1, 0x7d, 0x42, 0x66, 0xc9]),
+            (6, "vpcmpgtd %zmm17,%zmm16,%k1{%k2}".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xf3, 0x7d, 0x40, 0x3e, 0x0e, 0x01]),
+            (7, "vpcmpltub (%rsi),%zmm16,%k1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xf3, 0x6d, 0x48, 0x3e, 0xc9, 0x03]),
+            (7, "vpcmpub $0x3,%zmm1,%zmm2,%k1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xf2, 0xed, 0x48, 0x3b, 0xd9]),
+            (6, "vpminuq %zmm1,%zmm2,%zmm3".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0x62, 0xe3, 0xf5, 0x21, 0x25, 0x57, 0x02, 0x96]),
+            (8, "vpternlogq $0x96,0x40(%rdi),%ymm17,%ymm18{%k1}".to_string())
+        );
+        let instruction = decode(&[0x62, 0xe1, 0xfe, 0x48, 0x7f, 0x47, 0x01], 0, &|_| "".into());
+        let register = |name: &str| if name == "rdi" { 0x1000 } else { 0 };
+        assert_eq!(instruction.memory.unwrap().address(&register, 7), 0x1040);
+        assert!(instruction.vector);
+    }
+
+    #[test]
+    fn test_opmask() {
+        assert_eq!(
+            disassemble(&[0xc5, 0xfb, 0x93, 0xc0]),
+            (4, "kmovd  %k0,%eax".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0xe1, 0xf8, 0x91, 0x0f]),
+            (5, "kmovq  %k1,(%rdi)".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xf9, 0x90, 0x1e]),
+            (4, "kmovb  (%rsi),%k3".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0xe1, 0xf9, 0x98, 0xc8]),
+            (5, "kortestd %k0,%k1".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc4, 0xe1, 0xed, 0x45, 0xd9]),
+            (5, "kord   %k1,%k2,%k3".to_string())
+        );
+        assert_eq!(
+            disassemble(&[0xc5, 0xed, 0x4b, 0xd9]),
+            (4, "kunpckbw %k1,%k2,%k3".to_string())
+        );
+        assert!(decode(&[0xc5, 0xfb, 0x92, 0xc8], 0, &|_| "".into()).vector);
+    }
+
+    #[test]
+    fn test_bad_instructions() {
+        assert_eq!(disassemble(&[0x06]), (1, "(bad)".to_string()));
+        assert_eq!(opcode_byte(&[0x66, 0x48, 0x06]), Some(0x06));
+        // Cut short
+        assert_eq!(disassemble(&[0x48, 0x89]), (1, "(bad)".to_string()));
+    }
+}
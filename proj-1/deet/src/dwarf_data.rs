@@ -1,18 +1,43 @@
+use crate::console_println;
 use crate::gimli_wrapper;
+use crate::unwind::CallFrameInfo;
 use addr2line::Context;
-use object::Object;
+use object::{Object, ObjectSection, ObjectSegment, SymbolKind};
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{fmt, fs};
 
+// Hands out the ids that tell objects apart, see TypeRef
+static NEXT_OBJECT_ID: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub enum Error {
     ErrorOpeningFile,
     DwarfFormatError(gimli_wrapper::Error),
 }
 
+/// Debugging information for one ELF object: the executable or a shared library. Addresses it
+/// takes and returns are where the object is loaded in memory, which differs from the addresses it
+/// was linked at for PIE executables and shared libraries (see relocate).
 pub struct DwarfData {
+    id: usize,
+    path: String,
     files: Vec<File>,
+    // Every type in the program, by its offset in .debug_info
+    types: HashMap<usize, Type>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    call_frame_info: CallFrameInfo,
+    // Start, size and name of the functions in the ELF symbol tables, for code without debugging
+    // information such as libc
+    elf_functions: Vec<(usize, usize, String)>,
+    // Link-time addresses of the object's loadable segments, its entry point and its .dynamic
+    // section
+    link_range: (usize, usize),
+    link_entry: usize,
+    link_dynamic: Option<usize>,
+    // How far from its link-time addresses the object is loaded
+    bias: usize,
 }
 
 impl fmt::Debug for DwarfData {
@@ -38,20 +63,139 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let id = NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed);
+        let (files, types) = gimli_wrapper::load_file(&object, endian, id)?;
+        let link_range = object.segments().filter(|segment| segment.size() > 0).fold(
+            (usize::max_value(), 0),
+            |(low, high), segment| {
+                let start = segment.address() as usize;
+                (low.min(start), high.max(start + segment.size() as usize))
+            },
+        );
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            id,
+            path: path.to_string(),
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            call_frame_info: CallFrameInfo::load(&object, endian),
+            elf_functions: object
+                .symbols()
+                .chain(object.dynamic_symbols())
+                .filter(|(_, symbol)| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+                .filter_map(|(_, symbol)| {
+                    Some((
+                        symbol.address() as usize,
+                        symbol.size() as usize,
+                        symbol.name()?.to_string(),
+                    ))
+                })
+                .collect(),
+            link_range,
+            link_entry: object.entry() as usize,
+            link_dynamic: object
+                .section_by_name(".dynamic")
+                .map(|section| section.address() as usize),
+            bias: 0,
         })
     }
 
-    #[allow(dead_code)]
-    fn get_target_file(&self, file: &str) -> Option<&File> {
-        self.files.iter().find(|f| {
-            f.name == file || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
-        })
+    /// A number no other object loaded by this process has, which types refer to the object by.
+    pub fn id(&self) -> usize {
+        self.id
     }
 
-    #[allow(dead_code)]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn bias(&self) -> usize {
+        self.bias
+    }
+
+    /// Moves everything to where the object is loaded: `bias` bytes past the addresses it was
+    /// linked at. Objects start out as if loaded at their link-time addresses, which is where
+    /// non-PIE executables always are.
+    pub fn relocate(&mut self, bias: usize) {
+        let delta = bias.wrapping_sub(self.bias);
+        let relocate_variable = |var: &mut Variable| {
+            if let Location::Address(addr) = var.location {
+                var.location = Location::Address(addr.wrapping_add(delta));
+            }
+        };
+        for file in self.files.iter_mut() {
+            file.global_variables.iter_mut().for_each(relocate_variable);
+            for func in file.functions.iter_mut() {
+                func.address = func.address.wrapping_add(delta);
+                // Static locals
+                func.variables.iter_mut().for_each(relocate_variable);
+            }
+            for line in file.lines.iter_mut() {
+                line.address = line.address.wrapping_add(delta);
+            }
+        }
+        self.call_frame_info.relocate(bias as u64);
+        self.bias = bias;
+    }
+
+    /// Returns whether `addr` lies in one of the object's segments.
+    pub fn contains(&self, addr: usize) -> bool {
+        let link_addr = addr.wrapping_sub(self.bias);
+        link_addr >= self.link_range.0 && link_addr < self.link_range.1
+    }
+
+    /// Returns the start and end of the memory the object's segments are loaded at.
+    pub fn address_range(&self) -> (usize, usize) {
+        (
+            self.link_range.0.wrapping_add(self.bias),
+            self.link_range.1.wrapping_add(self.bias),
+        )
+    }
+
+    /// Returns whether the object has any DWARF, as opposed to just symbols and CFI.
+    pub fn has_debug_info(&self) -> bool {
+        !self.files.is_empty()
+    }
+
+    /// The entry point as linked, for working out the executable's bias from the one the kernel
+    /// reports.
+    pub fn link_entry(&self) -> usize {
+        self.link_entry
+    }
+
+    /// The entry point, where the program starts running.
+    pub fn entry(&self) -> usize {
+        self.link_entry.wrapping_add(self.bias)
+    }
+
+    /// The address of the .dynamic section, whose DT_DEBUG entry leads to the dynamic linker's
+    /// list of loaded libraries.
+    pub fn dynamic_section(&self) -> Option<usize> {
+        self.link_dynamic.map(|addr| addr.wrapping_add(self.bias))
+    }
+
+    /// Looks up a type by its offset in .debug_info, e.g. the pointee of a pointer.
+    pub fn get_type(&self, offset: usize) -> Option<&Type> {
+        self.types.get(&offset)
+    }
+
+    pub fn call_frame_info(&self) -> &CallFrameInfo {
+        &self.call_frame_info
+    }
+
+    /// Finds a compilation unit by its full name or by a trailing part of its path (`foo.c` or
+    /// `src/foo.c`).
+    pub fn get_target_file(&self, file: &str) -> Option<&File> {
+        self.files
+            .iter()
+            .find(|f| f.name == file || f.name.ends_with(&format!("/{}", file)))
+    }
+
+    pub fn get_file_names(&self) -> Vec<String> {
+        self.files.iter().map(|f| f.name.clone()).collect()
+    }
+
+    /// Returns the lowest address of the first line at or after `line_number` that has code.
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
             Some(filename) => self.get_target_file(filename)?,
@@ -61,7 +205,8 @@ impl DwarfData {
             target_file
                 .lines
                 .iter()
-                .find(|line| line.number >= line_number)?
+                .filter(|line| line.number >= line_number)
+                .min_by_key(|line| (line.number, line.address))?
                 .address,
         )
     }
@@ -73,12 +218,16 @@ impl DwarfData {
                 self.get_target_file(filename)?
                     .functions
                     .iter()
-                    .find(|func| func.name == func_name)?
+                    .find(|func| func.name == func_name && func.text_length > 0)?
                     .address,
             ),
             None => {
                 for file in &self.files {
-                    if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
+                    if let Some(func) = file
+                        .functions
+                        .iter()
+                        .find(|func| func.name == func_name && func.text_length > 0)
+                    {
                         return Some(func.address);
                     }
                 }
@@ -87,11 +236,40 @@ impl DwarfData {
         }
     }
 
+    /// Finds a function that has code, as opposed to a declaration, by name.
+    pub fn get_function(&self, name: &str) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.name == name && func.text_length > 0)
+    }
+
+    /// Finds a named type: a struct, union or enum by its tag (`struct point`), or a typedef or
+    /// base type.
+    pub fn get_type_by_name(&self, name: &str) -> Option<&Type> {
+        self.types.values().find(|found| found.name == name)
+    }
+
+    /// Returns the function whose code contains `curr_addr`.
+    pub fn get_function_containing(&self, curr_addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| curr_addr >= func.address && curr_addr < func.address + func.text_length)
+    }
+
+    pub fn get_global_variable(&self, name: &str) -> Option<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == name)
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
             .addr2line
-            .find_location(curr_addr.try_into().unwrap())
+            .find_location(curr_addr.wrapping_sub(self.bias).try_into().unwrap())
             .ok()??;
         Some(Line {
             file: location.file?.to_string(),
@@ -100,49 +278,101 @@ impl DwarfData {
         })
     }
 
-    #[allow(dead_code)]
+    /// Returns the name of the function containing `curr_addr`, from the debugging information
+    /// or else the symbol table.
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
             .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
-            .ok()?
-            .next()
-            .ok()??;
-        Some(frame.function?.raw_name().ok()?.to_string())
+            .find_frames(curr_addr.wrapping_sub(self.bias).try_into().unwrap())
+            .ok()
+            .and_then(|mut frames| frames.next().ok())
+            .and_then(|frame| frame);
+        if let Some(name) = frame
+            .and_then(|frame| frame.function)
+            .and_then(|func| Some(func.raw_name().ok()?.to_string()))
+        {
+            return Some(name);
+        }
+        self.get_symbol_containing(curr_addr)
+            .map(|(_, _, name)| name.to_string())
+    }
+
+    /// Returns the start, size and name of the function in the symbol table that contains
+    /// `curr_addr`.
+    pub fn get_symbol_containing(&self, curr_addr: usize) -> Option<(usize, usize, &str)> {
+        let link_addr = curr_addr.wrapping_sub(self.bias);
+        self.elf_functions
+            .iter()
+            .find(|(start, size, _)| link_addr >= *start && link_addr < start + size)
+            .map(|(start, size, name)| (start.wrapping_add(self.bias), *size, name.as_str()))
+    }
+
+    /// Looks up the address of a function in the symbol table.
+    pub fn get_symbol_address(&self, name: &str) -> Option<usize> {
+        self.elf_functions
+            .iter()
+            .find(|(_, _, symbol)| symbol == name)
+            .map(|(start, _, _)| start.wrapping_add(self.bias))
+    }
+
+    /// Reads up to `len` bytes at `addr` from the file rather than from memory, e.g. to look at
+    /// code before the program runs. Returns less if the section ends first, and None for
+    /// addresses without file contents.
+    pub fn read_file_bytes(&self, addr: usize, len: usize) -> Option<Vec<u8>> {
+        let file = fs::File::open(&self.path).ok()?;
+        let mmap = unsafe { memmap::Mmap::map(&file).ok()? };
+        let object = object::File::parse(&*mmap).ok()?;
+        let link_addr = addr.wrapping_sub(self.bias) as u64;
+        let section = object.sections().find(|section| {
+            link_addr >= section.address() && link_addr < section.address() + section.size()
+        })?;
+        let data = section.data();
+        let start = (link_addr - section.address()) as usize;
+        data.get(start..data.len().min(start.saturating_add(len)))
+            .map(|bytes| bytes.to_vec())
     }
 
     #[allow(dead_code)]
     pub fn print(&self) {
         for file in &self.files {
-            println!("------");
-            println!("{}", file.name);
-            println!("------");
+            console_println!("------");
+            console_println!("{}", file.name);
+            console_println!("------");
 
-            println!("Global variables:");
+            console_println!("Global variables:");
             for var in &file.global_variables {
-                println!(
+                console_println!(
                     "  * {} ({}, located at {}, declared at line {})",
-                    var.name, var.entity_type.name, var.location, var.line_number
+                    var.name,
+                    var.entity_type.name,
+                    var.location,
+                    var.line_number
                 );
             }
 
-            println!("Functions:");
+            console_println!("Functions:");
             for func in &file.functions {
-                println!(
+                console_println!(
                     "  * {} (declared on line {}, located at {:#x}, {} bytes long)",
-                    func.name, func.line_number, func.address, func.text_length
+                    func.name,
+                    func.line_number,
+                    func.address,
+                    func.text_length
                 );
                 for var in &func.variables {
-                    println!(
+                    console_println!(
                         "    * Variable: {} ({}, located at {}, declared at line {})",
-                        var.name, var.entity_type.name, var.location, var.line_number
+                        var.name,
+                        var.entity_type.name,
+                        var.location,
+                        var.line_number
                     );
                 }
             }
 
-            println!("Line numbers:");
+            console_println!("Line numbers:");
             for line in &file.lines {
-                println!("  * {} (at {:#x})", line.number, line.address);
+                console_println!("  * {} (at {:#x})", line.number, line.address);
             }
         }
     }
@@ -152,13 +382,249 @@ impl DwarfData {
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    Base,
+    // The pointee, or None for void *
+    Pointer(Option<TypeRef>),
+    // A pointer an expression made, e.g. with `&x`, which holds its pointee
+    PointerTo(Box<Type>),
+    // Structs and unions, whose members all have offset 0
+    Struct(Vec<Member>),
+    // Element type and count, which is unknown for `int x[]`
+    Array(Box<Type>, Option<usize>),
+    Enum(Vec<(String, i64)>),
+    // Typedefs and const or volatile qualified types, which are read like the type they name
+    Typedef(Box<Type>),
+    Function,
+}
+
+impl Default for TypeKind {
+    fn default() -> Self {
+        TypeKind::Base
+    }
+}
+
+/// Refers to a type by the object it is defined in (see DwarfData::id) and its offset in that
+/// object's .debug_info, since offsets alone aren't unique across the executable and libraries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypeRef {
+    pub object: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub offset: usize,
+    pub member_type: Type,
 }
 
+// Arrays longer than this are cut short when printed
+const MAX_PRINTED_ELEMENTS: usize = 200;
+
 impl Type {
     pub fn new(name: String, size: usize) -> Self {
         Type {
             name: name,
             size: size,
+            kind: TypeKind::Base,
+        }
+    }
+
+    /// The type of a pointer to a value of type `target`.
+    pub fn pointer_to(target: Type) -> Type {
+        let name = if target.name.ends_with('*') {
+            format!("{}*", target.name)
+        } else {
+            format!("{} *", target.name)
+        };
+        Type {
+            name,
+            size: 8,
+            kind: TypeKind::PointerTo(Box::new(target)),
+        }
+    }
+
+    /// Follows typedefs and qualifiers to the type they name.
+    pub fn strip_typedefs(&self) -> &Type {
+        match self.kind {
+            TypeKind::Typedef(ref target) => target.strip_typedefs(),
+            _ => self,
+        }
+    }
+
+    /// Returns whether values of this type can be used as numbers, e.g. in conditions.
+    pub fn is_scalar(&self) -> bool {
+        match self.strip_typedefs().kind {
+            TypeKind::Base | TypeKind::Pointer(_) | TypeKind::PointerTo(_) | TypeKind::Enum(_) => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        match self.strip_typedefs().kind {
+            TypeKind::Pointer(_) | TypeKind::PointerTo(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        let base = self.strip_typedefs();
+        match base.kind {
+            TypeKind::Base => base.name == "float" || base.name == "double",
+            _ => false,
+        }
+    }
+
+    pub fn is_void(&self) -> bool {
+        let base = self.strip_typedefs();
+        match base.kind {
+            TypeKind::Base => base.name == "void",
+            _ => false,
+        }
+    }
+
+    pub fn member(&self, name: &str) -> Option<&Member> {
+        match self.strip_typedefs().kind {
+            TypeKind::Struct(ref members) => members.iter().find(|member| member.name == name),
+            _ => None,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        let base = self.strip_typedefs();
+        match base.kind {
+            TypeKind::Base => !base.name.contains("unsigned") && base.name != "_Bool",
+            TypeKind::Enum(_) => true,
+            _ => false,
+        }
+    }
+
+    fn is_char(&self) -> bool {
+        let base = self.strip_typedefs();
+        match base.kind {
+            TypeKind::Base => base.name.contains("char") && base.size == 1,
+            _ => false,
+        }
+    }
+
+    /// Interprets `bytes` (in target, little endian, byte order) as a value of this type and
+    /// converts it to an integer. Floating point values are truncated.
+    pub fn integer_value(&self, bytes: &[u8]) -> i64 {
+        let len = bytes.len().min(8);
+        let mut raw = [0u8; 8];
+        raw[..len].copy_from_slice(&bytes[..len]);
+        let unsigned = u64::from_le_bytes(raw);
+        let name = &self.strip_typedefs().name;
+
+        if name == "float" && len == 4 {
+            f32::from_bits(unsigned as u32) as i64
+        } else if name == "double" && len == 8 {
+            f64::from_bits(unsigned) as i64
+        } else if self.is_signed() && len > 0 && len < 8 {
+            let shift = 64 - 8 * len;
+            ((unsigned << shift) as i64) >> shift
+        } else {
+            unsigned as i64
+        }
+    }
+
+    /// Formats a value of this type, given the `size` bytes it is made of, the way gdb does:
+    /// `{x = 1, y = 2}` for structs, `{1, 2, 3}` for arrays and so on.
+    pub fn format_value(&self, bytes: &[u8]) -> String {
+        match self.kind {
+            TypeKind::Typedef(ref target) => target.format_value(bytes),
+            TypeKind::Struct(ref members) => {
+                let fields: Vec<String> = members
+                    .iter()
+                    .map(|member| {
+                        let end = member.offset + member.member_type.size;
+                        match bytes.get(member.offset..end) {
+                            Some(member_bytes) => format!(
+                                "{} = {}",
+                                member.name,
+                                member.member_type.format_value(member_bytes)
+                            ),
+                            None => format!("{} = <unavailable>", member.name),
+                        }
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+            TypeKind::Array(ref element, _) if element.is_char() => {
+                // Print as a string, up to the terminating NUL if there is one
+                let end = bytes
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .unwrap_or(bytes.len());
+                let text: String = bytes[..end]
+                    .iter()
+                    .map(|byte| (*byte as char).escape_default().to_string())
+                    .collect();
+                format!("\"{}\"", text)
+            }
+            TypeKind::Array(ref element, count) => {
+                if element.size == 0 {
+                    return "{}".to_string();
+                }
+                let count = count.unwrap_or(bytes.len() / element.size);
+                let mut elements: Vec<String> = bytes
+                    .chunks(element.size)
+                    .take(count.min(MAX_PRINTED_ELEMENTS))
+                    .map(|element_bytes| element.format_value(element_bytes))
+                    .collect();
+                if count > MAX_PRINTED_ELEMENTS {
+                    elements.push("...".to_string());
+                }
+                format!("{{{}}}", elements.join(", "))
+            }
+            TypeKind::Enum(ref enumerators) => {
+                let value = self.integer_value(bytes);
+                match enumerators
+                    .iter()
+                    .find(|(_, enum_value)| *enum_value == value)
+                {
+                    Some((name, _)) => name.clone(),
+                    None => format!("{}", value),
+                }
+            }
+            TypeKind::Pointer(_) | TypeKind::PointerTo(_) => {
+                format!("{:#x}", self.integer_value(bytes))
+            }
+            TypeKind::Function => "{<function>}".to_string(),
+            TypeKind::Base => self.format_base_value(bytes),
+        }
+    }
+
+    fn format_base_value(&self, bytes: &[u8]) -> String {
+        if self.name == "void" {
+            return "void".to_string();
+        }
+        if bytes.len() > 8 {
+            return format!("<{} bytes of {}>", bytes.len(), self.name);
+        }
+
+        let value = self.integer_value(bytes);
+        if self.name == "float" && bytes.len() == 4 {
+            let mut raw = [0u8; 4];
+            raw.copy_from_slice(bytes);
+            format!("{}", f32::from_le_bytes(raw))
+        } else if self.name == "double" && bytes.len() == 8 {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(bytes);
+            format!("{}", f64::from_le_bytes(raw))
+        } else if self.name == "_Bool" {
+            format!("{}", value != 0)
+        } else if self.is_char() {
+            format!("{} '{}'", value, (bytes[0] as char).escape_default())
+        } else {
+            format!("{}", value)
         }
     }
 }
@@ -191,6 +657,7 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    pub is_parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -200,6 +667,8 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    // None for functions returning void
+    pub return_type: Option<Type>,
 }
 
 #[derive(Debug, Default, Clone)]
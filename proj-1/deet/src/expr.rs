@@ -0,0 +1,1385 @@
+//! A small C-like expression language used for breakpoint conditions, e.g. `i == 5 && p != 0`
+//! or `$rax > 3`, and for `print`, `set var` and `call`, e.g. `(char)nodes[1].value + 1` or
+//! `sum_list(&nodes[0])`. Expressions evaluate to values typed by the debugging information, with
+//! C's rules for arithmetic, pointers and casts. When evaluated with `eval`, the result is
+//! converted to a 64-bit signed integer.
+
+use crate::dwarf_data::{Type, TypeKind, TypeRef};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitOr => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Or => 1,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitXor => "^",
+            BinaryOp::BitOr => "|",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        let ops = [
+            BinaryOp::Mul,
+            BinaryOp::Div,
+            BinaryOp::Rem,
+            BinaryOp::Add,
+            BinaryOp::Sub,
+            BinaryOp::Shl,
+            BinaryOp::Shr,
+            BinaryOp::Lt,
+            BinaryOp::Le,
+            BinaryOp::Gt,
+            BinaryOp::Ge,
+            BinaryOp::Eq,
+            BinaryOp::Ne,
+            BinaryOp::BitAnd,
+            BinaryOp::BitXor,
+            BinaryOp::BitOr,
+            BinaryOp::And,
+            BinaryOp::Or,
+        ];
+        ops.iter().cloned().find(|op| op.symbol() == symbol)
+    }
+}
+
+/// The type a cast converts to: a base type such as `unsigned int`, `struct point` or a typedef,
+/// and how many pointers deep it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeName {
+    pub base: String,
+    pub pointers: usize,
+}
+
+impl fmt::Display for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base)?;
+        if self.pointers > 0 {
+            write!(f, " {}", "*".repeat(self.pointers))?;
+        }
+        Ok(())
+    }
+}
+
+// Words that make up the names of C's base types
+const TYPE_KEYWORDS: [&str; 12] = [
+    "void", "_Bool", "char", "short", "int", "long", "signed", "unsigned", "float", "double",
+    "const", "volatile",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Float(f64),
+    Variable(String),
+    Register(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    // s.field, p->field, a[i], *p and &x
+    Member(Box<Expr>, String),
+    PointerMember(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Deref(Box<Expr>),
+    AddressOf(Box<Expr>),
+    Cast(TypeName, Box<Expr>),
+    Assign(Box<Expr>, Box<Expr>),
+    // A call of a function by name, e.g. square(x + 1)
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Returns whether this is a postfix expression or simpler, which binds tighter than any
+    /// operator.
+    fn is_postfix(&self) -> bool {
+        match self {
+            Expr::Unary(..)
+            | Expr::Binary(..)
+            | Expr::Deref(_)
+            | Expr::AddressOf(_)
+            | Expr::Cast(..)
+            | Expr::Assign(..) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            // Debug formatting keeps the point in whole numbers, so they read back as floats
+            Expr::Float(value) => write!(f, "{:?}", value),
+            Expr::Variable(name) => write!(f, "{}", name),
+            Expr::Register(name) => write!(f, "${}", name),
+            Expr::Unary(op, operand) => {
+                let symbol = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                write_prefix(f, symbol, operand)
+            }
+            Expr::Deref(operand) => write_prefix(f, "*", operand),
+            Expr::AddressOf(operand) => write_prefix(f, "&", operand),
+            Expr::Cast(type_name, operand) => write_prefix(f, &format!("({})", type_name), operand),
+            Expr::Assign(lhs, rhs) => match **lhs {
+                Expr::Assign(..) => write!(f, "({}) = {}", lhs, rhs),
+                _ => write!(f, "{} = {}", lhs, rhs),
+            },
+            Expr::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::Member(base, field) => {
+                write_postfix_base(f, base)?;
+                write!(f, ".{}", field)
+            }
+            Expr::PointerMember(base, field) => {
+                write_postfix_base(f, base)?;
+                write!(f, "->{}", field)
+            }
+            Expr::Index(base, index) => {
+                write_postfix_base(f, base)?;
+                write!(f, "[{}]", index)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                // Operators are left associative, so an equal precedence operator on the right
+                // needs parentheses too
+                let write_operand =
+                    |f: &mut fmt::Formatter<'_>, operand: &Expr, is_rhs: bool| match operand {
+                        Expr::Binary(inner, ..)
+                            if inner.precedence() < op.precedence()
+                                || (is_rhs && inner.precedence() == op.precedence()) =>
+                        {
+                            write!(f, "({})", operand)
+                        }
+                        Expr::Assign(..) => write!(f, "({})", operand),
+                        _ => write!(f, "{}", operand),
+                    };
+                write_operand(f, lhs, false)?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, rhs, true)
+            }
+        }
+    }
+}
+
+fn write_prefix(f: &mut fmt::Formatter<'_>, symbol: &str, operand: &Expr) -> fmt::Result {
+    match operand {
+        Expr::Binary(..) | Expr::Assign(..) => write!(f, "{}({})", symbol, operand),
+        _ => write!(f, "{}{}", symbol, operand),
+    }
+}
+
+fn write_postfix_base(f: &mut fmt::Formatter<'_>, base: &Expr) -> fmt::Result {
+    if base.is_postfix() {
+        write!(f, "{}", base)
+    } else {
+        write!(f, "({})", base)
+    }
+}
+
+/// Gives expressions access to the program being debugged: its variables, registers, memory
+/// and types, and its functions to call.
+pub trait Context {
+    /// Finds a variable, returning its type and the address it is stored at.
+    fn variable(&self, name: &str) -> Result<(Type, usize), String>;
+    fn register(&self, name: &str) -> Result<i64, String>;
+    fn set_register(&self, name: &str, value: i64) -> Result<(), String>;
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String>;
+    fn write_memory(&self, addr: usize, bytes: &[u8]) -> Result<(), String>;
+    /// Looks up the type a pointer refers to as its pointee.
+    fn type_at(&self, type_ref: TypeRef) -> Option<Type>;
+    /// Looks up a type by the name a cast gives it, e.g. `struct point` or a typedef.
+    fn named_type(&self, name: &str) -> Option<Type>;
+    /// Calls the function `name` and returns its result. `return_type` is what a cast of the
+    /// call says the function returns, which is needed when there is no debugging information
+    /// for it.
+    fn call(&mut self, name: &str, args: &[Value], return_type: Option<&Type>)
+        -> Result<Value, String>;
+}
+
+/// What an expression evaluates to.
+#[derive(Debug, Clone)]
+pub enum Value {
+    // Stored in the inferior's memory at an address, and read when needed
+    Memory(Type, usize),
+    // Held in a register
+    Register(Type, String),
+    // Computed by the expression, given as its bytes in target (little endian) order
+    Computed(Type, Vec<u8>),
+}
+
+impl Value {
+    pub fn value_type(&self) -> &Type {
+        match self {
+            Value::Memory(value_type, _)
+            | Value::Register(value_type, _)
+            | Value::Computed(value_type, _) => value_type,
+        }
+    }
+
+    /// Returns where the value is stored, if it is in memory.
+    pub fn address(&self) -> Option<usize> {
+        match self {
+            Value::Memory(_, addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self, context: &dyn Context) -> Result<Vec<u8>, String> {
+        match self {
+            Value::Memory(value_type, addr) => context.read_memory(*addr, value_type.size),
+            Value::Register(_, name) => Ok(context.register(name)?.to_le_bytes().to_vec()),
+            Value::Computed(_, bytes) => Ok(bytes.clone()),
+        }
+    }
+
+    /// Converts the value to an integer, which numbers and pointers can be. Floating point
+    /// values are truncated.
+    pub fn to_integer(&self, context: &dyn Context) -> Result<i64, String> {
+        let value_type = self.value_type();
+        if !value_type.is_scalar() || value_type.is_void() {
+            return Err(format!(
+                "Value of type {} can't be used as a number.",
+                value_type.name
+            ));
+        }
+        Ok(value_type.integer_value(&self.bytes(context)?))
+    }
+
+    fn to_float(&self, context: &dyn Context) -> Result<f64, String> {
+        let value_type = self.value_type();
+        if !value_type.is_float() {
+            let value = self.to_integer(context)?;
+            return Ok(if value_type.is_signed() {
+                value as f64
+            } else {
+                value as u64 as f64
+            });
+        }
+        let bytes = self.bytes(context)?;
+        let mut raw = [0u8; 8];
+        raw[..bytes.len().min(8)].copy_from_slice(&bytes[..bytes.len().min(8)]);
+        Ok(if value_type.size == 4 {
+            f32::from_bits(u64::from_le_bytes(raw) as u32) as f64
+        } else {
+            f64::from_bits(u64::from_le_bytes(raw))
+        })
+    }
+
+    fn integer(value_type: Type, value: i64) -> Value {
+        let bytes = value.to_le_bytes()[..value_type.size.min(8)].to_vec();
+        Value::Computed(value_type, bytes)
+    }
+
+    fn float(value_type: Type, value: f64) -> Value {
+        let bytes = if value_type.size == 4 {
+            (value as f32).to_le_bytes().to_vec()
+        } else {
+            value.to_le_bytes().to_vec()
+        };
+        Value::Computed(value_type, bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Float(f64),
+    Ident(String),
+    Register(String),
+    Op(String),
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            // A fraction makes it a floating point number, e.g. 1.5
+            if i + 1 < chars.len()
+                && chars[i] == '.'
+                && chars[i + 1].is_ascii_digit()
+                && chars[start..i].iter().all(|c| c.is_ascii_digit())
+            {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().collect();
+                tokens.push(Token::Float(
+                    literal
+                        .parse()
+                        .or_else(|_| Err(format!("Invalid number \"{}\".", literal)))?,
+                ));
+                continue;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            let value = if literal.starts_with("0x") || literal.starts_with("0X") {
+                // Addresses above i64::MAX are still accepted, as their two's complement value
+                u64::from_str_radix(&literal[2..], 16).map(|value| value as i64)
+            } else {
+                literal.parse::<i64>()
+            };
+            tokens.push(Token::Number(
+                value.or_else(|_| Err(format!("Invalid number \"{}\".", literal)))?,
+            ));
+        } else if c == '\'' {
+            if i + 2 >= chars.len() || chars[i + 2] != '\'' {
+                return Err("Unmatched single quote.".to_string());
+            }
+            tokens.push(Token::Number(chars[i + 1] as i64));
+            i += 3;
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            if c == '$' {
+                if name.len() == 1 {
+                    return Err("Missing register name after '$'.".to_string());
+                }
+                tokens.push(Token::Register(name[1..].to_string()));
+            } else {
+                tokens.push(Token::Ident(name));
+            }
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let op = match two.as_str() {
+                "==" | "!=" | "<=" | ">=" | "&&" | "||" | "<<" | ">>" | "->" => two,
+                _ if "+-*/%<>&|^!~.[]=,".contains(c) => c.to_string(),
+                _ => return Err(format!("Invalid character '{}' in expression.", c)),
+            };
+            i += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Whether a name is a typedef, which makes `(name)` a cast
+    is_typedef: &'a dyn Fn(&str) -> bool,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Precedence climbing: parses a chain of binary operators binding at least as tightly as
+    /// `min_precedence`.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(symbol)) => match BinaryOp::from_symbol(symbol) {
+                    Some(op) if op.precedence() >= min_precedence => op,
+                    _ => break,
+                },
+                _ => break,
+            };
+            self.next();
+            let rhs = self.parse_binary(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// Assignment binds loosest of all, and to the right: `a = b = 1` sets both.
+    fn parse_assignment(&mut self) -> Result<Expr, String> {
+        let lhs = self.parse_binary(0)?;
+        match self.peek() {
+            Some(Token::Op(op)) if op == "=" => {
+                self.next();
+                let rhs = self.parse_assignment()?;
+                Ok(Expr::Assign(Box::new(lhs), Box::new(rhs)))
+            }
+            _ => Ok(lhs),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::LParen) {
+            if let Some(type_name) = self.parse_cast_type()? {
+                let operand = self.parse_unary()?;
+                return Ok(Expr::Cast(type_name, Box::new(operand)));
+            }
+        }
+        if let Some(Token::Op(op)) = self.peek() {
+            let op = op.clone();
+            self.next();
+            let operand = Box::new(self.parse_unary()?);
+            return Ok(match op.as_str() {
+                "-" => Expr::Unary(UnaryOp::Neg, operand),
+                "!" => Expr::Unary(UnaryOp::Not, operand),
+                "~" => Expr::Unary(UnaryOp::BitNot, operand),
+                "*" => Expr::Deref(operand),
+                "&" => Expr::AddressOf(operand),
+                "+" => *operand,
+                _ => return Err(format!("Unexpected '{}' in expression.", op)),
+            });
+        }
+        self.parse_postfix()
+    }
+
+    /// Parses the `(type)` of a cast, if the parenthesis at the current position starts one.
+    fn parse_cast_type(&mut self) -> Result<Option<TypeName>, String> {
+        let first = match self.tokens.get(self.pos + 1) {
+            Some(Token::Ident(word)) if self.starts_type(word) => word.clone(),
+            _ => return Ok(None),
+        };
+        self.pos += 2;
+        let base = if ["struct", "union", "enum"].contains(&first.as_str()) {
+            match self.next() {
+                Some(Token::Ident(tag)) => format!("{} {}", first, tag),
+                _ => return Err(format!("Expected a name after '{}'.", first)),
+            }
+        } else if TYPE_KEYWORDS.contains(&first.as_str()) {
+            let mut words = vec![first];
+            while let Some(Token::Ident(word)) = self.peek() {
+                if !TYPE_KEYWORDS.contains(&word.as_str()) {
+                    break;
+                }
+                words.push(word.clone());
+                self.pos += 1;
+            }
+            words.join(" ")
+        } else {
+            first
+        };
+        let mut pointers = 0;
+        while let Some(Token::Op(op)) = self.peek() {
+            if op != "*" {
+                break;
+            }
+            pointers += 1;
+            self.pos += 1;
+        }
+        match self.next() {
+            Some(Token::RParen) => Ok(Some(TypeName { base, pointers })),
+            _ => Err("Missing ')' in cast.".to_string()),
+        }
+    }
+
+    fn starts_type(&self, word: &str) -> bool {
+        TYPE_KEYWORDS.contains(&word)
+            || ["struct", "union", "enum"].contains(&word)
+            || (self.is_typedef)(word)
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if op == "." || op == "->" || op == "[" => op.clone(),
+                Some(Token::LParen) => "(".to_string(),
+                _ => return Ok(expr),
+            };
+            self.next();
+            expr = if op == "(" {
+                let name = match expr {
+                    Expr::Variable(name) => name,
+                    _ => return Err("Only functions can be called, by their name.".to_string()),
+                };
+                Expr::Call(name, self.parse_arguments()?)
+            } else if op == "[" {
+                let index = self.parse_assignment()?;
+                match self.next() {
+                    Some(Token::Op(ref close)) if close == "]" => {}
+                    _ => return Err("Missing ']' in expression.".to_string()),
+                }
+                Expr::Index(Box::new(expr), Box::new(index))
+            } else {
+                let field = match self.next() {
+                    Some(Token::Ident(field)) => field,
+                    _ => return Err(format!("Expected a field name after '{}'.", op)),
+                };
+                if op == "." {
+                    Expr::Member(Box::new(expr), field)
+                } else {
+                    Expr::PointerMember(Box::new(expr), field)
+                }
+            };
+        }
+    }
+
+    /// Parses the arguments of a call, which come after its opening parenthesis.
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.next();
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_assignment()?);
+            match self.next() {
+                Some(Token::RParen) => return Ok(args),
+                Some(Token::Op(ref op)) if op == "," => {}
+                _ => return Err("Missing ')' in expression.".to_string()),
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Float(value)) => Ok(Expr::Float(value)),
+            Some(Token::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Register(name)) => Ok(Expr::Register(name)),
+            Some(Token::LParen) => {
+                let expr = self.parse_assignment()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("Missing ')' in expression.".to_string()),
+                }
+            }
+            Some(Token::Op(op)) => Err(format!("Unexpected '{}' in expression.", op)),
+            Some(Token::RParen) => Err("Unexpected ')' in expression.".to_string()),
+            None => Err("Unexpected end of expression.".to_string()),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, String> {
+    parse_with_typedefs(input, &|_| false)
+}
+
+/// Parses an expression that may cast to typedefs, which `is_typedef` recognizes by name.
+pub fn parse_with_typedefs(input: &str, is_typedef: &dyn Fn(&str) -> bool) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        is_typedef,
+    };
+    let expr = parser.parse_assignment()?;
+    if parser.pos < parser.tokens.len() {
+        return Err("Junk at end of expression.".to_string());
+    }
+    Ok(expr)
+}
+
+const NOT_A_NUMBER: &str = "Argument to arithmetic operation not a number or boolean.";
+
+/// Evaluates `expr` to an integer, e.g. for a condition or an address.
+pub fn eval(expr: &Expr, context: &mut dyn Context) -> Result<i64, String> {
+    decay(evaluate(expr, context)?)?.to_integer(context)
+}
+
+/// Evaluates `expr` to a value of the type C would give it. Variables and what they contain
+/// evaluate to the memory they are stored in, anything else is computed.
+pub fn evaluate(expr: &Expr, context: &mut dyn Context) -> Result<Value, String> {
+    match expr {
+        Expr::Number(value) => {
+            let value_type = if *value == *value as i32 as i64 {
+                int_type()
+            } else {
+                long_type()
+            };
+            Ok(Value::integer(value_type, *value))
+        }
+        Expr::Float(value) => Ok(Value::float(double_type(), *value)),
+        Expr::Variable(name) => {
+            let (value_type, addr) = context.variable(name)?;
+            Ok(Value::Memory(value_type, addr))
+        }
+        Expr::Register(name) => {
+            // Read it now, so that a bad name is reported right away
+            context.register(name)?;
+            Ok(Value::Register(long_type(), name.clone()))
+        }
+        Expr::Member(base, field) => member(evaluate(base, context)?, field, context),
+        Expr::PointerMember(base, field) => {
+            let target = dereference(evaluate(base, context)?, context)?;
+            member(target, field, context)
+        }
+        Expr::Index(base, index) => {
+            let base = decay(evaluate(base, context)?)?;
+            if !base.value_type().is_pointer() {
+                return Err(format!(
+                    "cannot subscript something of type `{}'",
+                    base.value_type().name
+                ));
+            }
+            let element = arithmetic(BinaryOp::Add, base, evaluate(index, context)?, context)?;
+            dereference(element, context)
+        }
+        Expr::Deref(operand) => dereference(evaluate(operand, context)?, context),
+        Expr::AddressOf(operand) => {
+            let value = evaluate(operand, context)?;
+            match value.address() {
+                Some(addr) => Ok(Value::integer(
+                    Type::pointer_to(value.value_type().clone()),
+                    addr as i64,
+                )),
+                None => Err("Attempt to take address of value not located in memory.".to_string()),
+            }
+        }
+        Expr::Cast(type_name, operand) => {
+            let target = resolve_type_name(type_name, context)?;
+            let value = match **operand {
+                // Casting a call says what the function returns, in case nothing else does
+                Expr::Call(ref name, ref args) => call(name, args, Some(&target), context)?,
+                _ => evaluate(operand, context)?,
+            };
+            convert(value, &target, context)
+        }
+        Expr::Assign(lhs, rhs) => assign(evaluate(lhs, context)?, evaluate(rhs, context)?, context),
+        Expr::Call(name, args) => call(name, args, None, context),
+        Expr::Unary(UnaryOp::Not, operand) => {
+            let value = is_true(evaluate(operand, context)?, context)?;
+            Ok(Value::integer(int_type(), !value as i64))
+        }
+        Expr::Unary(op, operand) => {
+            let value = evaluate(operand, context)?;
+            let value_type = value.value_type().clone();
+            if !is_arithmetic(&value_type) {
+                return Err(NOT_A_NUMBER.to_string());
+            }
+            if value_type.is_float() {
+                return match op {
+                    UnaryOp::Neg => Ok(Value::float(double_type(), -value.to_float(context)?)),
+                    _ => Err("Integer only operation.".to_string()),
+                };
+            }
+            let result_type = arithmetic_type(&value_type, &value_type);
+            let value = convert(value, &result_type, context)?.to_integer(context)?;
+            Ok(Value::integer(
+                result_type,
+                match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::BitNot => !value,
+                    UnaryOp::Not => unreachable!(),
+                },
+            ))
+        }
+        // Short circuit like C does, so `p != 0 && *p` style conditions are safe
+        Expr::Binary(BinaryOp::And, lhs, rhs) => {
+            let value = is_true(evaluate(lhs, context)?, context)?
+                && is_true(evaluate(rhs, context)?, context)?;
+            Ok(Value::integer(int_type(), value as i64))
+        }
+        Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+            let value = is_true(evaluate(lhs, context)?, context)?
+                || is_true(evaluate(rhs, context)?, context)?;
+            Ok(Value::integer(int_type(), value as i64))
+        }
+        Expr::Binary(op, lhs, rhs) => arithmetic(
+            *op,
+            evaluate(lhs, context)?,
+            evaluate(rhs, context)?,
+            context,
+        ),
+    }
+}
+
+fn int_type() -> Type {
+    Type::new("int".to_string(), 4)
+}
+
+fn long_type() -> Type {
+    Type::new("long".to_string(), 8)
+}
+
+fn double_type() -> Type {
+    Type::new("double".to_string(), 8)
+}
+
+/// Whether C can do arithmetic on values of a type: integers, floating point numbers and enums.
+fn is_arithmetic(value_type: &Type) -> bool {
+    let value_type = value_type.strip_typedefs();
+    match value_type.kind {
+        TypeKind::Base => value_type.size > 0 && value_type.size <= 8,
+        TypeKind::Enum(_) => true,
+        _ => false,
+    }
+}
+
+/// The type C does integer arithmetic on two operands in: int, or the widest of them if that is
+/// wider, unsigned if an operand of that width is.
+fn arithmetic_type(lhs: &Type, rhs: &Type) -> Type {
+    let size = lhs.size.max(rhs.size).max(4);
+    let unsigned = [lhs, rhs]
+        .iter()
+        .any(|operand| operand.size == size && !operand.is_signed());
+    let name = match (size, unsigned) {
+        (4, false) => "int",
+        (4, true) => "unsigned int",
+        (_, false) => "long",
+        (_, true) => "unsigned long",
+    };
+    Type::new(name.to_string(), size)
+}
+
+/// Turns an array into a pointer to its first element, as C does wherever an array is used as a
+/// value.
+fn decay(value: Value) -> Result<Value, String> {
+    let element = match value.value_type().strip_typedefs().kind {
+        TypeKind::Array(ref element, _) => (**element).clone(),
+        _ => return Ok(value),
+    };
+    match value.address() {
+        Some(addr) => Ok(Value::integer(Type::pointer_to(element), addr as i64)),
+        None => Err("Attempt to take address of value not located in memory.".to_string()),
+    }
+}
+
+/// Returns the type a pointer points to.
+fn pointee(pointer_type: &Type, context: &dyn Context) -> Result<Type, String> {
+    match pointer_type.strip_typedefs().kind {
+        TypeKind::Pointer(Some(type_ref)) => context
+            .type_at(type_ref)
+            .ok_or_else(|| format!("Unknown type pointed to by {}.", pointer_type.name)),
+        TypeKind::Pointer(None) => Ok(Type::new("void".to_string(), 0)),
+        TypeKind::PointerTo(ref target) => Ok((**target).clone()),
+        _ => Err("Attempt to take contents of a non-pointer value.".to_string()),
+    }
+}
+
+fn dereference(value: Value, context: &dyn Context) -> Result<Value, String> {
+    let value = decay(value)?;
+    let target = pointee(value.value_type(), context)?;
+    if target.is_void() {
+        return Err("Attempt to take contents of a void pointer.".to_string());
+    }
+    Ok(Value::Memory(target, value.to_integer(context)? as usize))
+}
+
+fn member(value: Value, field: &str, context: &dyn Context) -> Result<Value, String> {
+    let member = match value.value_type().strip_typedefs().kind {
+        TypeKind::Struct(_) => value
+            .value_type()
+            .member(field)
+            .ok_or_else(|| format!("There is no member named {}.", field))?
+            .clone(),
+        _ => {
+            return Err(
+                "Attempt to extract a component of a value that is not a structure.".to_string(),
+            )
+        }
+    };
+    match value {
+        Value::Memory(_, addr) => Ok(Value::Memory(member.member_type, addr + member.offset)),
+        _ => {
+            let end = member.offset + member.member_type.size;
+            let bytes = value.bytes(context)?;
+            match bytes.get(member.offset..end) {
+                Some(bytes) => Ok(Value::Computed(member.member_type, bytes.to_vec())),
+                None => Err(format!("There is no member named {}.", field)),
+            }
+        }
+    }
+}
+
+/// Whether a value counts as true in a condition: anything but zero.
+fn is_true(value: Value, context: &dyn Context) -> Result<bool, String> {
+    let value = decay(value)?;
+    if value.value_type().is_float() {
+        Ok(value.to_float(context)? != 0.0)
+    } else {
+        Ok(value.to_integer(context)? != 0)
+    }
+}
+
+fn compare<T: PartialOrd>(op: BinaryOp, lhs: T, rhs: T) -> Value {
+    let result = match op {
+        BinaryOp::Lt => lhs < rhs,
+        BinaryOp::Le => lhs <= rhs,
+        BinaryOp::Gt => lhs > rhs,
+        BinaryOp::Ge => lhs >= rhs,
+        BinaryOp::Eq => lhs == rhs,
+        BinaryOp::Ne => lhs != rhs,
+        _ => unreachable!(),
+    };
+    Value::integer(int_type(), result as i64)
+}
+
+fn is_comparison(op: BinaryOp) -> bool {
+    match op {
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Eq | BinaryOp::Ne => {
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Applies a binary operator other than `&&` and `||`.
+fn arithmetic(
+    op: BinaryOp,
+    lhs: Value,
+    rhs: Value,
+    context: &dyn Context,
+) -> Result<Value, String> {
+    let lhs = decay(lhs)?;
+    let rhs = decay(rhs)?;
+    let lhs_type = lhs.value_type().clone();
+    let rhs_type = rhs.value_type().clone();
+    if lhs_type.is_pointer() || rhs_type.is_pointer() {
+        return pointer_arithmetic(op, lhs, rhs, context);
+    }
+    if !is_arithmetic(&lhs_type) || !is_arithmetic(&rhs_type) {
+        return Err(NOT_A_NUMBER.to_string());
+    }
+
+    if lhs_type.is_float() || rhs_type.is_float() {
+        let lhs = lhs.to_float(context)?;
+        let rhs = rhs.to_float(context)?;
+        return Ok(match op {
+            BinaryOp::Mul => Value::float(double_type(), lhs * rhs),
+            BinaryOp::Div => Value::float(double_type(), lhs / rhs),
+            BinaryOp::Add => Value::float(double_type(), lhs + rhs),
+            BinaryOp::Sub => Value::float(double_type(), lhs - rhs),
+            _ if is_comparison(op) => compare(op, lhs, rhs),
+            _ => return Err("Integer only operation.".to_string()),
+        });
+    }
+
+    let result_type = arithmetic_type(&lhs_type, &rhs_type);
+    let lhs = convert(lhs, &result_type, context)?.to_integer(context)?;
+    let rhs = convert(rhs, &result_type, context)?.to_integer(context)?;
+    // Unsigned values are zero extended to 64 bits, so their bits as a u64 are the value
+    let unsigned = !result_type.is_signed();
+    let value = match op {
+        BinaryOp::Mul => lhs.wrapping_mul(rhs),
+        BinaryOp::Div | BinaryOp::Rem if rhs == 0 => return Err("Division by zero".to_string()),
+        BinaryOp::Div if unsigned => (lhs as u64 / rhs as u64) as i64,
+        BinaryOp::Div => lhs.wrapping_div(rhs),
+        BinaryOp::Rem if unsigned => (lhs as u64 % rhs as u64) as i64,
+        BinaryOp::Rem => lhs.wrapping_rem(rhs),
+        BinaryOp::Add => lhs.wrapping_add(rhs),
+        BinaryOp::Sub => lhs.wrapping_sub(rhs),
+        BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+        BinaryOp::Shr if unsigned => (lhs as u64).wrapping_shr(rhs as u32) as i64,
+        BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+        _ if is_comparison(op) && unsigned => return Ok(compare(op, lhs as u64, rhs as u64)),
+        _ if is_comparison(op) => return Ok(compare(op, lhs, rhs)),
+        BinaryOp::BitAnd => lhs & rhs,
+        BinaryOp::BitXor => lhs ^ rhs,
+        BinaryOp::BitOr => lhs | rhs,
+        _ => unreachable!(),
+    };
+    Ok(Value::integer(result_type, value))
+}
+
+/// Arithmetic with a pointer on either side. Adding an integer to a pointer or subtracting one
+/// from it moves it by whole elements, subtracting pointers counts the elements between them,
+/// and comparisons compare addresses.
+fn pointer_arithmetic(
+    op: BinaryOp,
+    lhs: Value,
+    rhs: Value,
+    context: &dyn Context,
+) -> Result<Value, String> {
+    let lhs_pointer = lhs.value_type().is_pointer();
+    let rhs_pointer = rhs.value_type().is_pointer();
+    let offset = |pointer: Value, count: &Value, negate: bool| {
+        let count_type = count.value_type();
+        if !is_arithmetic(count_type) || count_type.is_float() {
+            return Err(NOT_A_NUMBER.to_string());
+        }
+        let mut count = count.to_integer(context)?;
+        if negate {
+            count = count.wrapping_neg();
+        }
+        let size = element_size(pointer.value_type(), context)?;
+        let addr = pointer
+            .to_integer(context)?
+            .wrapping_add(count.wrapping_mul(size as i64));
+        Ok(Value::integer(pointer.value_type().clone(), addr))
+    };
+    match op {
+        BinaryOp::Add | BinaryOp::Sub if lhs_pointer && !rhs_pointer => {
+            offset(lhs, &rhs, op == BinaryOp::Sub)
+        }
+        BinaryOp::Add if !lhs_pointer => offset(rhs, &lhs, false),
+        BinaryOp::Sub if lhs_pointer && rhs_pointer => {
+            let size = element_size(lhs.value_type(), context)?;
+            let distance = lhs
+                .to_integer(context)?
+                .wrapping_sub(rhs.to_integer(context)?);
+            Ok(Value::integer(long_type(), distance / size as i64))
+        }
+        _ if is_comparison(op) => Ok(compare(
+            op,
+            lhs.to_integer(context)? as u64,
+            rhs.to_integer(context)? as u64,
+        )),
+        _ => Err(NOT_A_NUMBER.to_string()),
+    }
+}
+
+/// The size of what a pointer points to, which like in gcc is 1 for `void *`.
+fn element_size(pointer_type: &Type, context: &dyn Context) -> Result<usize, String> {
+    Ok(pointee(pointer_type, context)?.size.max(1))
+}
+
+/// Converts a value to another type the way a C cast does. Numbers and pointers convert into
+/// each other, other values only to their own type.
+pub fn convert(value: Value, target: &Type, context: &dyn Context) -> Result<Value, String> {
+    if target.is_void() {
+        return Ok(Value::Computed(target.clone(), Vec::new()));
+    }
+    if !target.is_scalar() {
+        let source = value.value_type();
+        if source.name != target.name || source.size != target.size {
+            return Err("Invalid cast.".to_string());
+        }
+        return Ok(Value::Computed(target.clone(), value.bytes(context)?));
+    }
+    let value = decay(value)?;
+    if !value.value_type().is_scalar() || value.value_type().is_void() {
+        return Err("Invalid cast.".to_string());
+    }
+    if target.is_float() {
+        return Ok(Value::float(target.clone(), value.to_float(context)?));
+    }
+    let mut integer = value.to_integer(context)?;
+    if target.strip_typedefs().name == "_Bool" {
+        integer = (integer != 0) as i64;
+    }
+    Ok(Value::integer(target.clone(), integer))
+}
+
+/// Stores `value`, converted to the type of `target`, where `target` is stored. Returns the
+/// target, which now holds the value.
+fn assign(target: Value, value: Value, context: &dyn Context) -> Result<Value, String> {
+    if let Value::Computed(..) = target {
+        return Err("Left operand of assignment is not an lvalue.".to_string());
+    }
+    let value = convert(value, target.value_type(), context)?;
+    match target {
+        Value::Memory(_, addr) => context.write_memory(addr, &value.bytes(context)?)?,
+        Value::Register(_, ref name) => context.set_register(name, value.to_integer(context)?)?,
+        Value::Computed(..) => unreachable!(),
+    }
+    Ok(target)
+}
+
+fn call(
+    name: &str,
+    args: &[Expr],
+    return_type: Option<&Type>,
+    context: &mut dyn Context,
+) -> Result<Value, String> {
+    let args = args
+        .iter()
+        .map(|arg| decay(evaluate(arg, context)?))
+        .collect::<Result<Vec<Value>, String>>()?;
+    context.call(name, &args, return_type)
+}
+
+/// Finds the type a cast names.
+fn resolve_type_name(type_name: &TypeName, context: &dyn Context) -> Result<Type, String> {
+    let mut resolved =
+        match keyword_type(&type_name.base).or_else(|| context.named_type(&type_name.base)) {
+            Some(resolved) => resolved,
+            None => {
+                let mut words = type_name.base.splitn(2, ' ');
+                return Err(match (words.next(), words.next()) {
+                    (Some(kind), Some(tag)) if ["struct", "union", "enum"].contains(&kind) => {
+                        format!("No {} type named {}.", kind, tag)
+                    }
+                    _ => format!("No symbol \"{}\" in current context.", type_name.base),
+                });
+            }
+        };
+    for _ in 0..type_name.pointers {
+        resolved = Type::pointer_to(resolved);
+    }
+    Ok(resolved)
+}
+
+/// Builds the C base type a list of keywords names, e.g. `unsigned long int`.
+fn keyword_type(name: &str) -> Option<Type> {
+    let words: Vec<&str> = name
+        .split(' ')
+        .filter(|word| *word != "const" && *word != "volatile")
+        .collect();
+    if words.is_empty() || !words.iter().all(|word| TYPE_KEYWORDS.contains(word)) {
+        return None;
+    }
+    let has = |keyword: &str| words.contains(&keyword);
+    let sign = if has("unsigned") {
+        "unsigned "
+    } else if has("signed") && has("char") {
+        // Plain char may be either, so signed char is a type of its own
+        "signed "
+    } else {
+        ""
+    };
+    let (name, size) = if has("void") {
+        ("void", 0)
+    } else if has("_Bool") {
+        ("_Bool", 1)
+    } else if has("float") {
+        ("float", 4)
+    } else if has("double") && has("long") {
+        // 80-bit x87 values aren't supported
+        return None;
+    } else if has("double") {
+        ("double", 8)
+    } else if has("char") {
+        ("char", 1)
+    } else if has("short") {
+        ("short", 2)
+    } else if has("long") {
+        ("long", 8)
+    } else {
+        ("int", 4)
+    };
+    Some(Type::new(format!("{}{}", sign, name), size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dwarf_data::Member;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// A program with some variables in 8 KiB of memory, a register and a function `square`.
+    struct MapContext {
+        variables: HashMap<&'static str, (Type, usize)>,
+        memory: RefCell<Vec<u8>>,
+        rax: RefCell<i64>,
+    }
+
+    fn struct_type() -> Type {
+        let member = |name: &str, offset| Member {
+            name: name.to_string(),
+            offset,
+            member_type: int_type(),
+        };
+        Type {
+            name: "struct s".to_string(),
+            size: 8,
+            kind: TypeKind::Struct(vec![member("x", 0), member("y", 4)]),
+        }
+    }
+
+    impl MapContext {
+        fn new() -> MapContext {
+            let mut variables = HashMap::new();
+            let array = Type {
+                name: "int [4]".to_string(),
+                size: 16,
+                kind: TypeKind::Array(Box::new(int_type()), Some(4)),
+            };
+            let void_pointer = Type {
+                name: "void *".to_string(),
+                size: 8,
+                kind: TypeKind::Pointer(None),
+            };
+            variables.insert("i", (int_type(), 0x100));
+            variables.insert("p", (void_pointer, 0x108));
+            variables.insert("s", (struct_type(), 0x1000));
+            variables.insert("a", (array, 0x1010));
+            variables.insert("c", (Type::new("char".to_string(), 1), 0x1020));
+            variables.insert("d", (double_type(), 0x1028));
+            variables.insert("u", (Type::new("unsigned int".to_string(), 4), 0x1030));
+            let context = MapContext {
+                variables,
+                memory: RefCell::new(vec![0; 0x2000]),
+                rax: RefCell::new(3),
+            };
+            let values: [(usize, &[u8]); 9] = [
+                (0x100, &5i32.to_le_bytes()),
+                (0x1000, &7i32.to_le_bytes()),
+                (0x1004, &(-1i32).to_le_bytes()),
+                (0x1010, &10i32.to_le_bytes()),
+                (0x1014, &20i32.to_le_bytes()),
+                (0x1018, &30i32.to_le_bytes()),
+                (0x101c, &40i32.to_le_bytes()),
+                (0x1020, b"A"),
+                (0x1028, &2.5f64.to_le_bytes()),
+            ];
+            for (addr, bytes) in values.iter() {
+                context.write_memory(*addr, bytes).unwrap();
+            }
+            context.write_memory(0x1030, &1u32.to_le_bytes()).unwrap();
+            context
+        }
+
+        fn eval(&mut self, input: &str) -> Result<i64, String> {
+            let is_typedef = |name: &str| name == "myint";
+            eval(&parse_with_typedefs(input, &is_typedef)?, self)
+        }
+    }
+
+    impl Context for MapContext {
+        fn variable(&self, name: &str) -> Result<(Type, usize), String> {
+            self.variables
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("No symbol \"{}\" in current context.", name))
+        }
+
+        fn register(&self, name: &str) -> Result<i64, String> {
+            match name {
+                "rax" => Ok(*self.rax.borrow()),
+                _ => Err(format!("Invalid register ${}", name)),
+            }
+        }
+
+        fn set_register(&self, name: &str, value: i64) -> Result<(), String> {
+            self.register(name)?;
+            *self.rax.borrow_mut() = value;
+            Ok(())
+        }
+
+        fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+            self.memory
+                .borrow()
+                .get(addr..addr + len)
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| format!("Cannot access memory at address {:#x}", addr))
+        }
+
+        fn write_memory(&self, addr: usize, bytes: &[u8]) -> Result<(), String> {
+            match self.memory.borrow_mut().get_mut(addr..addr + bytes.len()) {
+                Some(memory) => {
+                    memory.copy_from_slice(bytes);
+                    Ok(())
+                }
+                None => Err(format!("Cannot access memory at address {:#x}", addr)),
+            }
+        }
+
+        fn type_at(&self, _type_ref: TypeRef) -> Option<Type> {
+            None
+        }
+
+        fn named_type(&self, name: &str) -> Option<Type> {
+            match name {
+                "struct s" => Some(struct_type()),
+                "myint" => Some(Type {
+                    name: "myint".to_string(),
+                    size: 4,
+                    kind: TypeKind::Typedef(Box::new(int_type())),
+                }),
+                _ => None,
+            }
+        }
+
+        fn call(
+            &mut self,
+            name: &str,
+            args: &[Value],
+            _: Option<&Type>,
+        ) -> Result<Value, String> {
+            if name != "square" {
+                return Err(format!("No symbol \"{}\" in current context.", name));
+            }
+            let arg = args
+                .first()
+                .ok_or_else(|| "Too few arguments in function call.".to_string())?
+                .to_integer(self)?;
+            Ok(Value::integer(int_type(), arg * arg))
+        }
+    }
+
+    fn eval_str(input: &str) -> Result<i64, String> {
+        MapContext::new().eval(input)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval_str("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval_str("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval_str("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval_str("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval_str("-0x10 + ~0 + !0"), Ok(-16));
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(eval_str("i == 5 && p != 0"), Ok(0));
+        assert_eq!(eval_str("i == 5 || p != 0"), Ok(1));
+        assert_eq!(eval_str("$rax > 2"), Ok(1));
+        assert_eq!(eval_str("'a' == 97"), Ok(1));
+        // The right hand side is never evaluated, so the unknown name doesn't matter
+        assert_eq!(eval_str("p != 0 && missing"), Ok(0));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(eval_str("missing == 1").is_err());
+        assert!(eval_str("i / p").is_err());
+        assert!(parse("(i == 5").is_err());
+        assert!(parse("i ==").is_err());
+        assert!(parse("i 5").is_err());
+        assert!(parse("i # 5").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let expr = parse("(i + 1) * 2 == $rax&&p").unwrap();
+        assert_eq!(expr.to_string(), "(i + 1) * 2 == $rax && p");
+        assert_eq!(parse(&expr.to_string()), Ok(expr));
+        assert_eq!(parse("i - (p - 1)").unwrap().to_string(), "i - (p - 1)");
+        assert_eq!(parse("!(i == 1)").unwrap().to_string(), "!(i == 1)");
+    }
+
+    #[test]
+    fn test_postfix() {
+        let expr = parse("*p->next.items[i + 1]").unwrap();
+        assert_eq!(expr.to_string(), "*p->next.items[i + 1]");
+        match expr {
+            Expr::Deref(ref operand) => match **operand {
+                Expr::Index(..) => {}
+                _ => panic!("postfix operators should bind tighter than *: {:?}", expr),
+            },
+            _ => panic!("expected a dereference: {:?}", expr),
+        }
+        assert_eq!(parse("(*p).x").unwrap().to_string(), "(*p).x");
+        assert_eq!(parse("a * *p").unwrap().to_string(), "a * *p");
+        assert_eq!(eval_str("s.x + 1"), Ok(8));
+        assert_eq!(eval_str("&s.x"), Ok(0x1000));
+        assert!(parse("s.").is_err());
+        assert!(parse("a[1").is_err());
+    }
+
+    #[test]
+    fn test_types() {
+        assert_eq!(eval_str("c + 1"), Ok(66));
+        assert_eq!(eval_str("(char)300"), Ok(44));
+        assert_eq!(eval_str("(unsigned char)-1"), Ok(255));
+        // Arithmetic with an unsigned int is unsigned
+        assert_eq!(eval_str("u - 2 > 0"), Ok(1));
+        assert_eq!(eval_str("-1 < u"), Ok(0));
+        assert_eq!(eval_str("(int)(d * 3)"), Ok(7));
+        assert_eq!(eval_str("7 / 2 == 3 && 7.0 / 2 == 3.5"), Ok(1));
+        assert_eq!(eval_str("(myint)d + 1"), Ok(3));
+        assert!(eval_str("1.5 % 2").is_err());
+        assert!(eval_str("(struct s)i").is_err());
+        assert!(eval_str("(struct t *)p").is_err());
+        // Without knowing the typedef, (myint) is a parenthesized variable
+        assert!(parse("(myint)d").is_err());
+    }
+
+    #[test]
+    fn test_pointers() {
+        assert_eq!(eval_str("*&i"), Ok(5));
+        assert_eq!(eval_str("a[2] + *(a + 3)"), Ok(70));
+        assert_eq!(eval_str("&a[3] - &a[1]"), Ok(2));
+        assert_eq!(eval_str("(long)(a + 1)"), Ok(0x1014));
+        assert_eq!(eval_str("((struct s *)0x1000)->y"), Ok(-1));
+        assert_eq!(eval_str("(char *)p + 2 == (int *)p + 0"), Ok(0));
+        assert!(eval_str("*p").is_err());
+        assert!(eval_str("i[1]").is_err());
+        assert!(eval_str("&1").is_err());
+    }
+
+    #[test]
+    fn test_assignment() {
+        let mut context = MapContext::new();
+        assert_eq!(context.eval("i = 9"), Ok(9));
+        assert_eq!(context.eval("i * 2"), Ok(18));
+        assert_eq!(context.eval("s.x = s.y = c"), Ok(65));
+        assert_eq!(context.eval("s.x + s.y"), Ok(130));
+        assert_eq!(context.eval("$rax = i + 1"), Ok(10));
+        assert_eq!(context.eval("$rax"), Ok(10));
+        assert_eq!(context.eval("(d = 1) / 2 == 0.5"), Ok(1));
+        assert!(context.eval("i + 1 = 2").is_err());
+        assert!(context.eval("i = s").is_err());
+        let expr = parse("x = y = (z = 1) + 2").unwrap();
+        assert_eq!(expr.to_string(), "x = y = (z = 1) + 2");
+        assert_eq!(parse(&expr.to_string()), Ok(expr));
+    }
+
+    #[test]
+    fn test_calls() {
+        let expr = parse("square(i + 1, (char *)p, 1.0)").unwrap();
+        assert_eq!(expr.to_string(), "square(i + 1, (char *)p, 1.0)");
+        assert_eq!(parse(&expr.to_string()), Ok(expr));
+        assert_eq!(eval_str("square(i) + 1"), Ok(26));
+        assert_eq!(eval_str("(long)square(a[0])"), Ok(100));
+        assert!(eval_str("cube(2)").is_err());
+        assert!(parse("square(1, )").is_err());
+        assert!(parse("s.x(1)").is_err());
+    }
+}
@@ -0,0 +1,816 @@
+//! Serving the program being debugged over the GDB Remote Serial Protocol, so that gdb (`target
+//! remote host:port`) or an IDE can drive deet's ptrace backend. Packets look like `$data#xx`,
+//! where xx is the checksum of data, and each one is answered with a reply packet. gdb takes care
+//! of breakpoints itself through Z0/z0, so the server just writes and removes the 0xcc, and hides
+//! it from memory reads.
+
+use crate::inferior::{Inferior, Status};
+use crate::process::Process;
+use crate::registers;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// The registers of gdb's amd64 `g` packet and their sizes, in order. The x87 and SSE registers
+// that come after them are left out, which gdb shows as unavailable.
+const REGISTERS: [(&str, usize); 24] = [
+    ("rax", 8),
+    ("rbx", 8),
+    ("rcx", 8),
+    ("rdx", 8),
+    ("rsi", 8),
+    ("rdi", 8),
+    ("rbp", 8),
+    ("rsp", 8),
+    ("r8", 8),
+    ("r9", 8),
+    ("r10", 8),
+    ("r11", 8),
+    ("r12", 8),
+    ("r13", 8),
+    ("r14", 8),
+    ("r15", 8),
+    ("rip", 8),
+    ("eflags", 4),
+    ("cs", 4),
+    ("ss", 4),
+    ("ds", 4),
+    ("es", 4),
+    ("fs", 4),
+    ("gs", 4),
+];
+
+// Tells gdb the architecture without it having to load the executable
+const TARGET_XML: &str = "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+                          <target><architecture>i386:x86-64</architecture></target>";
+
+// gdb sends this byte outside of any packet to interrupt the running program
+const INTERRUPT: u8 = 0x03;
+
+// The longest packet we tell gdb we can take, and the longest reply we send
+const PACKET_SIZE: usize = 0x4000;
+
+struct GdbServer {
+    inferior: Inferior,
+    stream: TcpStream,
+    // Set by QStartNoAckMode, after which packets aren't acknowledged with `+`
+    no_ack: bool,
+    // Addresses gdb asked for breakpoints at, mapped to the byte the 0xcc replaced
+    breakpoints: HashMap<usize, u8>,
+    // The reply to `?`: why the program last stopped
+    last_stop: String,
+    // The program exited, so only packets that don't need it can be answered
+    exited: bool,
+    // gdb killed or detached from the program, ending the session
+    done: bool,
+    // gdb detached, so the program is let go of rather than killed when the session ends
+    detaching: bool,
+}
+
+/// Starts `target` with `args` and serves it to a single gdb connecting to `address`
+/// (`host:port`, or `:port` for every interface), until gdb kills it, detaches or hangs up.
+pub fn serve(target: &str, args: &Vec<String>, address: &str) -> Result<(), String> {
+    let address = if address.starts_with(':') {
+        format!("0.0.0.0{}", address)
+    } else {
+        address.to_string()
+    };
+    let inferior = Inferior::new(target, args).ok_or_else(|| format!("Cannot exec {}.", target))?;
+    let pid = inferior.pid();
+    println!("Process {} created; pid = {}", target, pid);
+
+    let listener =
+        TcpListener::bind(&address).or_else(|err| Err(format!("{}: {}", address, err)))?;
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    println!("Listening on port {}", port);
+    let (stream, peer) = listener.accept().or_else(|err| Err(err.to_string()))?;
+    println!(
+        "Remote debugging from host {}, port {}",
+        peer.ip(),
+        peer.port()
+    );
+    serve_connection(inferior, stream)
+}
+
+/// Serves `inferior` to the gdb on the other end of `stream`, until gdb kills it, detaches or
+/// hangs up.
+fn serve_connection(inferior: Inferior, stream: TcpStream) -> Result<(), String> {
+    let pid = inferior.pid();
+    let last_stop = stop_reply(Signal::SIGTRAP, inferior.current_thread().1, false);
+    let mut server = GdbServer {
+        inferior,
+        stream,
+        no_ack: false,
+        breakpoints: HashMap::new(),
+        last_stop,
+        exited: false,
+        done: false,
+        detaching: false,
+    };
+    let result = server.run();
+    if server.detaching {
+        server
+            .inferior
+            .detach()
+            .or_else(|err| Err(format!("Cannot detach from process: {}", err)))?;
+        println!("Detaching from process {}", pid);
+    } else if !server.exited {
+        // Like gdbserver, don't leave a program we started behind
+        let _ = server.inferior.kill();
+    }
+    result.or_else(|err| Err(err.to_string()))
+}
+
+impl GdbServer {
+    fn run(&mut self) -> io::Result<()> {
+        while !self.done {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => {
+                    println!("Remote side has terminated connection.");
+                    return Ok(());
+                }
+            };
+            if let Some(reply) = self.handle_packet(&packet)? {
+                self.send_packet(&reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next packet and acknowledges it, skipping acknowledgements and stray
+    /// interrupts. Returns None once gdb hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = Vec::new();
+            for _ in 0..2 {
+                match self.read_byte()? {
+                    Some(byte) => checksum.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                == Some(checksum_of(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    /// Carries out a packet and returns the reply, which is empty for packets we don't support.
+    /// `k` is the one packet that doesn't get a reply.
+    fn handle_packet(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = match packet.chars().next() {
+            Some(command) => (command, &packet[command.len_utf8()..]),
+            None => return Ok(Some(String::new())),
+        };
+        if self.exited && "gGpPmMZzcCsSHT".contains(command) {
+            return Ok(Some("E01".to_string()));
+        }
+        Ok(Some(match command {
+            '?' => self.last_stop.clone(),
+            'g' => self.read_registers(),
+            'G' => self.write_registers(args),
+            'p' => self.read_register(args),
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            'Z' | 'z' => self.toggle_breakpoint(command == 'Z', args),
+            'c' | 'C' | 's' | 'S' => self.resume(command, args)?,
+            'H' => self.select_thread(args.get(1..).unwrap_or("")),
+            'T' => match parse_thread(args) {
+                Some(tid) if self.has_thread(tid) => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            'k' => {
+                self.kill();
+                return Ok(None);
+            }
+            'D' => self.detach(),
+            'q' | 'Q' | 'v' => self.handle_query(packet),
+            _ => String::new(),
+        }))
+    }
+
+    /// Answers the general queries and settings (`q` and `Q` packets) and the `v` packets.
+    fn handle_query(&mut self, packet: &str) -> String {
+        let name = packet
+            .split(|c| c == ':' || c == ',' || c == ';')
+            .next()
+            .unwrap_or("");
+        match name {
+            "qSupported" => format!(
+                "PacketSize={:x};QStartNoAckMode+;swbreak+;qXfer:features:read+",
+                PACKET_SIZE
+            ),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qXfer" if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &packet["qXfer:features:read:target.xml:".len()..];
+                match parse_pair(range, ',') {
+                    Some((offset, length)) => {
+                        let offset = offset.min(TARGET_XML.len());
+                        let end = offset.saturating_add(length).min(TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                        format!("{}{}", more, &TARGET_XML[offset..end])
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "qAttached" => "0".to_string(),
+            "qC" if !self.exited => format!("QC{:x}", self.inferior.current_thread().1.as_raw()),
+            "qfThreadInfo" if !self.exited => {
+                let tids: Vec<String> = self
+                    .inferior
+                    .threads()
+                    .iter()
+                    .map(|(_, tid)| format!("{:x}", tid.as_raw()))
+                    .collect();
+                format!("m{}", tids.join(","))
+            }
+            "qfThreadInfo" | "qsThreadInfo" => "l".to_string(),
+            "qSymbol" => "OK".to_string(),
+            "vKill" => {
+                self.kill();
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let regs = match self.inferior.getregs() {
+            Ok(regs) => regs,
+            Err(err) => return error_reply(err),
+        };
+        REGISTERS
+            .iter()
+            .map(|(name, size)| {
+                let value = registers::get_register(&regs, name).unwrap();
+                hex_bytes(&value.to_le_bytes()[..*size])
+            })
+            .collect()
+    }
+
+    fn write_registers(&self, args: &str) -> String {
+        let bytes = match parse_hex_bytes(args) {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+        let mut regs = match self.inferior.getregs() {
+            Ok(regs) => regs,
+            Err(err) => return error_reply(err),
+        };
+        let mut offset = 0;
+        for (name, size) in REGISTERS.iter() {
+            if offset + size > bytes.len() {
+                break;
+            }
+            registers::set_register(&mut regs, name, le_value(&bytes[offset..offset + size]));
+            offset += size;
+        }
+        match self.inferior.setregs(regs) {
+            Ok(()) => "OK".to_string(),
+            Err(err) => error_reply(err),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let (name, size) = match usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|num| REGISTERS.get(num))
+        {
+            Some(register) => *register,
+            None => return "E01".to_string(),
+        };
+        match self.inferior.getregs() {
+            Ok(regs) => {
+                let value = registers::get_register(&regs, name).unwrap();
+                hex_bytes(&value.to_le_bytes()[..size])
+            }
+            Err(err) => error_reply(err),
+        }
+    }
+
+    fn write_register(&self, args: &str) -> String {
+        let equals = match args.find('=') {
+            Some(equals) => equals,
+            None => return "E01".to_string(),
+        };
+        let register = usize::from_str_radix(&args[..equals], 16)
+            .ok()
+            .and_then(|num| REGISTERS.get(num));
+        let (name, size, bytes) = match (register, parse_hex_bytes(&args[equals + 1..])) {
+            (Some((name, size)), Some(bytes)) => (name, size, bytes),
+            _ => return "E01".to_string(),
+        };
+        let result = self.inferior.getregs().and_then(|mut regs| {
+            let len = (*size).min(bytes.len());
+            registers::set_register(&mut regs, name, le_value(&bytes[..len]));
+            self.inferior.setregs(regs)
+        });
+        match result {
+            Ok(()) => "OK".to_string(),
+            Err(err) => error_reply(err),
+        }
+    }
+
+    /// `m addr,length`: reads memory as it was before any breakpoints were written into it. Reads
+    /// longer than fit in a reply are cut short, which gdb allows for.
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_pair(args, ',') {
+            Some((addr, len)) if addr.checked_add(len).is_some() => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        // Each byte takes two hex digits, and the reply needs room for `$` and `#xx`
+        let len = len.min((PACKET_SIZE - 4) / 2);
+        match self.inferior.read_bytes(addr, len) {
+            Ok(mut bytes) => {
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    if let Some(orig_byte) = self.breakpoints.get(&(addr + i)) {
+                        *byte = *orig_byte;
+                    }
+                }
+                hex_bytes(&bytes)
+            }
+            Err(err) => error_reply(err),
+        }
+    }
+
+    /// `M addr,length:bytes`: writes memory, keeping breakpoints in place over the new bytes.
+    fn write_memory(&mut self, args: &str) -> String {
+        let colon = match args.find(':') {
+            Some(colon) => colon,
+            None => return "E01".to_string(),
+        };
+        let (addr, bytes) = match (
+            parse_pair(&args[..colon], ','),
+            parse_hex_bytes(&args[colon + 1..]),
+        ) {
+            (Some((addr, len)), Some(bytes))
+                if bytes.len() == len && addr.checked_add(len).is_some() =>
+            {
+                (addr, bytes)
+            }
+            _ => return "E01".to_string(),
+        };
+        for (i, byte) in bytes.iter().enumerate() {
+            if let Some(orig_byte) = self.breakpoints.get_mut(&(addr + i)) {
+                *orig_byte = *byte;
+            } else if let Err(err) = self.inferior.write_byte(addr + i, *byte) {
+                return error_reply(err);
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// `Z0,addr,kind` and `z0,addr,kind`: inserts or removes a software breakpoint. Other kinds of
+    /// breakpoints and watchpoints aren't supported.
+    fn toggle_breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let addr = match (fields.next(), fields.next()) {
+            (Some("0"), Some(addr)) => match usize::from_str_radix(addr, 16) {
+                Ok(addr) => addr,
+                Err(_) => return "E01".to_string(),
+            },
+            _ => return String::new(),
+        };
+        if insert {
+            if !self.breakpoints.contains_key(&addr) {
+                match self.inferior.breakpoint(addr) {
+                    Ok(orig_byte) => {
+                        self.breakpoints.insert(addr, orig_byte);
+                    }
+                    Err(err) => return error_reply(err),
+                }
+            }
+        } else if let Some(orig_byte) = self.breakpoints.remove(&addr) {
+            if let Err(err) = self.inferior.write_byte(addr, orig_byte) {
+                return error_reply(err);
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// `c`, `C sig`, `s` and `S sig`, optionally followed by the address to resume at: continues
+    /// or single steps the program and returns the stop reply once it stops again. Without a
+    /// signal, the one the thread stopped with is discarded; the thread can only be given the
+    /// signal it stopped with, not another one.
+    fn resume(&mut self, command: char, args: &str) -> io::Result<String> {
+        let addr = match command {
+            'c' | 's' => args,
+            _ => args
+                .find(';')
+                .map(|semicolon| &args[semicolon + 1..])
+                .unwrap_or(""),
+        };
+        if !addr.is_empty() {
+            let result = usize::from_str_radix(addr, 16).ok().map(|addr| {
+                self.inferior.getregs().and_then(|mut regs| {
+                    regs.rip = addr as u64;
+                    self.inferior.setregs(regs)
+                })
+            });
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(err)) => return Ok(error_reply(err)),
+                None => return Ok("E01".to_string()),
+            }
+        }
+        if command == 'c' || command == 's' {
+            self.inferior.discard_signal();
+        }
+
+        let step = command == 's' || command == 'S';
+        let status = self.run_interruptibly(|inferior| {
+            if step {
+                inferior.step()?;
+                inferior.wait(None)
+            } else {
+                inferior.cont()
+            }
+        })?;
+        let status = self.handle_process_events(status)?;
+
+        let reply = match status {
+            Status::Stopped(signal, rip) => {
+                let tid = self.inferior.current_thread().1;
+                // Report breakpoints at their address rather than after the 0xcc
+                let breakpoint = signal == Signal::SIGTRAP
+                    && !step
+                    && rip
+                        .checked_sub(1)
+                        .filter(|addr| self.breakpoints.contains_key(addr))
+                        .is_some();
+                if breakpoint {
+                    self.inferior.go_back_one_step().map_err(to_io_error)?;
+                }
+                stop_reply(signal, tid, breakpoint)
+            }
+            Status::Watchpoint(..) => {
+                stop_reply(Signal::SIGTRAP, self.inferior.current_thread().1, false)
+            }
+            Status::Exited(code) => {
+                self.exited = true;
+                println!("Child exited with status {}", code);
+                format!("W{:02x}", code & 0xff)
+            }
+            Status::Signaled(signal) => {
+                self.exited = true;
+                println!("Child terminated with signal = {:?}", signal);
+                format!("X{:02x}", gdb_signal(signal))
+            }
+            // Handled by handle_process_events
+            Status::Forked(..) | Status::VforkDone | Status::Execed | Status::ThreadExited => {
+                unreachable!()
+            }
+        };
+        self.last_stop = reply.clone();
+        Ok(reply)
+    }
+
+    /// Runs `run` (which resumes the inferior and waits for it) while watching the connection for
+    /// gdb interrupting the program, which stops it with a SIGINT.
+    fn run_interruptibly<F>(&mut self, run: F) -> io::Result<Status>
+    where
+        F: FnOnce(&mut Inferior) -> Result<Status, nix::Error>,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let stream = self.stream.try_clone()?;
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let watcher = {
+            let running = running.clone();
+            let pid = self.inferior.pid();
+            thread::spawn(move || {
+                let mut byte = [0u8];
+                while running.load(Ordering::SeqCst) {
+                    match (&stream).read(&mut byte) {
+                        Ok(1) if byte[0] == INTERRUPT => {
+                            let _ = signal::kill(pid, Signal::SIGINT);
+                        }
+                        Ok(0) => break,
+                        _ => {}
+                    }
+                }
+            })
+        };
+        let status = run(&mut self.inferior);
+        running.store(false, Ordering::SeqCst);
+        let _ = watcher.join();
+        self.stream.set_read_timeout(None)?;
+        status.map_err(to_io_error)
+    }
+
+    /// Deals with forks, execs and thread exits, which gdb isn't told about: forked children are
+    /// let go of without the breakpoints, the breakpoints are gone after an exec, and the rest of
+    /// the program goes on after a thread exits.
+    fn handle_process_events(&mut self, mut status: Status) -> io::Result<Status> {
+        loop {
+            match status {
+                Status::Forked(child_pid, _) => {
+                    let mut child = self.inferior.fork_child(child_pid).map_err(to_io_error)?;
+                    for (addr, orig_byte) in self.breakpoints.iter() {
+                        child.write_byte(*addr, *orig_byte).map_err(to_io_error)?;
+                    }
+                    child.detach().map_err(to_io_error)?;
+                }
+                Status::VforkDone => {
+                    // The vfork child took the breakpoints out of the memory it shared with us
+                    for addr in self.breakpoints.keys() {
+                        self.inferior.write_byte(*addr, 0xcc).map_err(to_io_error)?;
+                    }
+                }
+                Status::Execed => self.breakpoints.clear(),
+                Status::ThreadExited => {}
+                other => return Ok(other),
+            }
+            status = self.inferior.resume_after_event().map_err(to_io_error)?;
+        }
+    }
+
+    /// `Hg tid` and `Hc tid`: picks the thread registers are accessed in and that is single
+    /// stepped. 0 and -1 mean any thread, leaving the current one.
+    fn select_thread(&mut self, args: &str) -> String {
+        if args == "0" || args == "-1" {
+            return "OK".to_string();
+        }
+        let tid = match parse_thread(args) {
+            Some(tid) => tid,
+            None => return "E01".to_string(),
+        };
+        let num = self
+            .inferior
+            .threads()
+            .iter()
+            .find(|(_, thread)| *thread == tid)
+            .map(|(num, _)| *num);
+        match num {
+            Some(num) if self.inferior.select_thread(num) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn has_thread(&self, tid: Pid) -> bool {
+        self.inferior
+            .threads()
+            .iter()
+            .any(|(_, thread)| *thread == tid)
+    }
+
+    fn kill(&mut self) {
+        if !self.exited {
+            let _ = self.inferior.kill();
+            self.exited = true;
+        }
+        self.done = true;
+    }
+
+    /// `D`: takes the breakpoints out, after which the program is let go of and runs on its own.
+    fn detach(&mut self) -> String {
+        for (addr, orig_byte) in self.breakpoints.drain() {
+            if let Err(err) = self.inferior.write_byte(addr, orig_byte) {
+                return error_reply(err);
+            }
+        }
+        self.detaching = !self.exited;
+        self.done = true;
+        "OK".to_string()
+    }
+}
+
+/// The stop reply for a thread that stopped with `signal`, e.g. `T05thread:1a2b;swbreak:;`.
+fn stop_reply(signal: Signal, tid: Pid, breakpoint: bool) -> String {
+    format!(
+        "T{:02x}thread:{:x};{}",
+        gdb_signal(signal),
+        tid.as_raw(),
+        if breakpoint { "swbreak:;" } else { "" }
+    )
+}
+
+/// gdb numbers signals its own way, which matches Linux only for the traditional ones.
+fn gdb_signal(signal: Signal) -> u8 {
+    match signal {
+        Signal::SIGHUP => 1,
+        Signal::SIGINT => 2,
+        Signal::SIGQUIT => 3,
+        Signal::SIGILL => 4,
+        Signal::SIGTRAP => 5,
+        Signal::SIGABRT => 6,
+        Signal::SIGFPE => 8,
+        Signal::SIGKILL => 9,
+        Signal::SIGBUS => 10,
+        Signal::SIGSEGV => 11,
+        Signal::SIGSYS => 12,
+        Signal::SIGPIPE => 13,
+        Signal::SIGALRM => 14,
+        Signal::SIGTERM => 15,
+        Signal::SIGURG => 16,
+        Signal::SIGSTOP => 17,
+        Signal::SIGTSTP => 18,
+        Signal::SIGCONT => 19,
+        Signal::SIGCHLD => 20,
+        Signal::SIGTTIN => 21,
+        Signal::SIGTTOU => 22,
+        Signal::SIGIO => 23,
+        Signal::SIGXCPU => 24,
+        Signal::SIGXFSZ => 25,
+        Signal::SIGVTALRM => 26,
+        Signal::SIGPROF => 27,
+        Signal::SIGWINCH => 28,
+        Signal::SIGUSR1 => 30,
+        Signal::SIGUSR2 => 31,
+        Signal::SIGPWR => 32,
+        // GDB_SIGNAL_UNKNOWN
+        _ => 143,
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn error_reply(err: nix::Error) -> String {
+    let errno = err.as_errno().map(|errno| errno as i32).unwrap_or(1);
+    format!("E{:02x}", errno & 0xff)
+}
+
+fn to_io_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Two hex numbers separated by `separator`, e.g. the address and length of `m`.
+fn parse_pair(args: &str, separator: char) -> Option<(usize, usize)> {
+    let index = args.find(separator)?;
+    Some((
+        usize::from_str_radix(&args[..index], 16).ok()?,
+        usize::from_str_radix(&args[index + 1..], 16).ok()?,
+    ))
+}
+
+fn parse_thread(args: &str) -> Option<Pid> {
+    i32::from_str_radix(args, 16).ok().map(Pid::from_raw)
+}
+
+/// Decodes a little endian value of up to 8 bytes.
+fn le_value(bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_fields() {
+        assert_eq!(checksum_of(b"qSupported"), 0x37);
+        assert_eq!(parse_pair("4011b8,8", ','), Some((0x4011b8, 8)));
+        assert_eq!(parse_pair("4011b8", ','), None);
+        assert_eq!(
+            parse_hex_bytes("554889e5"),
+            Some(vec![0x55, 0x48, 0x89, 0xe5])
+        );
+        assert_eq!(parse_hex_bytes("5"), None);
+        assert_eq!(hex_bytes(&[0x0d, 0xff]), "0dff");
+        assert_eq!(le_value(&[0x2a, 0x01]), 0x12a);
+    }
+
+    #[test]
+    fn test_stop_replies() {
+        let tid = Pid::from_raw(0x1a2b);
+        assert_eq!(
+            stop_reply(Signal::SIGTRAP, tid, true),
+            "T05thread:1a2b;swbreak:;"
+        );
+        assert_eq!(stop_reply(Signal::SIGSEGV, tid, false), "T0bthread:1a2b;");
+        assert_eq!(stop_reply(Signal::SIGUSR1, tid, false), "T1ethread:1a2b;");
+    }
+
+    /// Sends `data` as a packet and returns the reply, acknowledging both.
+    fn exchange(stream: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).unwrap();
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(checksum, checksum_of(&reply));
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    // Needs ptrace, and waitpid(-1) may collect the children of tests running alongside it:
+    // run with `cargo test -- --ignored --test-threads 1`
+    #[test]
+    #[ignore]
+    fn test_session() {
+        // The server has to run on this thread, which is the one tracing the program
+        let inferior = Inferior::new("/bin/sleep", &vec!["10".to_string()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let supported = exchange(&mut stream, "qSupported:swbreak+");
+            assert!(supported.starts_with("PacketSize=4000;"));
+            assert!(exchange(&mut stream, "?").starts_with("T05thread:"));
+
+            let registers = exchange(&mut stream, "g");
+            let rip = parse_hex_bytes(&registers[16 * 16..17 * 16]).unwrap();
+            let rip = le_value(&rip) as usize;
+            assert_eq!(exchange(&mut stream, "p10"), &registers[16 * 16..17 * 16]);
+            assert_eq!(exchange(&mut stream, &format!("m{:x},4", rip)).len(), 8);
+            // Reads that don't fit in a packet are cut short
+            let memory = exchange(&mut stream, &format!("m{:x},100000", rip));
+            assert!(!memory.is_empty() && memory.len() < PACKET_SIZE);
+            // Ranges past the end of the address space are refused
+            assert_eq!(exchange(&mut stream, "mffffffffffffffff,2"), "E01");
+            assert_eq!(exchange(&mut stream, "Mffffffffffffffff,2:9090"), "E01");
+
+            let xml = "qXfer:features:read:target.xml:";
+            let whole = exchange(&mut stream, &format!("{}0,ffffffffffffffff", xml));
+            assert_eq!(whole, format!("l{}", TARGET_XML));
+            let part = exchange(&mut stream, &format!("{}4,8", xml));
+            assert_eq!(part, format!("m{}", &TARGET_XML[4..12]));
+            assert_eq!(
+                exchange(&mut stream, &format!("{}ffffffffffffffff,10", xml)),
+                "l"
+            );
+
+            stream.write_all(b"$k#6b").unwrap();
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+        });
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(serve_connection(inferior, stream), Ok(()));
+        client.join().unwrap();
+    }
+}
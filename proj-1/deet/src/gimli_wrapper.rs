@@ -10,13 +10,19 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{File, Function, Line, Location, Member, Type, TypeKind, TypeRef, Variable};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+/// Loads the compilation units and types of `object`, whose id (see DwarfData::id) pointer types
+/// refer to their pointee by.
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+    object_id: usize,
+) -> Result<(Vec<File>, HashMap<usize, Type>), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -39,8 +45,13 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
 
-    // Define a mapping from type offsets to type structs
-    let mut offset_to_type: HashMap<usize, Type> = HashMap::new();
+    // Define a mapping from type offsets to type structs. Types may refer to types defined after
+    // them, so they are all collected before anything else is loaded.
+    let raw_types = load_raw_types(&dwarf)?;
+    let offset_to_type: HashMap<usize, Type> = raw_types
+        .keys()
+        .map(|offset| (*offset, resolve_type(*offset, &raw_types, object_id, 0)))
+        .collect();
 
     let mut compilation_units: Vec<File> = Vec::new();
 
@@ -67,6 +78,16 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                     } else {
                         "<unknown>".to_string()
                     };
+                    // Line programs give full paths, so make relative names absolute to match
+                    let name = match unit.comp_dir {
+                        Some(ref comp_dir) if !name.starts_with('/') => {
+                            let mut full_path =
+                                path::PathBuf::from(comp_dir.to_string_lossy().as_ref());
+                            full_path.push(&name);
+                            full_path.to_string_lossy().into_owned()
+                        }
+                        _ => name,
+                    };
                     compilation_units.push(File {
                         name,
                         global_variables: Vec::new(),
@@ -74,33 +95,6 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -129,6 +123,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_type => {
+                                if let Ok(DebugValue::Size(offset)) = val {
+                                    func.return_type = offset_to_type.get(&offset).cloned();
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -175,6 +174,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             entity_type: entity_type.unwrap(),
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
+                            is_parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                         };
                         if depth == 1 {
                             compilation_units
@@ -240,7 +240,301 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+    Ok((compilation_units, offset_to_type))
+}
+
+/// A type DIE, with references to other types left as .debug_info offsets.
+struct RawType {
+    tag: gimli::DwTag,
+    name: Option<String>,
+    byte_size: Option<usize>,
+    // DW_AT_type: the pointee, element, typedef target, etc.
+    target: Option<usize>,
+    // Struct and union members: name, offset and type
+    members: Vec<(String, usize, usize)>,
+    enumerators: Vec<(String, i64)>,
+    // Element count of each dimension of an array, if known
+    dimensions: Vec<Option<usize>>,
+}
+
+fn is_type_tag(tag: gimli::DwTag) -> bool {
+    match tag {
+        gimli::DW_TAG_base_type
+        | gimli::DW_TAG_pointer_type
+        | gimli::DW_TAG_structure_type
+        | gimli::DW_TAG_union_type
+        | gimli::DW_TAG_array_type
+        | gimli::DW_TAG_enumeration_type
+        | gimli::DW_TAG_typedef
+        | gimli::DW_TAG_const_type
+        | gimli::DW_TAG_volatile_type
+        | gimli::DW_TAG_subroutine_type => true,
+        _ => false,
+    }
+}
+
+/// Collects every type DIE in the file, along with the members, enumerators and array bounds
+/// that are their children.
+fn load_raw_types<R: Reader>(dwarf: &gimli::Dwarf<R>) -> Result<HashMap<usize, RawType>, Error> {
+    let mut raw_types: HashMap<usize, RawType> = HashMap::new();
+    let mut iter = dwarf.units();
+    while let Some(header) = iter.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut depth = 0;
+        // The innermost type DIE we're inside of, and its depth
+        let mut parents: Vec<(isize, usize)> = Vec::new();
+        let mut entries = unit.entries();
+        while let Some((delta_depth, entry)) = entries.next_dfs()? {
+            depth += delta_depth;
+            while parents
+                .last()
+                .map_or(false, |(parent_depth, _)| *parent_depth >= depth)
+            {
+                parents.pop();
+            }
+
+            let name = match entry.attr(gimli::DW_AT_name)? {
+                Some(attr) => match get_attr_value(&attr, &unit, dwarf) {
+                    Ok(DebugValue::Str(name)) => Some(name),
+                    _ => None,
+                },
+                None => None,
+            };
+            let target = match entry.attr(gimli::DW_AT_type)? {
+                Some(attr) => match get_attr_value(&attr, &unit, dwarf) {
+                    Ok(DebugValue::Size(offset)) => Some(offset),
+                    _ => None,
+                },
+                None => None,
+            };
+            let udata = |at| -> Result<Option<u64>, Error> {
+                Ok(entry.attr(at)?.and_then(|attr| attr.udata_value()))
+            };
+
+            if is_type_tag(entry.tag()) {
+                let offset = get_section_offset(entry.offset(), &unit);
+                let byte_size = udata(gimli::DW_AT_byte_size)?.map(|size| size as usize);
+                raw_types.insert(
+                    offset,
+                    RawType {
+                        tag: entry.tag(),
+                        name,
+                        byte_size,
+                        target,
+                        members: Vec::new(),
+                        enumerators: Vec::new(),
+                        dimensions: Vec::new(),
+                    },
+                );
+                parents.push((depth, offset));
+                continue;
+            }
+
+            let parent = match parents.last() {
+                Some((parent_depth, offset)) if *parent_depth == depth - 1 => {
+                    raw_types.get_mut(offset).unwrap()
+                }
+                _ => continue,
+            };
+            match entry.tag() {
+                gimli::DW_TAG_member => {
+                    // Union members have no location, they all start at 0
+                    let member_offset = udata(gimli::DW_AT_data_member_location)?.unwrap_or(0);
+                    if let (Some(name), Some(target)) = (name, target) {
+                        parent.members.push((name, member_offset as usize, target));
+                    }
+                }
+                gimli::DW_TAG_enumerator => {
+                    let value = match entry.attr(gimli::DW_AT_const_value)? {
+                        Some(attr) => match attr.value() {
+                            gimli::AttributeValue::Sdata(value) => Some(value),
+                            _ => attr.udata_value().map(|value| value as i64),
+                        },
+                        None => None,
+                    };
+                    if let (Some(name), Some(value)) = (name, value) {
+                        parent.enumerators.push((name, value));
+                    }
+                }
+                gimli::DW_TAG_subrange_type => {
+                    // `int x[]` has neither
+                    let count = match udata(gimli::DW_AT_count)? {
+                        Some(count) => Some(count as usize),
+                        None => match entry.attr(gimli::DW_AT_upper_bound)? {
+                            Some(attr) => match attr.value() {
+                                gimli::AttributeValue::Sdata(bound) if bound < 0 => None,
+                                // `int x[0]` has an upper bound of -1 stored unsigned
+                                _ => attr
+                                    .udata_value()
+                                    .map(|bound| (bound as usize).checked_add(1).unwrap_or(0)),
+                            },
+                            None => None,
+                        },
+                    };
+                    parent.dimensions.push(count);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(raw_types)
+}
+
+/// Returns the C name of the type at `offset`, e.g. `struct node *` or `int [4]`.
+fn type_name(offset: Option<usize>, raw_types: &HashMap<usize, RawType>) -> String {
+    let raw = match offset.and_then(|offset| raw_types.get(&offset)) {
+        Some(raw) => raw,
+        None => return "void".to_string(),
+    };
+    let tag_name = |prefix: &str| match raw.name {
+        Some(ref name) => format!("{} {}", prefix, name),
+        None => format!("{} {{...}}", prefix),
+    };
+    match raw.tag {
+        gimli::DW_TAG_pointer_type => {
+            let pointee = type_name(raw.target, raw_types);
+            if pointee.ends_with('*') {
+                format!("{}*", pointee)
+            } else if pointee.ends_with(')') {
+                // Function pointers put the * in the middle: int (*)(int)
+                pointee.replacen(" (", " (*)(", 1)
+            } else {
+                format!("{} *", pointee)
+            }
+        }
+        gimli::DW_TAG_structure_type => tag_name("struct"),
+        gimli::DW_TAG_union_type => tag_name("union"),
+        gimli::DW_TAG_enumeration_type => tag_name("enum"),
+        gimli::DW_TAG_const_type => format!("const {}", type_name(raw.target, raw_types)),
+        gimli::DW_TAG_volatile_type => format!("volatile {}", type_name(raw.target, raw_types)),
+        gimli::DW_TAG_array_type => {
+            let dimensions: String = raw
+                .dimensions
+                .iter()
+                .map(|count| match count {
+                    Some(count) => format!("[{}]", count),
+                    None => "[]".to_string(),
+                })
+                .collect();
+            format!("{} {}", type_name(raw.target, raw_types), dimensions)
+        }
+        gimli::DW_TAG_subroutine_type => format!("{} ()", type_name(raw.target, raw_types)),
+        _ => raw.name.clone().unwrap_or_else(|| "<unknown>".to_string()),
+    }
+}
+
+/// Builds the type tree for the type at `offset`. Pointers refer to their pointee by offset
+/// instead of containing it, since structs can point to themselves.
+fn resolve_type(
+    offset: usize,
+    raw_types: &HashMap<usize, RawType>,
+    object_id: usize,
+    depth: usize,
+) -> Type {
+    let raw = &raw_types[&offset];
+    let name = type_name(Some(offset), raw_types);
+    // Types can only nest this deeply through a loop, which isn't valid C. Pointers don't resolve
+    // their target, or every struct in a linked list would be expanded over and over.
+    let target = match raw.target {
+        Some(target)
+            if depth < 64
+                && raw.tag != gimli::DW_TAG_pointer_type
+                && raw_types.contains_key(&target) =>
+        {
+            Some(resolve_type(target, raw_types, object_id, depth + 1))
+        }
+        _ => None,
+    };
+    let size = raw.byte_size.unwrap_or(0);
+
+    match raw.tag {
+        gimli::DW_TAG_pointer_type => Type {
+            name,
+            size: raw.byte_size.unwrap_or(8),
+            kind: TypeKind::Pointer(raw.target.map(|offset| TypeRef {
+                object: object_id,
+                offset,
+            })),
+        },
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => {
+            let members = raw
+                .members
+                .iter()
+                .filter(|(_, _, member_type)| raw_types.contains_key(member_type))
+                .map(|(member_name, member_offset, member_type)| Member {
+                    name: member_name.clone(),
+                    offset: *member_offset,
+                    member_type: resolve_type(*member_type, raw_types, object_id, depth + 1),
+                })
+                .collect();
+            Type {
+                name,
+                size,
+                kind: TypeKind::Struct(members),
+            }
+        }
+        gimli::DW_TAG_enumeration_type => Type {
+            name,
+            size,
+            kind: TypeKind::Enum(raw.enumerators.clone()),
+        },
+        gimli::DW_TAG_array_type => {
+            // int x[2][3] is an array of 2 arrays of 3 ints
+            let base = target.unwrap_or_default();
+            let dimension_names: Vec<String> = raw
+                .dimensions
+                .iter()
+                .map(|count| match count {
+                    Some(count) => format!("[{}]", count),
+                    None => "[]".to_string(),
+                })
+                .collect();
+            let mut element = base.clone();
+            for i in (1..raw.dimensions.len()).rev() {
+                let count = raw.dimensions[i];
+                element = Type {
+                    name: format!("{} {}", base.name, dimension_names[i..].concat()),
+                    size: array_size(element.size, count),
+                    kind: TypeKind::Array(Box::new(element), count),
+                };
+            }
+            let count = raw.dimensions.first().cloned().unwrap_or(None);
+            Type {
+                name,
+                size: array_size(element.size, count),
+                kind: TypeKind::Array(Box::new(element), count),
+            }
+        }
+        gimli::DW_TAG_typedef => {
+            let target = target.unwrap_or_default();
+            Type {
+                name,
+                size: target.size,
+                kind: TypeKind::Typedef(Box::new(target)),
+            }
+        }
+        // Qualifiers don't change how a value is read, so they are looked through like typedefs
+        gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+            let target = target.unwrap_or_default();
+            Type {
+                name,
+                size: target.size,
+                kind: TypeKind::Typedef(Box::new(target)),
+            }
+        }
+        gimli::DW_TAG_subroutine_type => Type {
+            name,
+            size: 0,
+            kind: TypeKind::Function,
+        },
+        _ => Type::new(name, size),
+    }
+}
+
+/// Returns the size of an array of `count` elements of `element_size` bytes, which is 0 when the
+/// count is unknown or the bounds are too large to make sense.
+fn array_size(element_size: usize, count: Option<usize>) -> usize {
+    element_size.checked_mul(count.unwrap_or(0)).unwrap_or(0)
 }
 
 #[derive(Debug, Clone)]
@@ -291,6 +585,15 @@ impl<'input, Endian> Reader for gimli::EndianSlice<'input, Endian> where
 
 trait Reader: gimli::Reader<Offset = usize> + Send + Sync {}
 
+/// Converts a unit-relative DIE offset into an offset into .debug_info, which is what type
+/// references resolve to (see the UnitRef case in get_attr_value).
+fn get_section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(goff) => goff.0,
+        UnitSectionOffset::DebugTypesOffset(goff) => goff.0,
+    }
+}
+
 fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) -> Option<Location> {
     if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
         let encoding = unit.encoding();
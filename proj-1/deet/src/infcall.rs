@@ -0,0 +1,155 @@
+//! Calls to the inferior's functions from expressions, e.g. `print square(3)`. The call is set up
+//! the way the System V x86-64 ABI says: integer and pointer arguments go in rdi, rsi, rdx, rcx,
+//! r8 and r9, floating point ones in xmm0-7, and any more on the stack, under a return address
+//! where a trap is placed. Only the current thread runs, until the function returns to the trap,
+//! and then its registers are put back the way they were. Running it is up to the caller, which
+//! deals with what else can happen on the way, like forks.
+
+use crate::inferior::{Inferior, Status};
+use crate::process::Process;
+use libc::{user_fpregs_struct, user_regs_struct};
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+// How much of the stack below rsp a function may use without moving rsp, which has to be left
+// alone in case the function being called from is a leaf function using it
+const RED_ZONE: u64 = 128;
+const INTEGER_REGISTERS: usize = 6;
+const SSE_REGISTERS: usize = 8;
+
+/// An argument, in the class the ABI passes it in.
+pub enum Argument {
+    // An integer or pointer, extended to 64 bits
+    Integer(u64),
+    // A float or double, as the bits that go in the low half of an xmm register
+    Sse(u64),
+}
+
+/// What happened to a call.
+pub enum Outcome {
+    /// The function returned, leaving these values in rax and in the low half of xmm0.
+    Returned(u64, u64),
+    /// Something else happened first, e.g. a signal arrived or the process exited. Unless the
+    /// thread or the program it ran is gone, the registers have been put back.
+    Interrupted(Status),
+}
+
+/// A call that has been set up, with what to put back once it is over.
+pub struct Call {
+    tid: Pid,
+    saved_regs: user_regs_struct,
+    saved_fpregs: user_fpregs_struct,
+    return_address: usize,
+    original_byte: Vec<u8>,
+}
+
+/// Sets up a call of the function at `func` with `args` in the current thread, returning to
+/// `return_address`, which has to be code that isn't run otherwise (like the entry point). The
+/// thread is then ready to run it.
+pub fn start(
+    inferior: &Inferior,
+    func: usize,
+    args: &[Argument],
+    return_address: usize,
+) -> Result<Call, nix::Error> {
+    let (_, tid) = inferior.current_thread();
+    let saved_regs = inferior.getregs()?;
+    let saved_fpregs = inferior.getfpregs()?;
+    let mut regs = saved_regs;
+    let mut fpregs = saved_fpregs;
+
+    let mut integers = 0;
+    let mut sses = 0;
+    let mut stack = Vec::new();
+    for arg in args {
+        match *arg {
+            Argument::Integer(value) if integers < INTEGER_REGISTERS => {
+                *integer_register(&mut regs, integers) = value;
+                integers += 1;
+            }
+            Argument::Sse(bits) if sses < SSE_REGISTERS => {
+                // Each xmm register is 4 words of xmm_space
+                fpregs.xmm_space[sses * 4] = bits as u32;
+                fpregs.xmm_space[sses * 4 + 1] = (bits >> 32) as u32;
+                fpregs.xmm_space[sses * 4 + 2] = 0;
+                fpregs.xmm_space[sses * 4 + 3] = 0;
+                sses += 1;
+            }
+            Argument::Integer(value) | Argument::Sse(value) => stack.push(value),
+        }
+    }
+
+    // Arguments on the stack start at a 16 byte boundary, right above the return address
+    let mut sp = (saved_regs.rsp - RED_ZONE - 8 * stack.len() as u64) & !0xf;
+    let stack_bytes: Vec<u8> = stack
+        .iter()
+        .flat_map(|value| value.to_le_bytes().to_vec())
+        .collect();
+    inferior.write_bytes(sp as usize, &stack_bytes)?;
+    sp -= 8;
+    inferior.write_bytes(sp as usize, &(return_address as u64).to_le_bytes())?;
+    regs.rsp = sp;
+    regs.rip = func as u64;
+    // Variadic functions like printf are told how many vector registers hold arguments in al
+    regs.rax = sses as u64;
+    // Otherwise, if the thread is stopped in a system call, the kernel would restart the call by
+    // moving rip back
+    regs.orig_rax = u64::MAX;
+
+    let original_byte = inferior.read_bytes(return_address, 1)?;
+    inferior.write_bytes(return_address, &[0xcc])?;
+    inferior.setregs(regs)?;
+    inferior.setfpregs_of(tid, &fpregs)?;
+    Ok(Call {
+        tid,
+        saved_regs,
+        saved_fpregs,
+        return_address,
+        original_byte,
+    })
+}
+
+/// Ends `call` once the thread running it stopped with `status`: takes the return value if the
+/// function returned, and puts back what the call changed where that is still there.
+pub fn finish(inferior: &Inferior, call: Call, status: Status) -> Result<Outcome, nix::Error> {
+    let outcome = match status {
+        // Nothing is left to put back
+        Status::Exited(_) | Status::Signaled(_) | Status::Execed => {
+            return Ok(Outcome::Interrupted(status))
+        }
+        // The other threads are left as they were, and so is the memory
+        Status::ThreadExited => {
+            inferior.write_bytes(call.return_address, &call.original_byte)?;
+            return Ok(Outcome::Interrupted(status));
+        }
+        Status::Stopped(signal::Signal::SIGTRAP, rip) if rip == call.return_address + 1 => {
+            let xmm0 = inferior.getfpregs()?.xmm_space;
+            Outcome::Returned(
+                inferior.getregs()?.rax,
+                u64::from(xmm0[0]) | u64::from(xmm0[1]) << 32,
+            )
+        }
+        status => Outcome::Interrupted(status),
+    };
+    // Another thread may have stopped the call. After following a fork, this is the child, whose
+    // only thread ran the call.
+    let tid = match inferior.threads().into_iter().find(|(_, tid)| *tid == call.tid) {
+        Some(_) => call.tid,
+        None => inferior.current_thread().1,
+    };
+    inferior.write_bytes(call.return_address, &call.original_byte)?;
+    inferior.setregs_of(tid, call.saved_regs)?;
+    inferior.setfpregs_of(tid, &call.saved_fpregs)?;
+    Ok(outcome)
+}
+
+fn integer_register(regs: &mut user_regs_struct, index: usize) -> &mut u64 {
+    match index {
+        0 => &mut regs.rdi,
+        1 => &mut regs.rsi,
+        2 => &mut regs.rdx,
+        3 => &mut regs.rcx,
+        4 => &mut regs.r8,
+        _ => &mut regs.r9,
+    }
+}
@@ -1,12 +1,35 @@
-use crate::dwarf_data::DwarfData;
+use crate::console_println;
+use crate::interpreter;
+use crate::process::Process;
+use libc::{user_fpregs_struct, user_regs_struct};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::Pid;
+use nix::unistd::{pipe2, Pid};
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::fs::File;
 use std::mem::size_of;
-use std::os::unix::process::CommandExt;
-use std::process::Child;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::ptr;
+
+// Register sets of PTRACE_GETREGSET, from <elf.h>
+const NT_PRFPREG: usize = 2;
+const NT_X86_XSTATE: usize = 0x202;
+// Bigger than the XSAVE area of any processor, of which the kernel tells how much it filled in
+const MAX_XSTATE_SIZE: usize = 1 << 14;
+
+thread_local! {
+    // Results waitpid(-1) collected for children of ours other than the process being waited for,
+    // e.g. a fork child held by `detach-on-fork off`, kept until that process is waited for
+    static OTHER_EVENTS: RefCell<Vec<WaitStatus>> = RefCell::default();
+}
 
 #[derive(Debug)]
 pub enum Status {
@@ -14,161 +37,1127 @@ pub enum Status {
     /// current instruction pointer that it is stopped at.
     Stopped(signal::Signal, usize),
 
+    /// Indicates inferior stopped because a hardware watchpoint fired. Contains the debug register
+    /// (0-3) that fired and the current instruction pointer.
+    Watchpoint(usize, usize),
+
+    /// Indicates the inferior forked. Contains the new process, which starts out traced and
+    /// stopped, and whether it was a vfork, in which case the two share memory until the child
+    /// execs or exits.
+    Forked(Pid, bool),
+
+    /// Indicates the child of a vfork exec'd or exited, so it no longer shares the inferior's
+    /// memory.
+    VforkDone,
+
+    /// Indicates the inferior called exec and is now running a new program.
+    Execed,
+
     /// Indicates inferior exited normally. Contains the exit status code.
     Exited(i32),
 
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the thread running a function called from the debugger exited, while the rest of
+    /// the process goes on.
+    ThreadExited,
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
-    println!("child_traceme is called");
     ptrace::traceme().or(Err(std::io::Error::new(
         std::io::ErrorKind::Other,
         "ptrace TRACEME failed",
     )))
 }
 
+/// Turns off address space randomization like gdb does, so that PIE executables and shared
+/// libraries are loaded at the same addresses on every run, and so are the watchpoints on them.
+fn disable_randomization() -> Result<(), std::io::Error> {
+    // From <sys/personality.h>; 0xffffffff queries the current persona without changing it
+    const ADDR_NO_RANDOMIZE: libc::c_ulong = 0x0040000;
+    let persona = unsafe { libc::personality(0xffff_ffff) };
+    if persona == -1
+        || unsafe { libc::personality(persona as libc::c_ulong | ADDR_NO_RANDOMIZE) } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A thread of the inferior. Threads are numbered from 1 in the order we learn about them.
+struct Thread {
+    num: usize,
+    tid: Pid,
+    // Whether the thread was resumed and hasn't reported a stop since
+    running: bool,
+    // A SIGSTOP is on its way to the thread, either the one new threads start with or one we sent
+    // to stop it. It is swallowed rather than reported.
+    stop_expected: bool,
+    // A stop the thread reported while we were stopping it for another thread's event, to be
+    // reported the next time the inferior is continued
+    pending: Option<Status>,
+    // The signal the thread stopped with, delivered to it when it is resumed
+    signal: Option<signal::Signal>,
+}
+
+/// The x87, SSE and AVX registers of a thread, as read by getxstate.
+pub struct XState {
+    // The register set it was read from: NT_X86_XSTATE, or NT_PRFPREG without XSAVE
+    regset: usize,
+    data: Vec<u8>,
+}
+
+impl XState {
+    /// The size of the register area, which is as much as xsave or fxsave store.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
 pub struct Inferior {
-    child: Child,
+    pid: Pid,
+    // Whether we attached to the process rather than starting it (or forked off one we attached to)
+    attached: bool,
+    threads: Vec<Thread>,
+    next_thread_num: usize,
+    // The thread registers are read from and that is single stepped: the one that reported the
+    // last stop, unless the user switched threads
+    current: Pid,
+    // Thread being single stepped while the others stay stopped, if any
+    stepping: Option<Pid>,
+    // Thread running a function called from an expression, which also runs on its own
+    calling: Option<Pid>,
+    // Debug registers are per thread, so remember what was set to program new threads the same
+    debug_registers: [u64; 8],
+    // New threads start with a SIGSTOP, which may be reported before the event that tells us about
+    // them
+    unclaimed_stops: Vec<Pid>,
+    // Threads an exec ended, whose exits are reported afterwards
+    exec_ended: Vec<Pid>,
 }
 
 impl Inferior {
+    fn with_pid(pid: Pid, attached: bool) -> Inferior {
+        Inferior {
+            pid,
+            attached,
+            threads: Vec::new(),
+            next_thread_num: 1,
+            current: pid,
+            stepping: None,
+            calling: None,
+            debug_registers: [0; 8],
+            unclaimed_stops: Vec::new(),
+            exec_ended: Vec::new(),
+        }
+    }
+
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
     /// an error is encountered.
     pub fn new(target: &str, args: &Vec<String>) -> Option<Inferior> {
-        println!("Inferior::new: target={}, args={:?}", target, args);
-
         let mut cmd = Command::new(target);
         // TODO: why need cmd2
         let cmd2 = cmd.args(args);
         unsafe {
+            cmd2.pre_exec(disable_randomization);
             cmd2.pre_exec(child_traceme);
         }
+        let mut output = None;
+        if interpreter::is_json() {
+            // Keep the program's output apart from the records, and the requests away from it
+            let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).ok()?;
+            let (reader, writer) =
+                unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+            cmd2.stdin(Stdio::null())
+                .stdout(writer.try_clone().ok()?)
+                .stderr(writer);
+            output = Some(reader);
+        }
         let child = cmd2.spawn().ok()?;
-        let inferior = Inferior { child };
+        if let Some(output) = output {
+            interpreter::forward_target_output(output);
+        }
+        let mut inferior = Inferior::with_pid(Pid::from_raw(child.id() as i32), false);
+        inferior.add_thread(inferior.pid, false);
 
-        match inferior.wait(None).unwrap() {
-            Status::Stopped(signal, rip) => {
-                println!("The program is stopped, signal={:?}, rip={:?}", signal, rip);
+        match inferior.wait(None) {
+            Ok(Status::Stopped(..)) => {
+                if ptrace::setoptions(inferior.pid, trace_options()).is_err() {
+                    // Don't leave the child stopped behind with nobody to reap it
+                    let _ = inferior.kill();
+                    return None;
+                }
                 Some(inferior)
             }
-            _other => {
-                println!("The program is not stopped, return None");
+            Ok(Status::Exited(_)) | Ok(Status::Signaled(_)) => None,
+            _ => {
+                let _ = inferior.kill();
                 None
             }
         }
     }
 
-    /// Returns the pid of this inferior.
-    pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+    /// Attaches to the already running process `pid` and all of its threads, which stops them.
+    pub fn attach(pid: Pid) -> Result<Inferior, nix::Error> {
+        ptrace::attach(pid)?;
+        let mut inferior = Inferior::with_pid(pid, true);
+        inferior.add_thread(pid, true);
+        if let Err(err) = inferior.attach_threads() {
+            // Don't leave the process stopped behind. Threads that exited or haven't stopped yet
+            // can't be detached from, and are let go when we exit.
+            for thread in inferior.threads.iter() {
+                let _ = ptrace::detach(thread.tid, None);
+            }
+            return Err(err);
+        }
+        Ok(inferior)
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
-            WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
-            WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                println!("[debug] the child is stopped, rip=#{}", regs.rip);
-                Status::Stopped(signal, regs.rip as usize)
+    /// Attaches to the threads of the process besides the main one, and checks that they can be
+    /// controlled.
+    fn attach_threads(&mut self) -> Result<(), nix::Error> {
+        // Threads have to be attached one by one, and may create more threads meanwhile, so keep
+        // going until there are no new ones
+        loop {
+            // PTRACE_ATTACH sends each thread a SIGSTOP, wait for them to arrive
+            self.stop_all_threads()?;
+            let new_threads: Vec<Pid> = list_threads(self.pid)?
+                .into_iter()
+                .filter(|tid| self.thread(*tid).is_none())
+                .collect();
+            if new_threads.is_empty() {
+                break;
             }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
-        })
+            for tid in new_threads {
+                // The thread may have exited since we listed it
+                if ptrace::attach(tid).is_ok() {
+                    self.add_thread(tid, true);
+                }
+            }
+        }
+        if self.thread(self.pid).is_none() {
+            return Err(nix::Error::Sys(Errno::ESRCH));
+        }
+        for thread in self.threads.iter() {
+            ptrace::setoptions(thread.tid, trace_options())?;
+        }
+        // The process may be a zombie, whose registers can't be read
+        self.getregs()?;
+        Ok(())
     }
 
-    pub fn cont(&self) -> Result<Status, nix::Error> {
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
+    /// Stops tracing the inferior and lets it continue running. Any breakpoints or watchpoints
+    /// must have been removed first.
+    pub fn detach(self) -> Result<(), nix::Error> {
+        for thread in self.threads.iter() {
+            ptrace::detach(thread.tid, thread.signal)?;
+        }
+        Ok(())
     }
 
-    pub fn kill(&mut self) -> Result<std::process::ExitStatus, std::io::Error> {
-        match self.child.kill() {
-            Ok(_) => {
-                let status = self.child.wait()?;
-                Ok(status)
+    /// Returns whether the inferior was attached to rather than started by us.
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Takes control of `child`, which the inferior just forked. Like new threads, it starts out
+    /// traced and about to stop, and inherits breakpoints but not debug registers.
+    pub fn fork_child(&mut self, child: Pid) -> Result<Inferior, nix::Error> {
+        // The child's stop may have been collected while waiting for the inferior
+        let stopped = take_other_event(|status| match status {
+            WaitStatus::Stopped(tid, signal::Signal::SIGSTOP) => *tid == child,
+            _ => false,
+        });
+        if stopped.is_none() {
+            loop {
+                if let WaitStatus::Stopped(_, signal::Signal::SIGSTOP) =
+                    waitpid(child, Some(WaitPidFlag::__WALL))?
+                {
+                    break;
+                }
             }
-            Err(err) => Err(err),
+        }
+        let mut inferior = Inferior::with_pid(child, self.attached);
+        inferior.add_thread(child, false);
+        inferior.thread_mut(child).unwrap().running = false;
+        Ok(inferior)
+    }
+
+    /// Returns whether the current thread has a handler installed for `signal`, as opposed to
+    /// ignoring it or taking the default action.
+    pub fn catches_signal(&self, signal: signal::Signal) -> bool {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.current))
+            .unwrap_or_default();
+        let caught = status
+            .lines()
+            .find(|line| line.starts_with("SigCgt:"))
+            .and_then(|line| u64::from_str_radix(line["SigCgt:".len()..].trim(), 16).ok())
+            .unwrap_or(0);
+        caught & (1 << (signal as u64 - 1)) != 0
+    }
+
+    /// Discards the signal the current thread stopped with instead of delivering it when the
+    /// thread is resumed.
+    pub fn discard_signal(&mut self) {
+        let current = self.current;
+        if let Some(thread) = self.thread_mut(current) {
+            thread.signal = None;
+        }
+    }
+
+    /// Takes the signal the current thread stopped with, so that it isn't delivered until it's
+    /// put back with set_signal.
+    pub fn take_signal(&mut self) -> Option<signal::Signal> {
+        let current = self.current;
+        self.thread_mut(current)
+            .and_then(|thread| thread.signal.take())
+    }
+
+    /// Makes the current thread receive the given signal when it's resumed.
+    pub fn set_signal(&mut self, signal: Option<signal::Signal>) {
+        let current = self.current;
+        if let Some(thread) = self.thread_mut(current) {
+            thread.signal = signal;
+        }
+    }
+
+    fn thread(&self, tid: Pid) -> Option<&Thread> {
+        self.threads.iter().find(|thread| thread.tid == tid)
+    }
+
+    fn thread_mut(&mut self, tid: Pid) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.tid == tid)
+    }
+
+    fn add_thread(&mut self, tid: Pid, stop_expected: bool) {
+        self.threads.push(Thread {
+            num: self.next_thread_num,
+            tid,
+            running: true,
+            stop_expected,
+            pending: None,
+            signal: None,
+        });
+        self.next_thread_num += 1;
+    }
+
+    /// Adds a thread we were told about by a clone event. Its initial stop may already have been
+    /// seen, in which case it is left stopped.
+    fn add_new_thread(&mut self, tid: Pid) -> Result<(), nix::Error> {
+        // The thread may have stopped for something else before its clone event arrived, see
+        // handle_wait_status
+        if self.thread(tid).is_some() {
+            return Ok(());
+        }
+        console_println!("[New Thread {}]", tid);
+        match self.unclaimed_stops.iter().position(|pid| *pid == tid) {
+            Some(index) => {
+                self.unclaimed_stops.remove(index);
+                self.add_thread(tid, false);
+                self.thread_mut(tid).unwrap().running = false;
+                self.copy_debug_registers(tid)
+            }
+            None => {
+                self.add_thread(tid, true);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_thread(&mut self, tid: Pid) {
+        self.threads.retain(|thread| thread.tid != tid);
+        if self.current == tid {
+            if let Some(thread) = self.threads.first() {
+                self.current = thread.tid;
+            }
+        }
+        if self.stepping == Some(tid) {
+            self.stepping = None;
+        }
+        if self.calling == Some(tid) {
+            self.calling = None;
         }
     }
 
-    pub fn backtrace(&self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
-        // println!("backtrace is called");
-        let regs = ptrace::getregs(self.pid())?;
-        let mut instruction_ptr = regs.rip;
-        // start of current frame
+    /// Waits for a thread of the inferior to stop or for the process to exit, and returns a Status
+    /// to indicate what happened. Once a thread stops, all others are stopped as well and the
+    /// stopped thread becomes the current thread.
+    pub fn wait(&mut self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
+        let flags = options.unwrap_or_else(WaitPidFlag::empty) | WaitPidFlag::__WALL;
+        loop {
+            let status = self.waitpid_any(flags)?;
+            if let Some(status) = self.handle_wait_status(status)? {
+                match status {
+                    Status::Exited(_) | Status::Signaled(_) => {}
+                    _ => self.stop_all_threads()?,
+                }
+                return Ok(status);
+            }
+        }
+    }
 
-        // memory: high ---> low
-        // frame:  start_of_frame(frame top) ............ end_of_frame(bottom of frame)
-        //              /\                                     /\
-        //               |                                      |
-        //              rbp                                    rsp
-        let mut frame_ptr = regs.rbp;
-        // TODO: consider better error handling
+    /// waitpid for whichever thread of the inferior has something to report. Waiting for any of
+    /// them takes waitpid(-1), which reports on every child of ours: results for other processes
+    /// are kept for when those are waited for rather than taken for new threads of this one.
+    fn waitpid_any(&self, flags: WaitPidFlag) -> Result<WaitStatus, nix::Error> {
+        let ours = take_other_event(|status| status.pid().filter(|tid| self.owns(*tid)).is_some());
+        if let Some(status) = ours {
+            return Ok(status);
+        }
         loop {
-            let line = debug_data
-                .get_line_from_addr(instruction_ptr as usize)
-                .unwrap();
-            let func = debug_data
-                .get_function_from_addr(instruction_ptr as usize)
-                .unwrap();
+            let status = waitpid(Pid::from_raw(-1), Some(flags))?;
+            match status.pid() {
+                Some(tid) if !self.owns(tid) => {
+                    OTHER_EVENTS.with(|events| events.borrow_mut().push(status))
+                }
+                _ => return Ok(status),
+            }
+        }
+    }
 
-            println!("{} ({}:{})", func, line.file, line.number);
+    /// Whether thread `tid` belongs to the inferior, including new threads we haven't been told
+    /// about yet. Forked children don't, until fork_child takes them on.
+    fn owns(&self, tid: Pid) -> bool {
+        tid == self.pid
+            || self.thread(tid).is_some()
+            || self.unclaimed_stops.contains(&tid)
+            || self.exec_ended.contains(&tid)
+            || thread_group(tid) == Some(self.pid)
+    }
 
-            if func == "main" {
-                break;
+    /// Updates the thread list for a waitpid result, and returns the Status to report if the
+    /// result is a stop the user should see rather than thread bookkeeping.
+    fn handle_wait_status(&mut self, status: WaitStatus) -> Result<Option<Status>, nix::Error> {
+        match status {
+            // The process only exits once all other threads are gone
+            WaitStatus::Exited(tid, exit_code) if tid == self.pid => {
+                self.threads.clear();
+                return Ok(Some(Status::Exited(exit_code)));
+            }
+            WaitStatus::Signaled(tid, signal, _core_dumped) if tid == self.pid => {
+                self.threads.clear();
+                return Ok(Some(Status::Signaled(signal)));
+            }
+            // Threads that exec wiped out are reported afterwards
+            WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, ..)
+                if self.thread(tid).is_none() =>
+            {
+                self.exec_ended.retain(|ended| *ended != tid);
             }
+            WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, ..) => {
+                let calling = self.calling == Some(tid);
+                self.remove_thread(tid);
+                console_println!("[Thread {} exited]", tid);
+                // The function it was running won't return
+                if calling {
+                    return Ok(Some(Status::ThreadExited));
+                }
+                // If the thread being stepped is gone, nothing is left running
+                if self.threads.iter().all(|thread| !thread.running) {
+                    self.resume_all()?;
+                }
+            }
+            WaitStatus::PtraceEvent(tid, _, event) if event == libc::PTRACE_EVENT_CLONE => {
+                let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                self.add_new_thread(new_tid)?;
+                if self.stepping.is_none() && self.thread(new_tid).map_or(false, |t| !t.running) {
+                    self.resume(new_tid)?;
+                }
+                self.resume(tid)?;
+            }
+            WaitStatus::PtraceEvent(tid, _, event) => {
+                self.add_unknown_thread(tid)?;
+                match self.event_status(tid, event)? {
+                    Some(status) => return Ok(Some(status)),
+                    None => self.resume(tid)?,
+                }
+            }
+            // A new thread whose clone event hasn't arrived yet
+            WaitStatus::Stopped(tid, signal::Signal::SIGSTOP) if self.thread(tid).is_none() => {
+                self.unclaimed_stops.push(tid);
+            }
+            WaitStatus::Stopped(tid, signal::Signal::SIGSTOP)
+                if self.thread(tid).map_or(false, |thread| thread.stop_expected) =>
+            {
+                if let Some(thread) = self.thread_mut(tid) {
+                    thread.stop_expected = false;
+                    thread.running = false;
+                }
+                self.copy_debug_registers(tid)?;
+                // Threads created while one thread is single stepped stay stopped with the rest
+                if self.stepping.is_none() {
+                    self.resume(tid)?;
+                }
+            }
+            WaitStatus::Stopped(tid, signal) => {
+                self.add_unknown_thread(tid)?;
+                if let Some(thread) = self.thread_mut(tid) {
+                    thread.running = false;
+                    thread.signal = deliverable(signal);
+                }
+                // Whatever stopped the thread being single stepped, the step is over, and so is
+                // a called function
+                if self.stepping == Some(tid) {
+                    self.stepping = None;
+                }
+                if self.calling == Some(tid) {
+                    self.calling = None;
+                }
+                self.current = tid;
+                return Ok(Some(self.stop_status(tid, signal)?));
+            }
+            // We don't trace system calls, so these stops are only carried on from
+            WaitStatus::PtraceSyscall(tid) if self.thread(tid).is_some() => self.resume(tid)?,
+            WaitStatus::PtraceSyscall(_) | WaitStatus::Continued(_) | WaitStatus::StillAlive => {}
+        }
+        Ok(None)
+    }
+
+    /// Registers thread `tid` if it reported a stop before the clone event that tells us about it,
+    /// which waitpid with __WALL may do.
+    fn add_unknown_thread(&mut self, tid: Pid) -> Result<(), nix::Error> {
+        if self.thread(tid).is_some() {
+            return Ok(());
+        }
+        console_println!("[New Thread {}]", tid);
+        self.add_thread(tid, false);
+        self.copy_debug_registers(tid)
+    }
+
+    /// Updates the thread list for a fork, vfork or exec event reported by thread `tid`, and builds
+    /// the Status for it. Returns None for events we didn't ask for, which are carried on from.
+    fn event_status(
+        &mut self,
+        tid: Pid,
+        event: libc::c_int,
+    ) -> Result<Option<Status>, nix::Error> {
+        let status = match event {
+            libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK => Status::Forked(
+                Pid::from_raw(ptrace::getevent(tid)? as i32),
+                event == libc::PTRACE_EVENT_VFORK,
+            ),
+            libc::PTRACE_EVENT_VFORK_DONE => Status::VforkDone,
+            libc::PTRACE_EVENT_EXEC => {
+                // Exec ends every other thread, and the thread that called it takes over the
+                // leader's id. The new program starts without watchpoints.
+                let pid = self.pid;
+                let ended = self.threads.iter().map(|thread| thread.tid);
+                self.exec_ended.extend(ended.filter(|tid| *tid != pid));
+                self.threads.retain(|thread| thread.tid == pid);
+                if self.threads.is_empty() {
+                    self.add_thread(pid, false);
+                }
+                let leader = self.thread_mut(pid).unwrap();
+                leader.stop_expected = false;
+                leader.pending = None;
+                self.stepping = None;
+                // A thread running a called function took the leader's id, see end_call
+                if self.calling.is_some() {
+                    self.calling = Some(pid);
+                }
+                self.debug_registers = [0; 8];
+                Status::Execed
+            }
+            _ => return Ok(None),
+        };
+        let tid = if event == libc::PTRACE_EVENT_EXEC {
+            self.pid
+        } else {
+            tid
+        };
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.running = false;
+        }
+        self.current = tid;
+        Ok(Some(status))
+    }
+
+    /// Builds the Status for thread `tid` stopping with `signal`.
+    fn stop_status(&self, tid: Pid, signal: signal::Signal) -> Result<Status, nix::Error> {
+        let regs = ptrace::getregs(tid)?;
+        if signal == signal::Signal::SIGTRAP {
+            if let Some(slot) = self.take_watchpoint_hit(tid)? {
+                return Ok(Status::Watchpoint(slot, regs.rip as usize));
+            }
+        }
+        Ok(Status::Stopped(signal, regs.rip as usize))
+    }
+
+    /// Stops every thread that is still running by sending it a SIGSTOP, and waits until they all
+    /// are stopped. Threads that stop for another reason first keep that stop pending.
+    fn stop_all_threads(&mut self) -> Result<(), nix::Error> {
+        loop {
+            let (tid, stop_expected) = match self.threads.iter().find(|thread| thread.running) {
+                Some(thread) => (thread.tid, thread.stop_expected),
+                None => return Ok(()),
+            };
+            if !stop_expected {
+                tgkill(self.pid, tid, signal::Signal::SIGSTOP)?;
+                if let Some(thread) = self.thread_mut(tid) {
+                    thread.stop_expected = true;
+                }
+            }
+
+            match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self.remove_thread(tid);
+                    console_println!("[Thread {} exited]", tid);
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == libc::PTRACE_EVENT_CLONE => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    self.add_new_thread(new_tid)?;
+                    // The SIGSTOP we sent is still on its way
+                    if let Some(thread) = self.thread_mut(tid) {
+                        thread.running = false;
+                    }
+                }
+                WaitStatus::PtraceEvent(_, _, event) => {
+                    // The thread that reported the stop being handled stays current
+                    let current = self.current;
+                    let status = match self.event_status(tid, event)? {
+                        Some(status) => status,
+                        // Let the thread carry on until the SIGSTOP we sent arrives
+                        None => {
+                            ptrace::cont(tid, None)?;
+                            continue;
+                        }
+                    };
+                    let stopped = self.current;
+                    if let Some(thread) = self.thread_mut(stopped) {
+                        thread.pending = Some(status);
+                    }
+                    if self.thread(current).is_some() {
+                        self.current = current;
+                    }
+                }
+                WaitStatus::Stopped(_, signal::Signal::SIGSTOP) => {
+                    if let Some(thread) = self.thread_mut(tid) {
+                        thread.stop_expected = false;
+                        thread.running = false;
+                    }
+                    self.copy_debug_registers(tid)?;
+                }
+                WaitStatus::Stopped(_, signal) => {
+                    let status = self.stop_status(tid, signal)?;
+                    if let Some(thread) = self.thread_mut(tid) {
+                        thread.pending = Some(status);
+                        thread.running = false;
+                        thread.signal = deliverable(signal);
+                    }
+                }
+                // Let the thread carry on until the SIGSTOP we sent arrives
+                WaitStatus::PtraceSyscall(_) => ptrace::cont(tid, None)?,
+                WaitStatus::Continued(_) | WaitStatus::StillAlive => {}
+            }
+        }
+    }
 
-            // get return address base on rbp
-            instruction_ptr =
-                ptrace::read(self.pid(), (frame_ptr + 8) as ptrace::AddressType)? as u64;
+    /// Lets a stopped thread carry on with whatever it was doing.
+    fn resume(&mut self, tid: Pid) -> Result<(), nix::Error> {
+        let stepping = self.stepping == Some(tid);
+        let thread = self
+            .thread_mut(tid)
+            .ok_or(nix::Error::Sys(Errno::ESRCH))?;
+        let signal = thread.signal.take();
+        if stepping {
+            ptrace::step(tid, signal)?;
+        } else {
+            ptrace::cont(tid, signal)?;
+        }
+        thread.running = true;
+        Ok(())
+    }
 
-            // walk stacks throuh the rbp
-            frame_ptr = ptrace::read(self.pid(), frame_ptr as ptrace::AddressType)? as u64;
+    fn resume_all(&mut self) -> Result<(), nix::Error> {
+        self.stepping = None;
+        self.calling = None;
+        let stopped: Vec<Pid> = self
+            .threads
+            .iter()
+            .filter(|thread| !thread.running)
+            .map(|thread| thread.tid)
+            .collect();
+        for tid in stopped {
+            self.resume(tid)?;
         }
+        Ok(())
+    }
+
+    /// Resumes all threads and waits for the next stop. A stop that happened while the threads
+    /// were being stopped last time is reported first, without running anything.
+    pub fn cont(&mut self) -> Result<Status, nix::Error> {
+        if let Some(thread) = self
+            .threads
+            .iter_mut()
+            .find(|thread| thread.pending.is_some())
+        {
+            self.current = thread.tid;
+            return Ok(thread.pending.take().unwrap());
+        }
+        self.resume_all()?;
+        self.wait(None)
+    }
+
+    /// Resumes just the current thread to run a function called from an expression, leaving the
+    /// others stopped, and waits for the next stop. Threads the function creates run as well, in
+    /// case it waits for them. If the thread exits, that is Status::ThreadExited.
+    pub fn run_current_thread(&mut self) -> Result<Status, nix::Error> {
+        self.calling = Some(self.current);
+        self.resume(self.current)?;
+        self.wait(None)
+    }
+
+    /// Whether a thread went off to run a called function (see run_current_thread) and hasn't
+    /// stopped since.
+    pub fn in_call(&self) -> bool {
+        self.calling.is_some()
+    }
+
+    /// Gives up on the called function a thread is running, which an exec took away. Returns
+    /// whether there was one.
+    pub fn end_call(&mut self) -> bool {
+        self.calling.take().is_some()
+    }
 
+    /// Resumes the inferior after it reported a fork or exec, carrying on with whatever it was
+    /// doing (continuing, single stepping or running a called function), and waits for the next
+    /// stop.
+    pub fn resume_after_event(&mut self) -> Result<Status, nix::Error> {
+        match self.stepping.or(self.calling) {
+            Some(tid) => self.resume(tid)?,
+            None => self.resume_all()?,
+        }
         self.wait(None)
     }
 
+    /// Collects the exit of a process that died while we weren't waiting for it, e.g. from a
+    /// SIGKILL someone else sent while it was stopped, which makes ptrace calls fail with ESRCH.
+    /// Returns None if the process is still around.
+    pub fn reap(&mut self) -> Result<Option<Status>, nix::Error> {
+        let flags = Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL);
+        // The exit may have been collected while waiting for another process
+        let exit = take_other_event(|status| match status {
+            WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, ..) => *tid == self.pid,
+            _ => false,
+        });
+        let status = match exit {
+            Some(status) => status,
+            None => {
+                // The thread group leader is only reported once the other threads are gone
+                for thread in self.threads.iter().filter(|thread| thread.tid != self.pid) {
+                    let _ = waitpid(thread.tid, flags);
+                }
+                waitpid(self.pid, flags)?
+            }
+        };
+        let status = match status {
+            WaitStatus::Exited(_, exit_code) => Status::Exited(exit_code),
+            WaitStatus::Signaled(_, signal, _core_dumped) => Status::Signaled(signal),
+            _ => return Ok(None),
+        };
+        self.threads.clear();
+        Ok(Some(status))
+    }
+
+    pub fn kill(&mut self) -> Result<std::process::ExitStatus, std::io::Error> {
+        // std::process can't reap the process once its threads are traced (or when it isn't our
+        // child at all), so kill it and wait for the exit ourselves
+        let to_io_error = |err: nix::Error| std::io::Error::new(std::io::ErrorKind::Other, err);
+        signal::kill(self.pid, signal::Signal::SIGKILL).map_err(to_io_error)?;
+        loop {
+            // Stops for signals that were already pending may be reported before the SIGKILL, and
+            // every thread reports its own exit
+            match self.waitpid_any(WaitPidFlag::__WALL).map_err(to_io_error)? {
+                WaitStatus::Exited(tid, exit_code) if tid == self.pid => {
+                    self.threads.clear();
+                    return Ok(ExitStatus::from_raw(exit_code << 8));
+                }
+                WaitStatus::Signaled(tid, signal, _core_dumped) if tid == self.pid => {
+                    self.threads.clear();
+                    return Ok(ExitStatus::from_raw(signal as i32));
+                }
+                _ => {}
+            }
+        }
+    }
+
     pub fn breakpoint(&mut self, addr: usize) -> Result<u8, nix::Error> {
         self.write_byte(addr, 204)
     }
 
     // TODO: use gdb to go through this fun
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
-        println!("[debug][write_byte] addr={}", addr);
         let aligned_addr = align_addr_to_word(addr);
         let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
+        let word = self.read_word(aligned_addr)?;
         let orig_byte = (word >> 8 * byte_offset) & 0xff;
         let masked_word = word & !(0xff << 8 * byte_offset);
         let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
         ptrace::write(
-            self.pid(),
+            self.current,
             aligned_addr as ptrace::AddressType,
             updated_word as *mut std::ffi::c_void,
         )?;
         Ok(orig_byte as u8)
     }
 
+    /// Writes `bytes` at `addr`, which doesn't need to be word aligned. Unlike write_byte, this
+    /// is for data rather than breakpoints.
+    pub fn write_bytes(&self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        let end = addr
+            .checked_add(bytes.len())
+            .ok_or(nix::Error::Sys(Errno::EIO))?;
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let mut word = self.read_word(word_addr)?.to_le_bytes();
+            for (i, byte) in word.iter_mut().enumerate() {
+                let byte_addr = word_addr + i;
+                if byte_addr >= addr && byte_addr < end {
+                    *byte = bytes[byte_addr - addr];
+                }
+            }
+            ptrace::write(
+                self.current,
+                word_addr as ptrace::AddressType,
+                u64::from_le_bytes(word) as *mut c_void,
+            )?;
+            word_addr += size_of::<usize>();
+        }
+        Ok(())
+    }
+
+    pub fn setregs(&self, regs: user_regs_struct) -> Result<(), nix::Error> {
+        ptrace::setregs(self.current, regs)
+    }
+
+    pub fn setregs_of(&self, tid: Pid, regs: user_regs_struct) -> Result<(), nix::Error> {
+        ptrace::setregs(tid, regs)
+    }
+
+    /// Reads the x87 and SSE registers of the current thread.
+    pub fn getfpregs(&self) -> Result<user_fpregs_struct, nix::Error> {
+        let mut fpregs: user_fpregs_struct = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETFPREGS,
+                libc::pid_t::from(self.current),
+                ptr::null_mut::<c_void>(),
+                &mut fpregs as *mut user_fpregs_struct as *mut c_void,
+            )
+        };
+        Errno::result(ret).map(|_| fpregs)
+    }
+
+    pub fn setfpregs_of(&self, tid: Pid, fpregs: &user_fpregs_struct) -> Result<(), nix::Error> {
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_SETFPREGS,
+                libc::pid_t::from(tid),
+                ptr::null_mut::<c_void>(),
+                fpregs as *const user_fpregs_struct as *mut c_void,
+            )
+        };
+        Errno::result(ret).map(drop)
+    }
+
+    /// Reads all of the vector registers of the current thread: its XSAVE area, which holds the
+    /// AVX registers too, or the x87 and SSE registers if the processor has no XSAVE.
+    pub fn getxstate(&self) -> Result<XState, nix::Error> {
+        match get_regset(self.current, NT_X86_XSTATE, MAX_XSTATE_SIZE) {
+            Ok(data) => Ok(XState {
+                regset: NT_X86_XSTATE,
+                data,
+            }),
+            Err(nix::Error::Sys(Errno::ENODEV)) | Err(nix::Error::Sys(Errno::EINVAL)) => {
+                let data = get_regset(self.current, NT_PRFPREG, size_of::<user_fpregs_struct>())?;
+                Ok(XState {
+                    regset: NT_PRFPREG,
+                    data,
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn setxstate_of(&self, tid: Pid, xstate: &XState) -> Result<(), nix::Error> {
+        let mut iov = libc::iovec {
+            iov_base: xstate.data.as_ptr() as *mut c_void,
+            iov_len: xstate.data.len(),
+        };
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_SETREGSET,
+                libc::pid_t::from(tid),
+                xstate.regset as *mut c_void,
+                &mut iov as *mut libc::iovec as *mut c_void,
+            )
+        };
+        Errno::result(ret).map(drop)
+    }
+
+    /// Sets a debug register in every thread, and remembers it for threads created later.
+    pub fn set_debug_register(&mut self, index: usize, value: u64) -> Result<(), nix::Error> {
+        for thread in self.threads.iter() {
+            poke_debug_register(thread.tid, index, value)?;
+        }
+        self.debug_registers[index] = value;
+        Ok(())
+    }
+
+    /// Programs the watchpoints set so far into thread `tid`.
+    fn copy_debug_registers(&self, tid: Pid) -> Result<(), nix::Error> {
+        if self.debug_registers[7] == 0 {
+            return Ok(());
+        }
+        // The kernel validates DR7 against the addresses, so set it last
+        for index in 0..4 {
+            poke_debug_register(tid, index, self.debug_registers[index])?;
+        }
+        poke_debug_register(tid, 7, self.debug_registers[7])
+    }
+
+    /// Checks DR6 of thread `tid` for a watchpoint that triggered, returning its debug register
+    /// and clearing the status so that the next stop doesn't see it again.
+    fn take_watchpoint_hit(&self, tid: Pid) -> Result<Option<usize>, nix::Error> {
+        let dr6 = peek_debug_register(tid, 6)?;
+        if dr6 & 0xf == 0 {
+            return Ok(None);
+        }
+        poke_debug_register(tid, 6, 0)?;
+        Ok(Some(dr6.trailing_zeros() as usize))
+    }
+
     pub fn go_back_one_step(&self) -> Result<(), nix::Error> {
-        let mut regs = ptrace::getregs(self.pid()).unwrap();
+        let mut regs = ptrace::getregs(self.current)?;
         regs.rip = regs.rip - 1;
-        ptrace::setregs(self.pid(), regs)
+        ptrace::setregs(self.current, regs)
     }
 
-    pub fn step(&self) -> Result<(), nix::Error> {
+    /// Single steps the current thread while the other threads stay stopped.
+    pub fn step(&mut self) -> Result<(), nix::Error> {
         // TODO: check ptrace step wait None vs some trap signal...
-        ptrace::step(self.pid(), None)
+        self.stepping = Some(self.current);
+        self.resume(self.current)
+    }
+}
+
+impl Process for Inferior {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn threads(&self) -> Vec<(usize, Pid)> {
+        self.threads
+            .iter()
+            .map(|thread| (thread.num, thread.tid))
+            .collect()
+    }
+
+    fn current_thread(&self) -> (usize, Pid) {
+        let thread = self.thread(self.current).unwrap();
+        (thread.num, thread.tid)
+    }
+
+    fn select_thread(&mut self, num: usize) -> bool {
+        match self.threads.iter().find(|thread| thread.num == num) {
+            Some(thread) => {
+                self.current = thread.tid;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn getregs(&self) -> Result<user_regs_struct, nix::Error> {
+        ptrace::getregs(self.current)
+    }
+
+    fn getregs_of(&self, tid: Pid) -> Result<user_regs_struct, nix::Error> {
+        ptrace::getregs(tid)
+    }
+
+    /// The range doesn't need to be word aligned, and fails with EIO/EFAULT if any word of it
+    /// isn't mapped.
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        // Memory wrapping around the end of the address space can't be mapped
+        let end = addr.checked_add(len).ok_or(nix::Error::Sys(Errno::EIO))?;
+        let mut bytes = Vec::new();
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let word = self.read_word(word_addr)?;
+            for (i, byte) in word.to_le_bytes().iter().enumerate() {
+                let byte_addr = word_addr + i;
+                if byte_addr >= addr && byte_addr < end {
+                    bytes.push(*byte);
+                }
+            }
+            word_addr += size_of::<usize>();
+        }
+        Ok(bytes)
+    }
+
+    fn read_word(&self, addr: usize) -> Result<u64, nix::Error> {
+        Ok(ptrace::read(self.current, addr as ptrace::AddressType)? as u64)
+    }
+
+    fn auxv(&self) -> Option<Vec<u8>> {
+        std::fs::read(format!("/proc/{}/auxv", self.pid)).ok()
+    }
+}
+
+fn peek_debug_register(tid: Pid, index: usize) -> Result<u64, nix::Error> {
+    let ret = unsafe {
+        Errno::clear();
+        libc::ptrace(
+            libc::PTRACE_PEEKUSER,
+            libc::pid_t::from(tid),
+            debug_register_offset(index),
+            ptr::null_mut::<c_void>(),
+        )
+    };
+    // PEEKUSER returns the value itself, so -1 is only an error if errno was set
+    match Errno::result(ret) {
+        Ok(..) | Err(nix::Error::Sys(Errno::UnknownErrno)) => Ok(ret as u64),
+        Err(err) => Err(err),
     }
 }
 
+/// Reads register set `regset` of thread `tid` with PTRACE_GETREGSET, which is at most `size`
+/// bytes long.
+fn get_regset(tid: Pid, regset: usize, size: usize) -> Result<Vec<u8>, nix::Error> {
+    let mut data = vec![0u8; size];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETREGSET,
+            libc::pid_t::from(tid),
+            regset as *mut c_void,
+            &mut iov as *mut libc::iovec as *mut c_void,
+        )
+    };
+    Errno::result(ret)?;
+    // The kernel shortens iov_len to what it filled in
+    data.truncate(iov.iov_len);
+    data.shrink_to_fit();
+    Ok(data)
+}
+
+fn poke_debug_register(tid: Pid, index: usize, value: u64) -> Result<(), nix::Error> {
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_POKEUSER,
+            libc::pid_t::from(tid),
+            debug_register_offset(index),
+            value as *mut c_void,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+/// The signal to deliver to a thread that stopped with `signal`. SIGTRAP comes from breakpoints
+/// and single stepping, so it is never the program's to handle.
+fn deliverable(signal: signal::Signal) -> Option<signal::Signal> {
+    if signal == signal::Signal::SIGTRAP {
+        None
+    } else {
+        Some(signal)
+    }
+}
+
+/// Events we want to hear about: new threads, forks and execs.
+fn trace_options() -> ptrace::Options {
+    ptrace::Options::PTRACE_O_TRACECLONE
+        | ptrace::Options::PTRACE_O_TRACEFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+        | ptrace::Options::PTRACE_O_TRACEEXEC
+}
+
+/// Sends `signal` to one thread of process `pid`, rather than to whichever thread the kernel picks.
+fn tgkill(pid: Pid, tid: Pid, signal: signal::Signal) -> Result<(), nix::Error> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_tgkill,
+            libc::pid_t::from(pid),
+            libc::pid_t::from(tid),
+            signal as libc::c_int,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+/// Lists the thread ids of process `pid`.
+fn list_threads(pid: Pid) -> Result<Vec<Pid>, nix::Error> {
+    let entries = std::fs::read_dir(format!("/proc/{}/task", pid))
+        .map_err(|_| nix::Error::Sys(Errno::ESRCH))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .collect())
+}
+
+/// The process thread `tid` belongs to, if it is still around.
+fn thread_group(tid: Pid) -> Option<Pid> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("Tgid:"))?;
+    line["Tgid:".len()..].trim().parse().ok().map(Pid::from_raw)
+}
+
+/// Takes the first of the waitpid results kept for other processes that `belongs` accepts.
+fn take_other_event<F>(belongs: F) -> Option<WaitStatus>
+where
+    F: Fn(&WaitStatus) -> bool,
+{
+    OTHER_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let index = events.iter().position(&belongs)?;
+        Some(events.remove(index))
+    })
+}
+
+/// Offset of u_debugreg[index] in struct user, which is what PTRACE_PEEKUSER/POKEUSER take.
+fn debug_register_offset(index: usize) -> *mut c_void {
+    let user: libc::user = unsafe { std::mem::zeroed() };
+    let base = &user as *const libc::user as usize;
+    let debugreg = &user.u_debugreg[index] as *const libc::c_ulonglong as usize;
+    (debugreg - base) as *mut c_void
+}
+
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    // Needs ptrace, and waitpid(-1) may collect the children of tests running alongside it:
+    // run with `cargo test -- --ignored --test-threads 1`
+    #[test]
+    #[ignore]
+    fn test_reap() {
+        let mut inferior = Inferior::new("/bin/sleep", &vec!["10".to_string()]).unwrap();
+        // Stopped, not gone
+        assert!(inferior.reap().unwrap().is_none());
+
+        // Killed behind our back: the exit is only there to collect once the kernel is done
+        signal::kill(inferior.pid(), signal::Signal::SIGKILL).unwrap();
+        let status = (0..100).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            inferior.reap().unwrap()
+        });
+        match status {
+            Some(Status::Signaled(signal::Signal::SIGKILL)) => {}
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(inferior.threads().is_empty());
+    }
+}
@@ -0,0 +1,413 @@
+//! The `--interpreter=json` mode, in which deet is driven by another program instead of a person.
+//! Commands are read as JSON lines such as `{"id": 1, "command": "break main"}`, and everything
+//! deet writes is a JSON line too. Stops, backtraces, values and breakpoint tables get records of
+//! their own, while other messages are wrapped in `{"type": "console", "text": ...}` records, much
+//! like gdb/MI separates its console stream from its results. Each command ends with a `done`
+//! record carrying its id. What the program being debugged writes to stdout and stderr comes in
+//! `{"type": "target", "text": ...}` records, and it gets no stdin, which carries the requests.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Prints like `print!`, or as a console record in JSON mode.
+#[macro_export]
+macro_rules! console_print {
+    ($($arg:tt)*) => {
+        $crate::interpreter::console(&format!($($arg)*))
+    };
+}
+
+/// Prints like `println!`, or as a console record in JSON mode.
+#[macro_export]
+macro_rules! console_println {
+    () => {
+        $crate::interpreter::console("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::interpreter::console(&format!("{}\n", format!($($arg)*)))
+    };
+}
+
+pub fn enable_json() {
+    JSON_MODE.store(true, Ordering::SeqCst);
+}
+
+pub fn is_json() -> bool {
+    JSON_MODE.load(Ordering::SeqCst)
+}
+
+/// Writes text meant for a person. In JSON mode, a line printed in several pieces becomes several
+/// console records.
+pub fn console(text: &str) {
+    if is_json() {
+        emit(&Json::object(vec![
+            ("type", "console".into()),
+            ("text", text.into()),
+        ]));
+    } else {
+        print!("{}", text);
+    }
+}
+
+/// Writes a record in JSON mode, on a line of its own.
+pub fn emit(record: &Json) {
+    println!("{}", record);
+}
+
+/// Forwards what the inferior writes to `output` as target records, until every process writing
+/// to it is gone.
+pub fn forward_target_output(mut output: File) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = match output.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            emit(&Json::object(vec![
+                ("type", "target".into()),
+                ("text", String::from_utf8_lossy(&buf[..len]).into_owned().into()),
+            ]));
+        }
+    });
+}
+
+/// Parses a command line of JSON mode into its id (any JSON value, or null if it has none) and
+/// the command, which is written like it would be typed at the `(deet)` prompt.
+pub fn parse_request(line: &str) -> Result<(Json, String), String> {
+    let request = Json::parse(line)?;
+    let id = request.get("id").cloned().unwrap_or(Json::Null);
+    match request.get("command") {
+        Some(Json::String(command)) => Ok((id, command.clone())),
+        _ => Err("Expected an object with a \"command\" string.".to_string()),
+    }
+}
+
+/// A JSON value. Only integers are supported as numbers, which is all deet needs; addresses are
+/// written as hex strings like gdb/MI does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Array(Vec<Json>),
+    // Fields are kept in the order they were added in
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Looks up a field of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Json, String> {
+        let mut chars = input.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected '{}' after JSON value.", c)),
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Json {
+        Json::Int(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        Json::Int(value as i64)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map(|value| value.into()).unwrap_or(Json::Null)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Int(value) => write!(f, "{}", value),
+            Json::String(value) => write_string(f, value),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let mut fields = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                if chars.next() != Some('"') {
+                    return Err("Expected a string as object key.".to_string());
+                }
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err("Expected ':' after object key.".to_string());
+                }
+                fields.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => return Err("Expected ',' or '}' in object.".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Json::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err("Expected ',' or ']' in array.".to_string()),
+                }
+            }
+        }
+        Some('"') => {
+            chars.next();
+            Ok(Json::String(parse_string(chars)?))
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || "-+.eE".contains(c)) {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            number
+                .parse()
+                .map(Json::Int)
+                .or_else(|_| Err(format!("Unsupported number {}.", number)))
+        }
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_alphabetic() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            match word.as_str() {
+                "null" => Ok(Json::Null),
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                _ => Err("Invalid JSON value.".to_string()),
+            }
+        }
+        None => Err("Unexpected end of JSON input.".to_string()),
+    }
+}
+
+/// Parses the rest of a string, after the opening quote.
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('/') => value.push('/'),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex4(chars)?;
+                    // Characters outside the BMP come as a surrogate pair
+                    if (0xd800..0xdc00).contains(&code) {
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err("Unpaired surrogate in string.".to_string());
+                        }
+                        let low = parse_hex4(chars)?;
+                        code =
+                            0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    value.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                }
+                _ => return Err("Invalid escape in string.".to_string()),
+            },
+            Some(c) => value.push(c),
+            None => return Err("Unterminated string.".to_string()),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+    let digits: String = chars.take(4).collect();
+    u32::from_str_radix(&digits, 16).or_else(|_| Err("Invalid \\u escape in string.".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Json::parse(r#" {"id": 7, "command": "break main", "x": [true, null, -3]} "#),
+            Ok(Json::object(vec![
+                ("id", Json::Int(7)),
+                ("command", "break main".into()),
+                (
+                    "x",
+                    Json::Array(vec![Json::Bool(true), Json::Null, Json::Int(-3)])
+                ),
+            ]))
+        );
+        assert_eq!(
+            Json::parse(r#""a\"\\\né😀""#),
+            Ok("a\"\\\né\u{1f600}".into())
+        );
+        assert!(Json::parse("{\"id\": 1").is_err());
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("1.5").is_err());
+        assert!(Json::parse("{} x").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let record = Json::object(vec![
+            ("type", "console".into()),
+            ("text", "say \"hi\"\n\u{1}".into()),
+            (
+                "values",
+                Json::Array(vec![Json::Int(1), None::<i64>.into()]),
+            ),
+        ]);
+        assert_eq!(
+            record.to_string(),
+            r#"{"type":"console","text":"say \"hi\"\n\u0001","values":[1,null]}"#
+        );
+        assert_eq!(Json::parse(&record.to_string()), Ok(record));
+    }
+
+    #[test]
+    fn test_requests() {
+        assert_eq!(
+            parse_request(r#"{"id": "a", "command": "bt"}"#),
+            Ok(("a".into(), "bt".to_string()))
+        );
+        assert_eq!(
+            parse_request(r#"{"command": "bt"}"#),
+            Ok((Json::Null, "bt".to_string()))
+        );
+        assert!(parse_request(r#"{"id": 1}"#).is_err());
+    }
+}
@@ -1,24 +1,164 @@
+mod breakpoint;
+mod core_dump;
 mod debugger;
 mod debugger_command;
+mod disassemble;
 mod dwarf_data;
+mod expr;
+mod gdbserver;
 mod gimli_wrapper;
+mod infcall;
 mod inferior;
+mod interpreter;
+mod process;
+mod record;
+mod registers;
+mod shared_libs;
+mod signals;
+mod symbols;
+mod unwind;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
+use std::fs;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
-    }
-    let target = &args[1];
+    let mut args: Vec<String> = env::args().collect();
+    let parsed = parse_options(&mut args).and_then(|options| Some((options, parse_args(&args)?)));
+    let (options, (target, source)) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            println!("Usage: {} [options] <target program>", args[0]);
+            println!("       {} [options] --pid <pid> [target program]", args[0]);
+            println!(
+                "       {} [options] <target program> --core <core file>",
+                args[0]
+            );
+            println!(
+                "       {} --gdbserver <host:port> <target program> [args...]",
+                args[0]
+            );
+            println!("Options:");
+            println!("  --interpreter=json  Read commands and write results as JSON lines");
+            println!("  -x <file>           Run the commands in <file> at startup");
+            println!("  -nx                 Don't run ~/.deetinit and ./.deetinit");
+            println!("  -batch              Quit after running the startup commands");
+            std::process::exit(1);
+        }
+    };
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    if let Source::GdbServer(address, args) = &source {
+        if let Err(err) = gdbserver::serve(&target, args, address) {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut debugger = Debugger::new(&target);
+    match source {
+        Source::Run => {}
+        Source::Attach(pid) => debugger.attach(pid),
+        Source::Core(path) => debugger.load_core(&path),
+        Source::GdbServer(..) => unreachable!(),
+    }
+    if !options.skip_init_files {
+        debugger.queue_init_files();
+    }
+    for script in &options.scripts {
+        debugger.queue_script(script);
+    }
+    if options.batch {
+        debugger.set_batch();
+    }
+    debugger.run();
+}
+
+/// The options that come before the target program.
+struct Options {
+    // -x: scripts to run after the .deetinit files
+    scripts: Vec<String>,
+    // -batch: quit after running the scripts
+    batch: bool,
+    // -nx: don't run the .deetinit files
+    skip_init_files: bool,
+}
+
+/// Takes the options off the front of `args`, returning None if one is missing its argument.
+fn parse_options(args: &mut Vec<String>) -> Option<Options> {
+    let mut options = Options {
+        scripts: Vec::new(),
+        batch: false,
+        skip_init_files: false,
+    };
+    while let Some(option) = args.get(1).cloned() {
+        match option.as_str() {
+            "-x" => {
+                if args.len() < 3 {
+                    return None;
+                }
+                options.scripts.push(args.remove(2));
+            }
+            "-batch" => options.batch = true,
+            "-nx" => options.skip_init_files = true,
+            _ if option.starts_with("--interpreter=") => match &option["--interpreter=".len()..] {
+                "json" => interpreter::enable_json(),
+                "console" => {}
+                other => {
+                    println!("Interpreter `{}' unrecognized", other);
+                    std::process::exit(1);
+                }
+            },
+            _ => break,
+        }
+        args.remove(1);
+    }
+    Some(options)
+}
+
+/// Where the program being debugged comes from, besides its executable.
+enum Source {
+    // Started later with `run`
+    Run,
+    // A running process to attach to
+    Attach(i32),
+    // A core dump it left behind
+    Core(String),
+    // Started with these arguments and served to gdb at this address instead of debugged here
+    GdbServer(String, Vec<String>),
+}
+
+/// Returns the target program and where to debug it from. When attaching, the target defaults to
+/// the executable the process is running.
+fn parse_args(args: &Vec<String>) -> Option<(String, Source)> {
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("--pid") => {
+            let pid: i32 = args.get(2)?.parse().ok()?;
+            let target = match args.get(3) {
+                Some(target) => target.clone(),
+                None => fs::read_link(format!("/proc/{}/exe", pid))
+                    .ok()?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            if args.len() > 4 {
+                return None;
+            }
+            Some((target, Source::Attach(pid)))
+        }
+        Some("--gdbserver") => Some((
+            args.get(3)?.clone(),
+            Source::GdbServer(args[2].clone(), args[4..].to_vec()),
+        )),
+        Some(target) if args.len() == 2 => Some((target.to_string(), Source::Run)),
+        Some(target) if args.len() == 4 && args[2] == "--core" => {
+            Some((target.to_string(), Source::Core(args[3].clone())))
+        }
+        _ => None,
+    }
 }
@@ -0,0 +1,40 @@
+use libc::user_regs_struct;
+use nix::unistd::Pid;
+
+/// Read access to the threads, registers and memory of the program being debugged, which is
+/// either a live inferior or a core dump. Errors are those ptrace would give, e.g. EIO for
+/// unmapped memory.
+pub trait Process {
+    /// Returns the pid of the process.
+    fn pid(&self) -> Pid;
+
+    /// Returns the number and thread id of every thread, in the order they were numbered.
+    fn threads(&self) -> Vec<(usize, Pid)>;
+
+    /// Returns the number and thread id of the current thread.
+    fn current_thread(&self) -> (usize, Pid);
+
+    /// Makes thread `num` the current thread. Returns false if there is no such thread.
+    fn select_thread(&mut self, num: usize) -> bool;
+
+    /// Returns the registers of the current thread.
+    fn getregs(&self) -> Result<user_regs_struct, nix::Error>;
+
+    /// Returns the registers of thread `tid`, which needn't be the current thread.
+    fn getregs_of(&self, tid: Pid) -> Result<user_regs_struct, nix::Error>;
+
+    /// Reads `len` bytes starting at `addr`, failing if any of them isn't mapped.
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error>;
+
+    /// Returns the auxiliary vector the kernel started the program with: pairs of 8-byte key and
+    /// value such as AT_ENTRY.
+    fn auxv(&self) -> Option<Vec<u8>>;
+
+    /// Reads the 8-byte word stored at `addr`.
+    fn read_word(&self, addr: usize) -> Result<u64, nix::Error> {
+        let bytes = self.read_bytes(addr, 8)?;
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes);
+        Ok(u64::from_le_bytes(word))
+    }
+}
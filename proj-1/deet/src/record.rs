@@ -0,0 +1,249 @@
+//! Process record, for running the inferior backwards. While recording, the inferior is single
+//! stepped and before each instruction runs, the registers and the memory it is about to write
+//! are saved; going back an instruction puts them back. The memory comes from the decoded
+//! instruction's memory operand, plus the stack slot that pushes and calls write to.
+//!
+//! Not logged are writes made by system calls (e.g. the buffer of read(2)) or by the kernel
+//! delivering a signal. Instructions the disassembler can't decode end the recording, since what
+//! they change isn't known. Other threads don't run while the inferior is being recorded.
+
+use crate::disassemble::Instruction;
+use crate::inferior::{Inferior, XState};
+use crate::process::Process;
+use crate::registers;
+use libc::user_regs_struct;
+use nix::unistd::Pid;
+use std::collections::VecDeque;
+
+/// The number of instructions kept by default, like gdb's `record full insn-number-max`.
+pub const DEFAULT_LIMIT: usize = 200000;
+
+/// How much memory is saved at a memory operand, which covers the widest operands the
+/// disassembler knows (zmm registers).
+const OPERAND_BYTES: usize = 64;
+
+const PAGE_SIZE: usize = 4096;
+
+/// What an instruction does to the call stack, which `reverse-next` follows to step back over
+/// calls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepKind {
+    Call,
+    Return,
+    Other,
+}
+
+impl StepKind {
+    fn of(instruction: &Instruction) -> StepKind {
+        let mnemonic = instruction
+            .text
+            .split_whitespace()
+            .find(|word| !["rep", "repz", "repnz", "bnd", "notrack", "lock"].contains(word))
+            .unwrap_or("");
+        if mnemonic.starts_with("call") {
+            StepKind::Call
+        } else if mnemonic.starts_with("ret") {
+            StepKind::Return
+        } else {
+            StepKind::Other
+        }
+    }
+}
+
+/// The state of a thread, and of the memory an instruction writes, from before it ran.
+pub struct RecordedStep {
+    pub tid: Pid,
+    pub regs: user_regs_struct,
+    // The x87, SSE and AVX registers, which only instructions that use them change
+    xstate: Option<XState>,
+    memory: Vec<(usize, Vec<u8>)>,
+    pub kind: StepKind,
+}
+
+impl RecordedStep {
+    /// Saves what running `instruction`, which the current thread is stopped at, can change.
+    pub fn capture(
+        inferior: &Inferior,
+        instruction: &Instruction,
+    ) -> Result<RecordedStep, nix::Error> {
+        let (_, tid) = inferior.current_thread();
+        let regs = inferior.getregs()?;
+        let xstate = if instruction.vector {
+            Some(inferior.getxstate()?)
+        } else {
+            None
+        };
+
+        let mut ranges = vec![(regs.rsp.wrapping_sub(8) as usize, 8)];
+        if let Some(operand) = instruction.memory {
+            let register = |name: &str| registers::get_register(&regs, name).unwrap_or(0);
+            let addr = operand.address(&register, regs.rip + instruction.len as u64);
+            // xsave and fxsave store all of the vector registers
+            let mnemonic = instruction.text.split_whitespace().next().unwrap_or("");
+            let len = match &xstate {
+                Some(xstate) if mnemonic.starts_with("xsave") || mnemonic.starts_with("fxsave") => {
+                    xstate.size()
+                }
+                _ => OPERAND_BYTES,
+            };
+            ranges.push((addr as usize, len));
+        }
+        // Memory that can't be read can't be written either. Pages can be read or not as a
+        // whole, so a range running off the end of the memory is saved up to there.
+        let memory = ranges
+            .into_iter()
+            .flat_map(|(addr, len)| split_at_pages(addr, len))
+            .filter_map(|(addr, len)| Some((addr, inferior.read_bytes(addr, len).ok()?)))
+            .collect();
+
+        Ok(RecordedStep {
+            tid,
+            regs,
+            xstate,
+            memory,
+            kind: StepKind::of(instruction),
+        })
+    }
+
+    /// Puts the inferior back into the state from before the instruction ran.
+    pub fn restore(&self, inferior: &mut Inferior) -> Result<(), nix::Error> {
+        for (addr, bytes) in self.memory.iter() {
+            inferior.write_bytes(*addr, bytes)?;
+        }
+        if let Some(xstate) = &self.xstate {
+            inferior.setxstate_of(self.tid, xstate)?;
+        }
+        inferior.setregs_of(self.tid, self.regs)
+    }
+}
+
+/// Splits the `len` bytes at `addr` into the parts on each page.
+fn split_at_pages(addr: usize, len: usize) -> Vec<(usize, usize)> {
+    let mut parts = Vec::new();
+    let (mut addr, mut len) = (addr, len);
+    while len > 0 {
+        let part = len.min(PAGE_SIZE - addr % PAGE_SIZE);
+        parts.push((addr, part));
+        addr = match addr.checked_add(part) {
+            Some(addr) => addr,
+            None => break,
+        };
+        len -= part;
+    }
+    parts
+}
+
+/// The log of a recording, holding the last `limit` instructions.
+pub struct Recording {
+    steps: VecDeque<RecordedStep>,
+    limit: usize,
+    // Instructions that were dropped from the start of the log to stay within the limit
+    dropped: usize,
+}
+
+impl Recording {
+    pub fn new(limit: usize) -> Recording {
+        Recording {
+            steps: VecDeque::new(),
+            limit,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, step: RecordedStep) {
+        self.steps.push_back(step);
+        self.trim();
+    }
+
+    /// Takes the last instruction off the log, to go back to before it.
+    pub fn pop(&mut self) -> Option<RecordedStep> {
+        self.steps.pop_back()
+    }
+
+    /// The instructions in the log, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &RecordedStep> {
+        self.steps.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// The number of the oldest instruction in the log, counting from 1 at the start of the
+    /// recording.
+    pub fn first_number(&self) -> usize {
+        self.dropped + 1
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.steps.len() > self.limit {
+            self.steps.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disassemble;
+
+    fn step(rip: u64) -> RecordedStep {
+        let mut regs: user_regs_struct = unsafe { std::mem::zeroed() };
+        regs.rip = rip;
+        RecordedStep {
+            tid: Pid::from_raw(1),
+            regs,
+            xstate: None,
+            memory: Vec::new(),
+            kind: StepKind::Other,
+        }
+    }
+
+    #[test]
+    fn test_step_kind() {
+        let kind = |bytes: &[u8]| StepKind::of(&disassemble::decode(bytes, 0x1000, &|_| "".into()));
+        // call 0x1005
+        assert_eq!(kind(&[0xe8, 0x00, 0x00, 0x00, 0x00]), StepKind::Call);
+        // call *%rax
+        assert_eq!(kind(&[0xff, 0xd0]), StepKind::Call);
+        // ret, repz ret
+        assert_eq!(kind(&[0xc3]), StepKind::Return);
+        assert_eq!(kind(&[0xf3, 0xc3]), StepKind::Return);
+        // push %rbp
+        assert_eq!(kind(&[0x55]), StepKind::Other);
+    }
+
+    #[test]
+    fn test_split_at_pages() {
+        assert_eq!(split_at_pages(0x1000, 64), vec![(0x1000, 64)]);
+        assert_eq!(split_at_pages(0x1fe0, 64), vec![(0x1fe0, 32), (0x2000, 32)]);
+        assert_eq!(split_at_pages(usize::MAX - 7, 64), vec![(usize::MAX - 7, 8)]);
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut recording = Recording::new(3);
+        for rip in 1..=5 {
+            recording.push(step(rip));
+        }
+        assert_eq!(recording.len(), 3);
+        assert_eq!(recording.first_number(), 3);
+        let rips: Vec<u64> = recording.iter().map(|step| step.regs.rip).collect();
+        assert_eq!(rips, vec![3, 4, 5]);
+
+        recording.set_limit(1);
+        assert_eq!(recording.first_number(), 5);
+        assert_eq!(recording.pop().map(|step| step.regs.rip), Some(5));
+        assert!(recording.pop().is_none());
+    }
+}
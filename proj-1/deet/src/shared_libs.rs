@@ -0,0 +1,124 @@
+//! Finding where the executable and its shared libraries are loaded. The kernel reports where the
+//! executable's entry point ended up (AT_ENTRY in the auxiliary vector), which gives the bias of a
+//! PIE executable. The dynamic linker keeps the list of loaded objects (the link map) in its
+//! `r_debug` structure, which the executable's DT_DEBUG entry points to once the dynamic linker
+//! has run, and calls `r_brk` whenever it changes the list.
+
+use crate::process::Process;
+
+const AT_NULL: u64 = 0;
+const AT_ENTRY: u64 = 9;
+const DT_NULL: u64 = 0;
+const DT_DEBUG: u64 = 21;
+// r_debug.r_state once the dynamic linker is done adding or removing objects
+const RT_CONSISTENT: u32 = 0;
+
+// Offsets into struct r_debug and struct link_map
+const R_DEBUG_MAP: usize = 8;
+const R_DEBUG_BRK: usize = 16;
+const R_DEBUG_STATE: usize = 24;
+const LINK_MAP_NAME: usize = 8;
+const LINK_MAP_NEXT: usize = 24;
+
+// Guards against looping forever on corrupt memory
+const MAX_ENTRIES: usize = 4096;
+
+/// A shared library in the link map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedLibrary {
+    pub path: String,
+    // Difference between the addresses the library is loaded at and those it was linked at
+    pub bias: usize,
+}
+
+/// Returns how far the executable is loaded from its link-time addresses, given where it was
+/// linked to start (its ELF entry point).
+pub fn executable_bias(process: &dyn Process, link_entry: usize) -> Option<usize> {
+    let auxv = process.auxv()?;
+    for entry in auxv.chunks_exact(16) {
+        let (key, value) = (word(&entry[..8]), word(&entry[8..]));
+        match key {
+            AT_ENTRY => return Some((value as usize).wrapping_sub(link_entry)),
+            AT_NULL => break,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the address of the dynamic linker's `r_debug`, found through the executable's
+/// .dynamic section at `dynamic`. None until the dynamic linker has filled in DT_DEBUG, and for
+/// static executables.
+pub fn find_r_debug(process: &dyn Process, dynamic: usize) -> Option<usize> {
+    for i in 0..MAX_ENTRIES {
+        let tag = process.read_word(dynamic + 16 * i).ok()?;
+        match tag {
+            DT_NULL => return None,
+            DT_DEBUG => {
+                let r_debug = process.read_word(dynamic + 16 * i + 8).ok()?;
+                return if r_debug == 0 {
+                    None
+                } else {
+                    Some(r_debug as usize)
+                };
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the address of the function the dynamic linker calls after changing the link map.
+pub fn r_brk(process: &dyn Process, r_debug: usize) -> Option<usize> {
+    match process.read_word(r_debug + R_DEBUG_BRK) {
+        Ok(0) | Err(_) => None,
+        Ok(addr) => Some(addr as usize),
+    }
+}
+
+/// Reads the shared libraries in the link map, in load order. Returns None while the dynamic
+/// linker is in the middle of changing it.
+pub fn read_link_map(process: &dyn Process, r_debug: usize) -> Option<Vec<LoadedLibrary>> {
+    let state = process.read_bytes(r_debug + R_DEBUG_STATE, 4).ok()?;
+    if word(&state) as u32 != RT_CONSISTENT {
+        return None;
+    }
+
+    let mut libraries = Vec::new();
+    let mut entry = process.read_word(r_debug + R_DEBUG_MAP).ok()? as usize;
+    while entry != 0 && libraries.len() < MAX_ENTRIES {
+        let bias = process.read_word(entry).ok()? as usize;
+        let name_addr = process.read_word(entry + LINK_MAP_NAME).ok()? as usize;
+        let path = read_c_string(process, name_addr).unwrap_or_default();
+        // The executable itself comes first, with an empty name
+        if !path.is_empty() {
+            libraries.push(LoadedLibrary { path, bias });
+        }
+        entry = process.read_word(entry + LINK_MAP_NEXT).ok()? as usize;
+    }
+    Some(libraries)
+}
+
+fn read_c_string(process: &dyn Process, addr: usize) -> Option<String> {
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_ENTRIES {
+        // Word by word, so that the string may end right before an unmapped page
+        let chunk_addr = addr + bytes.len();
+        let chunk = process.read_bytes(chunk_addr, 8 - chunk_addr % 8).ok()?;
+        match chunk.iter().position(|byte| *byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+    None
+}
+
+/// Decodes a little endian word of up to 8 bytes.
+fn word(bytes: &[u8]) -> u64 {
+    let mut raw = [0; 8];
+    raw[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(raw)
+}
@@ -0,0 +1,148 @@
+use nix::sys::signal::Signal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::str::FromStr;
+
+/// What to do when the inferior receives a signal, as set with `handle`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignalPolicy {
+    // Stop and give control back to the user
+    pub stop: bool,
+    // Say that the signal arrived, even if not stopping
+    pub print: bool,
+    // Deliver the signal to the inferior when it is resumed, rather than discarding it
+    pub pass: bool,
+}
+
+impl SignalPolicy {
+    /// gdb's defaults: signals that are part of normal operation are passed silently, SIGINT and
+    /// SIGTRAP belong to the debugger, and everything else stops the inferior before it gets it.
+    fn default_for(signal: Signal) -> SignalPolicy {
+        match signal {
+            Signal::SIGALRM
+            | Signal::SIGURG
+            | Signal::SIGCHLD
+            | Signal::SIGWINCH
+            | Signal::SIGIO
+            | Signal::SIGVTALRM
+            | Signal::SIGPROF => SignalPolicy {
+                stop: false,
+                print: false,
+                pass: true,
+            },
+            Signal::SIGINT | Signal::SIGTRAP => SignalPolicy {
+                stop: true,
+                print: true,
+                pass: false,
+            },
+            _ => SignalPolicy {
+                stop: true,
+                print: true,
+                pass: true,
+            },
+        }
+    }
+
+    /// Applies one of the actions `handle` takes. Stopping implies printing, and not printing
+    /// implies not stopping. Returns false if `action` isn't one.
+    pub fn apply(&mut self, action: &str) -> bool {
+        match action {
+            "stop" => {
+                self.stop = true;
+                self.print = true;
+            }
+            "nostop" => self.stop = false,
+            "print" => self.print = true,
+            "noprint" => {
+                self.print = false;
+                self.stop = false;
+            }
+            "pass" | "noignore" => self.pass = true,
+            "nopass" | "ignore" => self.pass = false,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// The policy for every signal, starting out with gdb's defaults.
+pub fn default_policies() -> HashMap<Signal, SignalPolicy> {
+    Signal::iterator()
+        .map(|signal| (signal, SignalPolicy::default_for(signal)))
+        .collect()
+}
+
+/// Parses a signal name as `handle` accepts it: `SIGSEGV`, `segv` or a number.
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = name.to_uppercase();
+    if name.starts_with("SIG") {
+        Signal::from_str(&name).ok()
+    } else {
+        Signal::from_str(&format!("SIG{}", name)).ok()
+    }
+}
+
+/// A description of the signal such as "Segmentation fault".
+pub fn description(signal: Signal) -> String {
+    unsafe { CStr::from_ptr(libc::strsignal(signal as libc::c_int)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        let cases = [
+            ("SIGSEGV", Some(Signal::SIGSEGV)),
+            ("segv", Some(Signal::SIGSEGV)),
+            ("SigInt", Some(Signal::SIGINT)),
+            ("11", Some(Signal::SIGSEGV)),
+            ("0", None),
+            ("65", None),
+            ("-1", None),
+            ("SIG", None),
+            ("SIGFOO", None),
+            ("", None),
+        ];
+        for &(name, expected) in cases.iter() {
+            assert_eq!(parse_signal(name), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let cases = [
+            (Signal::SIGUSR1, "nostop", (false, true, true)),
+            (Signal::SIGUSR1, "nopass", (true, true, false)),
+            (Signal::SIGUSR1, "ignore", (true, true, false)),
+            (Signal::SIGINT, "pass", (true, true, true)),
+            (Signal::SIGINT, "noignore", (true, true, true)),
+            (Signal::SIGALRM, "print", (false, true, true)),
+            // Stopping implies printing
+            (Signal::SIGALRM, "stop", (true, true, true)),
+            // Not printing implies not stopping
+            (Signal::SIGUSR1, "noprint", (false, false, true)),
+            (Signal::SIGUSR1, "noprint stop", (true, true, true)),
+            (Signal::SIGALRM, "stop nostop", (false, true, true)),
+        ];
+        for &(signal, actions, (stop, print, pass)) in cases.iter() {
+            let mut policy = SignalPolicy::default_for(signal);
+            for action in actions.split_whitespace() {
+                assert!(policy.apply(action), "{} isn't an action", action);
+            }
+            let expected = SignalPolicy { stop, print, pass };
+            assert_eq!(policy, expected, "handle {} {}", signal.as_str(), actions);
+        }
+
+        let mut policy = SignalPolicy::default_for(Signal::SIGUSR1);
+        assert!(!policy.apply("stopp"));
+        assert_eq!(policy, SignalPolicy::default_for(Signal::SIGUSR1));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::dwarf_data::{DwarfData, File, Function, Line, Type, TypeRef, Variable};
+use crate::unwind::CallFrameInfo;
+
+/// Debugging information for everything loaded in the program: the executable and the shared
+/// libraries the dynamic linker has mapped, each relocated to where it is in memory. Lookups by
+/// address go to the object containing the address, lookups by name try the executable first.
+pub struct Symbols {
+    // The executable comes first, then libraries in the order they were loaded
+    objects: Vec<DwarfData>,
+}
+
+impl Symbols {
+    pub fn new(executable: DwarfData) -> Symbols {
+        Symbols {
+            objects: vec![executable],
+        }
+    }
+
+    pub fn executable(&self) -> &DwarfData {
+        &self.objects[0]
+    }
+
+    pub fn executable_mut(&mut self) -> &mut DwarfData {
+        &mut self.objects[0]
+    }
+
+    pub fn libraries(&self) -> &[DwarfData] {
+        &self.objects[1..]
+    }
+
+    pub fn add_library(&mut self, library: DwarfData) {
+        self.objects.push(library);
+    }
+
+    /// Forgets the libraries `keep` returns false for, e.g. because they were unloaded.
+    pub fn retain_libraries<F: Fn(&DwarfData) -> bool>(&mut self, keep: F) {
+        let mut index = 0;
+        self.objects.retain(|object| {
+            index += 1;
+            index == 1 || keep(object)
+        });
+    }
+
+    fn object_containing(&self, addr: usize) -> Option<&DwarfData> {
+        self.objects.iter().find(|object| object.contains(addr))
+    }
+
+    /// Looks up a type in the object that defines it, e.g. the pointee of a pointer.
+    pub fn get_type(&self, type_ref: TypeRef) -> Option<&Type> {
+        self.objects
+            .iter()
+            .find(|object| object.id() == type_ref.object)?
+            .get_type(type_ref.offset)
+    }
+
+    /// Returns the CFI covering `pc`, for unwinding the frame executing there.
+    pub fn call_frame_info(&self, pc: u64) -> Option<&CallFrameInfo> {
+        self.object_containing(pc as usize)
+            .map(|object| object.call_frame_info())
+    }
+
+    /// Finds a type by name, in the executable first like other lookups by name.
+    pub fn get_type_by_name(&self, name: &str) -> Option<&Type> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_type_by_name(name))
+            .next()
+    }
+
+    pub fn get_target_file(&self, file: &str) -> Option<&File> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_target_file(file))
+            .next()
+    }
+
+    pub fn get_file_names(&self) -> Vec<String> {
+        self.objects
+            .iter()
+            .flat_map(|object| object.get_file_names())
+            .collect()
+    }
+
+    pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
+        match file {
+            Some(file) => self
+                .objects
+                .iter()
+                .find(|object| object.get_target_file(file).is_some())?
+                .get_addr_for_line(Some(file), line_number),
+            None => self.executable().get_addr_for_line(None, line_number),
+        }
+    }
+
+    pub fn get_addr_for_function(&self, file: Option<&str>, func_name: &str) -> Option<usize> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_addr_for_function(file, func_name))
+            .next()
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<&Function> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_function(name))
+            .next()
+    }
+
+    pub fn get_function_containing(&self, curr_addr: usize) -> Option<&Function> {
+        self.object_containing(curr_addr)?
+            .get_function_containing(curr_addr)
+    }
+
+    /// Returns the name, start and end of the function containing `curr_addr`, from the
+    /// debugging information or else the symbol table.
+    pub fn get_function_bounds(&self, curr_addr: usize) -> Option<(String, usize, usize)> {
+        let object = self.object_containing(curr_addr)?;
+        if let Some(func) = object.get_function_containing(curr_addr) {
+            return Some((
+                func.name.clone(),
+                func.address,
+                func.address + func.text_length,
+            ));
+        }
+        let (start, size, name) = object.get_symbol_containing(curr_addr)?;
+        Some((name.to_string(), start, start + size))
+    }
+
+    pub fn get_symbol_address(&self, name: &str) -> Option<usize> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_symbol_address(name))
+            .next()
+    }
+
+    pub fn read_file_bytes(&self, addr: usize, len: usize) -> Option<Vec<u8>> {
+        self.object_containing(addr)?.read_file_bytes(addr, len)
+    }
+
+    pub fn get_global_variable(&self, name: &str) -> Option<&Variable> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.get_global_variable(name))
+            .next()
+    }
+
+    pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
+        self.object_containing(curr_addr)?
+            .get_line_from_addr(curr_addr)
+    }
+
+    pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
+        self.object_containing(curr_addr)?
+            .get_function_from_addr(curr_addr)
+    }
+}
@@ -0,0 +1,323 @@
+//! Stack unwinding using the call frame information (CFI) in `.eh_frame` or `.debug_frame`, which
+//! works whether or not the code keeps a frame pointer. Code without CFI falls back to following
+//! the rbp chain.
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianSlice, Expression, Register, RegisterRule,
+    RunTimeEndian, UninitializedUnwindContext, UnwindSection, UnwindTableRow, X86_64,
+};
+use libc::user_regs_struct;
+use object::{Object, ObjectSection};
+
+// DWARF numbers rax, rdx, rcx, rbx, rsi, rdi, rbp, rsp, r8-r15 as 0-15, and uses 16 for the
+// return address, i.e. the rip of the frame.
+const NUM_REGISTERS: usize = 17;
+
+// Registers a callee has to restore before returning, so they keep their value in the caller
+// unless the CFI says where they were saved
+const CALLEE_SAVED: [Register; 6] = [
+    X86_64::RBX,
+    X86_64::RBP,
+    X86_64::R12,
+    X86_64::R13,
+    X86_64::R14,
+    X86_64::R15,
+];
+
+/// Register values in one frame of the stack, indexed by DWARF register number. In frames other
+/// than the innermost, registers the callee clobbered without saving are unknown.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameRegisters([Option<u64>; NUM_REGISTERS]);
+
+impl FrameRegisters {
+    pub fn from_regs(regs: &user_regs_struct) -> FrameRegisters {
+        FrameRegisters([
+            Some(regs.rax),
+            Some(regs.rdx),
+            Some(regs.rcx),
+            Some(regs.rbx),
+            Some(regs.rsi),
+            Some(regs.rdi),
+            Some(regs.rbp),
+            Some(regs.rsp),
+            Some(regs.r8),
+            Some(regs.r9),
+            Some(regs.r10),
+            Some(regs.r11),
+            Some(regs.r12),
+            Some(regs.r13),
+            Some(regs.r14),
+            Some(regs.r15),
+            Some(regs.rip),
+        ])
+    }
+
+    pub fn get(&self, register: Register) -> Option<u64> {
+        self.0.get(register.0 as usize).cloned().unwrap_or(None)
+    }
+
+    fn set(&mut self, register: Register, value: Option<u64>) {
+        self.0[register.0 as usize] = value;
+    }
+
+    pub fn pc(&self) -> Option<u64> {
+        self.get(X86_64::RA)
+    }
+
+    pub fn sp(&self) -> Option<u64> {
+        self.get(X86_64::RSP)
+    }
+}
+
+/// One frame of a backtrace.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    // Where the frame is executing, or resumes once its callee returns
+    pub pc: u64,
+    // The pc to look up the function, line and CFI of the frame with: the pc itself in the
+    // innermost frame and frames interrupted by a signal, otherwise that of the call instruction
+    pub lookup_pc: u64,
+    // Where this frame returns to, and the canonical frame address, i.e. rsp in the caller before
+    // the call instruction. gcc uses the latter as the frame base for locals.
+    pub return_addr: Option<u64>,
+    pub cfa: Option<u64>,
+}
+
+pub struct CallFrameInfo {
+    eh_frame: Vec<u8>,
+    debug_frame: Vec<u8>,
+    bases: BaseAddresses,
+    endian: RunTimeEndian,
+    // How far the object is loaded from its link-time addresses, which the CFI is written in
+    bias: u64,
+}
+
+impl CallFrameInfo {
+    pub fn load(object: &object::File, endian: RunTimeEndian) -> CallFrameInfo {
+        let section_data = |name: &str| {
+            object
+                .section_data_by_name(name)
+                .map(|data| data.into_owned())
+                .unwrap_or_default()
+        };
+        let section_address = |name: &str| {
+            object
+                .section_by_name(name)
+                .map(|section| section.address())
+                .unwrap_or(0)
+        };
+        // Pointers in .eh_frame may be encoded relative to these
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(section_address(".eh_frame_hdr"))
+            .set_eh_frame(section_address(".eh_frame"))
+            .set_text(section_address(".text"))
+            .set_got(section_address(".got"));
+        CallFrameInfo {
+            eh_frame: section_data(".eh_frame"),
+            debug_frame: section_data(".debug_frame"),
+            bases,
+            endian,
+            bias: 0,
+        }
+    }
+
+    /// Sets where the object is loaded, see DwarfData::relocate.
+    pub fn relocate(&mut self, bias: u64) {
+        self.bias = bias;
+    }
+
+    fn find_row(&self, pc: u64) -> Option<UnwindTableRow<EndianSlice<'_, RunTimeEndian>>> {
+        let mut ctx = UninitializedUnwindContext::new();
+        let eh_frame = EhFrame::new(&self.eh_frame, self.endian);
+        let debug_frame = DebugFrame::new(&self.debug_frame, self.endian);
+        eh_frame
+            .unwind_info_for_address(&self.bases, &mut ctx, pc, EhFrame::cie_from_offset)
+            .or_else(|_| {
+                debug_frame.unwind_info_for_address(
+                    &self.bases,
+                    &mut ctx,
+                    pc,
+                    DebugFrame::cie_from_offset,
+                )
+            })
+            .ok()
+    }
+
+    /// Returns whether `pc` is in a signal trampoline, i.e. its CIE has the 'S' augmentation. The
+    /// caller of such a frame was interrupted by the signal rather than calling it.
+    pub fn is_signal_frame(&self, pc: u64) -> bool {
+        let pc = pc.wrapping_sub(self.bias);
+        let eh_frame = EhFrame::new(&self.eh_frame, self.endian);
+        let debug_frame = DebugFrame::new(&self.debug_frame, self.endian);
+        eh_frame
+            .fde_for_address(&self.bases, pc, EhFrame::cie_from_offset)
+            .map(|fde| fde.is_signal_trampoline())
+            .or_else(|_| {
+                debug_frame
+                    .fde_for_address(&self.bases, pc, DebugFrame::cie_from_offset)
+                    .map(|fde| fde.is_signal_trampoline())
+            })
+            .unwrap_or(false)
+    }
+
+    /// Computes the registers of the caller of the frame whose registers are `regs`, looking up
+    /// the CFI for `pc`. Returns None if there is no CFI for `pc` or it can't be applied. If this
+    /// is the outermost frame, the caller's pc is unknown.
+    pub fn unwind(
+        &self,
+        pc: u64,
+        regs: &FrameRegisters,
+        read_word: &dyn Fn(u64) -> Option<u64>,
+    ) -> Option<FrameRegisters> {
+        let row = self.find_row(pc.wrapping_sub(self.bias))?;
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                (regs.get(*register)? as i64 + offset) as u64
+            }
+            CfaRule::Expression(expression) => evaluate(expression, regs, None, read_word)?,
+        };
+
+        let mut caller = FrameRegisters([None; NUM_REGISTERS]);
+        for i in 0..NUM_REGISTERS {
+            let register = Register(i as u16);
+            let value = match row.register(register) {
+                RegisterRule::Undefined if CALLEE_SAVED.contains(&register) => regs.get(register),
+                RegisterRule::Undefined => None,
+                RegisterRule::SameValue => regs.get(register),
+                RegisterRule::Offset(offset) => read_word((cfa as i64 + offset) as u64),
+                RegisterRule::ValOffset(offset) => Some((cfa as i64 + offset) as u64),
+                RegisterRule::Register(other) => regs.get(other),
+                RegisterRule::Expression(expression) => {
+                    read_word(evaluate(&expression, regs, Some(cfa), read_word)?)
+                }
+                RegisterRule::ValExpression(expression) => {
+                    evaluate(&expression, regs, Some(cfa), read_word)
+                }
+                RegisterRule::Architectural => None,
+            };
+            caller.set(register, value);
+        }
+        caller.set(X86_64::RSP, Some(cfa));
+        Some(caller)
+    }
+}
+
+/// Walks the stack starting from `regs`, returning at most `max_depth` frames, innermost first.
+/// Unwinding stops at a frame whose pc the CFI doesn't recover.
+/// `cfi_for` finds the CFI of the object containing a pc, since frames may be in any of the
+/// executable and its shared libraries.
+pub fn backtrace<'a>(
+    cfi_for: &dyn Fn(u64) -> Option<&'a CallFrameInfo>,
+    regs: &user_regs_struct,
+    read_word: &dyn Fn(u64) -> Option<u64>,
+    max_depth: usize,
+) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut registers = FrameRegisters::from_regs(regs);
+    let mut exact_pc = true;
+    while frames.len() < max_depth {
+        let pc = match registers.pc() {
+            Some(pc) if pc != 0 => pc,
+            _ => break,
+        };
+        // In callers, pc is the return address, which may already be past the end of the calling
+        // function if the call doesn't return, so look up the call instruction instead. A frame
+        // interrupted by a signal resumes at the instruction pc points to, though.
+        let lookup_pc = if exact_pc { pc } else { pc - 1 };
+        let cfi = cfi_for(lookup_pc);
+        let caller = cfi
+            .and_then(|cfi| cfi.unwind(lookup_pc, &registers, read_word))
+            .or_else(|| unwind_frame_pointer(&registers, read_word));
+        frames.push(Frame {
+            pc,
+            lookup_pc,
+            return_addr: caller.and_then(|caller| caller.pc()),
+            cfa: caller.and_then(|caller| caller.sp()),
+        });
+        exact_pc = cfi.map_or(false, |cfi| cfi.is_signal_frame(lookup_pc));
+
+        match caller {
+            // The stack grows down, so a caller must have a higher rsp. Anything else means the
+            // unwind info was wrong and we would loop forever.
+            Some(caller) if caller.sp() > registers.sp() => registers = caller,
+            _ => break,
+        }
+    }
+    frames
+}
+
+/// Unwinds a frame that has no CFI by assuming it starts with `push rbp; mov rbp, rsp`.
+fn unwind_frame_pointer(
+    regs: &FrameRegisters,
+    read_word: &dyn Fn(u64) -> Option<u64>,
+) -> Option<FrameRegisters> {
+    let rbp = regs.get(X86_64::RBP)?;
+    if rbp == 0 {
+        return None;
+    }
+    let mut caller = *regs;
+    caller.set(X86_64::RBP, Some(read_word(rbp)?));
+    caller.set(X86_64::RA, Some(read_word(rbp + 8)?));
+    caller.set(X86_64::RSP, Some(rbp + 16));
+    Some(caller)
+}
+
+/// Evaluates a DWARF expression from the CFI. These compute addresses from registers and memory,
+/// so only the handful of operations that compilers emit for that are supported. Register rules
+/// start with the CFA on the stack.
+fn evaluate(
+    expression: &Expression<EndianSlice<'_, RunTimeEndian>>,
+    regs: &FrameRegisters,
+    cfa: Option<u64>,
+    read_word: &dyn Fn(u64) -> Option<u64>,
+) -> Option<u64> {
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: 8,
+    };
+    let mut stack: Vec<u64> = cfa.into_iter().collect();
+    let mut pc = expression.0;
+    while !pc.is_empty() {
+        let op = gimli::Operation::parse(&mut pc, encoding).ok()?;
+        let value = match op {
+            gimli::Operation::RegisterOffset {
+                register, offset, ..
+            } => (regs.get(register)? as i64 + offset) as u64,
+            gimli::Operation::Deref { size, .. } => {
+                let word = read_word(stack.pop()?)?;
+                if size >= 8 {
+                    word
+                } else {
+                    word & ((1 << (8 * size as u64)) - 1)
+                }
+            }
+            gimli::Operation::UnsignedConstant { value } => value,
+            gimli::Operation::SignedConstant { value } => value as u64,
+            gimli::Operation::PlusConstant { value } => stack.pop()?.wrapping_add(value),
+            gimli::Operation::Plus => {
+                let rhs = stack.pop()?;
+                stack.pop()?.wrapping_add(rhs)
+            }
+            gimli::Operation::Minus => {
+                let rhs = stack.pop()?;
+                stack.pop()?.wrapping_sub(rhs)
+            }
+            gimli::Operation::And => {
+                let rhs = stack.pop()?;
+                stack.pop()? & rhs
+            }
+            gimli::Operation::Shl => {
+                let rhs = stack.pop()?;
+                stack.pop()?.checked_shl(rhs as u32).unwrap_or(0)
+            }
+            gimli::Operation::Ge => {
+                let rhs = stack.pop()? as i64;
+                (stack.pop()? as i64 >= rhs) as u64
+            }
+            _ => return None,
+        };
+        stack.push(value);
+    }
+    stack.pop()
+}